@@ -1,9 +1,18 @@
-use data::Data;
+use compression::CompressionAnalyzer;
+use convention::ConventionAnalyzer;
+use copies::CopyAnalyzer;
+use data::{default_name, Data, ROM_END};
 use disassembly;
 use gb::*;
+use noreturn::NoReturnAnalyzer;
+use pointers::PointerAnalyzer;
+use signatures;
+use sound::SoundAnalyzer;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::slice;
+use toolchain::ToolchainAnalyzer;
+use trace::TraceAnalyzer;
 
 pub trait RomAnalyzer {
     fn run(&self, cartridge: &Cartridge, data: &mut Data) -> bool;
@@ -28,10 +37,25 @@ struct FunctionAnalyzer;
 
 impl RomAnalyzer for FunctionAnalyzer {
     fn run(&self, cart: &Cartridge, data: &mut Data) -> bool {
-        let (is_new, entrypoint) = data.functions.get_or_add(Cartridge::ENTRY_POINT);
+        // The entry point is left out again if it isn't code
+        let started = data.functions.is_start(Cartridge::ENTRY_POINT)
+            || data.decode_errors.contains_key(&Cartridge::ENTRY_POINT);
 
-        if is_new {
+        if !started {
+            let (_, entrypoint) = data.functions.get_or_add(Cartridge::ENTRY_POINT);
             entrypoint.name = "entrypoint".to_string();
+
+            // Functions marked as not returning, and entry points the user
+            // gave, are analysed even if nothing calls them
+            let overrides: Vec<usize> = data
+                .noreturn_overrides
+                .iter()
+                .chain(data.entry_points.iter())
+                .cloned()
+                .collect();
+            for address in overrides {
+                data.functions.get_or_add(address);
+            }
             true
         } else {
             analyze_functions(cart, data)
@@ -39,30 +63,66 @@ impl RomAnalyzer for FunctionAnalyzer {
     }
 }
 
+// Why the function at `address` has no code: its first byte isn't an
+// instruction, like where a corrupt ROM calls into data
+fn entry_error(cart: &Cartridge, data: &Data, address: usize) -> Option<DecodeError> {
+    let rom_address = data
+        .rom_address(address)
+        .filter(|&rom_address| rom_address < cart.rom_size())?;
+    OpCode::decode_at(cart, rom_address)
+        .err()
+        .map(|error| DecodeError { address, ..error })
+}
+
 fn analyze_functions(cart: &Cartridge, data: &mut Data) -> bool {
-    let known_function_addresses: Vec<_> =
+    let mut known_function_addresses: Vec<_> =
         data.functions.iter().map(|(address, _)| *address).collect();
 
+    // Functions without code, like entry points the user got wrong, are
+    // reported instead of analysed
+    known_function_addresses.retain(|&address| match entry_error(cart, data, address) {
+        Some(error) => {
+            data.functions.remove(address);
+            data.decode_errors.insert(address, error);
+            false
+        }
+        None => true,
+    });
+
     let mut changes = false;
+    let mut owners: HashMap<usize, usize> = HashMap::new();
+    let mut jump_targets = HashSet::new();
 
     for function_address in known_function_addresses.into_iter() {
         let instructions = disassembly::collect_instructions(cart, data, function_address);
 
         for instruction in instructions.into_iter() {
-            if let Some(call_address) = instruction.call_target() {
+            *owners.entry(instruction.address).or_insert(0) += 1;
+            jump_targets.extend(instruction.jump_target());
+
+            // Calls into RAM are only followed once we know what was copied
+            // there
+            if let Some(call_address) = instruction
+                .call_target()
+                .filter(|&call_address| data.rom_address(call_address).is_some())
+            {
+                if let Some(error) = entry_error(cart, data, call_address) {
+                    data.decode_errors.insert(call_address, error);
+                    continue;
+                }
                 let (is_new_function, called_function) = data.functions.get_or_add(call_address);
                 changes |= is_new_function;
                 let is_new_callsite = called_function.call_sites.insert(instruction.address);
                 changes |= is_new_callsite;
             }
+        }
+    }
 
-            if instruction.is_return() {
-                let function = data.functions.get_mut(function_address).unwrap();
-                if !function.can_return {
-                    function.can_return = true;
-                    changes = true;
-                }
-            }
+    // Code jumped to from more than one function, like a shared epilogue,
+    // becomes a function of its own so that it is only decompiled once
+    for target in jump_targets {
+        if owners.get(&target).is_some_and(|&count| count > 1) {
+            changes |= data.functions.get_or_add(target).0;
         }
     }
 
@@ -91,6 +151,48 @@ impl RomAnalyzer for CompositeAnalyzer {
     }
 }
 
+/// Finds and analyses every function, starting from what is already known in
+/// `data`.
+pub fn analyse_functions(cartridge: &Cartridge, mut data: Data) -> Data {
+    let mut analyzer = CompositeAnalyzer::new();
+    analyzer.push(Box::new(FunctionAnalyzer) as Box<dyn RomAnalyzer>);
+    analyzer.push(Box::new(NoReturnAnalyzer) as Box<dyn RomAnalyzer>);
+    analyzer.push(Box::new(ToolchainAnalyzer) as Box<dyn RomAnalyzer>);
+    analyzer.push(Box::new(CopyAnalyzer) as Box<dyn RomAnalyzer>);
+    analyzer.push(Box::new(CompressionAnalyzer) as Box<dyn RomAnalyzer>);
+    analyzer.push(Box::new(ConventionAnalyzer) as Box<dyn RomAnalyzer>);
+    analyzer.push(Box::new(PointerAnalyzer) as Box<dyn RomAnalyzer>);
+    analyzer.push(Box::new(SoundAnalyzer) as Box<dyn RomAnalyzer>);
+    // Last, so that it only sees code static analysis can't reach once
+    // everything else has settled
+    analyzer.push(Box::new(TraceAnalyzer) as Box<dyn RomAnalyzer>);
+
+    analyzer.run_until_unchanged(cartridge, &mut data);
+
+    // Library functions are better known by their own names than by what
+    // they look like they do
+    signatures::apply(cartridge, &mut data);
+
+    let unnamed: Vec<usize> = data
+        .functions
+        .iter()
+        .filter(|(&address, function)| function.name == default_name(address))
+        .map(|(&address, _)| address)
+        .collect();
+    for address in unnamed {
+        let name = data.made_up_name(default_name(address));
+        data.functions.get_mut(address).unwrap().name = name;
+    }
+
+    // Names from symbol files win over any made up here
+    for (&address, function) in data.functions.iter_mut() {
+        if let Some(name) = data.symbols.get(address) {
+            function.name = name.to_string();
+        }
+    }
+    data
+}
+
 #[derive(Eq, PartialEq, Hash)]
 pub struct Todo {
     start_address: usize,
@@ -139,6 +241,12 @@ impl Todo {
     fn has_return(&self) -> bool {
         !self.return_addresses.is_empty()
     }
+
+    /// Whether a call returning to `return_address` is already under way, so
+    /// following it again would only grow the stack.
+    fn is_recursive(&self, return_address: usize) -> bool {
+        self.return_addresses.contains(&return_address)
+    }
 }
 
 pub struct AnalysisData {
@@ -146,11 +254,14 @@ pub struct AnalysisData {
     pub done: HashSet<Todo>,
     pub unknown_jumps: Vec<usize>,
     pub ancestors: HashMap<usize, Vec<usize>>,
+    /// Whether to print each instruction as it is traced.
+    echo: bool,
 }
 
 impl AnalysisData {
-    fn new() -> AnalysisData {
+    fn new(echo: bool) -> AnalysisData {
         AnalysisData {
+            echo,
             todo: Vec::new(),
             done: HashSet::new(),
             unknown_jumps: Vec::new(),
@@ -171,9 +282,9 @@ impl AnalysisData {
 }
 
 pub fn analyse(cartridge: &Cartridge) -> Result<AnalysisData, DecodeError> {
-    let mut data = AnalysisData::new();
+    let (data, result) = trace_static_paths(cartridge, true);
 
-    match analyse_static_paths(cartridge, &mut data) {
+    match result {
         Ok(()) => Ok(data),
         Err(error) => {
             print_error(&cartridge, &data, error);
@@ -182,6 +293,17 @@ pub fn analyse(cartridge: &Cartridge) -> Result<AnalysisData, DecodeError> {
     }
 }
 
+/// Traces paths from the entry point, keeping what was found before any
+/// error.
+pub fn trace_static_paths(
+    cartridge: &Cartridge,
+    echo: bool,
+) -> (AnalysisData, Result<(), DecodeError>) {
+    let mut data = AnalysisData::new(echo);
+    let result = analyse_static_paths(cartridge, &mut data);
+    (data, result)
+}
+
 pub fn print_error(cartridge: &Cartridge, data: &AnalysisData, error: DecodeError) {
     println!("");
     println!("Error trace:");
@@ -223,7 +345,12 @@ fn analyse_static_paths(cartridge: &Cartridge, data: &mut AnalysisData) -> Resul
         match data.todo.pop() {
             None => return Ok(()),
             Some(todo) => {
-                if data.done.contains(&todo) {
+                // Code copied to RAM is only followed by `analyse_functions`,
+                // and ROMs past 32KB are only mapped there through banks
+                if data.done.contains(&todo)
+                    || todo.start_address >= ROM_END
+                    || todo.start_address >= cartridge.rom_size()
+                {
                     continue;
                 }
 
@@ -256,15 +383,23 @@ fn analyse_path(
     todo: &Todo,
 ) -> Result<Vec<Todo>, DecodeError> {
     let mut next_address = todo.start_address;
+    let mut previous_instruction = None;
     let mut result = Vec::new();
 
     loop {
         let current_address = next_address;
         let instruction = OpCode::decode_at(&cartridge, current_address)?;
-        for _ in 0..todo.return_addresses.len() {
-            print!("  ");
+        let follows_push = matches!(
+            previous_instruction,
+            Some(OpCode::PUSH_BC) | Some(OpCode::PUSH_DE) | Some(OpCode::PUSH_HL)
+        );
+        previous_instruction = Some(instruction);
+        if data.echo {
+            for _ in 0..todo.return_addresses.len() {
+                print!("  ");
+            }
+            println!("{0:04X}: {1}", current_address, instruction);
         }
-        println!("{0:04X}: {1}", current_address, instruction);
         next_address += instruction.size();
 
         match instruction {
@@ -322,7 +457,9 @@ fn analyse_path(
             | OpCode::CALL_NZ_a16(value) => {
                 let target = value.value as usize;
 
-                result.push(todo.call(target, next_address));
+                if !todo.is_recursive(next_address) {
+                    result.push(todo.call(target, next_address));
+                }
                 data.add_ancestor(current_address, target);
 
                 result.push(todo.continue_from(next_address));
@@ -333,16 +470,20 @@ fn analyse_path(
             OpCode::CALL_a16(value) => {
                 let target = value.value as usize;
 
-                result.push(todo.call(target, next_address));
+                if !todo.is_recursive(next_address) {
+                    result.push(todo.call(target, next_address));
+                }
                 data.add_ancestor(current_address, target);
 
                 return Ok(result);
             }
+            // A pushed address followed by a return is a computed jump, and a
+            // return without a known return address has unbalanced the stack
+            OpCode::RET if follows_push || !todo.has_return() => {
+                data.unknown_jumps.push(current_address);
+                return Ok(result);
+            }
             OpCode::RET => {
-                if !todo.has_return() {
-                    panic!("No return address while at return instruction");
-                }
-
                 let next_todo = todo.ret();
                 let return_address = next_todo.start_address;
 
@@ -351,11 +492,17 @@ fn analyse_path(
 
                 return Ok(result);
             }
-            OpCode::RET_C | OpCode::RET_NC | OpCode::RET_Z | OpCode::RET_NZ | OpCode::RETI => {
-                if !todo.has_return() {
-                    panic!("No return address while at return instruction");
-                }
+            OpCode::RET_C | OpCode::RET_NC | OpCode::RET_Z | OpCode::RET_NZ | OpCode::RETI
+                if !todo.has_return() =>
+            {
+                data.unknown_jumps.push(current_address);
+
+                result.push(todo.continue_from(next_address));
+                data.add_ancestor(current_address, next_address);
 
+                return Ok(result);
+            }
+            OpCode::RET_C | OpCode::RET_NC | OpCode::RET_Z | OpCode::RET_NZ | OpCode::RETI => {
                 let next_todo = todo.ret();
                 let return_address = next_todo.start_address;
 
@@ -377,7 +524,9 @@ fn analyse_path(
             | OpCode::RST_38H => {
                 let value = get_rst_value(instruction);
 
-                result.push(todo.call(value, next_address));
+                if !todo.is_recursive(next_address) {
+                    result.push(todo.call(value, next_address));
+                }
                 data.add_ancestor(current_address, value);
 
                 result.push(todo.continue_from(next_address));
@@ -391,3 +540,66 @@ fn analyse_path(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decompiler;
+
+    #[test]
+    fn calls_into_bytes_that_are_not_instructions_are_reported() {
+        // $D3 at $0200
+        let cart = Cartridge::with_function(&[0xD3]);
+        let data = analyse_functions(&cart, Data::new());
+        assert!(data.functions.get(0x200).is_none());
+        assert_eq!(data.decode_errors[&0x200].opcode, 0xD3);
+
+        for (&address, _) in data.functions.iter() {
+            decompiler::decompile(&cart, &data, address);
+        }
+    }
+
+    #[test]
+    fn tails_reached_by_conditional_jumps_are_shared() {
+        // call $0200 / call $0208 / jr @, with `and a / jr z,$0210 / inc b /
+        // ret` at $0200 and `and a / jp z,$0210 / inc c / ret` at $0208 both
+        // ending in `ld a,b / ret` at $0210
+        let cart = Cartridge::with_code(&[
+            (0x100, &[0xC3, 0x50, 0x01]),
+            (0x150, &[0xCD, 0x00, 0x02, 0xCD, 0x08, 0x02, 0x18, 0xFE]),
+            (0x200, &[0xA7, 0x28, 0x0D, 0x04, 0xC9]),
+            (0x208, &[0xA7, 0xCA, 0x10, 0x02, 0x0C, 0xC9]),
+            (0x210, &[0x78, 0xC9]),
+        ]);
+        let data = analyse_functions(&cart, Data::new());
+        assert!(data.functions.is_start(0x210));
+
+        // Every instruction belongs to one function
+        let mut owners = HashMap::new();
+        for (&address, _) in data.functions.iter() {
+            for instruction in disassembly::collect_instructions(&cart, &data, address) {
+                *owners.entry(instruction.address).or_insert(0) += 1;
+            }
+        }
+        assert!(owners.values().all(|&count| count == 1), "{:?}", owners);
+
+        let output = decompiler::decompile(&cart, &data, 0x200);
+        assert!(
+            output.contains(&format!("return {}(", data.name(0x210))),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn calls_into_hram_are_not_traced_in_rom() {
+        // call $FF80 / jr @, on a 64KB ROM with a byte at $FF80 that isn't
+        // an instruction
+        let mut rom = vec![0; 0x10000];
+        rom[0x100..0x105].copy_from_slice(&[0xCD, 0x80, 0xFF, 0x18, 0xFE]);
+        rom[0xFF80] = 0xD3;
+        let (data, result) = trace_static_paths(&Cartridge::new(rom), false);
+        assert!(result.is_ok());
+        assert!(data.done.iter().all(|todo| todo.start_address < ROM_END));
+    }
+}
@@ -0,0 +1,541 @@
+use analyzer::RomAnalyzer;
+use constants;
+use constants::KnownState;
+use data::*;
+use disassembly;
+use gb::*;
+use graphics;
+use ir::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Anything that decompresses to more than this is taken to be a misreading.
+const MAX_SIZE: usize = 0x10000;
+/// How many instructions can come between those of a signature.
+const MAX_GAP: usize = 12;
+
+/// Instructions a decompression routine has, in this order, as the
+/// disassembly shows them.
+struct Signature {
+    compression: Compression,
+    instructions: &'static [&'static str],
+}
+
+// The most specific signatures come first, as pb16 routines also match pb8
+const SIGNATURES: [Signature; 4] = [
+    Signature {
+        compression: Compression::PokemonLz,
+        // Checking for the end marker, then for a long command
+        instructions: &["LD A,(HL)", "CP FF", "RET Z", "AND E0", "CP E0"],
+    },
+    Signature {
+        compression: Compression::Pb16,
+        // A control byte shifted through B with a sentinel bit, read twice
+        // for each byte of the two planes
+        instructions: &["LD A,(HL+)", "SCF", "RLA", "LD B,A", "SLA B", "SLA B"],
+    },
+    Signature {
+        compression: Compression::Pb8,
+        instructions: &["LD A,(HL+)", "SCF", "RLA", "LD B,A", "SLA B"],
+    },
+    Signature {
+        compression: Compression::Rle,
+        // Zero ends the data, bit 7 tells runs from literals
+        instructions: &["LD A,(HL+)", "RET Z", "BIT 7,A", "AND 7F", "LD A,(HL+)"],
+    },
+];
+
+// Whether `signature` appears in `instructions` from `start`, with at most
+// `MAX_GAP` instructions between each of its own
+fn matches_from(signature: &[&str], instructions: &[String], start: usize) -> bool {
+    let (first, rest) = match signature.split_first() {
+        Some(split) => split,
+        None => return true,
+    };
+    instructions
+        .iter()
+        .enumerate()
+        .skip(start)
+        .take(MAX_GAP + 1)
+        .filter(|(_, instruction)| instruction == first)
+        .any(|(index, _)| matches_from(rest, instructions, index + 1))
+}
+
+/// The compression that the routine at `address` decompresses, if it looks
+/// like a known one.
+pub fn identify(cart: &Cartridge, data: &Data, address: usize) -> Option<Compression> {
+    let mut instructions = disassembly::collect_instructions(cart, data, address);
+    instructions.sort_by_key(|instruction| instruction.address);
+    let text: Vec<String> = instructions
+        .iter()
+        .map(|instruction| instruction.op_code.to_string())
+        .collect();
+
+    SIGNATURES
+        .iter()
+        .find(|signature| {
+            let (first, rest) = signature.instructions.split_first().unwrap();
+            text.iter()
+                .enumerate()
+                .filter(|(_, instruction)| instruction == first)
+                .any(|(index, _)| matches_from(rest, &text, index + 1))
+        })
+        .map(|signature| signature.compression)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn next(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+}
+
+// A control byte then its length: with bit 7 set, the next byte repeated,
+// otherwise that many literal bytes. Zero ends the data.
+fn decompress_rle(input: &mut Reader) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    loop {
+        let control = input.next()?;
+        if control == 0 {
+            return Some(output);
+        }
+        let len = (control & 0x7F) as usize;
+        if control & 0x80 != 0 {
+            let byte = input.next()?;
+            output.extend((0..len).map(|_| byte));
+        } else {
+            for _ in 0..len {
+                output.push(input.next()?);
+            }
+        }
+        if output.len() > MAX_SIZE {
+            return None;
+        }
+    }
+}
+
+// Packets of a control byte and up to eight literal bytes. Each bit of the
+// control byte, from the top, is set for a byte repeated from `distance`
+// bytes before, which are zero before the start, and clear for a literal.
+fn decompress_packets(input: &mut Reader, size: usize, distance: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    while output.len() < size {
+        let control = input.next()?;
+        for bit in (0..8).rev() {
+            let byte = if control >> bit & 1 != 0 {
+                output
+                    .len()
+                    .checked_sub(distance)
+                    .map_or(0, |index| output[index])
+            } else {
+                input.next()?
+            };
+            output.push(byte);
+        }
+    }
+    Some(output)
+}
+
+// Commands in the top three bits and a length less one in the rest, or for
+// long commands, in the next two bits and ten more. `$FF` ends the data.
+fn decompress_pokemon_lz(input: &mut Reader) -> Option<Vec<u8>> {
+    let mut output: Vec<u8> = Vec::new();
+    loop {
+        let control = input.next()?;
+        if control == 0xFF {
+            return Some(output);
+        }
+        let (command, len) = if control & 0xE0 == 0xE0 {
+            let high = (control & 3) as usize;
+            (control >> 2 & 7, (high << 8 | input.next()? as usize) + 1)
+        } else {
+            (control >> 5, (control & 0x1F) as usize + 1)
+        };
+
+        match command {
+            // Literal
+            0 => {
+                for _ in 0..len {
+                    output.push(input.next()?);
+                }
+            }
+            // Iterate
+            1 => {
+                let byte = input.next()?;
+                output.extend((0..len).map(|_| byte));
+            }
+            // Alternate
+            2 => {
+                let bytes = [input.next()?, input.next()?];
+                output.extend((0..len).map(|index| bytes[index % 2]));
+            }
+            // Zero
+            3 => output.extend((0..len).map(|_| 0)),
+            // Repeat, flip and reverse what is already decompressed, from
+            // an offset back from the end or from the start
+            4..=6 => {
+                let offset = input.next()?;
+                let start = if offset & 0x80 != 0 {
+                    output.len().checked_sub((offset & 0x7F) as usize + 1)?
+                } else {
+                    (offset as usize) << 8 | input.next()? as usize
+                };
+                for index in 0..len {
+                    let from = if command == 6 {
+                        start.checked_sub(index)?
+                    } else {
+                        start + index
+                    };
+                    let byte = *output.get(from)?;
+                    output.push(if command == 5 {
+                        byte.reverse_bits()
+                    } else {
+                        byte
+                    });
+                }
+            }
+            _ => return None,
+        }
+        if output.len() > MAX_SIZE {
+            return None;
+        }
+    }
+}
+
+/// Decompresses `bytes`, returning the bytes decompressed and how many were
+/// read. Formats that don't mark their end need the `count` of 16-byte
+/// (pb16) or 8-byte (pb8) units to decompress.
+pub fn decompress(
+    compression: Compression,
+    bytes: &[u8],
+    count: Option<usize>,
+) -> Option<(Vec<u8>, usize)> {
+    let mut input = Reader { bytes, position: 0 };
+    let output = match compression {
+        Compression::Rle => decompress_rle(&mut input)?,
+        Compression::Pb8 => decompress_packets(&mut input, count? * 8, 1)?,
+        Compression::Pb16 => decompress_packets(&mut input, count? * 16, 2)?,
+        Compression::PokemonLz => decompress_pokemon_lz(&mut input)?,
+    };
+    if output.is_empty() {
+        None
+    } else {
+        Some((output, input.position))
+    }
+}
+
+/// The bytes `compressed` decompresses to.
+pub fn decompressed(cart: &Cartridge, compressed: &CompressedData) -> Vec<u8> {
+    let bytes = &cart[compressed.source..compressed.source + compressed.len];
+    decompress(compressed.compression, bytes, compressed.count)
+        .map(|(output, _)| output)
+        .unwrap_or_default()
+}
+
+/// Writes each block of compressed data to `directory` as it is in the ROM,
+/// for `INCBIN`, and decompressed: as 2bpp tiles and a PNG of them when
+/// it goes to tile memory, and as a `.bin` file otherwise. Returns the
+/// number of blocks.
+pub fn write_compressed(cart: &Cartridge, data: &Data, directory: &Path) -> io::Result<usize> {
+    fs::create_dir_all(directory)?;
+
+    let mut blocks: Vec<&CompressedData> = Vec::new();
+    for compressed in &data.compressed {
+        if blocks.iter().all(|block| block.source != compressed.source) {
+            blocks.push(compressed);
+        }
+    }
+
+    for compressed in &blocks {
+        let name = data.label(compressed.source);
+        fs::write(
+            directory.join(format!("{}.{}", name, compressed.compression.extension())),
+            &cart[compressed.source..compressed.source + compressed.len],
+        )?;
+
+        let output = decompressed(cart, compressed);
+        let (start, end) = TILE_DATA;
+        let is_tiles = compressed
+            .destination
+            .is_some_and(|destination| destination >= start && destination + output.len() <= end)
+            && output.len().is_multiple_of(TileFormat::TwoBpp.tile_size());
+        if is_tiles {
+            fs::write(directory.join(format!("{}.2bpp", name)), &output)?;
+            fs::write(
+                directory.join(format!("{}.png", name)),
+                graphics::tiles_to_png(&output, TileFormat::TwoBpp),
+            )?;
+        } else {
+            fs::write(directory.join(format!("{}.bin", name)), &output)?;
+        }
+    }
+    Ok(blocks.len())
+}
+
+// What a call to a decompression routine unpacks, going by the source in HL,
+// the destination in DE and any count in B
+fn call_data(
+    cart: &Cartridge,
+    state: &KnownState,
+    compression: Compression,
+) -> Option<CompressedData> {
+    let source = state.pair(Register::H, Register::L)? as usize;
+    let destination = state
+        .pair(Register::D, Register::E)
+        .map(|value| value as usize);
+    let count = match compression {
+        Compression::Pb8 | Compression::Pb16 => {
+            let count = state.get(Var::Register(Register::B)).as_const()? as usize;
+            Some(if count == 0 { 0x100 } else { count })
+        }
+        Compression::Rle | Compression::PokemonLz => None,
+    };
+
+    let end = cart.rom_size().min(ROM_END);
+    if source < Cartridge::HEADER_END || source >= end {
+        return None;
+    }
+    let (output, len) = decompress(compression, &cart[source..end], count)?;
+    Some(CompressedData {
+        source,
+        len,
+        destination,
+        size: output.len(),
+        compression,
+        count,
+    })
+}
+
+fn find_calls(
+    cart: &Cartridge,
+    data: &Data,
+    address: usize,
+    routines: &HashMap<usize, Compression>,
+    found: &mut Vec<CompressedData>,
+) {
+    let chunks = disassembly::chunk(cart, data, address);
+    let known_values = constants::analyse(cart, data, &chunks);
+
+    for instruction in chunks.instructions() {
+        let compression = match instruction
+            .call_target()
+            .and_then(|target| routines.get(&target))
+        {
+            Some(compression) => *compression,
+            None => continue,
+        };
+        found.extend(
+            known_values
+                .at(instruction.address)
+                .and_then(|state| call_data(cart, state, compression)),
+        );
+    }
+}
+
+/// Finds decompression routines by their signatures, and the data that
+/// calls to them decompress, so it isn't taken for code or text.
+pub struct CompressionAnalyzer;
+
+impl RomAnalyzer for CompressionAnalyzer {
+    fn run(&self, cart: &Cartridge, data: &mut Data) -> bool {
+        let mut addresses: Vec<usize> =
+            data.functions.iter().map(|(address, _)| *address).collect();
+        addresses.sort();
+
+        let routines: HashMap<usize, Compression> = addresses
+            .iter()
+            .filter_map(|&address| Some((address, identify(cart, data, address)?)))
+            .collect();
+        if routines.is_empty() {
+            return false;
+        }
+
+        let mut found = Vec::new();
+        for &address in &addresses {
+            find_calls(cart, data, address, &routines, &mut found);
+        }
+
+        let mut changes = false;
+        for compressed in found {
+            if !data.compressed.contains(&compressed) {
+                data.compressed.push(compressed);
+                changes = true;
+            }
+        }
+
+        for (&address, compression) in &routines {
+            let name = match compression {
+                Compression::Rle => "DecompressRLE",
+                Compression::Pb8 => "DecompressPB8",
+                Compression::Pb16 => "DecompressPB16",
+                Compression::PokemonLz => "DecompressLZ",
+            };
+            data.functions.suggest_name(address, name.to_string());
+        }
+
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tiles with runs, repeats and literals, like the graphics these
+    // formats are used for
+    fn sample() -> Vec<u8> {
+        let mut bytes = vec![0; 24];
+        bytes.extend((0..40).map(|index| (index * 37 % 256) as u8));
+        bytes.extend(vec![0xFF; 192]);
+        bytes.extend([0x3C, 0x3C, 0x42, 0x42].iter().cycle().take(32));
+        bytes
+    }
+
+    fn compress_rle(bytes: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut literals: Vec<u8> = Vec::new();
+        let mut position = 0;
+        while position < bytes.len() {
+            let byte = bytes[position];
+            let run = bytes[position..]
+                .iter()
+                .take(0x7F)
+                .take_while(|&&other| other == byte)
+                .count();
+            if (run >= 3 || literals.len() == 0x7F) && !literals.is_empty() {
+                output.push(literals.len() as u8);
+                output.append(&mut literals);
+            }
+            if run >= 3 {
+                output.extend_from_slice(&[0x80 | run as u8, byte]);
+                position += run;
+            } else {
+                literals.push(byte);
+                position += 1;
+            }
+        }
+        if !literals.is_empty() {
+            output.push(literals.len() as u8);
+            output.append(&mut literals);
+        }
+        output.push(0);
+        output
+    }
+
+    fn compress_packets(bytes: &[u8], distance: usize) -> Vec<u8> {
+        let mut output = Vec::new();
+        for (packet, chunk) in bytes.chunks(8).enumerate() {
+            let mut control = 0;
+            let mut literals = Vec::new();
+            for (index, &byte) in chunk.iter().enumerate() {
+                let position = packet * 8 + index;
+                let previous = position.checked_sub(distance).map_or(0, |from| bytes[from]);
+                if byte == previous {
+                    control |= 0x80 >> index;
+                } else {
+                    literals.push(byte);
+                }
+            }
+            output.push(control);
+            output.extend(literals);
+        }
+        output
+    }
+
+    // Only literals, iterates and zeros, at most 32 bytes each
+    fn compress_pokemon_lz(bytes: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        for chunk in bytes.chunks(32) {
+            let len = chunk.len() as u8 - 1;
+            if chunk.iter().all(|&byte| byte == 0) {
+                output.push(0x60 | len);
+            } else if chunk.iter().all(|&byte| byte == chunk[0]) {
+                output.extend_from_slice(&[0x20 | len, chunk[0]]);
+            } else {
+                output.push(len);
+                output.extend_from_slice(chunk);
+            }
+        }
+        output.push(0xFF);
+        output
+    }
+
+    #[test]
+    fn rle_round_trips() {
+        let compressed = compress_rle(&sample());
+        assert_eq!(
+            decompress(Compression::Rle, &compressed, None),
+            Some((sample(), compressed.len()))
+        );
+    }
+
+    #[test]
+    fn pb8_and_pb16_round_trip() {
+        let compressed = compress_packets(&sample(), 1);
+        assert_eq!(
+            decompress(Compression::Pb8, &compressed, Some(sample().len() / 8)),
+            Some((sample(), compressed.len()))
+        );
+
+        let compressed = compress_packets(&sample(), 2);
+        assert_eq!(
+            decompress(Compression::Pb16, &compressed, Some(sample().len() / 16)),
+            Some((sample(), compressed.len()))
+        );
+    }
+
+    #[test]
+    fn pokemon_lz_round_trips() {
+        let compressed = compress_pokemon_lz(&sample());
+        assert_eq!(
+            decompress(Compression::PokemonLz, &compressed, None),
+            Some((sample(), compressed.len()))
+        );
+    }
+
+    #[test]
+    fn pokemon_lz_copies_what_is_decompressed() {
+        let compressed = [
+            // Literal $01 $80, then alternate $AA $55 three times
+            0x01, 0x01, 0x80, 0x42, 0xAA, 0x55,
+            // Repeat 2 from the start, flip 2 from one back, reverse 3 from
+            // the fourth byte
+            0x81, 0x00, 0x00, 0xA1, 0x80, 0xC2, 0x00, 0x03,
+            // A long zero run of 258 bytes
+            0xED, 0x01, 0xFF,
+        ];
+        let mut expected = vec![0x01, 0x80, 0xAA, 0x55, 0xAA, 0x01, 0x80, 0x01, 0x80];
+        expected.extend_from_slice(&[0x55, 0xAA, 0x80]);
+        expected.extend(vec![0; 258]);
+
+        assert_eq!(
+            decompress(Compression::PokemonLz, &compressed, None),
+            Some((expected, compressed.len()))
+        );
+    }
+
+    #[test]
+    fn truncated_data_is_not_decompressed() {
+        let compressed = compress_rle(&sample());
+        let truncated = &compressed[..compressed.len() - 1];
+        assert_eq!(decompress(Compression::Rle, truncated, None), None);
+
+        let compressed = compress_packets(&sample(), 2);
+        assert_eq!(
+            decompress(
+                Compression::Pb16,
+                &compressed,
+                Some(sample().len() / 16 + 1)
+            ),
+            None
+        );
+    }
+}
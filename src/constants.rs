@@ -0,0 +1,454 @@
+use convention::implicit_defs;
+use data::Data;
+use disassembly::ChunkSet;
+use gb::*;
+use instruction_walker::{walk_chunks, Join};
+use ir::*;
+use ssa;
+use std::collections::HashMap;
+use std::fmt;
+use xrefs::Access;
+
+/// Loads below this address read the fixed ROM bank, so their value is known.
+const FIXED_BANK_END: u32 = 0x4000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KnownBits {
+    /// Bits set here have the corresponding bit of `value`; the rest are
+    /// unknown.
+    pub known: u32,
+    pub value: u32,
+}
+
+impl KnownBits {
+    pub fn constant(value: u32) -> KnownBits {
+        KnownBits { known: !0, value }
+    }
+
+    pub fn unknown() -> KnownBits {
+        KnownBits { known: 0, value: 0 }
+    }
+
+    /// An unknown value that fits in `width` bits.
+    pub fn unknown_width(width: u8) -> KnownBits {
+        KnownBits {
+            known: !low_mask(u32::from(width)),
+            value: 0,
+        }
+    }
+
+    fn new(known: u32, value: u32) -> KnownBits {
+        KnownBits {
+            known,
+            value: value & known,
+        }
+    }
+
+    pub fn as_const(&self) -> Option<u32> {
+        if self.known == !0 {
+            Some(self.value)
+        } else {
+            None
+        }
+    }
+
+    fn is_unknown_within(&self, width: u8) -> bool {
+        self.known & low_mask(u32::from(width)) == 0
+    }
+
+    fn format(&self, width: u8) -> String {
+        match (self.as_const(), width) {
+            (Some(value), 1) => format!("{}", value),
+            (Some(value), 8) => format!("${:02X}", value),
+            (Some(value), _) => format!("${:04X}", value),
+            (None, _) => {
+                let bits: String = (0..width)
+                    .rev()
+                    .map(|bit| match (self.known >> bit & 1, self.value >> bit & 1) {
+                        (0, _) => '?',
+                        (_, 0) => '0',
+                        _ => '1',
+                    })
+                    .collect();
+                format!("%{}", bits)
+            }
+        }
+    }
+}
+
+impl Join for KnownBits {
+    fn join(&self, other: &KnownBits) -> KnownBits {
+        KnownBits::new(
+            self.known & other.known & !(self.value ^ other.value),
+            self.value,
+        )
+    }
+}
+
+fn low_mask(bits: u32) -> u32 {
+    if bits >= 32 {
+        !0
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+// What `op` gives with the same operand on both sides, whatever its value,
+// like `xor a` clearing A
+fn same_operands(op: BinaryOp) -> Option<u32> {
+    match op {
+        BinaryOp::Xor | BinaryOp::Sub | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Gt => Some(0),
+        BinaryOp::Eq | BinaryOp::Le | BinaryOp::Ge => Some(1),
+        _ => None,
+    }
+}
+
+fn binary(op: BinaryOp, lhs: KnownBits, rhs: KnownBits) -> KnownBits {
+    if let (Some(lhs), Some(rhs)) = (lhs.as_const(), rhs.as_const()) {
+        return KnownBits::constant(op.apply(lhs as i32, rhs as i32) as u32);
+    }
+
+    match op {
+        BinaryOp::And => KnownBits::new(
+            (lhs.known & rhs.known) | (lhs.known & !lhs.value) | (rhs.known & !rhs.value),
+            lhs.value & rhs.value,
+        ),
+        BinaryOp::Or => KnownBits::new(
+            (lhs.known & rhs.known) | lhs.value | rhs.value,
+            lhs.value | rhs.value,
+        ),
+        BinaryOp::Xor => KnownBits::new(lhs.known & rhs.known, lhs.value ^ rhs.value),
+        BinaryOp::Shl => match rhs.as_const() {
+            Some(amount) if amount < 32 => KnownBits::new(
+                (lhs.known << amount) | low_mask(amount),
+                lhs.value << amount,
+            ),
+            _ => KnownBits::unknown(),
+        },
+        BinaryOp::Shr => match rhs.as_const() {
+            Some(amount) if amount < 32 => {
+                KnownBits::new((lhs.known >> amount) | !(!0 >> amount), lhs.value >> amount)
+            }
+            _ => KnownBits::unknown(),
+        },
+        // The low bits of a sum only depend on the low bits of its operands
+        BinaryOp::Add | BinaryOp::Sub => {
+            let bits = (lhs.known.trailing_ones()).min(rhs.known.trailing_ones());
+            let value = if op == BinaryOp::Add {
+                lhs.value.wrapping_add(rhs.value)
+            } else {
+                lhs.value.wrapping_sub(rhs.value)
+            };
+            KnownBits::new(low_mask(bits), value)
+        }
+        _ => KnownBits::unknown_width(1),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KnownState {
+    values: [KnownBits; Var::COUNT],
+}
+
+impl KnownState {
+    pub fn unknown() -> KnownState {
+        let mut state = KnownState {
+            values: [KnownBits::unknown(); Var::COUNT],
+        };
+        for &var in ssa::TRACKED_VARS.iter() {
+            state.set(var, KnownBits::unknown_width(var.width()));
+        }
+        state
+    }
+
+    pub fn get(&self, var: Var) -> KnownBits {
+        self.values[var.index()]
+    }
+
+    pub fn set(&mut self, var: Var, value: KnownBits) {
+        self.values[var.index()] = value;
+    }
+
+    /// The value of a register pair, if both halves are known.
+    pub fn pair(&self, high: Register, low: Register) -> Option<u32> {
+        let high = self.get(Var::Register(high)).as_const()?;
+        let low = self.get(Var::Register(low)).as_const()?;
+        Some((high << 8) | low)
+    }
+
+    fn evaluate(&self, cart: &Cartridge, expr: &Expr) -> KnownBits {
+        match expr {
+            Expr::Const(value) => KnownBits::constant(u32::from(*value)),
+            Expr::Var(var) => self.get(*var),
+            Expr::Load(address) => match self.evaluate(cart, address).as_const() {
+                Some(address) if address < FIXED_BANK_END => {
+                    KnownBits::constant(u32::from(cart[address as usize]))
+                }
+                _ => KnownBits::unknown_width(8),
+            },
+            Expr::Unary(UnaryOp::Not, operand) => {
+                let operand = self.evaluate(cart, operand);
+                if operand.known & operand.value != 0 {
+                    KnownBits::constant(0)
+                } else if operand.as_const() == Some(0) {
+                    KnownBits::constant(1)
+                } else {
+                    KnownBits::unknown_width(1)
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => match same_operands(*op) {
+                Some(value) if lhs == rhs => KnownBits::constant(value),
+                _ => binary(*op, self.evaluate(cart, lhs), self.evaluate(cart, rhs)),
+            },
+            Expr::Intrinsic(intrinsic, args) => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    match self.evaluate(cart, arg).as_const() {
+                        Some(value) => values.push(value as i32),
+                        None => return KnownBits::unknown(),
+                    }
+                }
+                KnownBits::constant(intrinsic.apply(&values) as u32)
+            }
+        }
+    }
+
+    fn execute(&mut self, cart: &Cartridge, data: &Data, stmt: &Stmt) {
+        match stmt {
+            Stmt::Assign(var, value) => {
+                let value = self.evaluate(cart, value);
+                self.set(*var, value);
+            }
+            Stmt::AssignPair(high, low, value) => {
+                let value = self.evaluate(cart, value);
+                let high_value = binary(BinaryOp::Shr, value, KnownBits::constant(8));
+                let mask = KnownBits::constant(0xFF);
+                self.set(*high, binary(BinaryOp::And, high_value, mask));
+                self.set(*low, binary(BinaryOp::And, value, mask));
+            }
+            _ => (),
+        }
+
+        for var in implicit_defs(data, stmt) {
+            self.set(var, KnownBits::unknown_width(var.width()));
+        }
+    }
+}
+
+impl Join for KnownState {
+    fn join(&self, other: &KnownState) -> KnownState {
+        let mut joined = *self;
+        for (value, other) in joined.values.iter_mut().zip(other.values.iter()) {
+            *value = value.join(other);
+        }
+        joined
+    }
+}
+
+const PAIRS: [(Register, Register); 3] = [
+    (Register::B, Register::C),
+    (Register::D, Register::E),
+    (Register::H, Register::L),
+];
+
+// Lists the registers and the zero and carry flags with at least one known
+// bit, combining register pairs when both halves are known
+impl fmt::Display for KnownState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        let push_var = |parts: &mut Vec<String>, var: Var| {
+            let value = self.get(var);
+            if !value.is_unknown_within(var.width()) {
+                parts.push(format!("{}={}", var, value.format(var.width())));
+            }
+        };
+
+        push_var(&mut parts, Var::Register(Register::A));
+
+        for &(high, low) in PAIRS.iter() {
+            match self.pair(high, low) {
+                Some(value) => {
+                    let name = pair_name(Var::Register(high), Var::Register(low)).unwrap();
+                    parts.push(format!(
+                        "{}={}",
+                        name,
+                        KnownBits::constant(value).format(16)
+                    ));
+                }
+                None => {
+                    push_var(&mut parts, Var::Register(high));
+                    push_var(&mut parts, Var::Register(low));
+                }
+            }
+        }
+
+        push_var(&mut parts, Var::StackPointer);
+        push_var(&mut parts, Var::Flag(Flag::Zero));
+        push_var(&mut parts, Var::Flag(Flag::Carry));
+
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// Known register and flag values before each instruction of a function.
+pub struct KnownValues {
+    states: HashMap<usize, KnownState>,
+}
+
+impl KnownValues {
+    pub fn at(&self, address: usize) -> Option<&KnownState> {
+        self.states.get(&address)
+    }
+}
+
+/// The known values after running `instruction` from `state`.
+pub fn step(
+    cart: &Cartridge,
+    data: &Data,
+    mut state: KnownState,
+    instruction: &Instruction,
+) -> KnownState {
+    for stmt in lift(instruction) {
+        state.execute(cart, data, &stmt);
+    }
+    // Temporaries never outlive the instruction that defines them
+    for temp in 0..3 {
+        state.set(Var::Temp(temp), KnownBits::unknown());
+    }
+    state
+}
+
+/// The addresses `instruction` reads and writes that are known when it runs
+/// from `state`, leaving out the stack.
+pub fn accesses(
+    cart: &Cartridge,
+    data: &Data,
+    mut state: KnownState,
+    instruction: &Instruction,
+) -> Vec<(Access, usize)> {
+    let known = |state: &KnownState, address: &Expr| {
+        if address.vars().contains(&Var::StackPointer) {
+            None
+        } else {
+            state
+                .evaluate(cart, address)
+                .as_const()
+                .map(|address| address as usize & 0xFFFF)
+        }
+    };
+
+    let mut accesses = Vec::new();
+    for stmt in lift(instruction) {
+        stmt.visit_exprs(|expr| {
+            for address in expr.load_addresses() {
+                accesses.extend(known(&state, address).map(|address| (Access::Read, address)));
+            }
+        });
+        if let Stmt::Store(address, _) = &stmt {
+            accesses.extend(known(&state, address).map(|address| (Access::Write, address)));
+        }
+        state.execute(cart, data, &stmt);
+    }
+    accesses
+}
+
+/// The addresses `instruction` writes to that are known when it runs from
+/// `state`, with the values written if those are known too.
+pub fn stores(
+    cart: &Cartridge,
+    data: &Data,
+    mut state: KnownState,
+    instruction: &Instruction,
+) -> Vec<(usize, Option<u32>)> {
+    let mut stores = Vec::new();
+    for stmt in lift(instruction) {
+        if let Stmt::Store(address, value) = &stmt {
+            if let Some(address) = state.evaluate(cart, address).as_const() {
+                let value = state.evaluate(cart, value).as_const();
+                stores.push((address as usize & 0xFFFF, value));
+            }
+        }
+        state.execute(cart, data, &stmt);
+    }
+    stores
+}
+
+pub fn analyse(cart: &Cartridge, data: &Data, chunks: &ChunkSet) -> KnownValues {
+    let states = walk_chunks(chunks, KnownState::unknown(), |state, instruction| {
+        step(cart, data, state, instruction)
+    });
+
+    KnownValues { states }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+    use disassembly;
+
+    fn known_values(code: &[u8]) -> (Cartridge, Data, KnownValues) {
+        let cart = Cartridge::with_function(code);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        let values = analyse(&cart, &data, &disassembly::chunk(&cart, &data, 0x200));
+        (cart, data, values)
+    }
+
+    #[test]
+    fn keeps_the_bits_both_paths_agree_on() {
+        // and a / jr z,.other / ld b,$12 / jr .done / .other: ld b,$13
+        // .done: ret
+        let (_, _, values) =
+            known_values(&[0xA7, 0x28, 0x04, 0x06, 0x12, 0x18, 0x02, 0x06, 0x13, 0xC9]);
+        let b = values.at(0x209).unwrap().get(Var::Register(Register::B));
+        assert_eq!(b.known & 0xFF, 0xFE);
+        assert_eq!(b.value & 0xFF, 0x12);
+    }
+
+    #[test]
+    fn keeps_pointers_loaded_before_a_loop() {
+        // ld hl,rLCDC / .loop: bit 7,[hl] / jr nz,.loop / ld [hl],a / ret
+        let (cart, data, values) =
+            known_values(&[0x21, 0x40, 0xFF, 0xCB, 0x7E, 0x20, 0xFC, 0x77, 0xC9]);
+        let state = *values.at(0x207).unwrap();
+        assert_eq!(
+            values.at(0x203).unwrap().pair(Register::H, Register::L),
+            Some(0xFF40)
+        );
+        assert_eq!(state.pair(Register::H, Register::L), Some(0xFF40));
+
+        let store = OpCode::decode_at(&cart, 0x207).unwrap();
+        let instruction = Instruction {
+            address: 0x207,
+            op_code: store,
+        };
+        assert_eq!(
+            accesses(&cart, &data, state, &instruction),
+            vec![(Access::Write, 0xFF40)]
+        );
+    }
+
+    #[test]
+    fn clears_a_with_xor_a() {
+        // xor a / ld [$C000],a / sub a / cp a / ret
+        let (cart, data, values) = known_values(&[0xAF, 0xEA, 0x00, 0xC0, 0x97, 0xBF, 0xC9]);
+        let state = values.at(0x201).unwrap();
+        assert_eq!(state.to_string(), "a=$00 f.z=1 f.c=0");
+
+        let store = Instruction {
+            address: 0x201,
+            op_code: OpCode::decode_at(&cart, 0x201).unwrap(),
+        };
+        assert_eq!(
+            stores(&cart, &data, *state, &store),
+            vec![(0xC000, Some(0))]
+        );
+
+        for &address in &[0x205, 0x206] {
+            let state = values.at(address).unwrap();
+            assert_eq!(state.get(Var::Register(Register::A)).as_const(), Some(0));
+            assert_eq!(state.get(Var::Flag(Flag::Zero)).as_const(), Some(1));
+            assert_eq!(state.get(Var::Flag(Flag::Carry)).as_const(), Some(0));
+        }
+    }
+}
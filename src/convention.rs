@@ -0,0 +1,602 @@
+use analyzer::RomAnalyzer;
+use data::*;
+use disassembly;
+use disassembly::ChunkSet;
+use gb::*;
+use instruction_walker::{walk_chunks, Join};
+use ir::*;
+use ssa;
+use ssa::TRACKED_VARS;
+use stack;
+use stack::{StackAnalysis, StackDepth, StackState};
+use std::collections::HashMap;
+
+/// Registers and flags that can carry values between functions.
+pub const CONVENTION_VARS: [Var; 11] = [
+    Var::Register(Register::A),
+    Var::Register(Register::B),
+    Var::Register(Register::C),
+    Var::Register(Register::D),
+    Var::Register(Register::E),
+    Var::Register(Register::H),
+    Var::Register(Register::L),
+    Var::Flag(Flag::Zero),
+    Var::Flag(Flag::Subtract),
+    Var::Flag(Flag::HalfCarry),
+    Var::Flag(Flag::Carry),
+];
+
+type VarSet = u32;
+
+fn bit(var: Var) -> VarSet {
+    1 << var.index()
+}
+
+fn set_of(vars: &[Var]) -> VarSet {
+    vars.iter().fold(0, |set, &var| set | bit(var))
+}
+
+fn vars_of(set: VarSet) -> Vec<Var> {
+    CONVENTION_VARS
+        .iter()
+        .cloned()
+        .filter(|&var| set & bit(var) != 0)
+        .collect()
+}
+
+// What a call to a function that isn't known may read: only where SDCC
+// passes arguments in registers, for code built with `__sdcccall(1)`. With
+// arguments on the stack, the callee can still be hand-written and read any.
+fn unknown_callee_inputs(data: &Data) -> Vec<Var> {
+    match data.toolchain.sdcccall {
+        Some(SdccCall::Registers) => [
+            Register::A,
+            Register::B,
+            Register::C,
+            Register::D,
+            Register::E,
+        ]
+        .iter()
+        .map(|&register| Var::Register(register))
+        .collect(),
+        Some(SdccCall::Stack) | None => CONVENTION_VARS.to_vec(),
+    }
+}
+
+fn callee_convention<'a>(data: &'a Data, stmt: &Stmt) -> Option<&'a CallingConvention> {
+    match stmt {
+        Stmt::Call {
+            target: Target::Direct(address),
+            ..
+        } => data
+            .functions
+            .get(*address)
+            .map(|function| &function.convention),
+        _ => None,
+    }
+}
+
+/// Variables read by a statement of the instruction at `address` beyond
+/// those in its expressions: a call reads its callee's inputs, a return the
+/// function's outputs.
+pub fn implicit_uses(data: &Data, chunks: &ChunkSet, address: usize, stmt: &Stmt) -> Vec<Var> {
+    let function = chunks.root().address();
+    let mut vars = match stmt {
+        Stmt::Call {
+            target: Target::Direct(_),
+            ..
+        } => match callee_convention(data, stmt) {
+            Some(convention) => convention.inputs.clone(),
+            None => unknown_callee_inputs(data),
+        },
+        Stmt::Call { .. } => unknown_callee_inputs(data),
+        // A tail call reads its callee's inputs, and whatever the callee
+        // leaves alone is returned as it is
+        Stmt::Jump { .. } if chunks.tail_call_target(address, stmt).is_some() => {
+            let target = chunks.tail_call_target(address, stmt).unwrap();
+            let callee = &data.functions.get(target).unwrap().convention;
+            let mut vars = callee.inputs.clone();
+            if let Some(function) = data.functions.get(function) {
+                vars.extend(
+                    function
+                        .convention
+                        .outputs
+                        .iter()
+                        .filter(|var| !callee.clobbered.contains(var)),
+                );
+            }
+            vars
+        }
+        Stmt::Return { .. } => data
+            .functions
+            .get(function)
+            .map(|function| function.convention.outputs.clone())
+            .unwrap_or_else(|| CONVENTION_VARS.to_vec()),
+        Stmt::Jump {
+            target: Target::Indirect(_),
+            ..
+        } => return TRACKED_VARS.to_vec(),
+        _ => return Vec::new(),
+    };
+
+    vars.push(Var::StackPointer);
+    vars
+}
+
+/// Variables a call may change: its callee's clobbered registers and flags.
+pub fn implicit_defs(data: &Data, stmt: &Stmt) -> Vec<Var> {
+    match stmt {
+        Stmt::Call {
+            target: Target::Direct(_),
+            ..
+        } => match callee_convention(data, stmt) {
+            Some(convention) => convention.clobbered.clone(),
+            None => CONVENTION_VARS.to_vec(),
+        },
+        Stmt::Call { .. } => CONVENTION_VARS.to_vec(),
+        _ => Vec::new(),
+    }
+}
+
+fn is_conditional(stmt: &Stmt) -> bool {
+    stmt.condition().is_some()
+}
+
+/// The variables live before `instruction` given those live after it.
+/// `on_call` is told which variables are live after each direct call or tail
+/// call.
+fn live_before<F>(
+    data: &Data,
+    function: usize,
+    chunks: &ChunkSet,
+    stack: &StackAnalysis,
+    instruction: &Instruction,
+    mut live: VarSet,
+    on_call: &mut F,
+) -> VarSet
+where
+    F: FnMut(usize, VarSet),
+{
+    for stmt in chunks.lift(stack, instruction).iter().rev() {
+        if let Stmt::Call {
+            target: Target::Direct(address),
+            ..
+        } = stmt
+        {
+            on_call(*address, live);
+        }
+
+        // Our caller reads the results of a tail call
+        if let Some(target) = chunks.tail_call_target(instruction.address, stmt) {
+            let outputs = data
+                .functions
+                .get(function)
+                .map(|function| set_of(&function.convention.outputs))
+                .unwrap_or(0);
+            on_call(target, outputs);
+        }
+
+        // A conditional call may not happen, so it cannot end a lifetime
+        if !is_conditional(stmt) {
+            live &= !set_of(&stmt.defined_vars());
+            live &= !set_of(&implicit_defs(data, stmt));
+        }
+
+        live |= set_of(&stmt.used_vars());
+        live |= set_of(&implicit_uses(data, chunks, instruction.address, stmt));
+    }
+
+    live
+}
+
+/// Backward liveness over the whole function, reporting what is live after
+/// each call.
+fn find_live_after_calls<F>(
+    data: &Data,
+    function: usize,
+    chunks: &ChunkSet,
+    stack: &StackAnalysis,
+    on_call: &mut F,
+) where
+    F: FnMut(usize, VarSet),
+{
+    let mut live_in: Vec<VarSet> = vec![0; chunks.len()];
+    let mut ignore_calls = |_, _| ();
+
+    loop {
+        let mut changed = false;
+
+        for index in (0..chunks.len()).rev() {
+            let mut live = chunks
+                .successors(index)
+                .iter()
+                .fold(0, |live, &successor| live | live_in[successor]);
+
+            for instruction in chunks.get(index).instructions().iter().rev() {
+                live = live_before(
+                    data,
+                    function,
+                    chunks,
+                    stack,
+                    instruction,
+                    live,
+                    &mut ignore_calls,
+                );
+            }
+
+            if live != live_in[index] {
+                live_in[index] = live;
+                changed = true;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    // One more pass now that the sets are final, to report the calls
+    for index in 0..chunks.len() {
+        let mut live = chunks
+            .successors(index)
+            .iter()
+            .fold(0, |live, &successor| live | live_in[successor]);
+
+        for instruction in chunks.get(index).instructions().iter().rev() {
+            live = live_before(data, function, chunks, stack, instruction, live, on_call);
+        }
+    }
+}
+
+/// The registers and flags read before they are written, leaving out reads
+/// that only feed code dead code elimination removes.
+fn inputs(data: &Data, chunks: &ChunkSet) -> VarSet {
+    let mut function = ssa::build(chunks, data);
+    function.eliminate_dead_code();
+    function
+        .use_counts()
+        .into_iter()
+        .filter(|&(var, count)| var.version == 0 && count > 0 && CONVENTION_VARS.contains(&var.var))
+        .fold(0, |set, (var, _)| set | bit(var.var))
+}
+
+/// Where the current value of a register or flag came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Origin {
+    Entry(Var),
+    /// The flags register as a whole, as pushed by `push af`.
+    EntryFlags,
+    Changed,
+}
+
+// Enough for the registers a function saves on entry
+const STACK_SLOTS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SavedState {
+    vars: [Origin; 11],
+    /// The two bytes of each pushed pair, by depth in pairs.
+    slots: [(Origin, Origin); STACK_SLOTS],
+    stack: StackState,
+}
+
+impl Join for SavedState {
+    fn join(&self, other: &SavedState) -> SavedState {
+        let join = |a: Origin, b: Origin| if a == b { a } else { Origin::Changed };
+        let mut joined = *self;
+
+        for (var, other) in joined.vars.iter_mut().zip(other.vars.iter()) {
+            *var = join(*var, *other);
+        }
+        for (slot, other) in joined.slots.iter_mut().zip(other.slots.iter()) {
+            *slot = (join(slot.0, other.0), join(slot.1, other.1));
+        }
+        joined.stack = self.stack.join(&other.stack);
+        joined
+    }
+}
+
+fn pair_registers(op_code: OpCode) -> Option<(Register, Option<Register>)> {
+    match op_code {
+        OpCode::PUSH_BC | OpCode::POP_BC => Some((Register::B, Some(Register::C))),
+        OpCode::PUSH_DE | OpCode::POP_DE => Some((Register::D, Some(Register::E))),
+        OpCode::PUSH_HL | OpCode::POP_HL => Some((Register::H, Some(Register::L))),
+        OpCode::PUSH_AF | OpCode::POP_AF => Some((Register::A, None)),
+        _ => None,
+    }
+}
+
+fn slot_index(depth: StackDepth) -> Option<usize> {
+    match depth {
+        StackDepth::Known(depth) if depth >= 0 && depth % 2 == 0 => {
+            Some(depth as usize / 2).filter(|&slot| slot < STACK_SLOTS)
+        }
+        _ => None,
+    }
+}
+
+impl SavedState {
+    fn entry() -> SavedState {
+        let mut vars = [Origin::Changed; 11];
+        for &var in CONVENTION_VARS.iter() {
+            vars[var.index()] = Origin::Entry(var);
+        }
+
+        SavedState {
+            vars,
+            slots: [(Origin::Changed, Origin::Changed); STACK_SLOTS],
+            stack: StackState::entry(),
+        }
+    }
+
+    fn origin(&self, var: Var) -> Origin {
+        self.vars
+            .get(var.index())
+            .cloned()
+            .unwrap_or(Origin::Changed)
+    }
+
+    fn set(&mut self, var: Var, origin: Origin) {
+        if let Some(slot) = self.vars.get_mut(var.index()) {
+            *slot = origin;
+        }
+    }
+
+    fn flags_origin(&self) -> Origin {
+        let unchanged = Flag::ALL
+            .iter()
+            .all(|&flag| self.origin(Var::Flag(flag)) == Origin::Entry(Var::Flag(flag)));
+
+        if unchanged {
+            Origin::EntryFlags
+        } else {
+            Origin::Changed
+        }
+    }
+
+    fn step(
+        mut self,
+        data: &Data,
+        chunks: &ChunkSet,
+        stack: &StackAnalysis,
+        instruction: &Instruction,
+    ) -> SavedState {
+        let op_code = instruction.op_code;
+        let depth_before = self.stack.depth;
+        self.stack = self.stack.step(instruction);
+
+        let is_push = matches!(
+            op_code,
+            OpCode::PUSH_BC | OpCode::PUSH_DE | OpCode::PUSH_HL | OpCode::PUSH_AF
+        );
+
+        match pair_registers(op_code) {
+            Some((high, low)) if is_push => {
+                if let Some(slot) = slot_index(depth_before) {
+                    let low = match low {
+                        Some(low) => self.origin(Var::Register(low)),
+                        None => self.flags_origin(),
+                    };
+                    self.slots[slot] = (self.origin(Var::Register(high)), low);
+                }
+            }
+            Some((high, low)) => {
+                let (high_origin, low_origin) = slot_index(self.stack.depth)
+                    .map(|slot| self.slots[slot])
+                    .unwrap_or((Origin::Changed, Origin::Changed));
+
+                self.set(Var::Register(high), high_origin);
+                match low {
+                    Some(low) => self.set(Var::Register(low), low_origin),
+                    None => {
+                        for &flag in Flag::ALL.iter() {
+                            let origin = if low_origin == Origin::EntryFlags {
+                                Origin::Entry(Var::Flag(flag))
+                            } else {
+                                Origin::Changed
+                            };
+                            self.set(Var::Flag(flag), origin);
+                        }
+                    }
+                }
+            }
+            None => {
+                for stmt in chunks.lift(stack, instruction) {
+                    match stmt {
+                        // Moves keep track of where the value came from
+                        Stmt::Assign(var, Expr::Var(source)) => {
+                            let origin = self.origin(source);
+                            self.set(var, origin);
+                        }
+                        _ => {
+                            for var in stmt.defined_vars() {
+                                self.set(var, Origin::Changed);
+                            }
+                        }
+                    }
+
+                    for var in implicit_defs(data, &stmt) {
+                        self.set(var, Origin::Changed);
+                    }
+                }
+            }
+        }
+
+        self
+    }
+}
+
+/// Works out which variables are changed on return, and which are written
+/// but restored, as `(clobbered, preserved)`.
+fn saved_registers(data: &Data, chunks: &ChunkSet, stack: &StackAnalysis) -> (VarSet, VarSet) {
+    let states = walk_chunks(chunks, SavedState::entry(), |state, instruction| {
+        state.step(data, chunks, stack, instruction)
+    });
+
+    let mut written = 0;
+    let mut clobbered = 0;
+    let mut returns = false;
+
+    for instruction in chunks.instructions() {
+        for stmt in chunks.lift(stack, instruction) {
+            written |= set_of(&stmt.defined_vars()) | set_of(&implicit_defs(data, &stmt));
+
+            // A tail call returns on our behalf, after clobbering more
+            let returned_clobbers = match chunks.tail_call_target(instruction.address, &stmt) {
+                Some(target) => Some(set_of(
+                    &data.functions.get(target).unwrap().convention.clobbered,
+                )),
+                None if matches!(stmt, Stmt::Return { .. }) => Some(0),
+                None => None,
+            };
+
+            if let Some(returned_clobbers) = returned_clobbers {
+                returns = true;
+                written |= returned_clobbers;
+                clobbered |= returned_clobbers;
+
+                // Instructions the walk never reached restore nothing
+                let state = match states.get(&instruction.address) {
+                    Some(state) => state.step(data, chunks, stack, instruction),
+                    None => continue,
+                };
+                for &var in CONVENTION_VARS.iter() {
+                    if state.origin(var) != Origin::Entry(var) {
+                        clobbered |= bit(var);
+                    }
+                }
+            }
+        }
+    }
+
+    written &= set_of(&CONVENTION_VARS);
+
+    if !returns {
+        clobbered = written;
+    }
+
+    (clobbered, written & !clobbered)
+}
+
+/// Infers the calling convention of every function. Outputs are the
+/// clobbered variables some caller reads after the call, so the analysis is
+/// repeated until it settles.
+pub struct ConventionAnalyzer;
+
+impl RomAnalyzer for ConventionAnalyzer {
+    fn run(&self, cart: &Cartridge, data: &mut Data) -> bool {
+        let mut addresses: Vec<usize> =
+            data.functions.iter().map(|(address, _)| *address).collect();
+        addresses.sort();
+
+        let mut conventions = HashMap::new();
+        let mut live_after_calls: HashMap<usize, VarSet> = HashMap::new();
+
+        for &address in &addresses {
+            let chunks = disassembly::chunk(cart, data, address);
+            let stack = stack::analyse(&chunks);
+
+            let (clobbered, preserved) = saved_registers(data, &chunks, &stack);
+            find_live_after_calls(data, address, &chunks, &stack, &mut |callee, live| {
+                *live_after_calls.entry(callee).or_insert(0) |= live;
+            });
+
+            // Nothing passes arguments to the entry point or interrupts
+            let is_entered = address == Cartridge::ENTRY_POINT
+                || Cartridge::INTERRUPT_VECTORS.contains(&address);
+            let inputs = if is_entered { 0 } else { inputs(data, &chunks) };
+
+            conventions.insert(address, (inputs, clobbered, preserved));
+        }
+
+        let mut changed = false;
+
+        for address in addresses {
+            let (inputs, clobbered, preserved) = conventions[&address];
+            let live_after = live_after_calls.get(&address).cloned().unwrap_or(0);
+
+            let convention = CallingConvention {
+                inputs: vars_of(inputs),
+                outputs: vars_of(clobbered & live_after),
+                clobbered: vars_of(clobbered),
+                preserved: vars_of(preserved),
+            };
+
+            let function = data.functions.get_mut(address).unwrap();
+            if function.convention != convention {
+                function.convention = convention;
+                changed = true;
+            }
+        }
+
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+
+    #[test]
+    fn skips_returns_not_reached() {
+        // A function that may jump into RAM nothing is copied to, leaving its
+        // `ret` unreached
+        let cart = Cartridge::with_function(&[0xC2, 0x00, 0xC0, 0xC9]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        assert!(data.functions.get(0x200).is_some());
+    }
+
+    #[test]
+    fn unknown_callees_read_any_register_with_stack_arguments() {
+        let mut data = Data::new();
+        data.toolchain.sdcccall = Some(SdccCall::Stack);
+        assert_eq!(unknown_callee_inputs(&data), CONVENTION_VARS.to_vec());
+
+        data.toolchain.sdcccall = Some(SdccCall::Registers);
+        let inputs = unknown_callee_inputs(&data);
+        assert!(inputs.contains(&Var::Register(Register::E)));
+        assert!(!inputs.contains(&Var::Register(Register::H)));
+    }
+
+    #[test]
+    fn skips_reads_that_only_feed_dead_code() {
+        // ld a,b / add e / ld a,h / or l / ret, whose caller stores a: the
+        // sum is overwritten unused
+        let cart = Cartridge::with_code(&[
+            (0x100, &[0xCD, 0x00, 0x02, 0xEA, 0x00, 0xC0, 0x18, 0xFE]),
+            (0x200, &[0x78, 0x83, 0x7C, 0xB5, 0xC9]),
+        ]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        assert_eq!(
+            data.functions.get(0x200).unwrap().convention.inputs,
+            vec![Var::Register(Register::H), Var::Register(Register::L)]
+        );
+    }
+
+    #[test]
+    fn entry_points_take_no_inputs() {
+        // An entry point and a timer interrupt that both read b
+        let cart = Cartridge::with_code(&[
+            (0x50, &[0x78, 0xE0, 0x80, 0xD9]),
+            (0x100, &[0x78, 0xE0, 0x80, 0x18, 0xFE]),
+        ]);
+        let mut data = Data::new();
+        data.entry_points.insert(0x50);
+        let data = analyzer::analyse_functions(&cart, data);
+        assert!(data
+            .functions
+            .get(0x50)
+            .unwrap()
+            .convention
+            .inputs
+            .is_empty());
+        assert!(data
+            .functions
+            .get(0x100)
+            .unwrap()
+            .convention
+            .inputs
+            .is_empty());
+    }
+}
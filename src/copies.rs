@@ -0,0 +1,548 @@
+use analyzer::RomAnalyzer;
+use constants;
+use constants::KnownState;
+use data::*;
+use disassembly;
+use gb::*;
+use ir::*;
+use std::collections::HashMap;
+
+const OAM_DMA_REGISTER: usize = 0xFF46;
+
+/// Copies into these ranges, work RAM and high RAM, may be of code.
+const RAM_RANGES: [(usize, usize); 2] = [(WRAM_START, 0xE000), (HRAM_START, 0xFFFF)];
+
+const VBK: usize = 0xFF4F;
+const BCPS: usize = 0xFF68;
+const BCPD: usize = 0xFF69;
+const OCPS: usize = 0xFF6A;
+const OCPD: usize = 0xFF6B;
+
+/// Registers that say where a copy into VRAM or palette memory goes.
+const SETUP_REGISTERS: [usize; 3] = [VBK, BCPS, OCPS];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operand {
+    Register(Register),
+    Pair(Register, Register),
+    /// `$FF00` plus a register, as used by `ldh [c],a`.
+    HighPage(Register),
+    /// A register written to over and over, like the palette data ports.
+    Port(usize),
+    /// A palette data port written to over and over with `ldh [c],a`, C
+    /// staying on it.
+    PortAt(Register),
+}
+
+impl Operand {
+    fn evaluate(&self, state: &KnownState) -> Option<usize> {
+        match self {
+            Operand::Register(register) => state
+                .get(Var::Register(*register))
+                .as_const()
+                .map(|value| value as usize),
+            Operand::Pair(high, low) => state.pair(*high, *low).map(|value| value as usize),
+            Operand::HighPage(register) => Operand::Register(*register)
+                .evaluate(state)
+                .map(|value| 0xFF00 + value),
+            Operand::Port(address) => Some(*address),
+            Operand::PortAt(register) => Operand::HighPage(*register)
+                .evaluate(state)
+                .filter(|address| [BCPD, OCPD].contains(address)),
+        }
+    }
+
+    // A count of zero runs the loop a full 256 or 65536 times
+    fn evaluate_count(&self, state: &KnownState) -> Option<usize> {
+        let count = self.evaluate(state)?;
+        match (self, count) {
+            (Operand::Register(_), 0) => Some(0x100),
+            (Operand::Pair(..), 0) => Some(0x10000),
+            _ => Some(count),
+        }
+    }
+}
+
+const HL: Operand = Operand::Pair(Register::H, Register::L);
+const DE: Operand = Operand::Pair(Register::D, Register::E);
+
+/// The registers a byte copy loop reads from, writes to and counts with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct CopyLoop {
+    source: Operand,
+    destination: Operand,
+    count: Operand,
+    /// How many times each byte read is written, which is twice when 1bpp
+    /// tiles are expanded into VRAM.
+    writes: usize,
+}
+
+/// A copy found, with the number of times each byte of it is written and
+/// the known values of the setup registers as it starts.
+struct FoundCopy {
+    copy: RamCopy,
+    writes: usize,
+    setup: HashMap<usize, u32>,
+}
+
+impl CopyLoop {
+    fn evaluate(&self, state: &KnownState) -> Option<FoundCopy> {
+        let copy = RamCopy {
+            source: self.source.evaluate(state)?,
+            destination: self.destination.evaluate(state)?,
+            len: self.count.evaluate_count(state)?,
+        };
+        Some(FoundCopy {
+            copy,
+            writes: self.writes,
+            setup: HashMap::new(),
+        })
+    }
+}
+
+// The values last written to the setup registers before `before`, going by
+// address, which is usually the order they run in
+fn setup_values(
+    cart: &Cartridge,
+    data: &Data,
+    instructions: &[Instruction],
+    known_values: &constants::KnownValues,
+    before: usize,
+) -> HashMap<usize, u32> {
+    let mut values = HashMap::new();
+    for instruction in instructions
+        .iter()
+        .filter(|instruction| instruction.address < before)
+    {
+        let state = match known_values.at(instruction.address) {
+            Some(state) => *state,
+            None => continue,
+        };
+        for (address, value) in constants::stores(cart, data, state, instruction) {
+            if !SETUP_REGISTERS.contains(&address) {
+                continue;
+            }
+            match value {
+                Some(value) => values.insert(address, value),
+                None => values.remove(&address),
+            };
+        }
+    }
+    values
+}
+
+// Matches a loop of a single chunk that copies one byte per iteration, like
+//
+//     ld a,[hli] / ld [de],a / inc de / dec bc / ld a,b / or c / jr nz,loop
+//     ld a,[hli] / ldh [c],a / inc c / dec b / jr nz,loop
+//     ld a,[hli] / ldh [c],a / dec b / jr nz,loop
+//     ld a,[hli] / ld [de],a / inc de / ld [de],a / inc de / dec b / jr nz,loop
+fn match_copy_loop(instructions: &[Instruction]) -> Option<CopyLoop> {
+    let (last, body) = instructions.split_last()?;
+    match last.op_code {
+        OpCode::JR_NZ_r8(_) | OpCode::JP_NZ_a16(_) => (),
+        _ => return None,
+    }
+    if last.jump_target() != Some(body.first()?.address) {
+        return None;
+    }
+
+    let mut source = None;
+    let mut destination = None;
+    let mut count = None;
+    let mut writes = 0;
+    let mut incremented = Vec::new();
+
+    for instruction in body {
+        match instruction.op_code {
+            OpCode::LD_A_pHLp => {
+                source = Some(HL);
+                incremented.push(HL);
+            }
+            OpCode::LD_A_pHL => source = Some(HL),
+            OpCode::LD_A_pDE => source = Some(DE),
+            OpCode::LD_pHLp_A => {
+                destination = Some(HL);
+                writes += 1;
+                incremented.push(HL);
+            }
+            OpCode::LD_pHL_A => {
+                destination = Some(HL);
+                writes += 1;
+            }
+            OpCode::LD_pDE_A => {
+                destination = Some(DE);
+                writes += 1;
+            }
+            OpCode::LD_pC_A => {
+                destination = Some(Operand::HighPage(Register::C));
+                writes += 1;
+            }
+            OpCode::LDH_pa8_A(port) if [BCPD, OCPD].contains(&(0xFF00 + port.value as usize)) => {
+                destination = Some(Operand::Port(0xFF00 + port.value as usize));
+                writes += 1;
+            }
+            OpCode::INC_HL => incremented.push(HL),
+            OpCode::INC_DE => incremented.push(DE),
+            OpCode::INC_C => incremented.push(Operand::HighPage(Register::C)),
+            OpCode::DEC_B => count = Some(Operand::Register(Register::B)),
+            OpCode::DEC_C => count = Some(Operand::Register(Register::C)),
+            OpCode::DEC_BC => count = Some(Operand::Pair(Register::B, Register::C)),
+            // Testing a 16-bit count for zero
+            OpCode::LD_A_B | OpCode::LD_A_C | OpCode::OR_B | OpCode::OR_C | OpCode::NOP => (),
+            _ => return None,
+        }
+    }
+
+    // Writing through C without moving it on is only a palette data port
+    let destination = match destination? {
+        Operand::HighPage(register)
+            if !incremented.contains(&Operand::HighPage(register))
+                && count != Some(Operand::Register(register)) =>
+        {
+            Operand::PortAt(register)
+        }
+        destination => destination,
+    };
+
+    // dec bc leaves the flags alone, so the loop only counts BC down when it
+    // tests BC for zero before branching
+    if count == Some(Operand::Pair(Register::B, Register::C)) && !tests_bc(body) {
+        return None;
+    }
+
+    let copy_loop = CopyLoop {
+        source: source?,
+        destination,
+        count: count?,
+        writes,
+    };
+
+    let is_port = matches!(copy_loop.destination, Operand::Port(_) | Operand::PortAt(_));
+    if copy_loop.source == copy_loop.destination
+        || !incremented.contains(&copy_loop.source)
+        || !(is_port || incremented.contains(&copy_loop.destination))
+    {
+        return None;
+    }
+
+    Some(copy_loop)
+}
+
+// Whether a loop body ends `ld a,b / or c` or `ld a,c / or b`
+fn tests_bc(body: &[Instruction]) -> bool {
+    match body {
+        [.., load, or] => matches!(
+            (&load.op_code, &or.op_code),
+            (OpCode::LD_A_B, OpCode::OR_C) | (OpCode::LD_A_C, OpCode::OR_B)
+        ),
+        _ => false,
+    }
+}
+
+fn is_code_copy(found: &FoundCopy) -> bool {
+    let copy = &found.copy;
+    found.writes == 1
+        && copy.source + copy.len <= ROM_END
+        && RAM_RANGES
+            .iter()
+            .any(|&(start, end)| copy.destination >= start && copy.destination + copy.len <= end)
+}
+
+// The VRAM bank a copy writes to, which is always 0 on the original Game Boy
+fn vram_bank(cart: &Cartridge, found: &FoundCopy) -> usize {
+    if cart.supports_cgb() {
+        found.setup.get(&VBK).map_or(0, |&value| value as usize & 1)
+    } else {
+        0
+    }
+}
+
+// Tiles copied from the ROM into VRAM, two bytes written for each one read
+// meaning 1bpp tiles
+fn tile_copy(cart: &Cartridge, found: &FoundCopy) -> Option<TileCopy> {
+    let copy = &found.copy;
+    let format = match found.writes {
+        1 => TileFormat::TwoBpp,
+        2 => TileFormat::OneBpp,
+        _ => return None,
+    };
+    let (start, end) = TILE_DATA;
+    let is_tiles = copy.source + copy.len <= ROM_END
+        && copy.destination >= start
+        && copy.destination + copy.len * found.writes <= end;
+
+    if is_tiles {
+        Some(TileCopy {
+            source: copy.source,
+            destination: copy.destination,
+            len: copy.len,
+            format,
+            bank: vram_bank(cart, found),
+        })
+    } else {
+        None
+    }
+}
+
+// Tile maps copied into VRAM, which are attributes when written to bank 1
+fn tilemap_copy(cart: &Cartridge, found: &FoundCopy) -> Option<TilemapCopy> {
+    let copy = &found.copy;
+    let (start, end) = TILE_MAPS;
+    let is_tilemap = found.writes == 1
+        && copy.source + copy.len <= ROM_END
+        && copy.destination >= start
+        && copy.destination + copy.len <= end;
+
+    if is_tilemap {
+        Some(TilemapCopy {
+            source: copy.source,
+            destination: copy.destination,
+            len: copy.len,
+            attributes: vram_bank(cart, found) == 1,
+        })
+    } else {
+        None
+    }
+}
+
+// Colours copied into palette memory, starting where BCPS or OCPS points
+fn palette_copy(found: &FoundCopy) -> Option<PaletteCopy> {
+    let copy = &found.copy;
+    let (objects, index_register) = match copy.destination {
+        BCPD => (false, BCPS),
+        OCPD => (true, OCPS),
+        _ => return None,
+    };
+    let index = found
+        .setup
+        .get(&index_register)
+        .map_or(0, |&value| value as usize & 0x3F);
+
+    // Each colour is two bytes
+    let is_palette = found.writes == 1
+        && copy.len.is_multiple_of(2)
+        && copy.source + copy.len <= ROM_END
+        && index + copy.len <= 0x40;
+
+    if is_palette {
+        Some(PaletteCopy {
+            source: copy.source,
+            len: copy.len,
+            objects,
+            index,
+        })
+    } else {
+        None
+    }
+}
+
+// Copies made by loops within a function, and functions that are nothing but
+// a copy loop, whose copies are made by their callers
+fn find_copies(
+    cart: &Cartridge,
+    data: &Data,
+    address: usize,
+    copies: &mut Vec<FoundCopy>,
+    routines: &mut HashMap<usize, CopyLoop>,
+) {
+    let chunks = disassembly::chunk(cart, data, address);
+    let known_values = constants::analyse(cart, data, &chunks);
+
+    for chunk in chunks.iter() {
+        let instructions = chunk.instructions();
+        let copy_loop = match match_copy_loop(instructions) {
+            Some(copy_loop) => copy_loop,
+            None => continue,
+        };
+
+        let head = instructions[0].address;
+        if head == address {
+            routines.insert(address, copy_loop);
+            continue;
+        }
+
+        // The registers are known as the loop is entered from the instruction
+        // before it
+        let entry = chunks
+            .instructions()
+            .iter()
+            .find(|instruction| instruction.address + instruction.size() == head);
+        let state = entry.and_then(|entry| {
+            let state = known_values.at(entry.address)?;
+            Some(constants::step(cart, data, *state, entry))
+        });
+
+        if let Some(mut found) = state.and_then(|state| copy_loop.evaluate(&state)) {
+            found.setup = setup_values(cart, data, chunks.instructions(), &known_values, head);
+            copies.push(found);
+        }
+    }
+}
+
+fn find_routine_calls(
+    cart: &Cartridge,
+    data: &Data,
+    address: usize,
+    routines: &HashMap<usize, CopyLoop>,
+    copies: &mut Vec<FoundCopy>,
+) {
+    let chunks = disassembly::chunk(cart, data, address);
+    let known_values = constants::analyse(cart, data, &chunks);
+
+    for instruction in chunks.instructions() {
+        let copy_loop = match instruction
+            .call_target()
+            .and_then(|target| routines.get(&target))
+        {
+            Some(copy_loop) => copy_loop,
+            None => continue,
+        };
+
+        let found = known_values
+            .at(instruction.address)
+            .and_then(|state| copy_loop.evaluate(state));
+        if let Some(mut found) = found {
+            found.setup = setup_values(
+                cart,
+                data,
+                chunks.instructions(),
+                &known_values,
+                instruction.address,
+            );
+            copies.push(found);
+        }
+    }
+}
+
+fn is_oam_dma(cart: &Cartridge, data: &Data, address: usize) -> bool {
+    disassembly::collect_instructions(cart, data, address)
+        .iter()
+        .any(|instruction| match instruction.op_code {
+            OpCode::LDH_pa8_A(a8) => 0xFF00 + a8.value as usize == OAM_DMA_REGISTER,
+            OpCode::LD_pa16_A(a16) => a16.value as usize == OAM_DMA_REGISTER,
+            _ => false,
+        })
+}
+
+/// Finds code copied from the ROM into RAM, so that calls into RAM can be
+/// followed, and the tiles, tile maps and palettes copied into VRAM and
+/// palette memory, so they aren't taken for code. Also names the routine
+/// that starts OAM DMA.
+pub struct CopyAnalyzer;
+
+impl RomAnalyzer for CopyAnalyzer {
+    fn run(&self, cart: &Cartridge, data: &mut Data) -> bool {
+        let mut addresses: Vec<usize> =
+            data.functions.iter().map(|(address, _)| *address).collect();
+        addresses.sort();
+
+        let mut copies = Vec::new();
+        let mut routines = HashMap::new();
+
+        for &address in &addresses {
+            find_copies(cart, data, address, &mut copies, &mut routines);
+        }
+
+        if !routines.is_empty() {
+            for &address in &addresses {
+                find_routine_calls(cart, data, address, &routines, &mut copies);
+            }
+        }
+
+        let mut changes = false;
+
+        for found in copies.iter().filter(|found| is_code_copy(found)) {
+            if !data.ram_copies.contains(&found.copy) {
+                data.ram_copies.push(found.copy);
+                changes = true;
+            }
+        }
+
+        for found in &copies {
+            if let Some(tiles) = tile_copy(cart, found) {
+                if !data.tile_copies.contains(&tiles) {
+                    data.tile_copies.push(tiles);
+                    changes = true;
+                }
+            }
+            if let Some(tilemap) = tilemap_copy(cart, found) {
+                if !data.tilemap_copies.contains(&tilemap) {
+                    data.tilemap_copies.push(tilemap);
+                    changes = true;
+                }
+            }
+            if let Some(palette) = palette_copy(found) {
+                if !data.palette_copies.contains(&palette) {
+                    data.palette_copies.push(palette);
+                    changes = true;
+                }
+            }
+        }
+
+        for address in addresses.into_iter().filter(|&address| address >= ROM_END) {
+            if is_oam_dma(cart, data, address) {
+                let prefix = if address >= HRAM_START { "h" } else { "w" };
+                data.functions
+                    .suggest_name(address, format!("{}OAMDMA", prefix));
+            }
+        }
+
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+
+    #[test]
+    fn finds_palettes_written_through_c() {
+        // ld a,$80 / ldh [rBCPS],a / ld hl,$0300 / ld c,LOW(rBCPD) / ld b,8
+        // .loop: ld a,[hli] / ld [c],a / dec b / jr nz,.loop / ret
+        let cart = Cartridge::with_function(&[
+            0x3E, 0x80, 0xE0, 0x68, 0x21, 0x00, 0x03, 0x0E, 0x69, 0x06, 0x08, 0x2A, 0xE2, 0x05,
+            0x20, 0xFB, 0xC9,
+        ]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        assert_eq!(
+            data.palette_copies,
+            vec![PaletteCopy {
+                source: 0x300,
+                len: 8,
+                objects: false,
+                index: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn counts_bc_only_when_the_loop_tests_it() {
+        // ld hl,$0300 / ld de,$8000 / ld bc,$0010
+        // .loop: ld a,[hli] / ld [de],a / inc de / dec bc / ld a,b / or c
+        // jr nz,.loop / ret
+        let setup = [0x21, 0x00, 0x03, 0x11, 0x00, 0x80, 0x01, 0x10, 0x00];
+        let tested = [
+            &setup[..],
+            &[0x2A, 0x12, 0x13, 0x0B, 0x78, 0xB1, 0x20, 0xF8, 0xC9],
+        ]
+        .concat();
+        let data = analyzer::analyse_functions(&Cartridge::with_function(&tested), Data::new());
+        assert_eq!(
+            data.tile_copies,
+            vec![TileCopy {
+                source: 0x300,
+                destination: 0x8000,
+                len: 0x10,
+                format: TileFormat::TwoBpp,
+                bank: 0,
+            }]
+        );
+
+        // Without the test the branch reads flags set before the loop
+        let untested = [&setup[..], &[0x2A, 0x12, 0x13, 0x0B, 0x20, 0xFA, 0xC9]].concat();
+        let data = analyzer::analyse_functions(&Cartridge::with_function(&untested), Data::new());
+        assert!(data.tile_copies.is_empty());
+    }
+}
@@ -1,37 +1,609 @@
+use gb::{register_name, DecodeError};
+use ir::Var;
+use pointers::Pointers;
+use signatures::Signatures;
 use std::collections::hash_map::Entry;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::fmt;
+use symbols::Symbols;
+use text::TextString;
+use trace::Trace;
+
+/// The end of the ROM in the address space; code above it has to be copied
+/// into RAM before it can run.
+pub const ROM_END: usize = 0x8000;
+pub const BANKED_ROM_START: usize = 0x4000;
+pub const ROM_BANK_SIZE: usize = 0x4000;
+
+pub const SRAM_START: usize = 0xA000;
+pub const WRAM_START: usize = 0xC000;
+pub const HRAM_START: usize = 0xFF80;
+
+/// Where tiles go in VRAM; the tile maps come after.
+pub const TILE_DATA: (usize, usize) = (0x8000, 0x9800);
+pub const TILE_MAPS: (usize, usize) = (0x9800, 0xA000);
 
 pub struct Data {
     pub functions: FunctionTable,
+    /// Functions known never to return, whatever their code suggests.
+    pub noreturn_overrides: HashSet<usize>,
+    /// Code the user knows about that nothing might be found to call.
+    pub entry_points: HashSet<usize>,
+    /// Bytes the user knows aren't code.
+    pub data_ranges: Vec<DataRange>,
+    pub comments: HashMap<usize, String>,
+    pub ram_copies: Vec<RamCopy>,
+    pub tile_copies: Vec<TileCopy>,
+    pub tilemap_copies: Vec<TilemapCopy>,
+    pub palette_copies: Vec<PaletteCopy>,
+    /// Data passed to decompression routines.
+    pub compressed: Vec<CompressedData>,
+    pub sound: Sound,
+    pub toolchain: Fingerprint,
+    /// Facts recorded by running the ROM, if it was run.
+    pub trace: Trace,
+    /// Names read from symbol files.
+    pub symbols: Symbols,
+    /// Library functions to recognise by their first bytes.
+    pub signatures: Signatures,
+    /// Calls and entry points left out because the first byte isn't an
+    /// instruction, by address.
+    pub decode_errors: BTreeMap<usize, DecodeError>,
+    pub pointers: Pointers,
+    /// Text found in the ROM, by address.
+    pub strings: BTreeMap<usize, TextString>,
 }
 
 impl Data {
     pub fn new() -> Data {
         Data {
             functions: FunctionTable::new(),
+            noreturn_overrides: HashSet::new(),
+            entry_points: HashSet::new(),
+            data_ranges: Vec::new(),
+            pointers: Pointers::default(),
+            strings: BTreeMap::new(),
+            comments: HashMap::new(),
+            ram_copies: Vec::new(),
+            tile_copies: Vec::new(),
+            tilemap_copies: Vec::new(),
+            palette_copies: Vec::new(),
+            compressed: Vec::new(),
+            sound: Sound::default(),
+            toolchain: Fingerprint::default(),
+            trace: Trace::default(),
+            symbols: Symbols::default(),
+            signatures: Signatures::default(),
+            decode_errors: BTreeMap::new(),
+        }
+    }
+
+    /// What to call the code at `address`, whether or not it starts a
+    /// function.
+    pub fn name(&self, address: usize) -> String {
+        match self.functions.get(address) {
+            Some(function) => function.name.clone(),
+            None => self
+                .symbols
+                .get(address)
+                .map_or_else(|| self.made_up_name(default_name(address)), str::to_string),
+        }
+    }
+
+    /// A made up name as the toolchain would have it: C names take the
+    /// leading underscore SDCC gives them.
+    pub fn made_up_name(&self, name: String) -> String {
+        if self.toolchain.is_compiled() {
+            format!("_{}", name)
+        } else {
+            name
+        }
+    }
+
+    /// What to call a pointer to `address` in the ROM, whether it points to
+    /// code or data.
+    pub fn label(&self, address: usize) -> String {
+        if let Some(function) = self.functions.get(address) {
+            return function.name.clone();
+        }
+        match self.symbols.get(address) {
+            Some(name) => name.to_string(),
+            None if self.tile_copies.iter().any(|tiles| tiles.source == address) => {
+                format!("tiles_{:04X}", address)
+            }
+            None if self
+                .tilemap_copies
+                .iter()
+                .any(|tilemap| tilemap.source == address) =>
+            {
+                let attributes = self
+                    .tilemap_copies
+                    .iter()
+                    .any(|tilemap| tilemap.source == address && tilemap.attributes);
+                if attributes {
+                    format!("attrmap_{:04X}", address)
+                } else {
+                    format!("tilemap_{:04X}", address)
+                }
+            }
+            None if self
+                .palette_copies
+                .iter()
+                .any(|palette| palette.source == address) =>
+            {
+                format!("palettes_{:04X}", address)
+            }
+            None if self
+                .compressed
+                .iter()
+                .any(|compressed| compressed.source == address) =>
+            {
+                format!("compressed_{:04X}", address)
+            }
+            None => match self.sound.table(address) {
+                Some(table) => format!("{}_{:04X}", table.kind.label(), address),
+                None if self.strings.contains_key(&address) => format!("text_{:04X}", address),
+                None if self.pointers.tables.contains_key(&address) => {
+                    format!("table_{:04X}", address)
+                }
+                None if self.pointers.labels.contains(&address) => {
+                    format!("data_{:04X}", address)
+                }
+                None => format!("label_{:04X}", address),
+            },
+        }
+    }
+
+    /// What to call the memory at `address`: its symbol, the I/O register
+    /// there, or a made up name in cartridge, work and high RAM.
+    pub fn variable_name(&self, address: usize) -> Option<String> {
+        self.symbols
+            .get(address)
+            .or_else(|| register_name(address))
+            .map(str::to_string)
+            .or_else(|| default_variable_name(address).map(|name| self.made_up_name(name)))
+    }
+
+    /// Where the byte run at `address` is stored in the ROM, following copies
+    /// into RAM.
+    pub fn rom_address(&self, address: usize) -> Option<usize> {
+        if address < ROM_END {
+            return Some(address);
+        }
+
+        self.ram_copies
+            .iter()
+            .find(|copy| copy.contains(address))
+            .map(|copy| copy.source + address - copy.destination)
+    }
+
+    pub fn ram_copy_at(&self, address: usize) -> Option<&RamCopy> {
+        self.ram_copies.iter().find(|copy| copy.contains(address))
+    }
+
+    /// Whether tiles, a tile map or colours are copied from `address`.
+    pub fn is_graphics(&self, address: usize) -> bool {
+        let contains = |source: usize, len: usize| address >= source && address < source + len;
+        self.tiles_at(address).is_some()
+            || self
+                .tilemap_copies
+                .iter()
+                .any(|tilemap| contains(tilemap.source, tilemap.len))
+            || self
+                .palette_copies
+                .iter()
+                .any(|palette| contains(palette.source, palette.len))
+    }
+
+    /// The tiles copied into VRAM from `address` in the ROM.
+    pub fn tiles_at(&self, address: usize) -> Option<&TileCopy> {
+        self.tile_copies
+            .iter()
+            .find(|tiles| address >= tiles.source && address < tiles.source + tiles.len)
+    }
+
+    /// Whether the byte at `address` is known not to be code.
+    pub fn is_data(&self, address: usize) -> bool {
+        self.trace.is_data(address)
+            || self.data_ranges.iter().any(|range| range.contains(address))
+            || self.is_graphics(address)
+            || self.compressed_at(address).is_some()
+            || self.sound.table_at(address).is_some()
+    }
+
+    /// The compressed data that `address` in the ROM is part of.
+    pub fn compressed_at(&self, address: usize) -> Option<&CompressedData> {
+        self.compressed.iter().find(|compressed| {
+            address >= compressed.source && address < compressed.source + compressed.len
+        })
+    }
+}
+
+/// Bytes holding data of a kind the user named, like `tiles`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DataRange {
+    pub start: usize,
+    /// The last byte of the range.
+    pub end: usize,
+    pub kind: String,
+}
+
+impl DataRange {
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.start && address <= self.end
+    }
+}
+
+/// Bytes copied from the ROM into RAM, from where they can be run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RamCopy {
+    pub source: usize,
+    pub destination: usize,
+    pub len: usize,
+}
+
+impl RamCopy {
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.destination && address < self.destination + self.len
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TileFormat {
+    /// Two bits a pixel, in 16-byte tiles.
+    TwoBpp,
+    /// One bit a pixel, in 8-byte tiles, written twice over into VRAM.
+    OneBpp,
+}
+
+impl TileFormat {
+    pub fn tile_size(self) -> usize {
+        match self {
+            TileFormat::TwoBpp => 16,
+            TileFormat::OneBpp => 8,
+        }
+    }
+
+    /// The extension RGBDS projects give files in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            TileFormat::TwoBpp => "2bpp",
+            TileFormat::OneBpp => "1bpp",
+        }
+    }
+}
+
+/// Tiles copied from the ROM into VRAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileCopy {
+    pub source: usize,
+    pub destination: usize,
+    /// The number of bytes read from the ROM.
+    pub len: usize,
+    pub format: TileFormat,
+    /// The VRAM bank, which can be 1 on colour cartridges.
+    pub bank: usize,
+}
+
+impl TileCopy {
+    pub fn tile_count(&self) -> usize {
+        self.len.div_ceil(self.format.tile_size())
+    }
+}
+
+/// A tile map copied into VRAM, or its attributes on colour cartridges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TilemapCopy {
+    pub source: usize,
+    pub destination: usize,
+    pub len: usize,
+    /// Written to VRAM bank 1, where each byte gives the palette, bank and
+    /// flips of a tile.
+    pub attributes: bool,
+}
+
+/// RGB555 colours copied into the palette memory of colour cartridges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PaletteCopy {
+    pub source: usize,
+    pub len: usize,
+    /// Whether the colours are for sprites, rather than the background.
+    pub objects: bool,
+    /// The byte of palette memory the copy starts at.
+    pub index: usize,
+}
+
+/// A way of compressing data that Game Boy games decompress with a routine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Runs of a repeated byte and of literal bytes, ended by a zero.
+    Rle,
+    /// Packets of eight bytes, each either literal or the byte before.
+    Pb8,
+    /// Packets of eight bytes, each either literal or the byte two before,
+    /// which is the same plane of the row above in 2bpp tiles.
+    Pb16,
+    /// The LZ77 variant of Pokémon Gold, Silver and Crystal.
+    PokemonLz,
+}
+
+impl Compression {
+    /// The extension disassembly projects give files in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Rle => "rle",
+            Compression::Pb8 => "pb8",
+            Compression::Pb16 => "pb16",
+            Compression::PokemonLz => "lz",
+        }
+    }
+}
+
+/// Compressed bytes in the ROM that a call to a decompression routine
+/// unpacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressedData {
+    pub source: usize,
+    /// The number of compressed bytes read.
+    pub len: usize,
+    /// Where the routine writes the bytes, if it is known.
+    pub destination: Option<usize>,
+    /// The number of bytes decompressed.
+    pub size: usize,
+    pub compression: Compression,
+    /// For formats that don't mark their end, the number of bytes the
+    /// routine is asked for.
+    pub count: Option<usize>,
+}
+
+/// A sound driver that can be told apart from others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SoundDriver {
+    HugeDriver,
+    GbtPlayer,
+    DevSound,
+}
+
+impl SoundDriver {
+    pub fn name(self) -> &'static str {
+        match self {
+            SoundDriver::HugeDriver => "hUGEDriver",
+            SoundDriver::GbtPlayer => "GBT Player",
+            SoundDriver::DevSound => "DevSound",
+        }
+    }
+}
+
+/// What a function of a sound driver does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SoundRole {
+    /// Sets up the driver or starts a song.
+    Init,
+    /// Plays the next step of the music, once a frame.
+    Update,
+    /// Anything else only the driver uses.
+    Driver,
+}
+
+impl fmt::Display for SoundRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SoundRole::Init => f.pad("init"),
+            SoundRole::Update => f.pad("update"),
+            SoundRole::Driver => f.pad("driver"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SoundTableKind {
+    /// A hUGEDriver song descriptor: the tempo, the length of the order
+    /// tables, then pointers to them and to the instruments, routines and
+    /// waves.
+    HugeSong,
+    /// A GBT Player song: pointers to its patterns, ending with zero.
+    GbtSong,
+    /// The patterns one channel plays, in order.
+    Orders,
+    Pattern,
+    Instruments,
+    Routines,
+    Waves,
+}
+
+impl SoundTableKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            SoundTableKind::HugeSong | SoundTableKind::GbtSong => "song",
+            SoundTableKind::Orders => "orders",
+            SoundTableKind::Pattern => "pattern",
+            SoundTableKind::Instruments => "instruments",
+            SoundTableKind::Routines => "routines",
+            SoundTableKind::Waves => "waves",
+        }
+    }
+}
+
+/// Music data a sound driver reads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SoundTable {
+    pub address: usize,
+    pub kind: SoundTableKind,
+    /// The pointers in the table, for those made of them.
+    pub pointers: Vec<usize>,
+}
+
+impl SoundTable {
+    /// The number of bytes known to be part of the table, which is 0 when
+    /// its size isn't known.
+    pub fn size(&self) -> usize {
+        match self.kind {
+            SoundTableKind::HugeSong => 21,
+            SoundTableKind::GbtSong => 2 * self.pointers.len() + 2,
+            SoundTableKind::Orders => 2 * self.pointers.len(),
+            _ => 0,
+        }
+    }
+}
+
+/// What is known of the sound driver.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sound {
+    /// The driver, when it is one that can be recognised.
+    pub driver: Option<SoundDriver>,
+    pub functions: BTreeMap<usize, SoundRole>,
+    pub tables: Vec<SoundTable>,
+}
+
+impl Sound {
+    pub fn table(&self, address: usize) -> Option<&SoundTable> {
+        self.tables.iter().find(|table| table.address == address)
+    }
+
+    /// The table of known size that `address` is part of.
+    pub fn table_at(&self, address: usize) -> Option<&SoundTable> {
+        self.tables
+            .iter()
+            .find(|table| address >= table.address && address < table.address + table.size())
+    }
+}
+
+/// What a ROM was built with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Toolchain {
+    Gbdk,
+    /// SDCC without GBDK's runtime.
+    Sdcc,
+    Rgbds,
+    Nintendo,
+}
+
+impl Toolchain {
+    pub fn name(self) -> &'static str {
+        match self {
+            Toolchain::Gbdk => "GBDK",
+            Toolchain::Sdcc => "SDCC",
+            Toolchain::Rgbds => "RGBDS",
+            Toolchain::Nintendo => "Nintendo SDK",
         }
     }
 }
 
+/// How SDCC passes arguments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SdccCall {
+    /// `__sdcccall(0)`: all on the stack, for the caller to remove.
+    Stack,
+    /// `__sdcccall(1)`, the default since SDCC 4.2: the first in A, E, DE
+    /// or BC, the rest on the stack for the callee to remove.
+    Registers,
+}
+
+impl fmt::Display for SdccCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SdccCall::Stack => write!(f, "sdcccall(0)"),
+            SdccCall::Registers => write!(f, "sdcccall(1)"),
+        }
+    }
+}
+
+/// The GBDK releases a ROM could have been built with, told apart by how its
+/// C runtime takes arguments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GbdkVersion {
+    /// Releases before 4.1.0, built with `__sdcccall(0)`.
+    Before4_1,
+    /// 4.1.0 and later, built with SDCC 4.2 and `__sdcccall(1)`.
+    Since4_1,
+}
+
+impl fmt::Display for GbdkVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GbdkVersion::Before4_1 => write!(f, "before 4.1"),
+            GbdkVersion::Since4_1 => write!(f, "4.1 or later"),
+        }
+    }
+}
+
+/// What is known of how the ROM was built.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub toolchain: Option<Toolchain>,
+    /// The GBDK release, when its runtime was found.
+    pub version: Option<GbdkVersion>,
+    /// How compiled code calls functions, when some was found.
+    pub sdcccall: Option<SdccCall>,
+    /// What gave the toolchain away.
+    pub evidence: Vec<String>,
+}
+
+impl Fingerprint {
+    pub fn is_compiled(&self) -> bool {
+        matches!(
+            self.toolchain,
+            Some(Toolchain::Gbdk) | Some(Toolchain::Sdcc)
+        )
+    }
+}
+
+/// How a function uses registers and flags, as seen by its callers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallingConvention {
+    /// Read before being written.
+    pub inputs: Vec<Var>,
+    /// Changed on return and read by some caller.
+    pub outputs: Vec<Var>,
+    /// Changed on return.
+    pub clobbered: Vec<Var>,
+    /// Written, but restored before returning.
+    pub preserved: Vec<Var>,
+}
+
 pub struct Function {
     pub address: usize,
     pub name: String,
     pub call_sites: HashSet<usize>,
     pub can_return: bool,
+    pub convention: CallingConvention,
 }
 
 impl Function {
     pub fn new(address: usize) -> Function {
         Function {
             address,
-            name: format!("function_{}", address),
+            name: default_name(address),
             call_sites: HashSet::new(),
             can_return: false,
+            convention: CallingConvention::default(),
         }
     }
 }
 
+// Code run from RAM is named after the memory it lives in
+pub fn default_name(address: usize) -> String {
+    if address >= HRAM_START {
+        format!("hFunction_{:04X}", address)
+    } else if address >= WRAM_START {
+        format!("wFunction_{:04X}", address)
+    } else {
+        format!("function_{}", address)
+    }
+}
+
+pub fn default_variable_name(address: usize) -> Option<String> {
+    let prefix = match address {
+        SRAM_START..=0xBFFF => "s",
+        WRAM_START..=0xDFFF => "w",
+        HRAM_START..=0xFFFE => "h",
+        _ => return None,
+    };
+    Some(format!("{}Var_{:04X}", prefix, address))
+}
+
 pub struct FunctionTable {
     functions: HashMap<usize, Function>,
 }
@@ -50,10 +622,29 @@ impl FunctionTable {
         }
     }
 
+    /// Names the function at `address` after what it was found to do, unless
+    /// it has a name already. Names don't affect the analysis, so analyzers
+    /// don't count this as a change.
+    pub fn suggest_name(&mut self, address: usize, name: String) {
+        if let Some(function) = self.functions.get_mut(&address) {
+            if function.name == default_name(address) {
+                function.name = name;
+            }
+        }
+    }
+
+    pub fn remove(&mut self, address: usize) {
+        self.functions.remove(&address);
+    }
+
     pub fn get(&self, address: usize) -> Option<&Function> {
         self.functions.get(&address)
     }
 
+    pub fn is_start(&self, address: usize) -> bool {
+        self.functions.contains_key(&address)
+    }
+
     pub fn get_mut(&mut self, address: usize) -> Option<&mut Function> {
         self.functions.get_mut(&address)
     }
@@ -61,4 +652,8 @@ impl FunctionTable {
     pub fn iter(&self) -> impl Iterator<Item = (&usize, &Function)> {
         self.functions.iter()
     }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&usize, &mut Function)> {
+        self.functions.iter_mut()
+    }
 }
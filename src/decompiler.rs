@@ -0,0 +1,446 @@
+use data::Data;
+use disassembly;
+use disassembly::ChunkSet;
+use frame;
+use frame::StackFrame;
+use gb::*;
+use ir::*;
+use pairs;
+use ssa;
+use ssa::{SsaFunction, SsaStmt, SsaVar};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+fn condition_prefix(condition: &Option<Expr<SsaVar>>, names: &MemoryNames<SsaVar>) -> String {
+    match condition {
+        Some(condition) => format!("if ({}) ", condition.named(names)),
+        None => String::new(),
+    }
+}
+
+/// Names for a list of registers and flags, with both halves of a register
+/// pair combined. Each name comes with its width in bits.
+fn operand_names(vars: &[Var]) -> Vec<(String, u8)> {
+    let mut names = Vec::new();
+
+    for &var in vars {
+        let partner = pair_partner(var).filter(|partner| vars.contains(partner));
+
+        match partner {
+            Some(low) if is_pair_high(var) => {
+                names.push((pair_name(var, low).unwrap().to_string(), 16));
+            }
+            Some(_) => (),
+            None => names.push((var.to_string(), var.width())),
+        }
+    }
+
+    names
+}
+
+fn type_name(width: u8) -> &'static str {
+    match width {
+        1 => "bool",
+        8 => "u8",
+        _ => "u16",
+    }
+}
+
+fn join_names(names: &[(String, u8)]) -> String {
+    let names: Vec<&str> = names.iter().map(|(name, _)| name.as_str()).collect();
+    names.join(", ")
+}
+
+/// The left-hand side for assigning several values at once, or `None` if
+/// there are none.
+fn result_names(names: &[(String, u8)]) -> Option<String> {
+    match names.len() {
+        0 => None,
+        1 => Some(names[0].0.clone()),
+        _ => Some(format!("({})", join_names(names))),
+    }
+}
+
+fn return_type(outputs: &[Var]) -> String {
+    let names = operand_names(outputs);
+    match names.len() {
+        0 => "void".to_string(),
+        1 => type_name(names[0].1).to_string(),
+        _ => {
+            let types: Vec<&str> = names.iter().map(|&(_, width)| type_name(width)).collect();
+            format!("({})", types.join(", "))
+        }
+    }
+}
+
+fn signature(data: &Data, address: usize, frame: &StackFrame) -> String {
+    let (inputs, outputs) = match data.functions.get(address) {
+        Some(function) => (
+            function.convention.inputs.clone(),
+            function.convention.outputs.clone(),
+        ),
+        None => (Vec::new(), Vec::new()),
+    };
+
+    let parameters: Vec<String> = operand_names(&inputs)
+        .into_iter()
+        .chain(
+            frame
+                .parameters
+                .iter()
+                .map(|slot| (slot.name.clone(), slot.width)),
+        )
+        .map(|(name, width)| format!("{} {}", type_name(width), name))
+        .collect();
+
+    let noreturn = match data.functions.get(address) {
+        Some(function) if !function.can_return => "noreturn ",
+        _ => "",
+    };
+
+    format!(
+        "{}{} {}({})",
+        noreturn,
+        return_type(&outputs),
+        data.name(address),
+        parameters.join(", ")
+    )
+}
+
+fn render_call(
+    data: &Data,
+    stmt: &SsaStmt,
+    address: usize,
+    counts: &HashMap<SsaVar, usize>,
+    names: &MemoryNames<SsaVar>,
+) -> String {
+    let condition = condition_prefix(
+        match &stmt.stmt {
+            Stmt::Call { condition, .. } => condition,
+            _ => &None,
+        },
+        names,
+    );
+
+    let arguments: Vec<Var> = stmt
+        .implicit_uses
+        .iter()
+        .map(|var| var.var)
+        .filter(|var| *var != Var::StackPointer)
+        .collect();
+
+    // Only the outputs this call site goes on to read are shown
+    let outputs = data
+        .functions
+        .get(address)
+        .map(|function| function.convention.outputs.clone())
+        .unwrap_or_default();
+
+    let results: Vec<Var> = stmt
+        .implicit_defs
+        .iter()
+        .filter(|var| outputs.contains(&var.var) && counts.get(var).is_some_and(|&count| count > 0))
+        .map(|var| var.var)
+        .collect();
+
+    let call = format!(
+        "{}({})",
+        data.name(address),
+        join_names(&operand_names(&arguments))
+    );
+
+    match result_names(&operand_names(&results)) {
+        Some(results) => format!("{}{} = {};", condition, results, call),
+        None => format!("{}{};", condition, call),
+    }
+}
+
+// The callee returns to our caller, so its results are ours
+fn render_tail_call(
+    data: &Data,
+    condition: &Option<Expr<SsaVar>>,
+    target: usize,
+    names: &MemoryNames<SsaVar>,
+) -> String {
+    let arguments = data
+        .functions
+        .get(target)
+        .map(|function| function.convention.inputs.clone())
+        .unwrap_or_default();
+
+    format!(
+        "{}return {}({});",
+        condition_prefix(condition, names),
+        data.name(target),
+        join_names(&operand_names(&arguments))
+    )
+}
+
+fn render_stmt(
+    data: &Data,
+    chunks: &ChunkSet,
+    stmt: &SsaStmt,
+    counts: &HashMap<SsaVar, usize>,
+    names: &MemoryNames<SsaVar>,
+) -> String {
+    match &stmt.stmt {
+        Stmt::Jump {
+            condition,
+            target: Target::Direct(address),
+        } => match chunks.tail_call_target(stmt.address, &stmt.stmt) {
+            Some(target) => render_tail_call(data, condition, target, names),
+            None => format!(
+                "{}goto {};",
+                condition_prefix(condition, names),
+                data.label(*address)
+            ),
+        },
+        Stmt::Jump {
+            condition,
+            target: Target::Indirect(target),
+        } => format!(
+            "{}goto *({});",
+            condition_prefix(condition, names),
+            target.named(names)
+        ),
+        Stmt::Call {
+            target: Target::Direct(address),
+            ..
+        } => render_call(data, stmt, *address, counts, names),
+        Stmt::Call {
+            condition,
+            target: Target::Indirect(target),
+        } => format!(
+            "{}(*({}))();",
+            condition_prefix(condition, names),
+            target.named(names)
+        ),
+        Stmt::Return { condition } => {
+            let outputs = data
+                .functions
+                .get(chunks.root().address())
+                .map(|function| operand_names(&function.convention.outputs))
+                .unwrap_or_default();
+
+            match result_names(&outputs) {
+                Some(results) => {
+                    format!("{}return {};", condition_prefix(condition, names), results)
+                }
+                None => format!("{}return;", condition_prefix(condition, names)),
+            }
+        }
+        Stmt::Assign(var, value) => render_assign(&var.to_string(), var.var.width(), value, names),
+        Stmt::AssignPair(high, low, value) => match pair_name(high.var, low.var) {
+            Some(name) => render_assign(name, 16, value, names),
+            None => format!("{}:{} = {};", high, low, value.named(names)),
+        },
+        Stmt::Store(address, value) => {
+            let target = Expr::Load(Box::new(address.clone()));
+            format!("{} = {};", target.named(names), value.named(names))
+        }
+        stmt => format!("{}", stmt),
+    }
+}
+
+// Names RAM variables and hardware registers by their constant address
+fn variable_names(data: &Data) -> impl Fn(&Expr<SsaVar>) -> Option<String> + '_ {
+    move |address| {
+        address
+            .as_const()
+            .and_then(|address| data.variable_name(address as usize))
+    }
+}
+
+fn assigns_hl(stmt: &Stmt<SsaVar>) -> bool {
+    match stmt {
+        Stmt::AssignPair(high, low, _) => pair_name(high.var, low.var) == Some("hl"),
+        _ => false,
+    }
+}
+
+fn is_compound_op(op: BinaryOp) -> bool {
+    matches!(
+        op,
+        BinaryOp::Add
+            | BinaryOp::Sub
+            | BinaryOp::And
+            | BinaryOp::Or
+            | BinaryOp::Xor
+            | BinaryOp::Shl
+            | BinaryOp::Shr
+    )
+}
+
+fn render_assign(
+    target: &str,
+    width: u8,
+    value: &Expr<SsaVar>,
+    names: &MemoryNames<SsaVar>,
+) -> String {
+    // Assigning to an 8 or 16-bit variable already truncates
+    let value = match (width, value) {
+        (8, Expr::Binary(BinaryOp::And, lhs, rhs)) if rhs.as_const() == Some(0xFF) => lhs,
+        (16, Expr::Binary(BinaryOp::And, lhs, rhs)) if rhs.as_const() == Some(0xFFFF) => lhs,
+        _ => value,
+    };
+
+    if let Expr::Binary(op, lhs, rhs) = value {
+        if is_compound_op(*op) && lhs.named(names).to_string() == target {
+            return match (op, rhs.as_const()) {
+                (BinaryOp::Add, Some(1)) => format!("{}++;", target),
+                (BinaryOp::Sub, Some(1)) => format!("{}--;", target),
+                _ => format!("{} {}= {};", target, op.symbol(), rhs.named(names)),
+            };
+        }
+    }
+
+    format!("{} = {};", target, value.named(names))
+}
+
+fn is_unconditional_transfer(stmt: &Stmt<SsaVar>) -> bool {
+    matches!(
+        stmt,
+        Stmt::Jump {
+            condition: None,
+            ..
+        } | Stmt::Return { condition: None }
+    )
+}
+
+pub fn build_ssa(chunks: &ChunkSet, data: &Data) -> SsaFunction {
+    let mut function = ssa::build(chunks, data);
+    function.eliminate_dead_code();
+    function.propagate_expressions();
+    function.eliminate_dead_code();
+    pairs::recover_pairs(&mut function);
+    function.eliminate_dead_code();
+    function
+}
+
+pub fn decompile(cart: &Cartridge, data: &Data, address: usize) -> String {
+    let chunks = disassembly::chunk(cart, data, address);
+    let function = build_ssa(&chunks, data);
+    let counts = function.use_counts();
+    let variables = variable_names(data);
+
+    // Only compiled code keeps its arguments and locals on the stack
+    let frame = if data.toolchain.is_compiled() {
+        frame::analyse(cart, data, address)
+    } else {
+        StackFrame::default()
+    };
+
+    // Chunks are printed in address order, with the entry chunk first
+    let mut order: Vec<usize> = (0..function.blocks.len()).collect();
+    order.sort_by_key(|&index| (index != function.entry, function.blocks[index].address));
+
+    // Work out which chunks need an explicit jump to their successor, and
+    // which chunks are referenced by a jump and so need a label
+    let mut fallthrough_gotos = Vec::new();
+    let mut redundant_jumps = HashSet::new();
+    let mut labels = HashSet::new();
+
+    for (position, &index) in order.iter().enumerate() {
+        let block = &function.blocks[index];
+        let next_printed = order.get(position + 1).cloned();
+        let next_address = next_printed.map(|next| function.blocks[next].address);
+
+        for (stmt_index, stmt) in block.stmts.iter().enumerate() {
+            if let Stmt::Jump {
+                ref condition,
+                target: Target::Direct(target),
+            } = stmt.stmt
+            {
+                let is_last = stmt_index + 1 == block.stmts.len();
+                if chunks.tail_call_target(stmt.address, &stmt.stmt).is_some() {
+                    continue;
+                } else if condition.is_none() && is_last && Some(target) == next_address {
+                    redundant_jumps.insert(index);
+                } else {
+                    labels.insert(target);
+                }
+            }
+        }
+
+        let ends_in_transfer = block
+            .stmts
+            .last()
+            .is_some_and(|stmt| is_unconditional_transfer(&stmt.stmt));
+
+        let fallthrough = match block.successors.len() {
+            1 if !ends_in_transfer => Some(block.successors[0]),
+            2 => Some(block.successors[1]),
+            _ => None,
+        };
+
+        match fallthrough {
+            Some(successor) if Some(successor) != next_printed => {
+                let address = function.blocks[successor].address;
+                labels.insert(address);
+                fallthrough_gotos.push(Some(address));
+            }
+            _ => fallthrough_gotos.push(None),
+        }
+    }
+
+    let mut output = String::new();
+    writeln!(output, "{} {{", signature(data, address, &frame)).unwrap();
+    for local in &frame.locals {
+        writeln!(output, "    {} {};", type_name(local.width), local.name).unwrap();
+    }
+
+    for (position, &index) in order.iter().enumerate() {
+        let block = &function.blocks[index];
+
+        if labels.contains(&block.address) {
+            writeln!(output, "{}:", data.label(block.address)).unwrap();
+        }
+
+        let stmt_count = if redundant_jumps.contains(&index) {
+            block.stmts.len() - 1
+        } else {
+            block.stmts.len()
+        };
+
+        for stmt in &block.stmts[..stmt_count] {
+            // What HL points to on the stack is a parameter or local
+            let slot = frame.access_name(stmt.address);
+            let names = |address: &Expr<SsaVar>| match slot {
+                Some(ref name) if address.pair_name() == Some("hl") => Some(name.clone()),
+                _ => variables(address),
+            };
+            let rendered = match frame.pointer_name(stmt.address) {
+                Some(name) if assigns_hl(&stmt.stmt) => format!("hl = &{};", name),
+                _ => render_stmt(data, &chunks, stmt, &counts, &names),
+            };
+            writeln!(output, "    {}", rendered).unwrap();
+        }
+
+        if let Some(address) = fallthrough_gotos[position] {
+            writeln!(output, "    goto {};", data.label(address)).unwrap();
+        }
+    }
+
+    writeln!(output, "}}").unwrap();
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+
+    #[test]
+    fn names_variables_by_address() {
+        // ld a,[$C0A3] / inc a / ld [$C0A3],a / ldh [rLCDC],a / ret
+        let cart =
+            Cartridge::with_function(&[0xFA, 0xA3, 0xC0, 0x3C, 0xEA, 0xA3, 0xC0, 0xE0, 0x40, 0xC9]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        let output = decompile(&cart, &data, 0x200);
+
+        let name = data.variable_name(0xC0A3).unwrap();
+        assert!(output.contains(&format!("{} = ", name)), "{}", output);
+        assert!(output.contains("rLCDC = "), "{}", output);
+        assert!(!output.contains("mem["), "{}", output);
+    }
+}
@@ -1,9 +1,10 @@
 use data::*;
 use gb::*;
 use instruction_walker;
+use ir::*;
+use stack::StackAnalysis;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::slice;
 use Cartridge;
 
 pub fn collect_instructions(cart: &Cartridge, data: &Data, address: usize) -> Vec<Instruction> {
@@ -18,56 +19,124 @@ pub fn collect_instructions(cart: &Cartridge, data: &Data, address: usize) -> Ve
 pub fn chunk(cart: &Cartridge, data: &Data, address: usize) -> ChunkSet {
     let instructions = collect_instructions(cart, data, address);
 
-    let jump_targets: HashSet<usize> = instructions
+    let is_tail_call = |target: usize| target != address && data.functions.is_start(target);
+
+    let addresses: HashSet<usize> = instructions
+        .iter()
+        .map(|instruction| instruction.address)
+        .collect();
+
+    // Jumps to the start of another function are tail calls, not edges,
+    // unless they jump back into code of this one, like a loop
+    let tail_jumps: HashMap<usize, usize> = instructions
+        .iter()
+        .filter_map(|instruction| {
+            instruction
+                .jump_target()
+                .map(|target| (instruction.address, target))
+        })
+        .filter(|&(_, target)| data.functions.is_start(target) && !addresses.contains(&target))
+        .collect();
+    let local_jump_target = |instruction: &Instruction| {
+        instruction
+            .jump_target()
+            .filter(|_| !tail_jumps.contains_key(&instruction.address))
+    };
+
+    // Instructions that fall through into the start of another function
+    let fallthrough_calls: HashMap<usize, usize> = instructions
         .iter()
-        .filter_map(|instruction| instruction.jump_target())
+        .filter(|instruction| {
+            !is_dead_end(data, instruction) && (instruction.can_continue() || instruction.is_call())
+        })
+        .map(|instruction| {
+            (
+                instruction.address,
+                instruction.address + instruction.size(),
+            )
+        })
+        .filter(|&(_, next_address)| is_tail_call(next_address))
         .collect();
 
-    // Build an index of addresses mapped to an instruction range
-    let mut blocks = HashMap::new();
+    // Chunks start at the entry and wherever control arrives other than from
+    // the instruction before: jump targets, and on overlapping instructions,
+    // where one falls through past the next
+    let mut chunk_starts: HashSet<usize> =
+        instructions.iter().filter_map(local_jump_target).collect();
+    chunk_starts.insert(address);
+    chunk_starts.extend(
+        instructions
+            .windows(2)
+            .map(|pair| (pair[0].address + pair[0].size(), pair[1].address))
+            .filter(|&(next_address, following)| next_address != following)
+            .map(|(next_address, _)| next_address),
+    );
+
+    // Split the instructions into ranges of consecutive instructions
+    let mut blocks = Vec::new();
     let mut start = 0;
-    let mut len = 0;
 
     for (index, instruction) in instructions.iter().enumerate() {
-        if jump_targets.contains(&instruction.address) && len > 0 {
-            blocks.insert(instructions[start].address, (start, len));
-            start = index;
-            len = 0;
-        }
+        if index > start {
+            let previous = instructions[index - 1];
+            let is_contiguous = previous.address + previous.size() == instruction.address;
 
-        len += 1;
+            if chunk_starts.contains(&instruction.address) || !is_contiguous {
+                blocks.push((start, index - start));
+                start = index;
+            }
+        }
 
-        if is_dead_end(data, instruction) || instruction.is_jump() {
-            blocks.insert(instructions[start].address, (start, len));
-            start = index;
-            len = 0;
+        if is_dead_end(data, instruction)
+            || instruction.is_jump()
+            || fallthrough_calls.contains_key(&instruction.address)
+        {
+            blocks.push((start, index + 1 - start));
+            start = index + 1;
         }
     }
 
-    if len > 0 {
-        blocks.insert(instructions[start].address, (start, len));
+    if start < instructions.len() {
+        blocks.push((start, instructions.len() - start));
     }
 
     // Build the actual chunk set
     let mut chunks = Vec::new();
     let mut address_map = HashMap::new();
 
-    // Put all chunks in the set
-    for (address, (start, len)) in blocks.into_iter() {
+    // Where control goes after `instruction` as `(jump, fall through)`. Paths
+    // running off into memory we can't decode, like RAM nothing is known to
+    // be copied to, end there, but a conditional jump there still falls
+    // through
+    let successor_addresses = |instruction: &Instruction| {
+        let next_address = instruction.address + instruction.size();
+        let falls_through = instruction.is_conditional() || !instruction.is_jump();
+        (
+            local_jump_target(instruction).filter(|target| addresses.contains(target)),
+            Some(next_address).filter(|next| falls_through && addresses.contains(next)),
+        )
+    };
+
+    // Put all chunks in the set, in address order
+    for (start, len) in blocks.into_iter() {
         let last_instruction = instructions[start + len - 1];
 
-        let chunk_type = if is_dead_end(data, &last_instruction) {
-            InternalChunkType::End
-        } else if let (true, Some(condition)) =
-            (last_instruction.is_jump(), last_instruction.condition())
+        let chunk_type = if is_dead_end(data, &last_instruction)
+            || fallthrough_calls.contains_key(&last_instruction.address)
+            || (tail_jumps.contains_key(&last_instruction.address)
+                && !last_instruction.is_conditional())
         {
-            InternalChunkType::Conditional {
-                condition,
-                if_true: 0,
-                if_false: 0,
-            }
+            InternalChunkType::End
         } else {
-            InternalChunkType::Unconditional { next: 0 }
+            match successor_addresses(&last_instruction) {
+                (Some(_), Some(_)) => InternalChunkType::Conditional {
+                    condition: last_instruction.condition().unwrap(),
+                    if_true: 0,
+                    if_false: 0,
+                },
+                (None, None) => InternalChunkType::End,
+                _ => InternalChunkType::Unconditional { next: 0 },
+            }
         };
 
         let index = chunks.len();
@@ -76,47 +145,52 @@ pub fn chunk(cart: &Cartridge, data: &Data, address: usize) -> ChunkSet {
             start,
             len,
         });
-        address_map.insert(address, index);
+        address_map.insert(instructions[start].address, index);
     }
 
     // Link the chunks
     for chunk in chunks.iter_mut() {
         let instruction = instructions[chunk.start + chunk.len - 1];
-        let next_address = instruction.address + instruction.size();
+        let (jump_address, next_address) = successor_addresses(&instruction);
 
         match &mut chunk.chunk_type {
             InternalChunkType::End => (),
             InternalChunkType::Conditional {
                 if_true, if_false, ..
             } => {
-                let jump_address = instruction.jump_target().unwrap();
-                *if_true = *address_map.get(&jump_address).unwrap();
-                *if_false = *address_map.get(&next_address).unwrap();
+                *if_true = *address_map.get(&jump_address.unwrap()).unwrap();
+                *if_false = *address_map.get(&next_address.unwrap()).unwrap();
             }
             InternalChunkType::Unconditional { next } => {
-                let address = instruction.jump_target().unwrap_or(next_address);
+                let address = jump_address.or(next_address).unwrap();
                 *next = *address_map.get(&address).unwrap();
             }
         }
     }
 
+    let entry = address_map[&address];
+
     ChunkSet {
         instructions,
         chunks,
+        entry,
+        tail_jumps,
+        fallthrough_calls,
     }
 }
 
 fn is_dead_end(data: &Data, instruction: &Instruction) -> bool {
     if instruction.is_conditional() {
         false
-    } else if instruction.is_return() {
+    } else if instruction.is_return() || instruction.is_indirect_jump() {
         true
     } else if let Some(call_target) = instruction.call_target() {
+        // Calls we can't follow are assumed to return
         if data
             .functions
             .get(call_target)
             .map(|function| function.can_return)
-            .unwrap_or(false)
+            .unwrap_or(true)
         {
             false
         } else {
@@ -130,30 +204,154 @@ fn is_dead_end(data: &Data, instruction: &Instruction) -> bool {
 pub struct ChunkSet {
     chunks: Vec<InternalChunk>,
     instructions: Vec<Instruction>,
+    entry: usize,
+    /// Jumps to the start of another function, by the address of that
+    /// function.
+    tail_jumps: HashMap<usize, usize>,
+    /// Instructions followed by the start of another function, by the
+    /// address of that function.
+    fallthrough_calls: HashMap<usize, usize>,
 }
 
 impl ChunkSet {
-    fn get_by_index(&self, index: usize) -> Chunk {
-        Chunk::from_internal(self, &self.chunks[index])
+    fn get_by_index(&self, index: usize) -> Chunk<'_> {
+        Chunk::from_internal(self, index)
+    }
+
+    pub fn root(&self) -> Chunk<'_> {
+        self.get_by_index(self.entry)
     }
 
-    pub fn root(&self) -> Chunk {
-        self.get_by_index(0)
+    pub fn entry_index(&self) -> usize {
+        self.entry
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn get(&self, index: usize) -> Chunk<'_> {
+        self.get_by_index(index)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Chunk<'_>> {
+        ChunkSetIter {
+            set: self,
+            index: 0,
+        }
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// Functions this one hands control to without calling them, by jumping
+    /// or falling through to their start.
+    pub fn tail_call_targets(&self) -> Vec<usize> {
+        self.tail_jumps
+            .values()
+            .chain(self.fallthrough_calls.values())
+            .cloned()
+            .collect()
+    }
+
+    /// The function a statement lifted from the instruction at `address`
+    /// hands control to, if it is a tail call.
+    pub fn tail_call_target<V>(&self, address: usize, stmt: &Stmt<V>) -> Option<usize> {
+        match *stmt {
+            Stmt::Jump {
+                target: Target::Direct(target),
+                ..
+            } => self
+                .tail_jumps
+                .get(&address)
+                .into_iter()
+                .chain(self.fallthrough_calls.get(&address))
+                .cloned()
+                .find(|&callee| callee == target),
+            _ => None,
+        }
+    }
+
+    /// Lifts an instruction of the function, adding a tail call when it
+    /// falls through into another function.
+    pub fn lift(&self, stack: &StackAnalysis, instruction: &Instruction) -> Vec<Stmt> {
+        let mut stmts = stack.lift(instruction);
+
+        if let Some(&target) = self.fallthrough_calls.get(&instruction.address) {
+            stmts.push(Stmt::Jump {
+                condition: None,
+                target: Target::Direct(target),
+            });
+        }
+
+        stmts
+    }
+
+    pub fn successors(&self, index: usize) -> Vec<usize> {
+        match self.chunks[index].chunk_type {
+            InternalChunkType::End => Vec::new(),
+            InternalChunkType::Conditional {
+                if_true, if_false, ..
+            } => vec![if_true, if_false],
+            InternalChunkType::Unconditional { next } => vec![next],
+        }
+    }
+
+    pub fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut predecessors = vec![Vec::new(); self.chunks.len()];
+        for index in 0..self.chunks.len() {
+            for successor in self.successors(index) {
+                if !predecessors[successor].contains(&index) {
+                    predecessors[successor].push(index);
+                }
+            }
+        }
+        predecessors
+    }
+
+    /// Chunk indices in reverse postorder from the entry; unreachable chunks
+    /// are left out.
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut visited = vec![false; self.chunks.len()];
+        let mut order = Vec::new();
+        let mut stack = vec![(self.entry, 0)];
+        visited[self.entry] = true;
+
+        while let Some((index, next_child)) = stack.pop() {
+            let successors = self.successors(index);
+            if next_child < successors.len() {
+                stack.push((index, next_child + 1));
+                let successor = successors[next_child];
+                if !visited[successor] {
+                    visited[successor] = true;
+                    stack.push((successor, 0));
+                }
+            } else {
+                order.push(index);
+            }
+        }
+
+        order.reverse();
+        order
     }
 }
 
 struct ChunkSetIter<'a> {
     set: &'a ChunkSet,
-    iter: slice::Iter<'a, InternalChunk>,
+    index: usize,
 }
 
 impl<'a> Iterator for ChunkSetIter<'a> {
     type Item = Chunk<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter
-            .next()
-            .map(|internal_chunk| Chunk::from_internal(self.set, internal_chunk))
+        if self.index < self.set.chunks.len() {
+            self.index += 1;
+            Some(Chunk::from_internal(self.set, self.index - 1))
+        } else {
+            None
+        }
     }
 }
 
@@ -178,19 +376,31 @@ struct InternalChunk {
 
 pub struct Chunk<'a> {
     set: &'a ChunkSet,
+    index: usize,
     internal_chunk_type: InternalChunkType,
     instructions: &'a [Instruction],
 }
 
 impl<'a> Chunk<'a> {
-    fn from_internal(set: &'a ChunkSet, chunk: &'a InternalChunk) -> Self {
+    fn from_internal(set: &'a ChunkSet, index: usize) -> Self {
+        let chunk = &set.chunks[index];
+
         Self {
             set,
+            index,
             internal_chunk_type: chunk.chunk_type,
             instructions: &set.instructions[chunk.start..chunk.start + chunk.len],
         }
     }
 
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn address(&self) -> usize {
+        self.instructions[0].address
+    }
+
     pub fn chunk_type(&self) -> ChunkType<'a> {
         match self.internal_chunk_type {
             InternalChunkType::End => ChunkType::End,
@@ -225,3 +435,58 @@ pub enum ChunkType<'a> {
         next: Chunk<'a>,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+
+    #[test]
+    fn jumps_out_of_code_keep_falling_through() {
+        // jp nz,$C000 / ret, with nothing known to be copied to $C000
+        let cart = Cartridge::with_function(&[0xC2, 0x00, 0xC0, 0xC9]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        assert!(data.functions.get(0x200).unwrap().can_return);
+
+        let chunks = chunk(&cart, &data, 0x200);
+        let successors = chunks.successors(chunks.entry_index());
+        assert_eq!(successors.len(), 1);
+        assert_eq!(chunks.get(successors[0]).address(), 0x203);
+    }
+
+    #[test]
+    fn conditional_jumps_into_other_functions_are_tail_calls() {
+        // call $0200 / call $0204 / jr @, and a function that jumps into
+        // $0204 on a condition, which loops back to itself with `jp $0204`
+        let cart = Cartridge::with_code(&[
+            (0x100, &[0xC3, 0x50, 0x01]),
+            (0x150, &[0xCD, 0x00, 0x02, 0xCD, 0x04, 0x02, 0x18, 0xFE]),
+            (
+                0x200,
+                &[
+                    0xA7, 0x20, 0x01, 0xC9, 0x05, 0x28, 0x03, 0xC3, 0x04, 0x02, 0xC9,
+                ],
+            ),
+        ]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        assert!(data.functions.is_start(0x204));
+
+        let chunks = chunk(&cart, &data, 0x200);
+        assert_eq!(chunks.tail_call_targets(), vec![0x204]);
+        assert!(chunks
+            .instructions()
+            .iter()
+            .all(|instruction| instruction.address < 0x204));
+        let successors = chunks.successors(chunks.entry_index());
+        assert_eq!(successors.len(), 1);
+        assert_eq!(chunks.get(successors[0]).address(), 0x203);
+
+        let chunks = chunk(&cart, &data, 0x204);
+        assert!(chunks.tail_call_targets().is_empty());
+        let jump = chunks
+            .iter()
+            .position(|chunk| chunk.address() == 0x207)
+            .unwrap();
+        assert_eq!(chunks.successors(jump).len(), 1);
+    }
+}
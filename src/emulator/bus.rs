@@ -0,0 +1,349 @@
+use data::ROM_BANK_SIZE;
+use gb::*;
+
+const RAM_BANK_SIZE: usize = 0x2000;
+const WRAM_BANK_SIZE: usize = 0x1000;
+
+pub const CYCLES_PER_LINE: u32 = 456;
+const LINES_PER_FRAME: u8 = 154;
+const VBLANK_LINE: u8 = 144;
+
+const JOYPAD: usize = 0x00;
+const DIV: usize = 0x04;
+const TIMA: usize = 0x05;
+const TMA: usize = 0x06;
+const TAC: usize = 0x07;
+pub const IF: usize = 0x0F;
+const LCDC: usize = 0x40;
+const STAT: usize = 0x41;
+const LY: usize = 0x44;
+const LYC: usize = 0x45;
+const DMA: usize = 0x46;
+const SVBK: usize = 0x70;
+
+const VBLANK_INTERRUPT: u8 = 0x01;
+const STAT_INTERRUPT: u8 = 0x02;
+const TIMER_INTERRUPT: u8 = 0x04;
+
+// Cycles per timer increment, by the low bits of TAC
+const TIMER_PERIODS: [u32; 4] = [1024, 16, 64, 256];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mbc {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+impl Mbc {
+    fn of(cart: &Cartridge) -> Mbc {
+        use gb::CartridgeType::*;
+
+        match cart.cartridge_type() {
+            Some(MBC1) | Some(MBC1_RAM) | Some(MBC1_RAM_BATTERY) => Mbc::Mbc1,
+            Some(MBC2) | Some(MBC2_BATTERY) => Mbc::Mbc2,
+            Some(MBC3_TIMER_BATTERY)
+            | Some(MBC3_TIMER_RAM_BATTERY)
+            | Some(MBC3)
+            | Some(MBC3_RAM)
+            | Some(MBC3_RAM_BATTERY) => Mbc::Mbc3,
+            Some(MBC5)
+            | Some(MBC5_RAM)
+            | Some(MBC5_RAM_BATTERY)
+            | Some(MBC5_RUMBLE)
+            | Some(MBC5_RUMBLE_RAM)
+            | Some(MBC5_RUMBLE_RAM_BATTERY) => Mbc::Mbc5,
+            _ => Mbc::None,
+        }
+    }
+}
+
+/// The memory map: cartridge with its bank controller, RAM, and just enough
+/// of the I/O registers for games to get through their main loop. Nothing is
+/// drawn and there is no sound.
+pub struct Bus<'a> {
+    cart: &'a Cartridge,
+    mbc: Mbc,
+    pub rom_bank: usize,
+    ram_bank: usize,
+    ram_enabled: bool,
+    vram: Vec<u8>,
+    external_ram: Vec<u8>,
+    wram: Vec<u8>,
+    oam: Vec<u8>,
+    io: Vec<u8>,
+    hram: Vec<u8>,
+    interrupt_enable: u8,
+    /// Buttons held down, in the order of the joypad register's bits:
+    /// right, left, up, down, then A, B, select, start.
+    pub buttons: u8,
+    divider: u32,
+    timer: u32,
+    line_cycles: u32,
+}
+
+impl<'a> Bus<'a> {
+    pub fn new(cart: &'a Cartridge) -> Bus<'a> {
+        let mut io = vec![0; 0x80];
+        io[JOYPAD] = 0xCF;
+        io[LCDC] = 0x91;
+        io[STAT] = 0x85;
+        io[0x47] = 0xFC;
+
+        Bus {
+            cart,
+            mbc: Mbc::of(cart),
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+            vram: vec![0; 0x2000],
+            external_ram: vec![0; 16 * RAM_BANK_SIZE],
+            wram: vec![0; 8 * WRAM_BANK_SIZE],
+            oam: vec![0; 0xA0],
+            io,
+            hram: vec![0; 0x7F],
+            interrupt_enable: 0,
+            buttons: 0,
+            divider: 0,
+            timer: 0,
+            line_cycles: 0,
+        }
+    }
+
+    fn rom_banks(&self) -> usize {
+        (self.cart.rom_size() / ROM_BANK_SIZE).max(2)
+    }
+
+    fn read_rom(&self, offset: usize) -> u8 {
+        if offset < self.cart.rom_size() {
+            self.cart[offset]
+        } else {
+            0xFF
+        }
+    }
+
+    fn wram_bank(&self) -> usize {
+        (self.io[SVBK] as usize & 0x07).max(1)
+    }
+
+    pub fn read(&self, address: usize) -> u8 {
+        match address {
+            0x0000..=0x3FFF => self.read_rom(address),
+            0x4000..=0x7FFF => self.read_rom(self.rom_bank * ROM_BANK_SIZE + address - 0x4000),
+            0x8000..=0x9FFF => self.vram[address - 0x8000],
+            0xA000..=0xBFFF if self.ram_enabled => {
+                self.external_ram[self.ram_bank * RAM_BANK_SIZE + address - 0xA000]
+            }
+            0xA000..=0xBFFF => 0xFF,
+            0xC000..=0xCFFF => self.wram[address - 0xC000],
+            0xD000..=0xDFFF => self.wram[self.wram_bank() * WRAM_BANK_SIZE + address - 0xD000],
+            0xE000..=0xFDFF => self.read(address - 0x2000),
+            0xFE00..=0xFE9F => self.oam[address - 0xFE00],
+            0xFEA0..=0xFEFF => 0xFF,
+            0xFF00 => self.joypad(),
+            0xFF01..=0xFF7F => self.io[address - 0xFF00],
+            0xFF80..=0xFFFE => self.hram[address - 0xFF80],
+            _ => self.interrupt_enable,
+        }
+    }
+
+    pub fn write(&mut self, address: usize, value: u8) {
+        match address {
+            0x0000..=0x7FFF => self.write_mbc(address, value),
+            0x8000..=0x9FFF => self.vram[address - 0x8000] = value,
+            0xA000..=0xBFFF if self.ram_enabled => {
+                self.external_ram[self.ram_bank * RAM_BANK_SIZE + address - 0xA000] = value
+            }
+            0xA000..=0xBFFF => (),
+            0xC000..=0xCFFF => self.wram[address - 0xC000] = value,
+            0xD000..=0xDFFF => {
+                let bank = self.wram_bank();
+                self.wram[bank * WRAM_BANK_SIZE + address - 0xD000] = value
+            }
+            0xE000..=0xFDFF => self.write(address - 0x2000, value),
+            0xFE00..=0xFE9F => self.oam[address - 0xFE00] = value,
+            0xFEA0..=0xFEFF => (),
+            0xFF00..=0xFF7F => self.write_io(address - 0xFF00, value),
+            0xFF80..=0xFFFE => self.hram[address - 0xFF80] = value,
+            _ => self.interrupt_enable = value,
+        }
+    }
+
+    fn write_mbc(&mut self, address: usize, value: u8) {
+        let value = value as usize;
+        let bank = match (self.mbc, address) {
+            (Mbc::None, _) => return,
+            (Mbc::Mbc2, 0x0000..=0x3FFF) if address & 0x100 != 0 => (value & 0x0F).max(1),
+            (Mbc::Mbc2, 0x0000..=0x3FFF) => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+                return;
+            }
+            (_, 0x0000..=0x1FFF) => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+                return;
+            }
+            (Mbc::Mbc1, 0x2000..=0x3FFF) => (self.rom_bank & !0x1F) | (value & 0x1F).max(1),
+            (Mbc::Mbc1, 0x4000..=0x5FFF) => {
+                self.ram_bank = value & 0x03;
+                (self.rom_bank & 0x1F) | ((value & 0x03) << 5)
+            }
+            (Mbc::Mbc3, 0x2000..=0x3FFF) => (value & 0x7F).max(1),
+            (Mbc::Mbc5, 0x2000..=0x2FFF) => (self.rom_bank & 0x100) | value,
+            (Mbc::Mbc5, 0x3000..=0x3FFF) => (self.rom_bank & 0xFF) | ((value & 0x01) << 8),
+            // The real-time clock registers of MBC3 aren't emulated
+            (_, 0x4000..=0x5FFF) => {
+                self.ram_bank = value & 0x0F;
+                return;
+            }
+            _ => return,
+        };
+        self.rom_bank = bank % self.rom_banks();
+    }
+
+    fn write_io(&mut self, register: usize, value: u8) {
+        match register {
+            DIV => self.divider = 0,
+            LY => (),
+            DMA => {
+                let source = (value as usize) << 8;
+                for offset in 0..self.oam.len() {
+                    self.oam[offset] = self.read(source + offset);
+                }
+            }
+            _ => self.io[register] = value,
+        }
+    }
+
+    fn joypad(&self) -> u8 {
+        let select = self.io[JOYPAD];
+        let mut pressed = 0;
+        if select & 0x10 == 0 {
+            pressed |= self.buttons & 0x0F;
+        }
+        if select & 0x20 == 0 {
+            pressed |= self.buttons >> 4;
+        }
+        0xC0 | (select & 0x30) | (!pressed & 0x0F)
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: u8) {
+        self.io[IF] |= interrupt;
+    }
+
+    /// Interrupts that are both requested and enabled.
+    pub fn pending_interrupts(&self) -> u8 {
+        self.io[IF] & self.interrupt_enable & 0x1F
+    }
+
+    /// Runs the timer and the LCD's line counter on by `cycles`.
+    pub fn tick(&mut self, cycles: u32) {
+        self.divider = self.divider.wrapping_add(cycles);
+        self.io[DIV] = (self.divider >> 8) as u8;
+
+        let control = self.io[TAC];
+        if control & 0x04 != 0 {
+            self.timer += cycles;
+            let period = TIMER_PERIODS[control as usize & 0x03];
+            while self.timer >= period {
+                self.timer -= period;
+                let (value, overflow) = self.io[TIMA].overflowing_add(1);
+                if overflow {
+                    self.io[TIMA] = self.io[TMA];
+                    self.request_interrupt(TIMER_INTERRUPT);
+                } else {
+                    self.io[TIMA] = value;
+                }
+            }
+        }
+
+        if self.io[LCDC] & 0x80 == 0 {
+            self.io[LY] = 0;
+            self.line_cycles = 0;
+            return;
+        }
+
+        self.line_cycles += cycles;
+        while self.line_cycles >= CYCLES_PER_LINE {
+            self.line_cycles -= CYCLES_PER_LINE;
+            self.io[LY] = (self.io[LY] + 1) % LINES_PER_FRAME;
+
+            if self.io[LY] == VBLANK_LINE {
+                self.request_interrupt(VBLANK_INTERRUPT);
+            }
+            if self.io[LY] == self.io[LYC] && self.io[STAT] & 0x40 != 0 {
+                self.request_interrupt(STAT_INTERRUPT);
+            }
+        }
+
+        let mode = if self.io[LY] >= VBLANK_LINE {
+            1
+        } else if self.line_cycles < 80 {
+            2
+        } else if self.line_cycles < 252 {
+            3
+        } else {
+            0
+        };
+        let coincidence = if self.io[LY] == self.io[LYC] { 0x04 } else { 0 };
+        self.io[STAT] = 0x80 | (self.io[STAT] & 0x78) | coincidence | mode;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 64KB ROM whose banks each start with their number
+    fn banked(cartridge_type: u8) -> Cartridge {
+        let mut data = vec![0; 4 * ROM_BANK_SIZE];
+        for bank in 0..4 {
+            data[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        data[0x147] = cartridge_type;
+        Cartridge::new(data)
+    }
+
+    #[test]
+    fn switches_rom_banks() {
+        // MBC1, which maps bank 1 when asked for bank 0
+        let cart = banked(0x01);
+        let mut bus = Bus::new(&cart);
+        assert_eq!(bus.read(0x4000), 1);
+        bus.write(0x2000, 3);
+        assert_eq!(bus.read(0x4000), 3);
+        bus.write(0x2000, 0);
+        assert_eq!(bus.read(0x4000), 1);
+        // Banks past the end of the ROM wrap around
+        bus.write(0x2000, 6);
+        assert_eq!(bus.read(0x4000), 2);
+
+        // MBC5, which can map bank 0
+        let cart = banked(0x19);
+        let mut bus = Bus::new(&cart);
+        bus.write(0x2000, 0);
+        assert_eq!(bus.read(0x4000), 0);
+
+        // Without a bank controller writes to the ROM do nothing
+        let cart = banked(0x00);
+        let mut bus = Bus::new(&cart);
+        bus.write(0x2000, 3);
+        assert_eq!(bus.read(0x4000), 1);
+    }
+
+    #[test]
+    fn switches_ram_banks_once_enabled() {
+        let cart = banked(0x03);
+        let mut bus = Bus::new(&cart);
+        bus.write(0xA000, 0x55);
+        assert_eq!(bus.read(0xA000), 0xFF);
+
+        bus.write(0x0000, 0x0A);
+        bus.write(0xA000, 0x55);
+        bus.write(0x4000, 1);
+        assert_eq!(bus.read(0xA000), 0x00);
+        bus.write(0x4000, 0);
+        assert_eq!(bus.read(0xA000), 0x55);
+    }
+}
@@ -0,0 +1,248 @@
+use super::bus::*;
+use gb::*;
+use ir::*;
+
+/// Where interrupt handlers start; bit `n` of the interrupt flags calls the
+/// handler at `$40 + 8 * n`.
+const INTERRUPT_VECTORS: usize = 0x40;
+
+/// What an instruction did that the trace is interested in.
+pub struct Step {
+    pub cycles: u32,
+    pub indirect_target: Option<usize>,
+}
+
+/// The SM83, running the statements each instruction is lifted to.
+pub struct Cpu {
+    values: [i32; Var::COUNT],
+    pub pc: usize,
+    halted: bool,
+}
+
+impl Cpu {
+    /// The state the boot ROM leaves a DMG in.
+    pub fn new() -> Cpu {
+        let mut cpu = Cpu {
+            values: [0; Var::COUNT],
+            pc: Cartridge::ENTRY_POINT,
+            halted: false,
+        };
+
+        let registers = [
+            (Register::A, 0x01),
+            (Register::B, 0x00),
+            (Register::C, 0x13),
+            (Register::D, 0x00),
+            (Register::E, 0xD8),
+            (Register::H, 0x01),
+            (Register::L, 0x4D),
+        ];
+        for &(register, value) in registers.iter() {
+            cpu.set(Var::Register(register), value);
+        }
+        cpu.set(Var::Flag(Flag::Zero), 1);
+        cpu.set(Var::Flag(Flag::HalfCarry), 1);
+        cpu.set(Var::Flag(Flag::Carry), 1);
+        cpu.set(Var::StackPointer, 0xFFFE);
+        cpu
+    }
+
+    fn get(&self, var: Var) -> i32 {
+        self.values[var.index()]
+    }
+
+    fn set(&mut self, var: Var, value: i32) {
+        // Temporaries keep the carry out of a sum
+        self.values[var.index()] = match var {
+            Var::Temp(_) => value,
+            _ => value & ((1 << var.width()) - 1),
+        };
+    }
+
+    fn evaluate(&self, bus: &Bus, expr: &Expr) -> i32 {
+        expr.evaluate(&|var| Some(self.get(var)), &|address| {
+            Some(i32::from(bus.read(address as usize)))
+        })
+        .unwrap()
+    }
+
+    fn is_taken(&self, bus: &Bus, condition: &Option<Expr>) -> bool {
+        condition
+            .as_ref()
+            .is_none_or(|condition| self.evaluate(bus, condition) != 0)
+    }
+
+    fn push(&mut self, bus: &mut Bus, value: usize) {
+        let sp = (self.get(Var::StackPointer) - 2) & 0xFFFF;
+        self.set(Var::StackPointer, sp);
+        bus.write(sp as usize, value as u8);
+        bus.write((sp as usize + 1) & 0xFFFF, (value >> 8) as u8);
+    }
+
+    fn pop(&mut self, bus: &Bus) -> usize {
+        let sp = self.get(Var::StackPointer) as usize;
+        self.set(Var::StackPointer, sp as i32 + 2);
+        bus.read(sp) as usize | (bus.read((sp + 1) & 0xFFFF) as usize) << 8
+    }
+
+    fn decode(&self, bus: &Bus) -> Result<Instruction, DecodeError> {
+        let bytes: Vec<u8> = (0..3)
+            .map(|offset| bus.read((self.pc + offset) & 0xFFFF))
+            .collect();
+        let op_code =
+            OpCode::decode_at(&Cartridge::new(bytes), 0).map_err(|error| DecodeError {
+                address: self.pc,
+                opcode: error.opcode,
+            })?;
+
+        Ok(Instruction {
+            address: self.pc,
+            op_code,
+        })
+    }
+
+    /// Runs one instruction. Timing is approximate: four cycles per byte of
+    /// the instruction and per memory write, plus a little for taken
+    /// branches.
+    pub fn step(&mut self, bus: &mut Bus) -> Result<Option<Step>, DecodeError> {
+        if self.halted {
+            return Ok(None);
+        }
+
+        let instruction = self.decode(bus)?;
+        let mut next = self.pc + instruction.size();
+        let mut cycles = 4 * instruction.size() as u32;
+        let mut indirect_target = None;
+
+        for stmt in lift(&instruction) {
+            match &stmt {
+                Stmt::Assign(var, value) => {
+                    let value = self.evaluate(bus, value);
+                    self.set(*var, value);
+                }
+                Stmt::AssignPair(high, low, value) => {
+                    let value = self.evaluate(bus, value);
+                    self.set(*high, value >> 8);
+                    self.set(*low, value);
+                }
+                Stmt::Store(address, value) => {
+                    let address = self.evaluate(bus, address) as usize & 0xFFFF;
+                    let value = self.evaluate(bus, value) as u8;
+                    bus.write(address, value);
+                    cycles += 4;
+                }
+                Stmt::Jump { condition, target } if self.is_taken(bus, condition) => {
+                    next = match target {
+                        Target::Direct(address) => *address,
+                        Target::Indirect(address) => {
+                            let address = self.evaluate(bus, address) as usize & 0xFFFF;
+                            indirect_target = Some(address);
+                            address
+                        }
+                    };
+                    cycles += 4;
+                }
+                Stmt::Call { condition, target } if self.is_taken(bus, condition) => {
+                    self.push(bus, next);
+                    next = match target {
+                        Target::Direct(address) => *address,
+                        Target::Indirect(address) => self.evaluate(bus, address) as usize,
+                    };
+                    cycles += 12;
+                }
+                Stmt::Return { condition } if self.is_taken(bus, condition) => {
+                    next = self.pop(bus);
+                    cycles += 12;
+                }
+                Stmt::Halt => self.halted = true,
+                _ => (),
+            }
+        }
+
+        self.pc = next & 0xFFFF;
+
+        Ok(Some(Step {
+            cycles,
+            indirect_target,
+        }))
+    }
+
+    /// Wakes from `halt` on any pending interrupt, and calls the handler of
+    /// the highest priority one if interrupts are enabled. Returns the cycles
+    /// taken.
+    pub fn service_interrupts(&mut self, bus: &mut Bus) -> u32 {
+        let pending = bus.pending_interrupts();
+        if pending == 0 {
+            return 0;
+        }
+
+        self.halted = false;
+        if self.get(Var::InterruptsEnabled) == 0 {
+            return 0;
+        }
+
+        let bit = pending.trailing_zeros() as usize;
+        bus.write(0xFF00 + IF, bus.read(0xFF00 + IF) & !(1 << bit));
+        self.set(Var::InterruptsEnabled, 0);
+        let pc = self.pc;
+        self.push(bus, pc);
+        self.pc = INTERRUPT_VECTORS + 8 * bit;
+        20
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returns_past_calls() {
+        // $0150: call $0160 / ld b,a
+        // $0160: ld a,$42 / ret
+        let cart = Cartridge::with_code(&[
+            (0x150, &[0xCD, 0x60, 0x01, 0x47]),
+            (0x160, &[0x3E, 0x42, 0xC9]),
+        ]);
+        let mut bus = Bus::new(&cart);
+        let mut cpu = Cpu::new();
+        cpu.pc = 0x150;
+
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.pc, 0x160);
+        assert_eq!(cpu.get(Var::StackPointer), 0xFFFC);
+
+        for _ in 0..3 {
+            cpu.step(&mut bus).unwrap();
+        }
+        assert_eq!(cpu.pc, 0x154);
+        assert_eq!(cpu.get(Var::StackPointer), 0xFFFE);
+        assert_eq!(cpu.get(Var::Register(Register::B)), 0x42);
+    }
+
+    #[test]
+    fn interrupts_call_their_handler() {
+        // $0048: reti
+        // $0150: nop
+        let cart = Cartridge::with_code(&[(0x48, &[0xD9]), (0x150, &[0x00])]);
+        let mut bus = Bus::new(&cart);
+        let mut cpu = Cpu::new();
+        cpu.pc = 0x150;
+        cpu.step(&mut bus).unwrap();
+
+        // Requested but not enabled
+        bus.request_interrupt(0x02);
+        cpu.set(Var::InterruptsEnabled, 1);
+        assert_eq!(cpu.service_interrupts(&mut bus), 0);
+
+        bus.write(0xFFFF, 0x02);
+        assert_eq!(cpu.service_interrupts(&mut bus), 20);
+        assert_eq!(cpu.pc, 0x48);
+        assert_eq!(cpu.get(Var::InterruptsEnabled), 0);
+        assert_eq!(bus.pending_interrupts(), 0);
+
+        cpu.step(&mut bus).unwrap();
+        assert_eq!(cpu.pc, 0x151);
+        assert_eq!(cpu.get(Var::InterruptsEnabled), 1);
+        assert_eq!(cpu.get(Var::StackPointer), 0xFFFE);
+    }
+}
@@ -0,0 +1,97 @@
+use parse::LineError;
+
+// Joypad register bits, with the direction keys in the low nibble and the
+// buttons in the high one
+const BUTTONS: [(&str, u8); 8] = [
+    ("right", 0x01),
+    ("left", 0x02),
+    ("up", 0x04),
+    ("down", 0x08),
+    ("a", 0x10),
+    ("b", 0x20),
+    ("select", 0x40),
+    ("start", 0x80),
+];
+
+/// Buttons to hold down from given frames on. Each line of a script is a
+/// frame number followed by the buttons held from then until the next line,
+/// like `120 start` or `300 a+right`; `none` lets go of everything. Blank
+/// lines and lines starting with `#` are skipped.
+#[derive(Clone, Debug, Default)]
+pub struct InputScript {
+    changes: Vec<(u32, u8)>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<InputScript, LineError> {
+        let mut changes = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |message: String| LineError {
+                line: index + 1,
+                message,
+            };
+
+            let mut words = line.split_whitespace();
+            let frame = words.next().unwrap();
+            let frame: u32 = frame
+                .parse()
+                .map_err(|_| error(format!("invalid frame number `{}`", frame)))?;
+
+            let mut buttons = 0;
+            for name in words.flat_map(|word| word.split('+')) {
+                if name == "none" {
+                    continue;
+                }
+                match BUTTONS.iter().find(|&&(button, _)| button == name) {
+                    Some(&(_, bit)) => buttons |= bit,
+                    None => return Err(error(format!("unknown button `{}`", name))),
+                }
+            }
+
+            changes.push((frame, buttons));
+        }
+
+        changes.sort_by_key(|&(frame, _)| frame);
+        Ok(InputScript { changes })
+    }
+
+    /// The buttons held during `frame`.
+    pub fn buttons_at(&self, frame: u32) -> u8 {
+        self.changes
+            .iter()
+            .take_while(|&&(start, _)| start <= frame)
+            .last()
+            .map_or(0, |&(_, buttons)| buttons)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_buttons_until_the_next_line() {
+        let script =
+            InputScript::parse("# title screen\n\n300 a+right\n120 start\n400 none\n").unwrap();
+        assert_eq!(script.buttons_at(0), 0);
+        assert_eq!(script.buttons_at(120), 0x80);
+        assert_eq!(script.buttons_at(299), 0x80);
+        assert_eq!(script.buttons_at(300), 0x11);
+        assert_eq!(script.buttons_at(1000), 0);
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        let error = InputScript::parse("120 start\nsoon a\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: invalid frame number `soon`");
+
+        let error = InputScript::parse("\n\n10 a+turbo\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 3: unknown button `turbo`");
+    }
+}
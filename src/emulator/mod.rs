@@ -0,0 +1,116 @@
+mod bus;
+mod cpu;
+mod joypad;
+
+pub use self::joypad::*;
+
+use self::bus::*;
+use self::cpu::Cpu;
+use gb::*;
+use trace::{BankAddress, Trace};
+
+/// Cycles in one frame of 154 lines.
+const CYCLES_PER_FRAME: u32 = 154 * CYCLES_PER_LINE;
+
+/// A headless Game Boy that records where the code goes as it runs.
+pub struct Emulator<'a> {
+    cpu: Cpu,
+    bus: Bus<'a>,
+    pub trace: Trace,
+}
+
+impl<'a> Emulator<'a> {
+    pub fn new(cart: &'a Cartridge) -> Emulator<'a> {
+        Emulator {
+            cpu: Cpu::new(),
+            bus: Bus::new(cart),
+            trace: Trace::default(),
+        }
+    }
+
+    fn bank_address(&self, address: usize) -> BankAddress {
+        BankAddress::new(self.bus.rom_bank, address)
+    }
+
+    /// Runs for `frames` frames, holding down the buttons `input` asks for.
+    /// Stops early at an opcode that doesn't exist.
+    pub fn run(&mut self, frames: u32, input: &InputScript) -> Result<(), DecodeError> {
+        for frame in 0..frames {
+            self.bus.buttons = input.buttons_at(frame);
+
+            let mut cycles = 0;
+            while cycles < CYCLES_PER_FRAME {
+                cycles += self.step()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<u32, DecodeError> {
+        let address = self.bank_address(self.cpu.pc);
+        let bank = self.bus.rom_bank;
+
+        let cycles = match self.cpu.step(&mut self.bus)? {
+            Some(step) => {
+                self.trace.executed.insert(address);
+
+                if let Some(target) = step.indirect_target {
+                    let target = self.bank_address(target);
+                    self.trace
+                        .indirect_jumps
+                        .entry(address)
+                        .or_default()
+                        .insert(target);
+                }
+
+                if self.bus.rom_bank != bank {
+                    self.trace
+                        .bank_switches
+                        .entry(address)
+                        .or_default()
+                        .insert(self.bus.rom_bank);
+                }
+
+                step.cycles
+            }
+            // Halted
+            None => 4,
+        };
+
+        self.bus.tick(cycles);
+
+        let interrupt_cycles = self.cpu.service_interrupts(&mut self.bus);
+        if interrupt_cycles > 0 {
+            self.trace.interrupts.insert(self.cpu.pc);
+        }
+        Ok(cycles + interrupt_cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whether start reads as held after running `frames` frames of a script
+    // pressing it from frame 2
+    fn start_held_after(frames: u32) -> bool {
+        // ld a,$10 / ldh [rP1],a
+        // .loop: ldh a,[rP1] / ld [$C000],a / jr .loop
+        let cart = Cartridge::with_code(&[(
+            0x100,
+            &[
+                0x3E, 0x10, 0xE0, 0x00, 0xF0, 0x00, 0xEA, 0x00, 0xC0, 0x18, 0xF9,
+            ],
+        )]);
+        let script = InputScript::parse("2 start\n").unwrap();
+        let mut emulator = Emulator::new(&cart);
+        emulator.run(frames, &script).unwrap();
+        emulator.bus.read(0xC000) & 0x08 == 0
+    }
+
+    #[test]
+    fn holds_buttons_from_their_frame() {
+        assert!(!start_held_after(2));
+        assert!(start_held_after(3));
+    }
+}
@@ -0,0 +1,248 @@
+use constants;
+use constants::{KnownBits, KnownState};
+use data::*;
+use disassembly;
+use gb::*;
+use ir::*;
+use stack;
+use stack::StackDepth;
+use std::collections::{BTreeSet, HashMap};
+
+/// Where a function's return address is, as an offset from the stack
+/// pointer on entry. Arguments come after it and locals before it.
+const RETURN_ADDRESS_SIZE: i32 = 2;
+
+/// A parameter or local variable on the stack.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackSlot {
+    pub name: String,
+    /// From the stack pointer on entry.
+    pub offset: i32,
+    pub width: u8,
+}
+
+/// The arguments a function reads from the stack and the locals it keeps
+/// there, found by following HL from each `ld hl,sp+n`.
+#[derive(Debug, Default)]
+pub struct StackFrame {
+    pub parameters: Vec<StackSlot>,
+    pub locals: Vec<StackSlot>,
+    /// The byte of the frame HL points to after each `ld hl,sp+n`.
+    pointers: HashMap<usize, i32>,
+    /// The byte of the frame each instruction reads or writes through HL.
+    accesses: HashMap<usize, i32>,
+}
+
+/// Where the frame is taken to be while following HL. An instruction is run
+/// from both, and HL is only followed while it ends up the same distance
+/// from each, moved rather than loaded with something else.
+const FRAME_BASES: [i32; 2] = [0x8000, 0xC000];
+
+// Where HL points after `instruction`, given where it pointed before
+fn step_hl(cart: &Cartridge, data: &Data, instruction: &Instruction, hl: i32) -> Option<i32> {
+    let mut offsets = FRAME_BASES.iter().map(|&base| {
+        let mut state = KnownState::unknown();
+        let address = (base + hl) as u32;
+        state.set(
+            Var::Register(Register::H),
+            KnownBits::constant(address >> 8),
+        );
+        state.set(
+            Var::Register(Register::L),
+            KnownBits::constant(address & 0xFF),
+        );
+        let state = constants::step(cart, data, state, instruction);
+        state
+            .pair(Register::H, Register::L)
+            .map(|address| address as i32 - base)
+    });
+    let offset = offsets.next()??;
+    if offsets.all(|other| other == Some(offset)) {
+        Some(offset)
+    } else {
+        None
+    }
+}
+
+fn is_hl(address: &Expr) -> bool {
+    address.pair_name() == Some("hl")
+}
+
+// Whether `instruction` reads or writes the memory HL points to
+fn accesses_hl(instruction: &Instruction) -> bool {
+    lift(instruction).iter().any(|stmt| {
+        let mut reads = false;
+        stmt.visit_exprs(|expr| reads |= expr.load_addresses().into_iter().any(is_hl));
+        match stmt {
+            Stmt::Store(address, _) => reads || is_hl(address),
+            _ => reads,
+        }
+    })
+}
+
+// Bytes read or written one after the other are taken to be a 16-bit value
+fn slots(bytes: &BTreeSet<i32>, words: &BTreeSet<i32>, prefix: &str) -> Vec<StackSlot> {
+    let mut slots: Vec<StackSlot> = Vec::new();
+    for &offset in bytes {
+        if slots
+            .last()
+            .is_some_and(|slot| slot.offset + i32::from(slot.width / 8) > offset)
+        {
+            continue;
+        }
+        let width = if words.contains(&offset) { 16 } else { 8 };
+        slots.push(StackSlot {
+            name: format!("{}{}", prefix, slots.len()),
+            offset,
+            width,
+        });
+    }
+    slots
+}
+
+impl StackFrame {
+    fn slot(&self, offset: i32) -> Option<&StackSlot> {
+        self.parameters
+            .iter()
+            .chain(self.locals.iter())
+            .find(|slot| offset >= slot.offset && offset < slot.offset + i32::from(slot.width / 8))
+    }
+
+    // The name of the byte at `offset`, with `.lo` or `.hi` for part of a
+    // 16-bit slot
+    fn byte_name(&self, offset: i32) -> Option<String> {
+        let slot = self.slot(offset)?;
+        Some(match (slot.width, offset - slot.offset) {
+            (8, _) => slot.name.clone(),
+            (_, 0) => format!("{}.lo", slot.name),
+            _ => format!("{}.hi", slot.name),
+        })
+    }
+
+    /// The parameter or local the `ld hl,sp+n` at `address` points HL at.
+    pub fn pointer_name(&self, address: usize) -> Option<String> {
+        let offset = *self.pointers.get(&address)?;
+        let slot = self.slot(offset)?;
+        if slot.offset == offset {
+            Some(slot.name.clone())
+        } else {
+            self.byte_name(offset)
+        }
+    }
+
+    /// The parameter or local the instruction at `address` reads or writes
+    /// through HL.
+    pub fn access_name(&self, address: usize) -> Option<String> {
+        self.byte_name(*self.accesses.get(&address)?)
+    }
+}
+
+/// Finds the stack frame of the function at `address`, as SDCC lays it out:
+/// arguments past the return address, read with `ld hl,sp+n`, and locals
+/// below it, made room for with `add sp,-n`.
+pub fn analyse(cart: &Cartridge, data: &Data, address: usize) -> StackFrame {
+    let chunks = disassembly::chunk(cart, data, address);
+    let stack = stack::analyse(&chunks);
+
+    let mut frame = StackFrame::default();
+    let mut words = BTreeSet::new();
+
+    for chunk in chunks.iter() {
+        let mut hl = None;
+        let mut previous: Option<i32> = None;
+
+        for instruction in chunk.instructions().iter() {
+            if let OpCode::LD_HL_SPp_r8(value) = instruction.op_code {
+                if let Some(StackDepth::Known(depth)) = stack.depth_at(instruction.address) {
+                    let offset = i32::from(value.value) - depth;
+                    frame.pointers.insert(instruction.address, offset);
+                    hl = Some(offset);
+                    previous = None;
+                    continue;
+                }
+            }
+
+            match hl.filter(|_| accesses_hl(instruction)) {
+                Some(offset) => {
+                    frame.accesses.insert(instruction.address, offset);
+                    if previous == Some(offset - 1) {
+                        words.insert(offset - 1);
+                    }
+                    previous = Some(offset);
+                }
+                None => {
+                    if !matches!(instruction.op_code, OpCode::INC_HL | OpCode::DEC_HL) {
+                        previous = None;
+                    }
+                }
+            }
+            hl = hl.and_then(|hl| step_hl(cart, data, instruction, hl));
+        }
+    }
+
+    let offsets: BTreeSet<i32> = frame.accesses.values().cloned().collect();
+    let mut parameters: BTreeSet<i32> = offsets
+        .iter()
+        .cloned()
+        .filter(|&offset| offset >= RETURN_ADDRESS_SIZE)
+        .collect();
+    let locals: BTreeSet<i32> = offsets
+        .iter()
+        .cloned()
+        .filter(|&offset| offset < 0)
+        .collect();
+
+    // Arguments before the last one read are there even if unused
+    if let Some(&last) = parameters.iter().next_back() {
+        parameters.extend(RETURN_ADDRESS_SIZE..last);
+    }
+
+    frame.parameters = slots(&parameters, &words, "arg");
+    frame.locals = slots(&locals, &words, "local");
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+
+    #[test]
+    fn follows_hl_through_the_frame() {
+        // ld hl,sp+2 / ld a,[hli] / ld b,[hl] / ld hl,$C000 / ld [hl],a / ret
+        let cart =
+            Cartridge::with_function(&[0xF8, 0x02, 0x2A, 0x46, 0x21, 0x00, 0xC0, 0x77, 0xC9]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        let frame = analyse(&cart, &data, 0x200);
+
+        assert_eq!(
+            frame.parameters,
+            vec![StackSlot {
+                name: "arg0".to_string(),
+                offset: 2,
+                width: 16,
+            }]
+        );
+        assert!(frame.locals.is_empty());
+        assert_eq!(frame.pointer_name(0x200), Some("arg0".to_string()));
+        assert_eq!(frame.access_name(0x202), Some("arg0.lo".to_string()));
+        assert_eq!(frame.access_name(0x203), Some("arg0.hi".to_string()));
+        assert_eq!(frame.access_name(0x207), None);
+    }
+
+    #[test]
+    fn finds_locals_in_frames_made_through_hl() {
+        // ld hl,-2 / add hl,sp / ld sp,hl / ld hl,sp+4 / ld a,[hl]
+        // ld hl,sp+0 / ld [hl],a / ld hl,2 / add hl,sp / ld sp,hl / ret
+        let cart = Cartridge::with_function(&[
+            0x21, 0xFE, 0xFF, 0x39, 0xF9, 0xF8, 0x04, 0x7E, 0xF8, 0x00, 0x77, 0x21, 0x02, 0x00,
+            0x39, 0xF9, 0xC9,
+        ]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        let frame = analyse(&cart, &data, 0x200);
+
+        assert_eq!(frame.access_name(0x207), Some("arg0".to_string()));
+        assert_eq!(frame.access_name(0x20A), Some("local0".to_string()));
+        assert_eq!(frame.locals.len(), 1);
+    }
+}
@@ -6,12 +6,81 @@ pub struct Cartridge {
 
 impl Cartridge {
     pub const ENTRY_POINT: usize = 0x0100;
+    pub const INTERRUPT_VECTORS: [usize; 5] = [0x40, 0x48, 0x50, 0x58, 0x60];
     pub const TYPE_ADDRESS: usize = 0x0147;
+    pub const GLOBAL_CHECKSUM_ADDRESS: usize = 0x014E;
+    pub const HEADER_END: usize = 0x0150;
 
     pub fn new<T: Into<Box<[u8]>>>(data: T) -> Cartridge {
         Cartridge { data: data.into() }
     }
 
+    /// A 32KB ROM of `nop`s with `code` placed at each address, for tests.
+    #[cfg(test)]
+    pub fn with_code(code: &[(usize, &[u8])]) -> Cartridge {
+        let mut data = vec![0; 0x8000];
+        for &(address, bytes) in code {
+            data[address..address + bytes.len()].copy_from_slice(bytes);
+        }
+        Cartridge::new(data)
+    }
+
+    /// A ROM whose entry point calls `code` at $0200 and then loops, for
+    /// tests.
+    #[cfg(test)]
+    pub fn with_function(code: &[u8]) -> Cartridge {
+        // jp $0150, then call $0200 / jr @
+        Cartridge::with_code(&[
+            (0x100, &[0xC3, 0x50, 0x01]),
+            (0x150, &[0xCD, 0x00, 0x02, 0x18, 0xFE]),
+            (0x200, code),
+        ])
+    }
+
+    pub fn rom_size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The name in the header, which is cut short on colour cartridges.
+    pub fn title(&self) -> String {
+        self.data[0x134..0x144]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as char)
+            .collect()
+    }
+
+    /// The publisher's code: the two characters of the new licensee code,
+    /// or the old one in hex.
+    pub fn licensee(&self) -> String {
+        match self.data[0x14B] {
+            0x33 => self.data[0x144..0x146]
+                .iter()
+                .map(|&byte| byte as char)
+                .collect(),
+            code => format!("{:02X}", code),
+        }
+    }
+
+    pub fn header_checksum(&self) -> u8 {
+        self.data[0x14D]
+    }
+
+    pub fn supports_cgb(&self) -> bool {
+        self.data[0x143] & 0x80 != 0
+    }
+
+    pub fn supports_sgb(&self) -> bool {
+        self.data[0x146] == 0x03
+    }
+
+    /// The big-endian sum of every byte of the ROM but these two, from the
+    /// header.
+    pub fn global_checksum(&self) -> u16 {
+        let address = Cartridge::GLOBAL_CHECKSUM_ADDRESS;
+        u16::from(self.data[address]) << 8 | u16::from(self.data[address + 1])
+    }
+
     pub fn cartridge_type(&self) -> Option<CartridgeType> {
         CartridgeType::from_byte(self.data[CARTRIDGE_TYPE_ADDRESS])
     }
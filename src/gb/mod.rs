@@ -1,9 +1,11 @@
 mod cartridge;
 mod decode;
 mod opcodes;
+mod registers;
 mod value;
 
 pub use self::cartridge::*;
 pub use self::decode::*;
 pub use self::opcodes::*;
+pub use self::registers::*;
 pub use self::value::*;
@@ -8,11 +8,6 @@ pub struct Instruction {
 }
 
 impl Instruction {
-    pub fn decode_at(cart: &Cartridge, address: usize) -> Result<Self, DecodeError> {
-        let op_code = OpCode::decode_at(cart, address)?;
-        Ok(Self { address, op_code })
-    }
-
     pub fn condition(&self) -> Option<Condition> {
         self.op_code.condition()
     }
@@ -29,6 +24,10 @@ impl Instruction {
         self.op_code.is_jump()
     }
 
+    pub fn is_indirect_jump(&self) -> bool {
+        self.op_code.is_indirect_jump()
+    }
+
     pub fn can_continue(&self) -> bool {
         self.op_code.can_continue()
     }
@@ -601,18 +600,23 @@ impl OpCode {
             | OpCode::JP_NC_a16(a16)
             | OpCode::JP_Z_a16(a16)
             | OpCode::JP_NZ_a16(a16) => Some(a16.value as usize),
-            OpCode::JP_pHL => todo!("Trace dynamic jumps"),
             OpCode::JR_r8(r8)
             | OpCode::JR_C_r8(r8)
             | OpCode::JR_NC_r8(r8)
             | OpCode::JR_Z_r8(r8)
-            | OpCode::JR_NZ_r8(r8) => Some((address as isize + r8.value as isize) as usize),
+            | OpCode::JR_NZ_r8(r8) => {
+                Some(((address + self.size()) as isize + r8.value as isize) as usize)
+            }
             _ => None,
         }
     }
 
     pub fn is_jump(&self) -> bool {
-        self.jump_target(0).is_some()
+        self.jump_target(0).is_some() || self.is_indirect_jump()
+    }
+
+    pub fn is_indirect_jump(&self) -> bool {
+        matches!(self, OpCode::JP_pHL)
     }
 
     pub fn can_continue(&self) -> bool {
@@ -0,0 +1,307 @@
+use constants;
+use data::*;
+use disassembly;
+use gb::*;
+use png;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// How many tiles wide the images written are.
+const TILES_PER_ROW: usize = 16;
+
+const VRAM_START: usize = 0x8000;
+const VRAM_SIZE: usize = 0x2000;
+const LCDC: usize = 0xFF40;
+
+/// The default palette, from colour 0 to 3.
+const GRAYS: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+// The colour, 0 to 3, of each pixel of a tile, row by row
+fn decode_tile(bytes: &[u8], format: TileFormat) -> [u8; 64] {
+    let byte = |index: usize| bytes.get(index).cloned().unwrap_or(0);
+    let mut pixels = [0; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            let bit = 7 - x;
+            pixels[y * 8 + x] = match format {
+                TileFormat::TwoBpp => (byte(2 * y) >> bit & 1) | (byte(2 * y + 1) >> bit & 1) << 1,
+                TileFormat::OneBpp => (byte(y) >> bit & 1) * 3,
+            };
+        }
+    }
+    pixels
+}
+
+/// The tiles as a PNG, in the default grayscale palette where colour 0 is
+/// white.
+pub fn to_png(cart: &Cartridge, tiles: &TileCopy) -> Vec<u8> {
+    tiles_to_png(&cart[tiles.source..tiles.source + tiles.len], tiles.format)
+}
+
+/// Tiles in `format` as a PNG, in the default grayscale palette.
+pub fn tiles_to_png(bytes: &[u8], format: TileFormat) -> Vec<u8> {
+    let count = bytes.len().div_ceil(format.tile_size());
+    let width = TILES_PER_ROW.min(count) * 8;
+    let height = count.div_ceil(TILES_PER_ROW) * 8;
+
+    // Space left in the last row is white
+    let mut pixels = vec![3; width * height];
+    for (index, tile) in bytes.chunks(format.tile_size()).enumerate() {
+        let left = index % TILES_PER_ROW * 8;
+        let top = index / TILES_PER_ROW * 8;
+        for (pixel, colour) in decode_tile(tile, format).iter().enumerate() {
+            pixels[(top + pixel / 8) * width + left + pixel % 8] = 3 - colour;
+        }
+    }
+    png::encode_gray2(width, height, &pixels)
+}
+
+// Each copy from the ROM once, by address, leaving out those within another
+fn unique<T: Clone, F: Fn(&T) -> (usize, usize)>(copies: &[T], range: F) -> Vec<T> {
+    let mut copies = copies.to_vec();
+    copies.sort_by_key(|copy| {
+        let (source, len) = range(copy);
+        (source, !len)
+    });
+
+    let mut unique: Vec<T> = Vec::new();
+    for copy in copies {
+        let (source, _) = range(&copy);
+        match unique.last().map(&range) {
+            Some((last, len)) if source < last + len => (),
+            _ => unique.push(copy),
+        }
+    }
+    unique
+}
+
+/// Every set of tiles copied into VRAM once, by address.
+pub fn tile_sets(data: &Data) -> Vec<TileCopy> {
+    unique(&data.tile_copies, |tiles| (tiles.source, tiles.len))
+}
+
+/// Every tile map and attribute map copied into VRAM once, by address.
+pub fn tilemaps(data: &Data) -> Vec<TilemapCopy> {
+    unique(&data.tilemap_copies, |tilemap| {
+        (tilemap.source, tilemap.len)
+    })
+}
+
+/// Every table of colours copied into palette memory once, by address.
+pub fn palette_tables(data: &Data) -> Vec<PaletteCopy> {
+    unique(&data.palette_copies, |palette| {
+        (palette.source, palette.len)
+    })
+}
+
+/// The extension RGBDS projects give a tile map or attribute map.
+pub fn tilemap_extension(tilemap: &TilemapCopy) -> &'static str {
+    if tilemap.attributes {
+        "attrmap"
+    } else {
+        "tilemap"
+    }
+}
+
+// VRAM as the copies found leave it, a bank at a time
+fn vram(cart: &Cartridge, data: &Data) -> [Vec<u8>; 2] {
+    let mut banks = [vec![0; VRAM_SIZE], vec![0; VRAM_SIZE]];
+
+    for tiles in &data.tile_copies {
+        let bytes = &cart[tiles.source..tiles.source + tiles.len];
+        let offset = tiles.destination - VRAM_START;
+        for (index, &byte) in bytes.iter().enumerate() {
+            match tiles.format {
+                TileFormat::TwoBpp => banks[tiles.bank][offset + index] = byte,
+                TileFormat::OneBpp => {
+                    banks[tiles.bank][offset + 2 * index] = byte;
+                    banks[tiles.bank][offset + 2 * index + 1] = byte;
+                }
+            }
+        }
+    }
+    for tilemap in &data.tilemap_copies {
+        let offset = tilemap.destination - VRAM_START;
+        banks[tilemap.attributes as usize][offset..offset + tilemap.len]
+            .copy_from_slice(&cart[tilemap.source..tilemap.source + tilemap.len]);
+    }
+    banks
+}
+
+// Background palette memory as the copies found leave it, if there are any
+fn background_palettes(cart: &Cartridge, data: &Data) -> Option<Vec<u8>> {
+    let mut memory = vec![0; 0x40];
+    let mut copied = false;
+    for palette in data
+        .palette_copies
+        .iter()
+        .filter(|palette| !palette.objects)
+    {
+        memory[palette.index..palette.index + palette.len]
+            .copy_from_slice(&cart[palette.source..palette.source + palette.len]);
+        copied = true;
+    }
+    if copied {
+        Some(memory)
+    } else {
+        None
+    }
+}
+
+fn rgb555(colour: u16) -> [u8; 3] {
+    let scale = |value: u16| ((value & 0x1F) << 3 | (value & 0x1F) >> 2) as u8;
+    [scale(colour), scale(colour >> 5), scale(colour >> 10)]
+}
+
+// Whether the background takes tile numbers as signed offsets from $9000,
+// going by the last value written to LCDC that turns the screen on
+fn signed_tile_numbers(cart: &Cartridge, data: &Data) -> bool {
+    let mut addresses: Vec<usize> = data.functions.iter().map(|(&address, _)| address).collect();
+    addresses.sort();
+
+    let mut lcdc = None;
+    for address in addresses {
+        let chunks = disassembly::chunk(cart, data, address);
+        let known_values = constants::analyse(cart, data, &chunks);
+        for instruction in chunks.instructions() {
+            let state = match known_values.at(instruction.address) {
+                Some(state) => *state,
+                None => continue,
+            };
+            for (address, value) in constants::stores(cart, data, state, instruction) {
+                match value {
+                    Some(value) if address == LCDC && value & 0x80 != 0 => lcdc = Some(value),
+                    _ => (),
+                }
+            }
+        }
+    }
+    lcdc.is_some_and(|lcdc| lcdc & 0x10 == 0)
+}
+
+/// The tile maps at `$9800` and `$9C00` that something is copied into.
+pub fn backgrounds(data: &Data) -> Vec<usize> {
+    let mut maps: Vec<usize> = data
+        .tilemap_copies
+        .iter()
+        .filter(|tilemap| !tilemap.attributes)
+        .map(|tilemap| tilemap.destination & !0x3FF)
+        .collect();
+    maps.sort();
+    maps.dedup();
+    maps
+}
+
+/// The 32 by 32 tile background at `map` as a PNG, drawn with the tiles,
+/// attributes and colours copied in before it is shown, or in grayscale
+/// where no colours are.
+pub fn render_background(cart: &Cartridge, data: &Data, map: usize) -> Vec<u8> {
+    let banks = vram(cart, data);
+    let palettes = background_palettes(cart, data);
+    let signed = signed_tile_numbers(cart, data);
+
+    let width = 32 * 8;
+    let mut pixels = vec![[0; 3]; width * width];
+    for cell in 0..32 * 32 {
+        let offset = map - VRAM_START + cell;
+        let number = banks[0][offset];
+        let attributes = banks[1][offset];
+
+        let tile = if signed {
+            (0x9000 + 16 * (number as i8 as isize)) as usize
+        } else {
+            0x8000 + 16 * number as usize
+        };
+        let bank = (attributes >> 3 & 1) as usize;
+        let start = tile - VRAM_START;
+        let pixels_of_tile = decode_tile(&banks[bank][start..start + 16], TileFormat::TwoBpp);
+
+        for (pixel, &colour) in pixels_of_tile.iter().enumerate() {
+            let (mut x, mut y) = (pixel % 8, pixel / 8);
+            if attributes & 0x20 != 0 {
+                x = 7 - x;
+            }
+            if attributes & 0x40 != 0 {
+                y = 7 - y;
+            }
+            let rgb = match &palettes {
+                Some(memory) => {
+                    let index = (attributes & 7) as usize * 8 + colour as usize * 2;
+                    rgb555(memory[index] as u16 | (memory[index + 1] as u16) << 8)
+                }
+                None => {
+                    let shade = GRAYS[colour as usize];
+                    [shade, shade, shade]
+                }
+            };
+            pixels[(cell / 32 * 8 + y) * width + cell % 32 * 8 + x] = rgb;
+        }
+    }
+
+    png::encode_rgb(width, width, &pixels)
+}
+
+/// Writes each set of tiles to `directory` as a PNG, and as the bytes in the
+/// ROM for `INCBIN`, along with the tile maps and a PNG of each background.
+/// Returns the number of files.
+pub fn write_graphics(cart: &Cartridge, data: &Data, directory: &Path) -> io::Result<usize> {
+    fs::create_dir_all(directory)?;
+    let mut files = 0;
+
+    for tiles in tile_sets(data) {
+        let name = data.label(tiles.source);
+        fs::write(
+            directory.join(format!("{}.png", name)),
+            to_png(cart, &tiles),
+        )?;
+        fs::write(
+            directory.join(format!("{}.{}", name, tiles.format.extension())),
+            &cart[tiles.source..tiles.source + tiles.len],
+        )?;
+        files += 2;
+    }
+    for tilemap in tilemaps(data) {
+        fs::write(
+            directory.join(format!(
+                "{}.{}",
+                data.label(tilemap.source),
+                tilemap_extension(&tilemap)
+            )),
+            &cart[tilemap.source..tilemap.source + tilemap.len],
+        )?;
+        files += 1;
+    }
+    for map in backgrounds(data) {
+        fs::write(
+            directory.join(format!("bg_{:04X}.png", map)),
+            render_background(cart, data, map),
+        )?;
+        files += 1;
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_tiles_in_grayscale() {
+        // A 2bpp tile with a row of colour 1 and a row of colour 2, then a
+        // 1bpp tile with its first row set
+        let mut tile = vec![0xFF, 0x00, 0x00, 0xFF];
+        tile.resize(16, 0);
+        let (width, height, pixels) = png::decode_gray2(&tiles_to_png(&tile, TileFormat::TwoBpp));
+        assert_eq!((width, height), (8, 8));
+        assert_eq!(pixels[..8], [2; 8]);
+        assert_eq!(pixels[8..16], [1; 8]);
+        assert!(pixels[16..].iter().all(|&pixel| pixel == 3));
+
+        let tiles = [0x80; 16];
+        let (width, height, pixels) = png::decode_gray2(&tiles_to_png(&tiles, TileFormat::OneBpp));
+        assert_eq!((width, height), (16, 8));
+        assert_eq!(pixels[..2], [0, 3]);
+        assert_eq!(pixels[8..10], [0, 3]);
+    }
+}
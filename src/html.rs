@@ -0,0 +1,598 @@
+use constants;
+use constants::KnownState;
+use data::*;
+use disassembly;
+use disassembly::ChunkSet;
+use gb::*;
+use ir::lift;
+use listing::var_list;
+use noreturn;
+use stack;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use xrefs::Xrefs;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; }
+td, th { padding: 0 1em 0 0; text-align: left; vertical-align: top; }
+pre, .listing td { font-family: monospace; }
+.label td { font-weight: bold; padding-top: 0.5em; }
+.comment { color: #666; }
+:target { background: #ffc; }
+svg text { font-family: monospace; font-size: 11px; }
+";
+
+// Control flow graph layout, in pixels
+const BLOCK_WIDTH: usize = 190;
+const LINE_HEIGHT: usize = 14;
+const MAX_BLOCK_LINES: usize = 8;
+const GAP: usize = 40;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <style>{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape(title),
+        STYLE,
+        body
+    )
+}
+
+fn page_name(address: usize) -> String {
+    format!("function_{:04X}.html", address)
+}
+
+fn section_name(address: usize) -> &'static str {
+    if address < 0x4000 {
+        "ROM bank 0"
+    } else if address < ROM_END {
+        "ROM bank 1"
+    } else {
+        "RAM"
+    }
+}
+
+fn is_memory(address: usize) -> bool {
+    address >= ROM_END
+}
+
+/// Who owns each instruction, and which functions refer to each address in
+/// memory.
+struct Site<'a> {
+    cart: &'a Cartridge,
+    data: &'a Data,
+    owners: HashMap<usize, usize>,
+    memory: BTreeMap<usize, BTreeSet<usize>>,
+}
+
+impl<'a> Site<'a> {
+    fn new(cart: &'a Cartridge, data: &'a Data) -> Site<'a> {
+        let mut addresses: Vec<usize> =
+            data.functions.iter().map(|(&address, _)| address).collect();
+        addresses.sort();
+
+        let mut owners = HashMap::new();
+        for &function in &addresses {
+            for instruction in disassembly::collect_instructions(cart, data, function) {
+                owners.entry(instruction.address).or_insert(function);
+            }
+        }
+
+        let xrefs = Xrefs::collect(cart, data);
+        let mut memory: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+        for address in xrefs.addresses() {
+            if is_memory(address) {
+                memory
+                    .entry(address)
+                    .or_default()
+                    .extend(xrefs.to(address).iter().map(|xref| xref.function));
+            }
+        }
+
+        Site {
+            cart,
+            data,
+            owners,
+            memory,
+        }
+    }
+
+    fn memory_name(&self, address: usize) -> Option<String> {
+        self.data.variable_name(address)
+    }
+
+    // A link to what's at `address`, as seen from the page of `function`
+    fn link(&self, function: usize, address: usize) -> String {
+        let text = |default: String| {
+            escape(
+                self.data
+                    .symbols
+                    .get(address)
+                    .map_or(default, str::to_string)
+                    .as_str(),
+            )
+        };
+
+        if let Some(target) = self.data.functions.get(address) {
+            format!(
+                "<a href=\"{}\">{}</a>",
+                page_name(address),
+                escape(&target.name)
+            )
+        } else if let Some(&owner) = self.owners.get(&address) {
+            let page = if owner == function {
+                String::new()
+            } else {
+                page_name(owner)
+            };
+            format!(
+                "<a href=\"{}#a{:04X}\">{}</a>",
+                page,
+                address,
+                text(format!("${:04X}", address))
+            )
+        } else if is_memory(address) {
+            let name = self
+                .memory_name(address)
+                .unwrap_or_else(|| format!("${:04X}", address));
+            format!(
+                "<a href=\"index.html#m{:04X}\">{}</a>",
+                address,
+                escape(&name)
+            )
+        } else {
+            text(format!("${:04X}", address))
+        }
+    }
+
+    fn function_link(&self, address: usize) -> String {
+        format!(
+            "<a href=\"{}\">{}</a>",
+            page_name(address),
+            escape(&self.data.name(address))
+        )
+    }
+}
+
+fn cfg_svg(chunks: &ChunkSet) -> String {
+    // Each block goes a row below every block that reaches it without
+    // looping back
+    let order = chunks.reverse_postorder();
+    let mut position = vec![usize::MAX; chunks.len()];
+    for (index, &chunk) in order.iter().enumerate() {
+        position[chunk] = index;
+    }
+    let mut rows = vec![0; chunks.len()];
+    for &chunk in &order {
+        for successor in chunks.successors(chunk) {
+            if position[successor] > position[chunk] {
+                rows[successor] = rows[successor].max(rows[chunk] + 1);
+            }
+        }
+    }
+
+    let mut layout: Vec<Vec<usize>> = Vec::new();
+    for &chunk in &order {
+        if layout.len() <= rows[chunk] {
+            layout.resize(rows[chunk] + 1, Vec::new());
+        }
+        layout[rows[chunk]].push(chunk);
+    }
+
+    let lines = |chunk: usize| {
+        chunks
+            .get(chunk)
+            .instructions()
+            .len()
+            .min(MAX_BLOCK_LINES + 1)
+    };
+    let mut boxes = vec![(0, 0, 0); chunks.len()];
+    let mut y = GAP / 2;
+    let mut width = 0;
+    for row in &mut layout {
+        row.sort_by_key(|&chunk| chunks.get(chunk).address());
+        let mut height = 0;
+        for (column, &chunk) in row.iter().enumerate() {
+            let x = GAP / 2 + column * (BLOCK_WIDTH + GAP);
+            let block_height = lines(chunk) * LINE_HEIGHT + 8;
+            boxes[chunk] = (x, y, block_height);
+            height = height.max(block_height);
+            width = width.max(x + BLOCK_WIDTH + GAP);
+        }
+        y += height + GAP;
+    }
+
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">",
+        width, y
+    )
+    .unwrap();
+    writeln!(
+        svg,
+        "<defs><marker id=\"arrow\" viewBox=\"0 0 10 10\" refX=\"10\" refY=\"5\" \
+         markerWidth=\"6\" markerHeight=\"6\" orient=\"auto\">\
+         <path d=\"M0,0 L10,5 L0,10 z\"/></marker></defs>"
+    )
+    .unwrap();
+
+    for &chunk in &order {
+        let (x, y, height) = boxes[chunk];
+        let successors = chunks.successors(chunk);
+        for (index, &successor) in successors.iter().enumerate() {
+            // The taken branch of a conditional block first
+            let colour = match (successors.len(), index) {
+                (2, 0) => "green",
+                (2, _) => "red",
+                _ => "gray",
+            };
+            let (to_x, to_y, to_height) = boxes[successor];
+            let path = if position[successor] > position[chunk] {
+                format!(
+                    "M{},{} L{},{}",
+                    x + BLOCK_WIDTH / 2,
+                    y + height,
+                    to_x + BLOCK_WIDTH / 2,
+                    to_y
+                )
+            } else {
+                // Loops go round the right-hand side, leaving the bottom of a
+                // block and entering the top so that a block looping to
+                // itself shows
+                let (from_y, to_y) = (y + 2 * height / 3, to_y + to_height / 3);
+                let side = x.max(to_x) + BLOCK_WIDTH + GAP / 2;
+                format!(
+                    "M{},{} C{},{} {},{} {},{}",
+                    x + BLOCK_WIDTH,
+                    from_y,
+                    side,
+                    from_y,
+                    side,
+                    to_y,
+                    to_x + BLOCK_WIDTH,
+                    to_y
+                )
+            };
+            writeln!(
+                svg,
+                "<path d=\"{}\" stroke=\"{}\" fill=\"none\" marker-end=\"url(#arrow)\"/>",
+                path, colour
+            )
+            .unwrap();
+        }
+    }
+
+    for &chunk in &order {
+        let (x, y, height) = boxes[chunk];
+        let block = chunks.get(chunk);
+        let instructions = block.instructions();
+        writeln!(svg, "<a href=\"#a{:04X}\">", instructions[0].address).unwrap();
+        writeln!(
+            svg,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#f8f8f8\" stroke=\"black\"/>",
+            x, y, BLOCK_WIDTH, height
+        )
+        .unwrap();
+        for (line, instruction) in instructions.iter().take(MAX_BLOCK_LINES).enumerate() {
+            let text = format!("{:04X} {}", instruction.address, instruction.op_code);
+            writeln!(
+                svg,
+                "<text x=\"{}\" y=\"{}\">{}</text>",
+                x + 4,
+                y + 4 + (line + 1) * LINE_HEIGHT - 3,
+                escape(&text)
+            )
+            .unwrap();
+        }
+        if instructions.len() > MAX_BLOCK_LINES {
+            writeln!(
+                svg,
+                "<text x=\"{}\" y=\"{}\">...</text>",
+                x + 4,
+                y + 4 + (MAX_BLOCK_LINES + 1) * LINE_HEIGHT - 3
+            )
+            .unwrap();
+        }
+        writeln!(svg, "</a>").unwrap();
+    }
+
+    writeln!(svg, "</svg>").unwrap();
+    svg
+}
+
+fn function_page(site: &Site, address: usize) -> String {
+    let (cart, data) = (site.cart, site.data);
+    let function = data.functions.get(address).unwrap();
+    let chunks = disassembly::chunk(cart, data, address);
+    let known_values = constants::analyse(cart, data, &chunks);
+    let stack = stack::analyse(&chunks);
+
+    let mut body = String::new();
+    writeln!(
+        body,
+        "<p><a href=\"index.html\">Index</a></p>\n<h1>{}</h1>",
+        escape(&function.name)
+    )
+    .unwrap();
+
+    writeln!(body, "<table>").unwrap();
+    let convention = &function.convention;
+    let mut facts = vec![
+        (
+            "Address",
+            format!("${:04X} ({})", address, section_name(address)),
+        ),
+        ("Inputs", var_list(&convention.inputs)),
+        ("Outputs", var_list(&convention.outputs)),
+        ("Clobbers", var_list(&convention.clobbered)),
+        ("Preserves", var_list(&convention.preserved)),
+    ];
+    if let Some(copy) = data.ram_copy_at(address) {
+        facts.push((
+            "Copied from",
+            format!("${:04X}", copy.source + address - copy.destination),
+        ));
+    }
+    if let Some(reason) = noreturn::reason(cart, data, address) {
+        facts.push(("Doesn't return", reason.describe(data)));
+    }
+    for (name, value) in facts {
+        writeln!(
+            body,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            name,
+            escape(&value)
+        )
+        .unwrap();
+    }
+    writeln!(body, "</table>").unwrap();
+
+    let mut call_sites: Vec<usize> = function.call_sites.iter().cloned().collect();
+    call_sites.sort();
+    if !call_sites.is_empty() {
+        writeln!(body, "<h2>Called from</h2>\n<ul>").unwrap();
+        for call_site in call_sites {
+            match site.owners.get(&call_site) {
+                Some(&owner) => writeln!(
+                    body,
+                    "<li><a href=\"{}#a{:04X}\">{}</a> at ${:04X}</li>",
+                    page_name(owner),
+                    call_site,
+                    escape(&data.name(owner)),
+                    call_site
+                ),
+                None => writeln!(body, "<li>${:04X}</li>", call_site),
+            }
+            .unwrap();
+        }
+        writeln!(body, "</ul>").unwrap();
+    }
+
+    writeln!(body, "<h2>Control flow</h2>\n{}", cfg_svg(&chunks)).unwrap();
+
+    writeln!(body, "<h2>Listing</h2>\n<table class=\"listing\">").unwrap();
+    for instruction in chunks.instructions() {
+        if instruction.address != address {
+            if let Some(name) = data.symbols.get(instruction.address) {
+                writeln!(
+                    body,
+                    "<tr class=\"label\"><td colspan=\"4\">{}:</td></tr>",
+                    escape(name)
+                )
+                .unwrap();
+            }
+        }
+
+        let state = known_values
+            .at(instruction.address)
+            .cloned()
+            .unwrap_or_else(KnownState::unknown);
+        let accessed = constants::accesses(cart, data, state, instruction)
+            .into_iter()
+            .map(|(_, address)| address);
+        let mut references: Vec<usize> = Vec::new();
+        for reference in lift(instruction)
+            .iter()
+            .flat_map(|stmt| stmt.constant_addresses())
+            .chain(accessed)
+        {
+            if !references.contains(&reference) {
+                references.push(reference);
+            }
+        }
+        let links: Vec<String> = references
+            .iter()
+            .map(|&reference| site.link(address, reference))
+            .collect();
+
+        let mut notes = Vec::new();
+        if let Some(comment) = data.comments.get(&instruction.address) {
+            notes.push(escape(comment));
+        }
+        match stack.depth_at(instruction.address) {
+            Some(stack::StackDepth::Known(0)) | None => (),
+            Some(depth) => notes.push(format!("stack={}", depth)),
+        }
+        if let Some(state) = known_values.at(instruction.address) {
+            let state = state.to_string();
+            if !state.is_empty() {
+                notes.push(escape(&state));
+            }
+        }
+
+        writeln!(
+            body,
+            "<tr id=\"a{0:04X}\"><td>{0:04X}</td><td>{1}</td><td>{2}</td>\
+             <td class=\"comment\">{3}</td></tr>",
+            instruction.address,
+            escape(&instruction.op_code.to_string()),
+            links.join(", "),
+            notes.join(" ")
+        )
+        .unwrap();
+    }
+    writeln!(body, "</table>").unwrap();
+
+    page(&function.name, &body)
+}
+
+fn index_page(site: &Site) -> String {
+    let (cart, data) = (site.cart, site.data);
+    let mut body = String::new();
+
+    let title = match cart.title() {
+        title if title.is_empty() => "ROM".to_string(),
+        title => title,
+    };
+    writeln!(body, "<h1>{}</h1>", escape(&title)).unwrap();
+    writeln!(
+        body,
+        "<p>{}, {} KiB</p>",
+        cart.cartridge_type().map_or_else(
+            || "Unknown cartridge".to_string(),
+            |kind| format!("{:?}", kind)
+        ),
+        cart.rom_size() / 1024
+    )
+    .unwrap();
+
+    let mut sections: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (&address, _) in data.functions.iter() {
+        sections
+            .entry(section_name(address))
+            .or_default()
+            .push(address);
+    }
+    writeln!(body, "<h2>Functions</h2>").unwrap();
+    for (section, mut addresses) in sections {
+        addresses.sort();
+        writeln!(
+            body,
+            "<h3>{}</h3>\n<table>\n<tr><th>Address</th><th>Name</th><th>Callers</th></tr>",
+            section
+        )
+        .unwrap();
+        for address in addresses {
+            let callers = data.functions.get(address).unwrap().call_sites.len();
+            writeln!(
+                body,
+                "<tr><td>{:04X}</td><td>{}</td><td>{}</td></tr>",
+                address,
+                site.function_link(address),
+                callers
+            )
+            .unwrap();
+        }
+        writeln!(body, "</table>").unwrap();
+    }
+
+    let (registers, variables): (Vec<_>, Vec<_>) = site
+        .memory
+        .iter()
+        .partition(|&(&address, _)| is_io(address));
+    for (title, addresses) in [
+        ("RAM variables", variables),
+        ("Hardware registers", registers),
+    ]
+    .iter()
+    {
+        if addresses.is_empty() {
+            continue;
+        }
+        writeln!(
+            body,
+            "<h2>{}</h2>\n<table>\n<tr><th>Address</th><th>Name</th><th>Used by</th></tr>",
+            title
+        )
+        .unwrap();
+        for &(&address, functions) in addresses {
+            let users: Vec<String> = functions
+                .iter()
+                .map(|&function| site.function_link(function))
+                .collect();
+            writeln!(
+                body,
+                "<tr id=\"m{0:04X}\"><td>{0:04X}</td><td>{1}</td><td>{2}</td></tr>",
+                address,
+                escape(&site.memory_name(address).unwrap_or_default()),
+                users.join(", ")
+            )
+            .unwrap();
+        }
+        writeln!(body, "</table>").unwrap();
+    }
+
+    page(&title, &body)
+}
+
+/// Writes a static site to `directory`: an index of functions, RAM variables
+/// and hardware registers, and a page for each function with its control
+/// flow graph and a listing linked to everything it refers to. Returns the
+/// number of pages.
+pub fn write_report(cart: &Cartridge, data: &Data, directory: &Path) -> io::Result<usize> {
+    let site = Site::new(cart, data);
+    fs::create_dir_all(directory)?;
+
+    fs::write(directory.join("index.html"), index_page(&site))?;
+    let mut pages = 1;
+    for (&address, _) in data.functions.iter() {
+        fs::write(
+            directory.join(page_name(address)),
+            function_page(&site, address),
+        )?;
+        pages += 1;
+    }
+    Ok(pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+
+    #[test]
+    fn links_calls_and_variables() {
+        // ld [$C100],a / ret
+        let cart = Cartridge::with_function(&[0xEA, 0x00, 0xC1, 0xC9]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        let site = Site::new(&cart, &data);
+
+        let entry = function_page(&site, 0x100);
+        assert!(
+            entry.contains("<a href=\"function_0200.html\">"),
+            "{}",
+            entry
+        );
+
+        let function = function_page(&site, 0x200);
+        assert!(
+            function.contains("<a href=\"index.html#mC100\">"),
+            "{}",
+            function
+        );
+        assert!(function.contains("<svg"), "{}", function);
+
+        let index = index_page(&site);
+        assert!(index.contains("function_0200.html"), "{}", index);
+    }
+
+    #[test]
+    fn escapes_text() {
+        assert_eq!(
+            escape("a < b && \"c\""),
+            "a &lt; b &amp;&amp; &quot;c&quot;"
+        );
+    }
+}
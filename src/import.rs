@@ -0,0 +1,179 @@
+use data::ROM_BANK_SIZE;
+use gb::*;
+use std::fmt;
+use trace::{BankAddress, Trace};
+
+// Flags of a code/data log
+const CDL_CODE: u8 = 0x01;
+const CDL_DATA: u8 = 0x02;
+
+#[derive(Debug)]
+pub enum ImportError {
+    NoAddresses,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::NoAddresses => write!(f, "no bank:address entries found"),
+        }
+    }
+}
+
+fn bank_address(offset: usize) -> BankAddress {
+    if offset < ROM_BANK_SIZE {
+        BankAddress::new(0, offset)
+    } else {
+        BankAddress::new(
+            offset / ROM_BANK_SIZE,
+            ROM_BANK_SIZE + offset % ROM_BANK_SIZE,
+        )
+    }
+}
+
+fn rom_offset(cart: &Cartridge, address: BankAddress) -> Option<usize> {
+    let offset = match address.address {
+        0x0000..=0x3FFF => address.address,
+        0x4000..=0x7FFF => address.bank * ROM_BANK_SIZE + address.address - ROM_BANK_SIZE,
+        _ => return None,
+    };
+    // Leave room for the longest instruction
+    if offset + 3 <= cart.rom_size() {
+        Some(offset)
+    } else {
+        None
+    }
+}
+
+fn decode(cart: &Cartridge, address: BankAddress) -> Option<Instruction> {
+    let op_code = OpCode::decode_at(cart, rom_offset(cart, address)?).ok()?;
+    Some(Instruction {
+        address: address.address,
+        op_code,
+    })
+}
+
+// Working out from the instruction run before `next` how control got there
+fn classify(cart: &Cartridge, trace: &mut Trace, previous: BankAddress, next: BankAddress) {
+    let instruction = match decode(cart, previous) {
+        Some(instruction) => instruction,
+        None => return,
+    };
+
+    if instruction.is_indirect_jump() {
+        trace
+            .indirect_jumps
+            .entry(previous)
+            .or_default()
+            .insert(next);
+    } else if Cartridge::INTERRUPT_VECTORS.contains(&next.address)
+        && next.address != previous.address + instruction.size()
+        && instruction.call_target() != Some(next.address)
+        && instruction.jump_target() != Some(next.address)
+    {
+        trace.interrupts.insert(next.address);
+    }
+}
+
+/// Reads an execution log with one executed instruction per line, found as
+/// the first `bank:address` on the line, as BGB and SameBoy can write.
+fn import_log(cart: &Cartridge, text: &str) -> Trace {
+    let mut trace = Trace::default();
+    let mut previous = None;
+
+    for line in text.lines() {
+        let address = match line.split_whitespace().find_map(BankAddress::parse) {
+            Some(address) => address,
+            None => continue,
+        };
+
+        trace.executed.insert(address);
+        if let Some(previous) = previous {
+            classify(cart, &mut trace, previous, address);
+        }
+        previous = Some(address);
+    }
+
+    trace
+}
+
+/// Reads a code/data log, with a byte of flags for every byte of the ROM.
+fn import_code_data_log(cart: &Cartridge, flags: &[u8]) -> Trace {
+    let mut trace = Trace::default();
+
+    for (offset, &flag) in flags.iter().enumerate() {
+        if flag & CDL_DATA != 0 {
+            trace.read.insert(bank_address(offset));
+        }
+    }
+
+    // Every byte of an executed instruction is marked, so decode each run of
+    // them to find where the instructions start
+    let mut offset = 0;
+    while offset < flags.len() {
+        if flags[offset] & CDL_CODE == 0 {
+            offset += 1;
+            continue;
+        }
+
+        let address = bank_address(offset);
+        trace.executed.insert(address);
+        offset += decode(cart, address).map_or(1, |instruction| instruction.size());
+    }
+
+    trace
+}
+
+/// Reads a trace recorded by another emulator: a code/data log if it is the
+/// size of the ROM, otherwise an execution log.
+pub fn import(cart: &Cartridge, bytes: &[u8]) -> Result<Trace, ImportError> {
+    let trace = if bytes.len() == cart.rom_size() {
+        import_code_data_log(cart, bytes)
+    } else {
+        import_log(cart, &String::from_utf8_lossy(bytes))
+    };
+
+    if trace.executed.is_empty() && trace.read.is_empty() {
+        Err(ImportError::NoAddresses)
+    } else {
+        Ok(trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_code_data_logs_the_size_of_the_rom() {
+        // ld a,$01 / ret, with a byte read from bank 1
+        let cart = Cartridge::with_code(&[(0x150, &[0x3E, 0x01, 0xC9])]);
+        let mut flags = vec![0; cart.rom_size()];
+        flags[0x150..0x153].copy_from_slice(&[CDL_CODE; 3]);
+        flags[0x4000] = CDL_DATA;
+
+        let trace = import(&cart, &flags).unwrap();
+        let mut executed: Vec<_> = trace.executed.into_iter().collect();
+        executed.sort();
+        assert_eq!(
+            executed,
+            vec![BankAddress::new(0, 0x150), BankAddress::new(0, 0x152)]
+        );
+        assert!(trace.read.contains(&BankAddress::new(1, 0x4000)));
+    }
+
+    #[test]
+    fn reads_execution_logs() {
+        // jp hl to a nop in bank 1, which a vblank interrupt follows
+        let cart = Cartridge::with_code(&[(0x150, &[0xE9])]);
+        let log = "ROM0:0150 jp hl\nROM1:4123 nop\nROM0:0040 push af\n";
+
+        let trace = import(&cart, log.as_bytes()).unwrap();
+        assert_eq!(trace.executed.len(), 3);
+        assert!(trace.indirect_jumps[&BankAddress::new(0, 0x150)]
+            .contains(&BankAddress::new(1, 0x4123)));
+        assert!(trace.interrupts.contains(&0x40));
+
+        assert!(import(&cart, b"no addresses here\n").is_err());
+    }
+}
@@ -1,8 +1,15 @@
 use data::*;
+use disassembly::ChunkSet;
 use gb::*;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::collections::VecDeque;
 
+/// State that can be merged where control flow joins.
+pub trait Join {
+    fn join(&self, other: &Self) -> Self;
+}
+
 pub fn walk<T, F>(cartridge: &Cartridge, data: &Data, address: usize, initial: T, mut func: F)
 where
     T: Copy,
@@ -19,25 +26,93 @@ where
         };
     }
 
+    let start = address;
     add_todo!(address, initial);
 
+    // Reaching the start of another function, by jumping or falling through,
+    // is a tail call: that code belongs to the other function
+    let is_tail_call = |address: usize| address != start && data.functions.is_start(address);
+
     while let Some((address, input)) = todo.pop_front() {
-        let instruction = OpCode::decode_at(cartridge, address).unwrap();
+        // Code in RAM can only be followed if we know what was copied there,
+        // and bytes known to be data aren't code
+        let rom_address = match data.rom_address(address) {
+            Some(_) if data.is_data(address) => continue,
+            Some(rom_address) if rom_address < cartridge.rom_size() => rom_address,
+            _ => continue,
+        };
+        // Paths into bytes that aren't instructions end there; the path
+        // tracer reports them
+        let instruction = match OpCode::decode_at(cartridge, rom_address) {
+            Ok(instruction) => instruction,
+            Err(_) => continue,
+        };
         let next_address = address + instruction.size();
 
         let output = func(input, address, instruction);
 
-        if instruction.can_continue()
+        // Calls we can't follow, like those into RAM nothing is known to be
+        // copied to, are assumed to return
+        if (instruction.can_continue()
             || instruction
                 .call_target(address)
-                .and_then(|call_address| data.functions.get(call_address))
-                .map_or(false, |function| function.can_return)
+                .is_some_and(|call_address| {
+                    data.functions
+                        .get(call_address)
+                        .map_or(true, |function| function.can_return)
+                }))
+            && !is_tail_call(next_address)
         {
             add_todo!(next_address, output);
         }
 
+        // A conditional jump into another function is a tail call too, but
+        // still falls through
         if let Some(jump_address) = instruction.jump_target(address) {
-            add_todo!(jump_address, output);
+            if !is_tail_call(jump_address) {
+                add_todo!(jump_address, output);
+            }
+        }
+    }
+}
+
+/// Threads state through the chunks of a function like `walk`, but merges the
+/// states flowing into a chunk and revisits it until nothing changes. Returns
+/// the state before each instruction.
+pub fn walk_chunks<T, F>(chunks: &ChunkSet, initial: T, mut func: F) -> HashMap<usize, T>
+where
+    T: Copy + PartialEq + Join,
+    F: FnMut(T, &Instruction) -> T,
+{
+    let mut inputs: Vec<Option<T>> = vec![None; chunks.len()];
+    let mut todo: VecDeque<usize> = VecDeque::new();
+    let mut states = HashMap::new();
+
+    inputs[chunks.entry_index()] = Some(initial);
+    todo.push_back(chunks.entry_index());
+
+    while let Some(index) = todo.pop_front() {
+        let mut state = inputs[index].unwrap();
+
+        for instruction in chunks.get(index).instructions() {
+            states.insert(instruction.address, state);
+            state = func(state, instruction);
+        }
+
+        for successor in chunks.successors(index) {
+            let joined = match inputs[successor] {
+                Some(input) => input.join(&state),
+                None => state,
+            };
+
+            if inputs[successor] != Some(joined) {
+                inputs[successor] = Some(joined);
+                if !todo.contains(&successor) {
+                    todo.push_back(successor);
+                }
+            }
         }
     }
+
+    states
 }
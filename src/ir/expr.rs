@@ -0,0 +1,497 @@
+use ir::var::*;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Not,
+}
+
+impl UnaryOp {
+    fn symbol(&self) -> &'static str {
+        match *self {
+            UnaryOp::Not => "!",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
+}
+
+impl BinaryOp {
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::And => "&",
+            BinaryOp::Or => "|",
+            BinaryOp::Xor => "^",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Gt => ">",
+            BinaryOp::Le => "<=",
+            BinaryOp::Ge => ">=",
+            BinaryOp::LogicalAnd => "&&",
+            BinaryOp::LogicalOr => "||",
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match *self {
+            BinaryOp::Add | BinaryOp::Sub => 12,
+            BinaryOp::Shl | BinaryOp::Shr => 11,
+            BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => 10,
+            BinaryOp::Eq | BinaryOp::Ne => 9,
+            BinaryOp::And => 8,
+            BinaryOp::Xor => 7,
+            BinaryOp::Or => 6,
+            BinaryOp::LogicalAnd => 5,
+            BinaryOp::LogicalOr => 4,
+        }
+    }
+
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            *self,
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge
+        )
+    }
+
+    pub fn is_bitwise(&self) -> bool {
+        matches!(
+            *self,
+            BinaryOp::And | BinaryOp::Or | BinaryOp::Xor | BinaryOp::Shl | BinaryOp::Shr
+        )
+    }
+
+    pub fn apply(&self, lhs: i32, rhs: i32) -> i32 {
+        match *self {
+            BinaryOp::Add => lhs.wrapping_add(rhs),
+            BinaryOp::Sub => lhs.wrapping_sub(rhs),
+            BinaryOp::And => lhs & rhs,
+            BinaryOp::Or => lhs | rhs,
+            BinaryOp::Xor => lhs ^ rhs,
+            BinaryOp::Shl => lhs.wrapping_shl(rhs as u32),
+            BinaryOp::Shr => lhs.wrapping_shr(rhs as u32),
+            BinaryOp::Eq => (lhs == rhs) as i32,
+            BinaryOp::Ne => (lhs != rhs) as i32,
+            BinaryOp::Lt => (lhs < rhs) as i32,
+            BinaryOp::Gt => (lhs > rhs) as i32,
+            BinaryOp::Le => (lhs <= rhs) as i32,
+            BinaryOp::Ge => (lhs >= rhs) as i32,
+            BinaryOp::LogicalAnd => (lhs != 0 && rhs != 0) as i32,
+            BinaryOp::LogicalOr => (lhs != 0 || rhs != 0) as i32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Intrinsic {
+    Swap,
+    Daa,
+    DaaCarry,
+}
+
+impl Intrinsic {
+    fn name(&self) -> &'static str {
+        match *self {
+            Intrinsic::Swap => "swap",
+            Intrinsic::Daa => "daa",
+            Intrinsic::DaaCarry => "daa_carry",
+        }
+    }
+
+    pub fn apply(&self, args: &[i32]) -> i32 {
+        match *self {
+            Intrinsic::Swap => ((args[0] << 4) | (args[0] >> 4)) & 0xFF,
+            Intrinsic::Daa => daa(args[0], args[1], args[2], args[3]).0,
+            Intrinsic::DaaCarry => daa(args[0], args[1], args[2], args[3]).1,
+        }
+    }
+}
+
+fn daa(a: i32, subtract: i32, half_carry: i32, carry: i32) -> (i32, i32) {
+    let mut adjust = 0;
+    let mut carry_out = 0;
+
+    if subtract != 0 {
+        if half_carry != 0 {
+            adjust |= 0x06;
+        }
+        if carry != 0 {
+            adjust |= 0x60;
+            carry_out = 1;
+        }
+        ((a - adjust) & 0xFF, carry_out)
+    } else {
+        if half_carry != 0 || (a & 0x0F) > 0x09 {
+            adjust |= 0x06;
+        }
+        if carry != 0 || a > 0x99 {
+            adjust |= 0x60;
+            carry_out = 1;
+        }
+        ((a + adjust) & 0xFF, carry_out)
+    }
+}
+
+/// Values are unbounded integers; truncation to a register's width is
+/// always spelled out with an explicit mask.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Expr<V = Var> {
+    Const(u16),
+    Var(V),
+    Load(Box<Expr<V>>),
+    Unary(UnaryOp, Box<Expr<V>>),
+    Binary(BinaryOp, Box<Expr<V>>, Box<Expr<V>>),
+    Intrinsic(Intrinsic, Vec<Expr<V>>),
+}
+
+impl Expr {
+    pub fn register(register: Register) -> Expr {
+        Expr::Var(Var::Register(register))
+    }
+
+    pub fn flag(flag: Flag) -> Expr {
+        Expr::Var(Var::Flag(flag))
+    }
+
+    pub fn pair(high: Register, low: Register) -> Expr {
+        Expr::binary(
+            BinaryOp::Or,
+            Expr::binary(BinaryOp::Shl, Expr::register(high), Expr::Const(8)),
+            Expr::register(low),
+        )
+    }
+}
+
+impl<V: Copy> Expr<V> {
+    pub fn var(var: V) -> Expr<V> {
+        Expr::Var(var)
+    }
+
+    pub fn load(address: Expr<V>) -> Expr<V> {
+        Expr::Load(Box::new(address))
+    }
+
+    pub fn unary(op: UnaryOp, operand: Expr<V>) -> Expr<V> {
+        Expr::Unary(op, Box::new(operand))
+    }
+
+    pub fn binary(op: BinaryOp, lhs: Expr<V>, rhs: Expr<V>) -> Expr<V> {
+        if let (Some(lhs), Some(rhs)) = (lhs.as_const(), rhs.as_const()) {
+            let value = op.apply(i32::from(lhs), i32::from(rhs));
+            if (0..=0xFFFF).contains(&value) {
+                return Expr::Const(value as u16);
+            }
+        }
+
+        Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    pub fn mask(value: Expr<V>, mask: u16) -> Expr<V> {
+        Expr::binary(BinaryOp::And, value, Expr::Const(mask))
+    }
+
+    pub fn as_const(&self) -> Option<u16> {
+        match *self {
+            Expr::Const(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn visit_vars<F: FnMut(V)>(&self, func: &mut F) {
+        match self {
+            Expr::Const(_) => (),
+            Expr::Var(var) => func(*var),
+            Expr::Load(address) => address.visit_vars(func),
+            Expr::Unary(_, operand) => operand.visit_vars(func),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.visit_vars(func);
+                rhs.visit_vars(func);
+            }
+            Expr::Intrinsic(_, args) => {
+                for arg in args {
+                    arg.visit_vars(func);
+                }
+            }
+        }
+    }
+
+    pub fn vars(&self) -> Vec<V> {
+        let mut vars = Vec::new();
+        self.visit_vars(&mut |var| vars.push(var));
+        vars
+    }
+
+    pub fn map_vars<W, F: FnMut(V) -> W>(&self, func: &mut F) -> Expr<W> {
+        match self {
+            Expr::Const(value) => Expr::Const(*value),
+            Expr::Var(var) => Expr::Var(func(*var)),
+            Expr::Load(address) => Expr::Load(Box::new(address.map_vars(func))),
+            Expr::Unary(op, operand) => Expr::Unary(*op, Box::new(operand.map_vars(func))),
+            Expr::Binary(op, lhs, rhs) => Expr::Binary(
+                *op,
+                Box::new(lhs.map_vars(func)),
+                Box::new(rhs.map_vars(func)),
+            ),
+            Expr::Intrinsic(intrinsic, args) => Expr::Intrinsic(
+                *intrinsic,
+                args.iter().map(|arg| arg.map_vars(func)).collect(),
+            ),
+        }
+    }
+
+    /// Replaces every occurrence of a variable for which `func` returns an
+    /// expression.
+    pub fn substitute<F: FnMut(V) -> Option<Expr<V>>>(&mut self, func: &mut F) {
+        match self {
+            Expr::Const(_) => (),
+            Expr::Var(var) => {
+                if let Some(replacement) = func(*var) {
+                    *self = replacement;
+                }
+            }
+            Expr::Load(address) => address.substitute(func),
+            Expr::Unary(_, operand) => operand.substitute(func),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.substitute(func);
+                rhs.substitute(func);
+            }
+            Expr::Intrinsic(_, args) => {
+                for arg in args.iter_mut() {
+                    arg.substitute(func);
+                }
+            }
+        }
+    }
+
+    pub fn reads_memory(&self) -> bool {
+        match self {
+            Expr::Const(_) | Expr::Var(_) => false,
+            Expr::Load(_) => true,
+            Expr::Unary(_, operand) => operand.reads_memory(),
+            Expr::Binary(_, lhs, rhs) => lhs.reads_memory() || rhs.reads_memory(),
+            Expr::Intrinsic(_, args) => args.iter().any(|arg| arg.reads_memory()),
+        }
+    }
+
+    /// The addresses the expression loads from, outermost first.
+    pub fn load_addresses(&self) -> Vec<&Expr<V>> {
+        let mut addresses = Vec::new();
+        match self {
+            Expr::Const(_) | Expr::Var(_) => (),
+            Expr::Load(address) => {
+                addresses.push(&**address);
+                addresses.extend(address.load_addresses());
+            }
+            Expr::Unary(_, operand) => addresses.extend(operand.load_addresses()),
+            Expr::Binary(_, lhs, rhs) => {
+                addresses.extend(lhs.load_addresses());
+                addresses.extend(rhs.load_addresses());
+            }
+            Expr::Intrinsic(_, args) => {
+                for arg in args {
+                    addresses.extend(arg.load_addresses());
+                }
+            }
+        }
+        addresses
+    }
+
+    /// The value of the expression if it doesn't depend on any variable or
+    /// memory.
+    pub fn constant_value(&self) -> Option<u16> {
+        self.evaluate(&|_| None, &|_| None)
+            .map(|value| value as u16)
+    }
+
+    /// The constant addresses the expression loads from.
+    pub fn constant_loads(&self) -> Vec<u16> {
+        let mut addresses = Vec::new();
+        match self {
+            Expr::Const(_) | Expr::Var(_) => (),
+            Expr::Load(address) => {
+                addresses.extend(address.constant_value());
+                addresses.extend(address.constant_loads());
+            }
+            Expr::Unary(_, operand) => addresses.extend(operand.constant_loads()),
+            Expr::Binary(_, lhs, rhs) => {
+                addresses.extend(lhs.constant_loads());
+                addresses.extend(rhs.constant_loads());
+            }
+            Expr::Intrinsic(_, args) => {
+                for arg in args {
+                    addresses.extend(arg.constant_loads());
+                }
+            }
+        }
+        addresses
+    }
+
+    /// Evaluates the expression, looking up variables and memory through the
+    /// given closures. Returns `None` as soon as either lookup fails.
+    pub fn evaluate<L, M>(&self, var: &L, memory: &M) -> Option<i32>
+    where
+        L: Fn(V) -> Option<i32>,
+        M: Fn(u16) -> Option<i32>,
+    {
+        match self {
+            Expr::Const(value) => Some(i32::from(*value)),
+            Expr::Var(v) => var(*v),
+            Expr::Load(address) => memory(address.evaluate(var, memory)? as u16),
+            Expr::Unary(UnaryOp::Not, operand) => {
+                Some((operand.evaluate(var, memory)? == 0) as i32)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                Some(op.apply(lhs.evaluate(var, memory)?, rhs.evaluate(var, memory)?))
+            }
+            Expr::Intrinsic(intrinsic, args) => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(arg.evaluate(var, memory)?);
+                }
+                Some(intrinsic.apply(&values))
+            }
+        }
+    }
+}
+
+impl<V: Variable + fmt::Display> Expr<V> {
+    /// The name of the register pair this expression reads, if it is of the
+    /// form `(high << 8) | low`.
+    pub fn pair_name(&self) -> Option<&'static str> {
+        match self {
+            Expr::Binary(BinaryOp::Or, lhs, rhs) => match (&**lhs, &**rhs) {
+                (Expr::Binary(BinaryOp::Shl, high, shift), Expr::Var(low))
+                    if shift.as_const() == Some(8) =>
+                {
+                    match **high {
+                        Expr::Var(high) => pair_name(high.base(), low.base()),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Const(_) | Expr::Var(_) | Expr::Load(_) | Expr::Intrinsic(..) => 16,
+            Expr::Binary(..) if self.pair_name().is_some() => 16,
+            Expr::Unary(..) => 14,
+            Expr::Binary(op, ..) => op.precedence(),
+        }
+    }
+
+    /// Displays the expression with `names` naming the memory it reads,
+    /// given its address. Memory without a name is shown as `mem[address]`.
+    pub fn named<'a>(&'a self, names: &'a MemoryNames<'a, V>) -> Named<'a, V> {
+        Named { expr: self, names }
+    }
+
+    fn fmt_operand(
+        &self,
+        f: &mut fmt::Formatter,
+        names: &MemoryNames<V>,
+        parent: BinaryOp,
+        is_rhs: bool,
+    ) -> fmt::Result {
+        // Mixing bitwise and other operators is always parenthesised, even
+        // where C precedence would make it unambiguous
+        let mixes_bitwise = match self {
+            Expr::Binary(..) if self.pair_name().is_some() => false,
+            Expr::Binary(op, ..) => *op != parent && (op.is_bitwise() || parent.is_bitwise()),
+            _ => false,
+        };
+
+        if mixes_bitwise
+            || self.precedence() < parent.precedence()
+            || (is_rhs && self.precedence() == parent.precedence())
+        {
+            write!(f, "({})", self.named(names))
+        } else {
+            write!(f, "{}", self.named(names))
+        }
+    }
+
+    fn fmt_named(&self, f: &mut fmt::Formatter, names: &MemoryNames<V>) -> fmt::Result {
+        if let Some(name) = self.pair_name() {
+            return write!(f, "{}", name);
+        }
+
+        match self {
+            Expr::Const(value) if *value < 10 => write!(f, "{}", value),
+            Expr::Const(value) if *value < 0x100 => write!(f, "0x{:02X}", value),
+            Expr::Const(value) => write!(f, "0x{:04X}", value),
+            Expr::Var(var) => write!(f, "{}", var),
+            Expr::Load(address) => match names(address) {
+                Some(name) => write!(f, "{}", name),
+                None => write!(f, "mem[{}]", address.named(names)),
+            },
+            Expr::Unary(op, operand) => {
+                if operand.precedence() < 14 {
+                    write!(f, "{}({})", op.symbol(), operand.named(names))
+                } else {
+                    write!(f, "{}{}", op.symbol(), operand.named(names))
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                lhs.fmt_operand(f, names, *op, false)?;
+                write!(f, " {} ", op.symbol())?;
+                rhs.fmt_operand(f, names, *op, true)
+            }
+            Expr::Intrinsic(intrinsic, args) => {
+                write!(f, "{}(", intrinsic.name())?;
+                for (index, arg) in args.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg.named(names))?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Names for memory, given the expression for its address.
+pub type MemoryNames<'a, V> = dyn Fn(&Expr<V>) -> Option<String> + 'a;
+
+/// An expression displayed with names for the memory it reads.
+pub struct Named<'a, V: 'a> {
+    expr: &'a Expr<V>,
+    names: &'a MemoryNames<'a, V>,
+}
+
+impl<'a, V: Variable + fmt::Display> fmt::Display for Named<'a, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.expr.fmt_named(f, self.names)
+    }
+}
+
+impl<V: Variable + fmt::Display> fmt::Display for Expr<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_named(f, &|_| None)
+    }
+}
@@ -0,0 +1,1335 @@
+use gb::*;
+use ir::expr::*;
+use ir::stmt::*;
+use ir::var::*;
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(Register),
+    Immediate(u8),
+    IndirectHL,
+}
+
+const A: Operand = Operand::Register(Register::A);
+const B: Operand = Operand::Register(Register::B);
+const C: Operand = Operand::Register(Register::C);
+const D: Operand = Operand::Register(Register::D);
+const E: Operand = Operand::Register(Register::E);
+const H: Operand = Operand::Register(Register::H);
+const L: Operand = Operand::Register(Register::L);
+const IND_HL: Operand = Operand::IndirectHL;
+
+#[derive(Clone, Copy)]
+enum Pair {
+    Registers(Register, Register),
+    StackPointer,
+    AccumulatorFlags,
+}
+
+const BC: Pair = Pair::Registers(Register::B, Register::C);
+const DE: Pair = Pair::Registers(Register::D, Register::E);
+const HL: Pair = Pair::Registers(Register::H, Register::L);
+const SP: Pair = Pair::StackPointer;
+const AF: Pair = Pair::AccumulatorFlags;
+
+impl Pair {
+    fn expr(&self) -> Expr {
+        match *self {
+            Pair::Registers(high, low) => Expr::pair(high, low),
+            Pair::StackPointer => Expr::var(Var::StackPointer),
+            Pair::AccumulatorFlags => Expr::binary(
+                BinaryOp::Or,
+                Expr::binary(BinaryOp::Shl, Expr::register(Register::A), Expr::Const(8)),
+                flags_byte(),
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum AluOp {
+    Add,
+    AddWithCarry,
+    Sub,
+    SubWithCarry,
+    And,
+    Xor,
+    Or,
+    Compare,
+}
+
+#[derive(Clone, Copy)]
+enum ShiftOp {
+    RotateLeftCircular,
+    RotateRightCircular,
+    RotateLeft,
+    RotateRight,
+    ShiftLeftArithmetic,
+    ShiftRightArithmetic,
+    Swap,
+    ShiftRightLogical,
+}
+
+// Scratch variables, only live within the statements of a single instruction
+const TEMP: Var = Var::Temp(0);
+const TEMP_OPERAND: Var = Var::Temp(1);
+const TEMP_RESULT: Var = Var::Temp(2);
+
+const FLAG_BITS: [(Flag, u16); 4] = [
+    (Flag::Zero, 7),
+    (Flag::Subtract, 6),
+    (Flag::HalfCarry, 5),
+    (Flag::Carry, 4),
+];
+
+fn flags_byte() -> Expr {
+    FLAG_BITS
+        .iter()
+        .map(|&(flag, bit)| Expr::binary(BinaryOp::Shl, Expr::flag(flag), Expr::Const(bit)))
+        .fold(None, |acc, bit| match acc {
+            None => Some(bit),
+            Some(acc) => Some(Expr::binary(BinaryOp::Or, acc, bit)),
+        })
+        .unwrap()
+}
+
+fn add(lhs: Expr, rhs: Expr) -> Expr {
+    Expr::binary(BinaryOp::Add, lhs, rhs)
+}
+
+fn sub(lhs: Expr, rhs: Expr) -> Expr {
+    Expr::binary(BinaryOp::Sub, lhs, rhs)
+}
+
+fn or(lhs: Expr, rhs: Expr) -> Expr {
+    Expr::binary(BinaryOp::Or, lhs, rhs)
+}
+
+fn shl(value: Expr, amount: u16) -> Expr {
+    Expr::binary(BinaryOp::Shl, value, Expr::Const(amount))
+}
+
+fn shr(value: Expr, amount: u16) -> Expr {
+    Expr::binary(BinaryOp::Shr, value, Expr::Const(amount))
+}
+
+fn offset(base: Expr, offset: i8) -> Expr {
+    if offset < 0 {
+        sub(base, Expr::Const(u16::from(offset.unsigned_abs())))
+    } else {
+        add(base, Expr::Const(offset as u16))
+    }
+}
+
+pub fn condition_expr(condition: Condition) -> Expr {
+    match condition {
+        Condition::NotZero => Expr::unary(UnaryOp::Not, Expr::flag(Flag::Zero)),
+        Condition::Zero => Expr::flag(Flag::Zero),
+        Condition::NoCarry => Expr::unary(UnaryOp::Not, Expr::flag(Flag::Carry)),
+        Condition::Carry => Expr::flag(Flag::Carry),
+    }
+}
+
+struct Lifter {
+    stmts: Vec<Stmt>,
+}
+
+impl Lifter {
+    fn push(&mut self, stmt: Stmt) {
+        self.stmts.push(stmt);
+    }
+
+    fn assign(&mut self, var: Var, value: Expr) {
+        self.push(Stmt::Assign(var, value));
+    }
+
+    fn flag(&mut self, flag: Flag, value: Expr) {
+        self.assign(Var::Flag(flag), value);
+    }
+
+    fn flag_const(&mut self, flag: Flag, value: bool) {
+        self.flag(flag, Expr::Const(value as u16));
+    }
+
+    fn zero_flag(&mut self, result: Expr) {
+        self.flag(
+            Flag::Zero,
+            Expr::binary(BinaryOp::Eq, result, Expr::Const(0)),
+        );
+    }
+
+    fn read(&mut self, operand: Operand) -> Expr {
+        match operand {
+            Operand::Register(register) => Expr::register(register),
+            Operand::Immediate(value) => Expr::Const(u16::from(value)),
+            Operand::IndirectHL => Expr::load(HL.expr()),
+        }
+    }
+
+    /// Like `read`, but memory operands are loaded into a temporary so the
+    /// value can be used several times without repeating the memory access.
+    fn read_once(&mut self, operand: Operand) -> Expr {
+        match operand {
+            Operand::IndirectHL => {
+                self.assign(TEMP_OPERAND, Expr::load(HL.expr()));
+                Expr::var(TEMP_OPERAND)
+            }
+            _ => self.read(operand),
+        }
+    }
+
+    /// Writes the value to the operand and returns an expression that reads
+    /// back the written value.
+    fn write(&mut self, operand: Operand, value: Expr) -> Expr {
+        match operand {
+            Operand::Register(register) => {
+                self.assign(Var::Register(register), value);
+                Expr::register(register)
+            }
+            Operand::IndirectHL => {
+                let value = match value {
+                    Expr::Const(_) | Expr::Var(_) => value,
+                    _ => {
+                        self.assign(TEMP_RESULT, value);
+                        Expr::var(TEMP_RESULT)
+                    }
+                };
+                self.push(Stmt::Store(HL.expr(), value.clone()));
+                value
+            }
+            Operand::Immediate(_) => panic!("Cannot write to an immediate operand"),
+        }
+    }
+
+    fn write_pair(&mut self, pair: Pair, value: Expr) {
+        match pair {
+            Pair::Registers(high, low) => {
+                let value = match value {
+                    Expr::Const(_) => value,
+                    _ => Expr::mask(value, 0xFFFF),
+                };
+                self.push(Stmt::AssignPair(
+                    Var::Register(high),
+                    Var::Register(low),
+                    value,
+                ));
+            }
+            Pair::StackPointer => match value {
+                Expr::Const(_) => self.assign(Var::StackPointer, value),
+                _ => self.assign(Var::StackPointer, Expr::mask(value, 0xFFFF)),
+            },
+            Pair::AccumulatorFlags => panic!("AF can only be written through POP"),
+        }
+    }
+
+    fn ld(&mut self, target: Operand, source: Operand) {
+        let value = self.read(source);
+        self.write(target, value);
+    }
+
+    fn load_pair(&mut self, pair: Pair, value: u16) {
+        self.write_pair(pair, Expr::Const(value));
+    }
+
+    fn store_a(&mut self, pair: Pair) {
+        self.push(Stmt::Store(pair.expr(), Expr::register(Register::A)));
+    }
+
+    fn load_a(&mut self, pair: Pair) {
+        self.assign(Var::Register(Register::A), Expr::load(pair.expr()));
+    }
+
+    fn store_a_hl(&mut self, step: BinaryOp) {
+        self.store_a(HL);
+        self.step_pair(HL, step);
+    }
+
+    fn load_a_hl(&mut self, step: BinaryOp) {
+        self.load_a(HL);
+        self.step_pair(HL, step);
+    }
+
+    fn store_a_high(&mut self, offset: Expr) {
+        let address = match offset.as_const() {
+            Some(offset) => Expr::Const(0xFF00 | offset),
+            None => add(Expr::Const(0xFF00), offset),
+        };
+        self.push(Stmt::Store(address, Expr::register(Register::A)));
+    }
+
+    fn load_a_high(&mut self, offset: Expr) {
+        let address = match offset.as_const() {
+            Some(offset) => Expr::Const(0xFF00 | offset),
+            None => add(Expr::Const(0xFF00), offset),
+        };
+        self.assign(Var::Register(Register::A), Expr::load(address));
+    }
+
+    fn store_a_absolute(&mut self, address: u16) {
+        self.push(Stmt::Store(
+            Expr::Const(address),
+            Expr::register(Register::A),
+        ));
+    }
+
+    fn load_a_absolute(&mut self, address: u16) {
+        self.assign(Var::Register(Register::A), Expr::load(Expr::Const(address)));
+    }
+
+    fn store_sp(&mut self, address: u16) {
+        let sp = Expr::var(Var::StackPointer);
+        self.push(Stmt::Store(
+            Expr::Const(address),
+            Expr::mask(sp.clone(), 0xFF),
+        ));
+        self.push(Stmt::Store(
+            Expr::Const(address.wrapping_add(1)),
+            shr(sp, 8),
+        ));
+    }
+
+    fn step_pair(&mut self, pair: Pair, step: BinaryOp) {
+        let value = Expr::binary(step, pair.expr(), Expr::Const(1));
+        self.write_pair(pair, value);
+    }
+
+    fn add_hl(&mut self, pair: Pair) {
+        let hl = HL.expr();
+        let operand = pair.expr();
+
+        self.assign(TEMP, add(hl.clone(), operand.clone()));
+        self.flag(
+            Flag::HalfCarry,
+            Expr::binary(
+                BinaryOp::Gt,
+                add(Expr::mask(hl, 0xFFF), Expr::mask(operand, 0xFFF)),
+                Expr::Const(0xFFF),
+            ),
+        );
+        self.flag(
+            Flag::Carry,
+            Expr::binary(BinaryOp::Gt, Expr::var(TEMP), Expr::Const(0xFFFF)),
+        );
+        self.flag_const(Flag::Subtract, false);
+        self.write_pair(HL, Expr::var(TEMP));
+    }
+
+    fn stack_offset_flags(&mut self, offset: i8) {
+        let sp = Expr::var(Var::StackPointer);
+        let operand = Expr::Const(u16::from(offset as u8));
+
+        self.flag(
+            Flag::HalfCarry,
+            Expr::binary(
+                BinaryOp::Gt,
+                add(
+                    Expr::mask(sp.clone(), 0xF),
+                    Expr::mask(operand.clone(), 0xF),
+                ),
+                Expr::Const(0xF),
+            ),
+        );
+        self.flag(
+            Flag::Carry,
+            Expr::binary(
+                BinaryOp::Gt,
+                add(Expr::mask(sp, 0xFF), operand),
+                Expr::Const(0xFF),
+            ),
+        );
+        self.flag_const(Flag::Zero, false);
+        self.flag_const(Flag::Subtract, false);
+    }
+
+    fn add_sp(&mut self, value: i8) {
+        self.stack_offset_flags(value);
+        self.write_pair(SP, offset(Expr::var(Var::StackPointer), value));
+    }
+
+    fn load_hl_sp(&mut self, value: i8) {
+        self.stack_offset_flags(value);
+        self.write_pair(HL, offset(Expr::var(Var::StackPointer), value));
+    }
+
+    fn push_pair(&mut self, pair: Pair) {
+        let (high, low) = match pair {
+            Pair::Registers(high, low) => (Expr::register(high), Expr::register(low)),
+            Pair::AccumulatorFlags => (Expr::register(Register::A), flags_byte()),
+            Pair::StackPointer => panic!("SP cannot be pushed"),
+        };
+        let sp = Expr::var(Var::StackPointer);
+
+        self.write_pair(SP, sub(sp.clone(), Expr::Const(2)));
+        self.push(Stmt::Store(add(sp.clone(), Expr::Const(1)), high));
+        self.push(Stmt::Store(sp, low));
+    }
+
+    fn pop_pair(&mut self, pair: Pair) {
+        let sp = Expr::var(Var::StackPointer);
+        let low = Expr::load(sp.clone());
+        let high = Expr::load(add(sp.clone(), Expr::Const(1)));
+
+        match pair {
+            Pair::Registers(high_register, low_register) => {
+                self.assign(Var::Register(low_register), low);
+                self.assign(Var::Register(high_register), high);
+            }
+            Pair::AccumulatorFlags => {
+                self.assign(TEMP, low);
+                for &(flag, bit) in FLAG_BITS.iter() {
+                    self.flag(flag, Expr::mask(shr(Expr::var(TEMP), bit), 1));
+                }
+                self.assign(Var::Register(Register::A), high);
+            }
+            Pair::StackPointer => panic!("SP cannot be popped"),
+        }
+
+        self.write_pair(SP, add(sp, Expr::Const(2)));
+    }
+
+    fn inc(&mut self, operand: Operand) {
+        let value = self.read_once(operand);
+        self.flag(
+            Flag::HalfCarry,
+            Expr::binary(
+                BinaryOp::Eq,
+                Expr::mask(value.clone(), 0xF),
+                Expr::Const(0xF),
+            ),
+        );
+        let result = self.write(operand, Expr::mask(add(value, Expr::Const(1)), 0xFF));
+        self.zero_flag(result);
+        self.flag_const(Flag::Subtract, false);
+    }
+
+    fn dec(&mut self, operand: Operand) {
+        let value = self.read_once(operand);
+        self.flag(
+            Flag::HalfCarry,
+            Expr::binary(BinaryOp::Eq, Expr::mask(value.clone(), 0xF), Expr::Const(0)),
+        );
+        let result = self.write(operand, Expr::mask(sub(value, Expr::Const(1)), 0xFF));
+        self.zero_flag(result);
+        self.flag_const(Flag::Subtract, true);
+    }
+
+    fn alu(&mut self, op: AluOp, operand: Operand) {
+        let a = Expr::register(Register::A);
+        let value = self.read_once(operand);
+        let carry = Expr::flag(Flag::Carry);
+
+        match op {
+            AluOp::Add => {
+                self.flag(
+                    Flag::HalfCarry,
+                    Expr::binary(
+                        BinaryOp::Gt,
+                        add(Expr::mask(a.clone(), 0xF), Expr::mask(value.clone(), 0xF)),
+                        Expr::Const(0xF),
+                    ),
+                );
+                self.flag(
+                    Flag::Carry,
+                    Expr::binary(
+                        BinaryOp::Gt,
+                        add(a.clone(), value.clone()),
+                        Expr::Const(0xFF),
+                    ),
+                );
+                self.assign(
+                    Var::Register(Register::A),
+                    Expr::mask(add(a.clone(), value), 0xFF),
+                );
+                self.zero_flag(a);
+                self.flag_const(Flag::Subtract, false);
+            }
+            AluOp::AddWithCarry => {
+                self.assign(TEMP, add(add(a.clone(), value.clone()), carry.clone()));
+                self.flag(
+                    Flag::HalfCarry,
+                    Expr::binary(
+                        BinaryOp::Gt,
+                        add(
+                            add(Expr::mask(a.clone(), 0xF), Expr::mask(value, 0xF)),
+                            carry,
+                        ),
+                        Expr::Const(0xF),
+                    ),
+                );
+                self.flag(
+                    Flag::Carry,
+                    Expr::binary(BinaryOp::Gt, Expr::var(TEMP), Expr::Const(0xFF)),
+                );
+                self.assign(
+                    Var::Register(Register::A),
+                    Expr::mask(Expr::var(TEMP), 0xFF),
+                );
+                self.zero_flag(a);
+                self.flag_const(Flag::Subtract, false);
+            }
+            AluOp::Sub | AluOp::Compare => {
+                self.flag(
+                    Flag::HalfCarry,
+                    Expr::binary(
+                        BinaryOp::Lt,
+                        Expr::mask(a.clone(), 0xF),
+                        Expr::mask(value.clone(), 0xF),
+                    ),
+                );
+                self.flag(
+                    Flag::Carry,
+                    Expr::binary(BinaryOp::Lt, a.clone(), value.clone()),
+                );
+                self.flag(
+                    Flag::Zero,
+                    Expr::binary(BinaryOp::Eq, a.clone(), value.clone()),
+                );
+                self.flag_const(Flag::Subtract, true);
+                if let AluOp::Sub = op {
+                    self.assign(Var::Register(Register::A), Expr::mask(sub(a, value), 0xFF));
+                }
+            }
+            AluOp::SubWithCarry => {
+                self.assign(TEMP, sub(sub(a.clone(), value.clone()), carry.clone()));
+                self.flag(
+                    Flag::HalfCarry,
+                    Expr::binary(
+                        BinaryOp::Lt,
+                        Expr::mask(a.clone(), 0xF),
+                        add(Expr::mask(value.clone(), 0xF), carry.clone()),
+                    ),
+                );
+                self.flag(
+                    Flag::Carry,
+                    Expr::binary(BinaryOp::Lt, a.clone(), add(value, carry)),
+                );
+                self.assign(
+                    Var::Register(Register::A),
+                    Expr::mask(Expr::var(TEMP), 0xFF),
+                );
+                self.zero_flag(a);
+                self.flag_const(Flag::Subtract, true);
+            }
+            AluOp::And | AluOp::Xor | AluOp::Or => {
+                let bin_op = match op {
+                    AluOp::And => BinaryOp::And,
+                    AluOp::Xor => BinaryOp::Xor,
+                    _ => BinaryOp::Or,
+                };
+                self.assign(
+                    Var::Register(Register::A),
+                    Expr::binary(bin_op, a.clone(), value),
+                );
+                self.zero_flag(a);
+                self.flag_const(Flag::Subtract, false);
+                self.flag_const(Flag::HalfCarry, bin_op == BinaryOp::And);
+                self.flag_const(Flag::Carry, false);
+            }
+        }
+    }
+
+    fn shift_impl(&mut self, op: ShiftOp, operand: Operand) -> Expr {
+        let value = self.read_once(operand);
+        let carry = Expr::flag(Flag::Carry);
+
+        match op {
+            ShiftOp::RotateLeftCircular => {
+                self.flag(Flag::Carry, shr(value.clone(), 7));
+                self.write(
+                    operand,
+                    Expr::mask(or(shl(value.clone(), 1), shr(value, 7)), 0xFF),
+                )
+            }
+            ShiftOp::RotateRightCircular => {
+                self.flag(Flag::Carry, Expr::mask(value.clone(), 1));
+                self.write(
+                    operand,
+                    Expr::mask(or(shr(value.clone(), 1), shl(value, 7)), 0xFF),
+                )
+            }
+            ShiftOp::RotateLeft => {
+                self.assign(TEMP, shr(value.clone(), 7));
+                let result = self.write(operand, Expr::mask(or(shl(value, 1), carry), 0xFF));
+                self.flag(Flag::Carry, Expr::var(TEMP));
+                result
+            }
+            ShiftOp::RotateRight => {
+                self.assign(TEMP, Expr::mask(value.clone(), 1));
+                let result = self.write(operand, or(shr(value, 1), shl(carry, 7)));
+                self.flag(Flag::Carry, Expr::var(TEMP));
+                result
+            }
+            ShiftOp::ShiftLeftArithmetic => {
+                self.flag(Flag::Carry, shr(value.clone(), 7));
+                self.write(operand, Expr::mask(shl(value, 1), 0xFF))
+            }
+            ShiftOp::ShiftRightArithmetic => {
+                self.flag(Flag::Carry, Expr::mask(value.clone(), 1));
+                self.write(operand, or(shr(value.clone(), 1), Expr::mask(value, 0x80)))
+            }
+            ShiftOp::Swap => {
+                self.flag_const(Flag::Carry, false);
+                self.write(operand, Expr::Intrinsic(Intrinsic::Swap, vec![value]))
+            }
+            ShiftOp::ShiftRightLogical => {
+                self.flag(Flag::Carry, Expr::mask(value.clone(), 1));
+                self.write(operand, shr(value, 1))
+            }
+        }
+    }
+
+    fn shift(&mut self, op: ShiftOp, operand: Operand) {
+        let result = self.shift_impl(op, operand);
+        self.zero_flag(result);
+        self.flag_const(Flag::Subtract, false);
+        self.flag_const(Flag::HalfCarry, false);
+    }
+
+    fn shift_a(&mut self, op: ShiftOp) {
+        self.shift_impl(op, A);
+        self.flag_const(Flag::Zero, false);
+        self.flag_const(Flag::Subtract, false);
+        self.flag_const(Flag::HalfCarry, false);
+    }
+
+    fn bit(&mut self, bit: u8, operand: Operand) {
+        let value = self.read(operand);
+        self.zero_flag(Expr::mask(value, 1 << bit));
+        self.flag_const(Flag::Subtract, false);
+        self.flag_const(Flag::HalfCarry, true);
+    }
+
+    fn res(&mut self, bit: u8, operand: Operand) {
+        let value = self.read_once(operand);
+        self.write(operand, Expr::mask(value, 0xFF & !(1 << bit)));
+    }
+
+    fn set(&mut self, bit: u8, operand: Operand) {
+        let value = self.read_once(operand);
+        self.write(operand, or(value, Expr::Const(1 << bit)));
+    }
+
+    fn daa(&mut self) {
+        let args = vec![
+            Expr::register(Register::A),
+            Expr::flag(Flag::Subtract),
+            Expr::flag(Flag::HalfCarry),
+            Expr::flag(Flag::Carry),
+        ];
+
+        self.assign(TEMP, Expr::Intrinsic(Intrinsic::Daa, args.clone()));
+        self.flag(Flag::Carry, Expr::Intrinsic(Intrinsic::DaaCarry, args));
+        self.assign(Var::Register(Register::A), Expr::var(TEMP));
+        self.zero_flag(Expr::register(Register::A));
+        self.flag_const(Flag::HalfCarry, false);
+    }
+
+    fn cpl(&mut self) {
+        self.assign(
+            Var::Register(Register::A),
+            Expr::binary(
+                BinaryOp::Xor,
+                Expr::register(Register::A),
+                Expr::Const(0xFF),
+            ),
+        );
+        self.flag_const(Flag::Subtract, true);
+        self.flag_const(Flag::HalfCarry, true);
+    }
+
+    fn scf(&mut self) {
+        self.flag_const(Flag::Carry, true);
+        self.flag_const(Flag::Subtract, false);
+        self.flag_const(Flag::HalfCarry, false);
+    }
+
+    fn ccf(&mut self) {
+        self.flag(
+            Flag::Carry,
+            Expr::unary(UnaryOp::Not, Expr::flag(Flag::Carry)),
+        );
+        self.flag_const(Flag::Subtract, false);
+        self.flag_const(Flag::HalfCarry, false);
+    }
+
+    fn jump(&mut self, instruction: &Instruction) {
+        self.push(Stmt::Jump {
+            condition: instruction.condition().map(condition_expr),
+            target: Target::Direct(instruction.jump_target().unwrap()),
+        });
+    }
+
+    fn call(&mut self, instruction: &Instruction) {
+        self.push(Stmt::Call {
+            condition: instruction.condition().map(condition_expr),
+            target: Target::Direct(instruction.call_target().unwrap()),
+        });
+    }
+
+    fn ret(&mut self, instruction: &Instruction) {
+        self.push(Stmt::Return {
+            condition: instruction.condition().map(condition_expr),
+        });
+    }
+}
+
+pub fn lift(instruction: &Instruction) -> Vec<Stmt> {
+    let mut lifter = Lifter { stmts: Vec::new() };
+
+    match instruction.op_code {
+        OpCode::NOP => (),
+        OpCode::LD_BC_d16(value) => lifter.load_pair(BC, value.value),
+        OpCode::LD_DE_d16(value) => lifter.load_pair(DE, value.value),
+        OpCode::LD_HL_d16(value) => lifter.load_pair(HL, value.value),
+        OpCode::LD_SP_d16(value) => lifter.load_pair(SP, value.value),
+        OpCode::LD_pBC_A => lifter.store_a(BC),
+        OpCode::LD_A_pBC => lifter.load_a(BC),
+        OpCode::LD_pDE_A => lifter.store_a(DE),
+        OpCode::LD_A_pDE => lifter.load_a(DE),
+        OpCode::LD_pHLp_A => lifter.store_a_hl(BinaryOp::Add),
+        OpCode::LD_pHLm_A => lifter.store_a_hl(BinaryOp::Sub),
+        OpCode::LD_A_pHLp => lifter.load_a_hl(BinaryOp::Add),
+        OpCode::LD_A_pHLm => lifter.load_a_hl(BinaryOp::Sub),
+        OpCode::INC_BC => lifter.step_pair(BC, BinaryOp::Add),
+        OpCode::DEC_BC => lifter.step_pair(BC, BinaryOp::Sub),
+        OpCode::ADD_HL_BC => lifter.add_hl(BC),
+        OpCode::INC_DE => lifter.step_pair(DE, BinaryOp::Add),
+        OpCode::DEC_DE => lifter.step_pair(DE, BinaryOp::Sub),
+        OpCode::ADD_HL_DE => lifter.add_hl(DE),
+        OpCode::INC_HL => lifter.step_pair(HL, BinaryOp::Add),
+        OpCode::DEC_HL => lifter.step_pair(HL, BinaryOp::Sub),
+        OpCode::ADD_HL_HL => lifter.add_hl(HL),
+        OpCode::INC_SP => lifter.step_pair(SP, BinaryOp::Add),
+        OpCode::DEC_SP => lifter.step_pair(SP, BinaryOp::Sub),
+        OpCode::ADD_HL_SP => lifter.add_hl(SP),
+        OpCode::INC_B => lifter.inc(B),
+        OpCode::DEC_B => lifter.dec(B),
+        OpCode::LD_B_d8(value) => lifter.ld(B, Operand::Immediate(value.value)),
+        OpCode::INC_C => lifter.inc(C),
+        OpCode::DEC_C => lifter.dec(C),
+        OpCode::LD_C_d8(value) => lifter.ld(C, Operand::Immediate(value.value)),
+        OpCode::INC_D => lifter.inc(D),
+        OpCode::DEC_D => lifter.dec(D),
+        OpCode::LD_D_d8(value) => lifter.ld(D, Operand::Immediate(value.value)),
+        OpCode::INC_E => lifter.inc(E),
+        OpCode::DEC_E => lifter.dec(E),
+        OpCode::LD_E_d8(value) => lifter.ld(E, Operand::Immediate(value.value)),
+        OpCode::INC_H => lifter.inc(H),
+        OpCode::DEC_H => lifter.dec(H),
+        OpCode::LD_H_d8(value) => lifter.ld(H, Operand::Immediate(value.value)),
+        OpCode::INC_L => lifter.inc(L),
+        OpCode::DEC_L => lifter.dec(L),
+        OpCode::LD_L_d8(value) => lifter.ld(L, Operand::Immediate(value.value)),
+        OpCode::INC_pHL => lifter.inc(IND_HL),
+        OpCode::DEC_pHL => lifter.dec(IND_HL),
+        OpCode::LD_pHL_d8(value) => lifter.ld(IND_HL, Operand::Immediate(value.value)),
+        OpCode::INC_A => lifter.inc(A),
+        OpCode::DEC_A => lifter.dec(A),
+        OpCode::LD_A_d8(value) => lifter.ld(A, Operand::Immediate(value.value)),
+        OpCode::RLCA => lifter.shift_a(ShiftOp::RotateLeftCircular),
+        OpCode::RRCA => lifter.shift_a(ShiftOp::RotateRightCircular),
+        OpCode::RLA => lifter.shift_a(ShiftOp::RotateLeft),
+        OpCode::RRA => lifter.shift_a(ShiftOp::RotateRight),
+        OpCode::LD_pa16_SP(address) => lifter.store_sp(address.value),
+        OpCode::STOP_0 => lifter.push(Stmt::Stop),
+        OpCode::HALT => lifter.push(Stmt::Halt),
+        OpCode::DAA => lifter.daa(),
+        OpCode::CPL => lifter.cpl(),
+        OpCode::SCF => lifter.scf(),
+        OpCode::CCF => lifter.ccf(),
+        OpCode::LD_B_B => lifter.ld(B, B),
+        OpCode::LD_B_C => lifter.ld(B, C),
+        OpCode::LD_B_D => lifter.ld(B, D),
+        OpCode::LD_B_E => lifter.ld(B, E),
+        OpCode::LD_B_H => lifter.ld(B, H),
+        OpCode::LD_B_L => lifter.ld(B, L),
+        OpCode::LD_B_pHL => lifter.ld(B, IND_HL),
+        OpCode::LD_B_A => lifter.ld(B, A),
+        OpCode::LD_C_B => lifter.ld(C, B),
+        OpCode::LD_C_C => lifter.ld(C, C),
+        OpCode::LD_C_D => lifter.ld(C, D),
+        OpCode::LD_C_E => lifter.ld(C, E),
+        OpCode::LD_C_H => lifter.ld(C, H),
+        OpCode::LD_C_L => lifter.ld(C, L),
+        OpCode::LD_C_pHL => lifter.ld(C, IND_HL),
+        OpCode::LD_C_A => lifter.ld(C, A),
+        OpCode::LD_D_B => lifter.ld(D, B),
+        OpCode::LD_D_C => lifter.ld(D, C),
+        OpCode::LD_D_D => lifter.ld(D, D),
+        OpCode::LD_D_E => lifter.ld(D, E),
+        OpCode::LD_D_H => lifter.ld(D, H),
+        OpCode::LD_D_L => lifter.ld(D, L),
+        OpCode::LD_D_pHL => lifter.ld(D, IND_HL),
+        OpCode::LD_D_A => lifter.ld(D, A),
+        OpCode::LD_E_B => lifter.ld(E, B),
+        OpCode::LD_E_C => lifter.ld(E, C),
+        OpCode::LD_E_D => lifter.ld(E, D),
+        OpCode::LD_E_E => lifter.ld(E, E),
+        OpCode::LD_E_H => lifter.ld(E, H),
+        OpCode::LD_E_L => lifter.ld(E, L),
+        OpCode::LD_E_pHL => lifter.ld(E, IND_HL),
+        OpCode::LD_E_A => lifter.ld(E, A),
+        OpCode::LD_H_B => lifter.ld(H, B),
+        OpCode::LD_H_C => lifter.ld(H, C),
+        OpCode::LD_H_D => lifter.ld(H, D),
+        OpCode::LD_H_E => lifter.ld(H, E),
+        OpCode::LD_H_H => lifter.ld(H, H),
+        OpCode::LD_H_L => lifter.ld(H, L),
+        OpCode::LD_H_pHL => lifter.ld(H, IND_HL),
+        OpCode::LD_H_A => lifter.ld(H, A),
+        OpCode::LD_L_B => lifter.ld(L, B),
+        OpCode::LD_L_C => lifter.ld(L, C),
+        OpCode::LD_L_D => lifter.ld(L, D),
+        OpCode::LD_L_E => lifter.ld(L, E),
+        OpCode::LD_L_H => lifter.ld(L, H),
+        OpCode::LD_L_L => lifter.ld(L, L),
+        OpCode::LD_L_pHL => lifter.ld(L, IND_HL),
+        OpCode::LD_L_A => lifter.ld(L, A),
+        OpCode::LD_pHL_B => lifter.ld(IND_HL, B),
+        OpCode::LD_pHL_C => lifter.ld(IND_HL, C),
+        OpCode::LD_pHL_D => lifter.ld(IND_HL, D),
+        OpCode::LD_pHL_E => lifter.ld(IND_HL, E),
+        OpCode::LD_pHL_H => lifter.ld(IND_HL, H),
+        OpCode::LD_pHL_L => lifter.ld(IND_HL, L),
+        OpCode::LD_pHL_A => lifter.ld(IND_HL, A),
+        OpCode::LD_A_B => lifter.ld(A, B),
+        OpCode::LD_A_C => lifter.ld(A, C),
+        OpCode::LD_A_D => lifter.ld(A, D),
+        OpCode::LD_A_E => lifter.ld(A, E),
+        OpCode::LD_A_H => lifter.ld(A, H),
+        OpCode::LD_A_L => lifter.ld(A, L),
+        OpCode::LD_A_pHL => lifter.ld(A, IND_HL),
+        OpCode::LD_A_A => lifter.ld(A, A),
+        OpCode::ADD_A_B => lifter.alu(AluOp::Add, B),
+        OpCode::ADD_A_C => lifter.alu(AluOp::Add, C),
+        OpCode::ADD_A_D => lifter.alu(AluOp::Add, D),
+        OpCode::ADD_A_E => lifter.alu(AluOp::Add, E),
+        OpCode::ADD_A_H => lifter.alu(AluOp::Add, H),
+        OpCode::ADD_A_L => lifter.alu(AluOp::Add, L),
+        OpCode::ADD_A_pHL => lifter.alu(AluOp::Add, IND_HL),
+        OpCode::ADD_A_A => lifter.alu(AluOp::Add, A),
+        OpCode::ADD_A_d8(value) => lifter.alu(AluOp::Add, Operand::Immediate(value.value)),
+        OpCode::ADC_A_B => lifter.alu(AluOp::AddWithCarry, B),
+        OpCode::ADC_A_C => lifter.alu(AluOp::AddWithCarry, C),
+        OpCode::ADC_A_D => lifter.alu(AluOp::AddWithCarry, D),
+        OpCode::ADC_A_E => lifter.alu(AluOp::AddWithCarry, E),
+        OpCode::ADC_A_H => lifter.alu(AluOp::AddWithCarry, H),
+        OpCode::ADC_A_L => lifter.alu(AluOp::AddWithCarry, L),
+        OpCode::ADC_A_pHL => lifter.alu(AluOp::AddWithCarry, IND_HL),
+        OpCode::ADC_A_A => lifter.alu(AluOp::AddWithCarry, A),
+        OpCode::ADC_A_d8(value) => lifter.alu(AluOp::AddWithCarry, Operand::Immediate(value.value)),
+        OpCode::SUB_B => lifter.alu(AluOp::Sub, B),
+        OpCode::SUB_C => lifter.alu(AluOp::Sub, C),
+        OpCode::SUB_D => lifter.alu(AluOp::Sub, D),
+        OpCode::SUB_E => lifter.alu(AluOp::Sub, E),
+        OpCode::SUB_H => lifter.alu(AluOp::Sub, H),
+        OpCode::SUB_L => lifter.alu(AluOp::Sub, L),
+        OpCode::SUB_pHL => lifter.alu(AluOp::Sub, IND_HL),
+        OpCode::SUB_A => lifter.alu(AluOp::Sub, A),
+        OpCode::SUB_d8(value) => lifter.alu(AluOp::Sub, Operand::Immediate(value.value)),
+        OpCode::SBC_A_B => lifter.alu(AluOp::SubWithCarry, B),
+        OpCode::SBC_A_C => lifter.alu(AluOp::SubWithCarry, C),
+        OpCode::SBC_A_D => lifter.alu(AluOp::SubWithCarry, D),
+        OpCode::SBC_A_E => lifter.alu(AluOp::SubWithCarry, E),
+        OpCode::SBC_A_H => lifter.alu(AluOp::SubWithCarry, H),
+        OpCode::SBC_A_L => lifter.alu(AluOp::SubWithCarry, L),
+        OpCode::SBC_A_pHL => lifter.alu(AluOp::SubWithCarry, IND_HL),
+        OpCode::SBC_A_A => lifter.alu(AluOp::SubWithCarry, A),
+        OpCode::SBC_A_d8(value) => lifter.alu(AluOp::SubWithCarry, Operand::Immediate(value.value)),
+        OpCode::AND_B => lifter.alu(AluOp::And, B),
+        OpCode::AND_C => lifter.alu(AluOp::And, C),
+        OpCode::AND_D => lifter.alu(AluOp::And, D),
+        OpCode::AND_E => lifter.alu(AluOp::And, E),
+        OpCode::AND_H => lifter.alu(AluOp::And, H),
+        OpCode::AND_L => lifter.alu(AluOp::And, L),
+        OpCode::AND_pHL => lifter.alu(AluOp::And, IND_HL),
+        OpCode::AND_A => lifter.alu(AluOp::And, A),
+        OpCode::AND_d8(value) => lifter.alu(AluOp::And, Operand::Immediate(value.value)),
+        OpCode::XOR_B => lifter.alu(AluOp::Xor, B),
+        OpCode::XOR_C => lifter.alu(AluOp::Xor, C),
+        OpCode::XOR_D => lifter.alu(AluOp::Xor, D),
+        OpCode::XOR_E => lifter.alu(AluOp::Xor, E),
+        OpCode::XOR_H => lifter.alu(AluOp::Xor, H),
+        OpCode::XOR_L => lifter.alu(AluOp::Xor, L),
+        OpCode::XOR_pHL => lifter.alu(AluOp::Xor, IND_HL),
+        OpCode::XOR_A => lifter.alu(AluOp::Xor, A),
+        OpCode::XOR_d8(value) => lifter.alu(AluOp::Xor, Operand::Immediate(value.value)),
+        OpCode::OR_B => lifter.alu(AluOp::Or, B),
+        OpCode::OR_C => lifter.alu(AluOp::Or, C),
+        OpCode::OR_D => lifter.alu(AluOp::Or, D),
+        OpCode::OR_E => lifter.alu(AluOp::Or, E),
+        OpCode::OR_H => lifter.alu(AluOp::Or, H),
+        OpCode::OR_L => lifter.alu(AluOp::Or, L),
+        OpCode::OR_pHL => lifter.alu(AluOp::Or, IND_HL),
+        OpCode::OR_A => lifter.alu(AluOp::Or, A),
+        OpCode::OR_d8(value) => lifter.alu(AluOp::Or, Operand::Immediate(value.value)),
+        OpCode::CP_B => lifter.alu(AluOp::Compare, B),
+        OpCode::CP_C => lifter.alu(AluOp::Compare, C),
+        OpCode::CP_D => lifter.alu(AluOp::Compare, D),
+        OpCode::CP_E => lifter.alu(AluOp::Compare, E),
+        OpCode::CP_H => lifter.alu(AluOp::Compare, H),
+        OpCode::CP_L => lifter.alu(AluOp::Compare, L),
+        OpCode::CP_pHL => lifter.alu(AluOp::Compare, IND_HL),
+        OpCode::CP_A => lifter.alu(AluOp::Compare, A),
+        OpCode::CP_d8(value) => lifter.alu(AluOp::Compare, Operand::Immediate(value.value)),
+        OpCode::PUSH_BC => lifter.push_pair(BC),
+        OpCode::POP_BC => lifter.pop_pair(BC),
+        OpCode::PUSH_DE => lifter.push_pair(DE),
+        OpCode::POP_DE => lifter.pop_pair(DE),
+        OpCode::PUSH_HL => lifter.push_pair(HL),
+        OpCode::POP_HL => lifter.pop_pair(HL),
+        OpCode::PUSH_AF => lifter.push_pair(AF),
+        OpCode::POP_AF => lifter.pop_pair(AF),
+        OpCode::LDH_pa8_A(address) => lifter.store_a_high(Expr::Const(u16::from(address.value))),
+        OpCode::LDH_A_pa8(address) => lifter.load_a_high(Expr::Const(u16::from(address.value))),
+        OpCode::LD_pC_A => lifter.store_a_high(Expr::register(Register::C)),
+        OpCode::LD_A_pC => lifter.load_a_high(Expr::register(Register::C)),
+        OpCode::LD_pa16_A(address) => lifter.store_a_absolute(address.value),
+        OpCode::LD_A_pa16(address) => lifter.load_a_absolute(address.value),
+        OpCode::ADD_SP_r8(offset) => lifter.add_sp(offset.value),
+        OpCode::LD_HL_SPp_r8(offset) => lifter.load_hl_sp(offset.value),
+        OpCode::LD_SP_HL => lifter.assign(Var::StackPointer, HL.expr()),
+        OpCode::DI => lifter.assign(Var::InterruptsEnabled, Expr::Const(0)),
+        OpCode::EI => lifter.assign(Var::InterruptsEnabled, Expr::Const(1)),
+        OpCode::JP_pHL => lifter.push(Stmt::Jump {
+            condition: None,
+            target: Target::Indirect(HL.expr()),
+        }),
+        OpCode::JR_r8(_)
+        | OpCode::JR_NZ_r8(_)
+        | OpCode::JR_Z_r8(_)
+        | OpCode::JR_NC_r8(_)
+        | OpCode::JR_C_r8(_)
+        | OpCode::JP_a16(_)
+        | OpCode::JP_NZ_a16(_)
+        | OpCode::JP_Z_a16(_)
+        | OpCode::JP_NC_a16(_)
+        | OpCode::JP_C_a16(_) => lifter.jump(instruction),
+        OpCode::CALL_a16(_)
+        | OpCode::CALL_NZ_a16(_)
+        | OpCode::CALL_Z_a16(_)
+        | OpCode::CALL_NC_a16(_)
+        | OpCode::CALL_C_a16(_)
+        | OpCode::RST_00H
+        | OpCode::RST_08H
+        | OpCode::RST_10H
+        | OpCode::RST_18H
+        | OpCode::RST_20H
+        | OpCode::RST_28H
+        | OpCode::RST_30H
+        | OpCode::RST_38H => lifter.call(instruction),
+        OpCode::RET | OpCode::RET_NZ | OpCode::RET_Z | OpCode::RET_NC | OpCode::RET_C => {
+            lifter.ret(instruction)
+        }
+        OpCode::RETI => {
+            lifter.assign(Var::InterruptsEnabled, Expr::Const(1));
+            lifter.ret(instruction)
+        }
+        OpCode::PREFIX_CB(op_code) => lift_cb(&mut lifter, op_code),
+    }
+
+    lifter.stmts
+}
+
+fn lift_cb(lifter: &mut Lifter, op_code: CBOpCode) {
+    match op_code {
+        CBOpCode::RLC_B => lifter.shift(ShiftOp::RotateLeftCircular, B),
+        CBOpCode::RLC_C => lifter.shift(ShiftOp::RotateLeftCircular, C),
+        CBOpCode::RLC_D => lifter.shift(ShiftOp::RotateLeftCircular, D),
+        CBOpCode::RLC_E => lifter.shift(ShiftOp::RotateLeftCircular, E),
+        CBOpCode::RLC_H => lifter.shift(ShiftOp::RotateLeftCircular, H),
+        CBOpCode::RLC_L => lifter.shift(ShiftOp::RotateLeftCircular, L),
+        CBOpCode::RLC_pHL => lifter.shift(ShiftOp::RotateLeftCircular, IND_HL),
+        CBOpCode::RLC_A => lifter.shift(ShiftOp::RotateLeftCircular, A),
+        CBOpCode::RRC_B => lifter.shift(ShiftOp::RotateRightCircular, B),
+        CBOpCode::RRC_C => lifter.shift(ShiftOp::RotateRightCircular, C),
+        CBOpCode::RRC_D => lifter.shift(ShiftOp::RotateRightCircular, D),
+        CBOpCode::RRC_E => lifter.shift(ShiftOp::RotateRightCircular, E),
+        CBOpCode::RRC_H => lifter.shift(ShiftOp::RotateRightCircular, H),
+        CBOpCode::RRC_L => lifter.shift(ShiftOp::RotateRightCircular, L),
+        CBOpCode::RRC_pHL => lifter.shift(ShiftOp::RotateRightCircular, IND_HL),
+        CBOpCode::RRC_A => lifter.shift(ShiftOp::RotateRightCircular, A),
+        CBOpCode::RL_B => lifter.shift(ShiftOp::RotateLeft, B),
+        CBOpCode::RL_C => lifter.shift(ShiftOp::RotateLeft, C),
+        CBOpCode::RL_D => lifter.shift(ShiftOp::RotateLeft, D),
+        CBOpCode::RL_E => lifter.shift(ShiftOp::RotateLeft, E),
+        CBOpCode::RL_H => lifter.shift(ShiftOp::RotateLeft, H),
+        CBOpCode::RL_L => lifter.shift(ShiftOp::RotateLeft, L),
+        CBOpCode::RL_pHL => lifter.shift(ShiftOp::RotateLeft, IND_HL),
+        CBOpCode::RL_A => lifter.shift(ShiftOp::RotateLeft, A),
+        CBOpCode::RR_B => lifter.shift(ShiftOp::RotateRight, B),
+        CBOpCode::RR_C => lifter.shift(ShiftOp::RotateRight, C),
+        CBOpCode::RR_D => lifter.shift(ShiftOp::RotateRight, D),
+        CBOpCode::RR_E => lifter.shift(ShiftOp::RotateRight, E),
+        CBOpCode::RR_H => lifter.shift(ShiftOp::RotateRight, H),
+        CBOpCode::RR_L => lifter.shift(ShiftOp::RotateRight, L),
+        CBOpCode::RR_pHL => lifter.shift(ShiftOp::RotateRight, IND_HL),
+        CBOpCode::RR_A => lifter.shift(ShiftOp::RotateRight, A),
+        CBOpCode::SLA_B => lifter.shift(ShiftOp::ShiftLeftArithmetic, B),
+        CBOpCode::SLA_C => lifter.shift(ShiftOp::ShiftLeftArithmetic, C),
+        CBOpCode::SLA_D => lifter.shift(ShiftOp::ShiftLeftArithmetic, D),
+        CBOpCode::SLA_E => lifter.shift(ShiftOp::ShiftLeftArithmetic, E),
+        CBOpCode::SLA_H => lifter.shift(ShiftOp::ShiftLeftArithmetic, H),
+        CBOpCode::SLA_L => lifter.shift(ShiftOp::ShiftLeftArithmetic, L),
+        CBOpCode::SLA_pHL => lifter.shift(ShiftOp::ShiftLeftArithmetic, IND_HL),
+        CBOpCode::SLA_A => lifter.shift(ShiftOp::ShiftLeftArithmetic, A),
+        CBOpCode::SRA_B => lifter.shift(ShiftOp::ShiftRightArithmetic, B),
+        CBOpCode::SRA_C => lifter.shift(ShiftOp::ShiftRightArithmetic, C),
+        CBOpCode::SRA_D => lifter.shift(ShiftOp::ShiftRightArithmetic, D),
+        CBOpCode::SRA_E => lifter.shift(ShiftOp::ShiftRightArithmetic, E),
+        CBOpCode::SRA_H => lifter.shift(ShiftOp::ShiftRightArithmetic, H),
+        CBOpCode::SRA_L => lifter.shift(ShiftOp::ShiftRightArithmetic, L),
+        CBOpCode::SRA_pHL => lifter.shift(ShiftOp::ShiftRightArithmetic, IND_HL),
+        CBOpCode::SRA_A => lifter.shift(ShiftOp::ShiftRightArithmetic, A),
+        CBOpCode::SWAP_B => lifter.shift(ShiftOp::Swap, B),
+        CBOpCode::SWAP_C => lifter.shift(ShiftOp::Swap, C),
+        CBOpCode::SWAP_D => lifter.shift(ShiftOp::Swap, D),
+        CBOpCode::SWAP_E => lifter.shift(ShiftOp::Swap, E),
+        CBOpCode::SWAP_H => lifter.shift(ShiftOp::Swap, H),
+        CBOpCode::SWAP_L => lifter.shift(ShiftOp::Swap, L),
+        CBOpCode::SWAP_pHL => lifter.shift(ShiftOp::Swap, IND_HL),
+        CBOpCode::SWAP_A => lifter.shift(ShiftOp::Swap, A),
+        CBOpCode::SRL_B => lifter.shift(ShiftOp::ShiftRightLogical, B),
+        CBOpCode::SRL_C => lifter.shift(ShiftOp::ShiftRightLogical, C),
+        CBOpCode::SRL_D => lifter.shift(ShiftOp::ShiftRightLogical, D),
+        CBOpCode::SRL_E => lifter.shift(ShiftOp::ShiftRightLogical, E),
+        CBOpCode::SRL_H => lifter.shift(ShiftOp::ShiftRightLogical, H),
+        CBOpCode::SRL_L => lifter.shift(ShiftOp::ShiftRightLogical, L),
+        CBOpCode::SRL_pHL => lifter.shift(ShiftOp::ShiftRightLogical, IND_HL),
+        CBOpCode::SRL_A => lifter.shift(ShiftOp::ShiftRightLogical, A),
+        CBOpCode::BIT_0_B => lifter.bit(0, B),
+        CBOpCode::BIT_0_C => lifter.bit(0, C),
+        CBOpCode::BIT_0_D => lifter.bit(0, D),
+        CBOpCode::BIT_0_E => lifter.bit(0, E),
+        CBOpCode::BIT_0_H => lifter.bit(0, H),
+        CBOpCode::BIT_0_L => lifter.bit(0, L),
+        CBOpCode::BIT_0_pHL => lifter.bit(0, IND_HL),
+        CBOpCode::BIT_0_A => lifter.bit(0, A),
+        CBOpCode::BIT_1_B => lifter.bit(1, B),
+        CBOpCode::BIT_1_C => lifter.bit(1, C),
+        CBOpCode::BIT_1_D => lifter.bit(1, D),
+        CBOpCode::BIT_1_E => lifter.bit(1, E),
+        CBOpCode::BIT_1_H => lifter.bit(1, H),
+        CBOpCode::BIT_1_L => lifter.bit(1, L),
+        CBOpCode::BIT_1_pHL => lifter.bit(1, IND_HL),
+        CBOpCode::BIT_1_A => lifter.bit(1, A),
+        CBOpCode::BIT_2_B => lifter.bit(2, B),
+        CBOpCode::BIT_2_C => lifter.bit(2, C),
+        CBOpCode::BIT_2_D => lifter.bit(2, D),
+        CBOpCode::BIT_2_E => lifter.bit(2, E),
+        CBOpCode::BIT_2_H => lifter.bit(2, H),
+        CBOpCode::BIT_2_L => lifter.bit(2, L),
+        CBOpCode::BIT_2_pHL => lifter.bit(2, IND_HL),
+        CBOpCode::BIT_2_A => lifter.bit(2, A),
+        CBOpCode::BIT_3_B => lifter.bit(3, B),
+        CBOpCode::BIT_3_C => lifter.bit(3, C),
+        CBOpCode::BIT_3_D => lifter.bit(3, D),
+        CBOpCode::BIT_3_E => lifter.bit(3, E),
+        CBOpCode::BIT_3_H => lifter.bit(3, H),
+        CBOpCode::BIT_3_L => lifter.bit(3, L),
+        CBOpCode::BIT_3_pHL => lifter.bit(3, IND_HL),
+        CBOpCode::BIT_3_A => lifter.bit(3, A),
+        CBOpCode::BIT_4_B => lifter.bit(4, B),
+        CBOpCode::BIT_4_C => lifter.bit(4, C),
+        CBOpCode::BIT_4_D => lifter.bit(4, D),
+        CBOpCode::BIT_4_E => lifter.bit(4, E),
+        CBOpCode::BIT_4_H => lifter.bit(4, H),
+        CBOpCode::BIT_4_L => lifter.bit(4, L),
+        CBOpCode::BIT_4_pHL => lifter.bit(4, IND_HL),
+        CBOpCode::BIT_4_A => lifter.bit(4, A),
+        CBOpCode::BIT_5_B => lifter.bit(5, B),
+        CBOpCode::BIT_5_C => lifter.bit(5, C),
+        CBOpCode::BIT_5_D => lifter.bit(5, D),
+        CBOpCode::BIT_5_E => lifter.bit(5, E),
+        CBOpCode::BIT_5_H => lifter.bit(5, H),
+        CBOpCode::BIT_5_L => lifter.bit(5, L),
+        CBOpCode::BIT_5_pHL => lifter.bit(5, IND_HL),
+        CBOpCode::BIT_5_A => lifter.bit(5, A),
+        CBOpCode::BIT_6_B => lifter.bit(6, B),
+        CBOpCode::BIT_6_C => lifter.bit(6, C),
+        CBOpCode::BIT_6_D => lifter.bit(6, D),
+        CBOpCode::BIT_6_E => lifter.bit(6, E),
+        CBOpCode::BIT_6_H => lifter.bit(6, H),
+        CBOpCode::BIT_6_L => lifter.bit(6, L),
+        CBOpCode::BIT_6_pHL => lifter.bit(6, IND_HL),
+        CBOpCode::BIT_6_A => lifter.bit(6, A),
+        CBOpCode::BIT_7_B => lifter.bit(7, B),
+        CBOpCode::BIT_7_C => lifter.bit(7, C),
+        CBOpCode::BIT_7_D => lifter.bit(7, D),
+        CBOpCode::BIT_7_E => lifter.bit(7, E),
+        CBOpCode::BIT_7_H => lifter.bit(7, H),
+        CBOpCode::BIT_7_L => lifter.bit(7, L),
+        CBOpCode::BIT_7_pHL => lifter.bit(7, IND_HL),
+        CBOpCode::BIT_7_A => lifter.bit(7, A),
+        CBOpCode::RES_0_B => lifter.res(0, B),
+        CBOpCode::RES_0_C => lifter.res(0, C),
+        CBOpCode::RES_0_D => lifter.res(0, D),
+        CBOpCode::RES_0_E => lifter.res(0, E),
+        CBOpCode::RES_0_H => lifter.res(0, H),
+        CBOpCode::RES_0_L => lifter.res(0, L),
+        CBOpCode::RES_0_pHL => lifter.res(0, IND_HL),
+        CBOpCode::RES_0_A => lifter.res(0, A),
+        CBOpCode::RES_1_B => lifter.res(1, B),
+        CBOpCode::RES_1_C => lifter.res(1, C),
+        CBOpCode::RES_1_D => lifter.res(1, D),
+        CBOpCode::RES_1_E => lifter.res(1, E),
+        CBOpCode::RES_1_H => lifter.res(1, H),
+        CBOpCode::RES_1_L => lifter.res(1, L),
+        CBOpCode::RES_1_pHL => lifter.res(1, IND_HL),
+        CBOpCode::RES_1_A => lifter.res(1, A),
+        CBOpCode::RES_2_B => lifter.res(2, B),
+        CBOpCode::RES_2_C => lifter.res(2, C),
+        CBOpCode::RES_2_D => lifter.res(2, D),
+        CBOpCode::RES_2_E => lifter.res(2, E),
+        CBOpCode::RES_2_H => lifter.res(2, H),
+        CBOpCode::RES_2_L => lifter.res(2, L),
+        CBOpCode::RES_2_pHL => lifter.res(2, IND_HL),
+        CBOpCode::RES_2_A => lifter.res(2, A),
+        CBOpCode::RES_3_B => lifter.res(3, B),
+        CBOpCode::RES_3_C => lifter.res(3, C),
+        CBOpCode::RES_3_D => lifter.res(3, D),
+        CBOpCode::RES_3_E => lifter.res(3, E),
+        CBOpCode::RES_3_H => lifter.res(3, H),
+        CBOpCode::RES_3_L => lifter.res(3, L),
+        CBOpCode::RES_3_pHL => lifter.res(3, IND_HL),
+        CBOpCode::RES_3_A => lifter.res(3, A),
+        CBOpCode::RES_4_B => lifter.res(4, B),
+        CBOpCode::RES_4_C => lifter.res(4, C),
+        CBOpCode::RES_4_D => lifter.res(4, D),
+        CBOpCode::RES_4_E => lifter.res(4, E),
+        CBOpCode::RES_4_H => lifter.res(4, H),
+        CBOpCode::RES_4_L => lifter.res(4, L),
+        CBOpCode::RES_4_pHL => lifter.res(4, IND_HL),
+        CBOpCode::RES_4_A => lifter.res(4, A),
+        CBOpCode::RES_5_B => lifter.res(5, B),
+        CBOpCode::RES_5_C => lifter.res(5, C),
+        CBOpCode::RES_5_D => lifter.res(5, D),
+        CBOpCode::RES_5_E => lifter.res(5, E),
+        CBOpCode::RES_5_H => lifter.res(5, H),
+        CBOpCode::RES_5_L => lifter.res(5, L),
+        CBOpCode::RES_5_pHL => lifter.res(5, IND_HL),
+        CBOpCode::RES_5_A => lifter.res(5, A),
+        CBOpCode::RES_6_B => lifter.res(6, B),
+        CBOpCode::RES_6_C => lifter.res(6, C),
+        CBOpCode::RES_6_D => lifter.res(6, D),
+        CBOpCode::RES_6_E => lifter.res(6, E),
+        CBOpCode::RES_6_H => lifter.res(6, H),
+        CBOpCode::RES_6_L => lifter.res(6, L),
+        CBOpCode::RES_6_pHL => lifter.res(6, IND_HL),
+        CBOpCode::RES_6_A => lifter.res(6, A),
+        CBOpCode::RES_7_B => lifter.res(7, B),
+        CBOpCode::RES_7_C => lifter.res(7, C),
+        CBOpCode::RES_7_D => lifter.res(7, D),
+        CBOpCode::RES_7_E => lifter.res(7, E),
+        CBOpCode::RES_7_H => lifter.res(7, H),
+        CBOpCode::RES_7_L => lifter.res(7, L),
+        CBOpCode::RES_7_pHL => lifter.res(7, IND_HL),
+        CBOpCode::RES_7_A => lifter.res(7, A),
+        CBOpCode::SET_0_B => lifter.set(0, B),
+        CBOpCode::SET_0_C => lifter.set(0, C),
+        CBOpCode::SET_0_D => lifter.set(0, D),
+        CBOpCode::SET_0_E => lifter.set(0, E),
+        CBOpCode::SET_0_H => lifter.set(0, H),
+        CBOpCode::SET_0_L => lifter.set(0, L),
+        CBOpCode::SET_0_pHL => lifter.set(0, IND_HL),
+        CBOpCode::SET_0_A => lifter.set(0, A),
+        CBOpCode::SET_1_B => lifter.set(1, B),
+        CBOpCode::SET_1_C => lifter.set(1, C),
+        CBOpCode::SET_1_D => lifter.set(1, D),
+        CBOpCode::SET_1_E => lifter.set(1, E),
+        CBOpCode::SET_1_H => lifter.set(1, H),
+        CBOpCode::SET_1_L => lifter.set(1, L),
+        CBOpCode::SET_1_pHL => lifter.set(1, IND_HL),
+        CBOpCode::SET_1_A => lifter.set(1, A),
+        CBOpCode::SET_2_B => lifter.set(2, B),
+        CBOpCode::SET_2_C => lifter.set(2, C),
+        CBOpCode::SET_2_D => lifter.set(2, D),
+        CBOpCode::SET_2_E => lifter.set(2, E),
+        CBOpCode::SET_2_H => lifter.set(2, H),
+        CBOpCode::SET_2_L => lifter.set(2, L),
+        CBOpCode::SET_2_pHL => lifter.set(2, IND_HL),
+        CBOpCode::SET_2_A => lifter.set(2, A),
+        CBOpCode::SET_3_B => lifter.set(3, B),
+        CBOpCode::SET_3_C => lifter.set(3, C),
+        CBOpCode::SET_3_D => lifter.set(3, D),
+        CBOpCode::SET_3_E => lifter.set(3, E),
+        CBOpCode::SET_3_H => lifter.set(3, H),
+        CBOpCode::SET_3_L => lifter.set(3, L),
+        CBOpCode::SET_3_pHL => lifter.set(3, IND_HL),
+        CBOpCode::SET_3_A => lifter.set(3, A),
+        CBOpCode::SET_4_B => lifter.set(4, B),
+        CBOpCode::SET_4_C => lifter.set(4, C),
+        CBOpCode::SET_4_D => lifter.set(4, D),
+        CBOpCode::SET_4_E => lifter.set(4, E),
+        CBOpCode::SET_4_H => lifter.set(4, H),
+        CBOpCode::SET_4_L => lifter.set(4, L),
+        CBOpCode::SET_4_pHL => lifter.set(4, IND_HL),
+        CBOpCode::SET_4_A => lifter.set(4, A),
+        CBOpCode::SET_5_B => lifter.set(5, B),
+        CBOpCode::SET_5_C => lifter.set(5, C),
+        CBOpCode::SET_5_D => lifter.set(5, D),
+        CBOpCode::SET_5_E => lifter.set(5, E),
+        CBOpCode::SET_5_H => lifter.set(5, H),
+        CBOpCode::SET_5_L => lifter.set(5, L),
+        CBOpCode::SET_5_pHL => lifter.set(5, IND_HL),
+        CBOpCode::SET_5_A => lifter.set(5, A),
+        CBOpCode::SET_6_B => lifter.set(6, B),
+        CBOpCode::SET_6_C => lifter.set(6, C),
+        CBOpCode::SET_6_D => lifter.set(6, D),
+        CBOpCode::SET_6_E => lifter.set(6, E),
+        CBOpCode::SET_6_H => lifter.set(6, H),
+        CBOpCode::SET_6_L => lifter.set(6, L),
+        CBOpCode::SET_6_pHL => lifter.set(6, IND_HL),
+        CBOpCode::SET_6_A => lifter.set(6, A),
+        CBOpCode::SET_7_B => lifter.set(7, B),
+        CBOpCode::SET_7_C => lifter.set(7, C),
+        CBOpCode::SET_7_D => lifter.set(7, D),
+        CBOpCode::SET_7_E => lifter.set(7, E),
+        CBOpCode::SET_7_H => lifter.set(7, H),
+        CBOpCode::SET_7_L => lifter.set(7, L),
+        CBOpCode::SET_7_pHL => lifter.set(7, IND_HL),
+        CBOpCode::SET_7_A => lifter.set(7, A),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Runs the statements lifted from `code` on `vars`
+    fn run(code: &[u8], vars: &mut HashMap<Var, i32>) {
+        let cart = Cartridge::new(code.to_vec());
+        let mut address = 0;
+        while address < code.len() {
+            let instruction = Instruction {
+                address,
+                op_code: OpCode::decode_at(&cart, address).unwrap(),
+            };
+            for stmt in lift(&instruction) {
+                let value = stmt.value().map(|value| {
+                    value
+                        .evaluate(&|var| vars.get(&var).cloned(), &|_| None)
+                        .unwrap()
+                });
+                match stmt {
+                    // Temporaries keep the carry out of a sum
+                    Stmt::Assign(var @ Var::Temp(_), _) => {
+                        vars.insert(var, value.unwrap());
+                    }
+                    Stmt::Assign(var, _) => {
+                        vars.insert(var, value.unwrap() & ((1 << var.width()) - 1));
+                    }
+                    Stmt::AssignPair(high, low, _) => {
+                        vars.insert(high, (value.unwrap() >> 8) & 0xFF);
+                        vars.insert(low, value.unwrap() & 0xFF);
+                    }
+                    _ => panic!("unexpected {}", stmt),
+                }
+            }
+            address += instruction.size();
+        }
+    }
+
+    fn state(registers: &[(Register, i32)]) -> HashMap<Var, i32> {
+        let mut vars: HashMap<Var, i32> =
+            Flag::ALL.iter().map(|&flag| (Var::Flag(flag), 0)).collect();
+        vars.extend(
+            registers
+                .iter()
+                .map(|&(register, value)| (Var::Register(register), value)),
+        );
+        vars
+    }
+
+    fn flags(vars: &HashMap<Var, i32>) -> [i32; 4] {
+        [
+            vars[&Var::Flag(Flag::Zero)],
+            vars[&Var::Flag(Flag::Subtract)],
+            vars[&Var::Flag(Flag::HalfCarry)],
+            vars[&Var::Flag(Flag::Carry)],
+        ]
+    }
+
+    #[test]
+    fn add_sets_flags() {
+        // add a,b
+        let mut vars = state(&[(Register::A, 0xFF), (Register::B, 0x01)]);
+        run(&[0x80], &mut vars);
+        assert_eq!(vars[&Var::Register(Register::A)], 0x00);
+        assert_eq!(flags(&vars), [1, 0, 1, 1]);
+
+        let mut vars = state(&[(Register::A, 0x3A), (Register::B, 0x05)]);
+        run(&[0x80], &mut vars);
+        assert_eq!(vars[&Var::Register(Register::A)], 0x3F);
+        assert_eq!(flags(&vars), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn sub_sets_flags() {
+        // sub b
+        let mut vars = state(&[(Register::A, 0x10), (Register::B, 0x01)]);
+        run(&[0x90], &mut vars);
+        assert_eq!(vars[&Var::Register(Register::A)], 0x0F);
+        assert_eq!(flags(&vars), [0, 1, 1, 0]);
+
+        // cp b
+        let mut vars = state(&[(Register::A, 0x05), (Register::B, 0x06)]);
+        run(&[0xB8], &mut vars);
+        assert_eq!(vars[&Var::Register(Register::A)], 0x05);
+        assert_eq!(flags(&vars), [0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn daa_adjusts_bcd() {
+        // add a,b / daa
+        let mut vars = state(&[(Register::A, 0x15), (Register::B, 0x27)]);
+        run(&[0x80, 0x27], &mut vars);
+        assert_eq!(vars[&Var::Register(Register::A)], 0x42);
+        assert_eq!(flags(&vars), [0, 0, 0, 0]);
+
+        let mut vars = state(&[(Register::A, 0x99), (Register::B, 0x01)]);
+        run(&[0x80, 0x27], &mut vars);
+        assert_eq!(vars[&Var::Register(Register::A)], 0x00);
+        assert_eq!(flags(&vars), [1, 0, 0, 1]);
+
+        // sub b / daa
+        let mut vars = state(&[(Register::A, 0x42), (Register::B, 0x15)]);
+        run(&[0x90, 0x27], &mut vars);
+        assert_eq!(vars[&Var::Register(Register::A)], 0x27);
+        assert_eq!(flags(&vars), [0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn add_hl_sets_carries_from_bits_11_and_15() {
+        // add hl,de
+        let mut vars = state(&[
+            (Register::H, 0x8F),
+            (Register::L, 0xFF),
+            (Register::D, 0x70),
+            (Register::E, 0x01),
+        ]);
+        vars.insert(Var::Flag(Flag::Zero), 1);
+        run(&[0x19], &mut vars);
+        assert_eq!(vars[&Var::Register(Register::H)], 0x00);
+        assert_eq!(vars[&Var::Register(Register::L)], 0x00);
+        assert_eq!(flags(&vars), [1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn inc_dec_16_bit_leave_flags() {
+        // inc de / dec bc
+        let mut vars = state(&[
+            (Register::D, 0xFF),
+            (Register::E, 0xFF),
+            (Register::B, 0x00),
+            (Register::C, 0x00),
+        ]);
+        run(&[0x13, 0x0B], &mut vars);
+        assert_eq!(vars[&Var::Register(Register::D)], 0x00);
+        assert_eq!(vars[&Var::Register(Register::E)], 0x00);
+        assert_eq!(vars[&Var::Register(Register::B)], 0xFF);
+        assert_eq!(vars[&Var::Register(Register::C)], 0xFF);
+        assert_eq!(flags(&vars), [0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,11 @@
+mod expr;
+mod lift;
+mod simplify;
+mod stmt;
+mod var;
+
+pub use self::expr::*;
+pub use self::lift::*;
+pub use self::simplify::*;
+pub use self::stmt::*;
+pub use self::var::*;
@@ -0,0 +1,87 @@
+use ir::expr::*;
+use ir::var::*;
+
+fn is_boolean<V: Variable>(expr: &Expr<V>) -> bool {
+    match expr {
+        Expr::Var(var) => var.is_boolean(),
+        Expr::Unary(UnaryOp::Not, _) => true,
+        Expr::Binary(op, ..) => {
+            op.is_comparison() || *op == BinaryOp::LogicalAnd || *op == BinaryOp::LogicalOr
+        }
+        Expr::Const(value) => *value <= 1,
+        _ => false,
+    }
+}
+
+fn negate_comparison(op: BinaryOp) -> Option<BinaryOp> {
+    match op {
+        BinaryOp::Eq => Some(BinaryOp::Ne),
+        BinaryOp::Ne => Some(BinaryOp::Eq),
+        BinaryOp::Lt => Some(BinaryOp::Ge),
+        BinaryOp::Ge => Some(BinaryOp::Lt),
+        BinaryOp::Gt => Some(BinaryOp::Le),
+        BinaryOp::Le => Some(BinaryOp::Gt),
+        _ => None,
+    }
+}
+
+fn not<V: Variable>(operand: Expr<V>) -> Expr<V> {
+    match operand {
+        Expr::Const(value) => Expr::Const((value == 0) as u16),
+        Expr::Unary(UnaryOp::Not, inner) if is_boolean(&inner) => *inner,
+        Expr::Binary(op, lhs, rhs) => match negate_comparison(op) {
+            Some(negated) => Expr::Binary(negated, lhs, rhs),
+            None => Expr::unary(UnaryOp::Not, Expr::Binary(op, lhs, rhs)),
+        },
+        operand => Expr::unary(UnaryOp::Not, operand),
+    }
+}
+
+fn binary<V: Variable>(op: BinaryOp, lhs: Expr<V>, rhs: Expr<V>) -> Expr<V> {
+    match (op, rhs.as_const()) {
+        (BinaryOp::Eq, Some(0)) if is_boolean(&lhs) => return not(lhs),
+        (BinaryOp::Ne, Some(0)) | (BinaryOp::Eq, Some(1)) if is_boolean(&lhs) => return lhs,
+        (BinaryOp::Add, Some(0))
+        | (BinaryOp::Sub, Some(0))
+        | (BinaryOp::Or, Some(0))
+        | (BinaryOp::Xor, Some(0))
+        | (BinaryOp::Shl, Some(0))
+        | (BinaryOp::Shr, Some(0)) => return lhs,
+        (BinaryOp::And, Some(0)) => return Expr::Const(0),
+        (BinaryOp::And, Some(1)) if is_boolean(&lhs) => return lhs,
+        _ => (),
+    }
+
+    match (op, lhs.as_const()) {
+        (BinaryOp::Add, Some(0)) | (BinaryOp::Or, Some(0)) | (BinaryOp::Xor, Some(0)) => {
+            return rhs
+        }
+        _ => (),
+    }
+
+    if lhs == rhs {
+        match op {
+            BinaryOp::And | BinaryOp::Or => return lhs,
+            BinaryOp::Xor | BinaryOp::Sub | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Gt => {
+                return Expr::Const(0)
+            }
+            BinaryOp::Eq | BinaryOp::Le | BinaryOp::Ge => return Expr::Const(1),
+            _ => (),
+        }
+    }
+
+    Expr::binary(op, lhs, rhs)
+}
+
+/// Rewrites an expression into a simpler equivalent, bottom up.
+pub fn simplify<V: Variable>(expr: Expr<V>) -> Expr<V> {
+    match expr {
+        Expr::Const(_) | Expr::Var(_) => expr,
+        Expr::Load(address) => Expr::load(simplify(*address)),
+        Expr::Unary(UnaryOp::Not, operand) => not(simplify(*operand)),
+        Expr::Binary(op, lhs, rhs) => binary(op, simplify(*lhs), simplify(*rhs)),
+        Expr::Intrinsic(intrinsic, args) => {
+            Expr::Intrinsic(intrinsic, args.into_iter().map(simplify).collect())
+        }
+    }
+}
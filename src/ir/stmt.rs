@@ -0,0 +1,244 @@
+use ir::expr::*;
+use ir::var::*;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Target<V = Var> {
+    Direct(usize),
+    Indirect(Expr<V>),
+}
+
+fn map_target<V: Copy, W, F>(target: &Target<V>, map: &mut F) -> Target<W>
+where
+    F: FnMut(&Expr<V>) -> Expr<W>,
+{
+    match target {
+        Target::Direct(address) => Target::Direct(*address),
+        Target::Indirect(expr) => Target::Indirect(map(expr)),
+    }
+}
+
+impl<V: Variable + fmt::Display> fmt::Display for Target<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Direct(address) => write!(f, "${:04X}", address),
+            Target::Indirect(expr) => write!(f, "[{}]", expr),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Stmt<V = Var> {
+    Assign(V, Expr<V>),
+    /// Assigns the high and low byte of a 16-bit value to a register pair.
+    AssignPair(V, V, Expr<V>),
+    Store(Expr<V>, Expr<V>),
+    Jump {
+        condition: Option<Expr<V>>,
+        target: Target<V>,
+    },
+    Call {
+        condition: Option<Expr<V>>,
+        target: Target<V>,
+    },
+    Return {
+        condition: Option<Expr<V>>,
+    },
+    Halt,
+    Stop,
+}
+
+impl<V: Copy> Stmt<V> {
+    pub fn defined_vars(&self) -> Vec<V> {
+        match *self {
+            Stmt::Assign(var, _) => vec![var],
+            Stmt::AssignPair(high, low, _) => vec![high, low],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn value(&self) -> Option<&Expr<V>> {
+        match self {
+            Stmt::Assign(_, value) | Stmt::AssignPair(_, _, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn condition(&self) -> Option<&Expr<V>> {
+        match self {
+            Stmt::Jump { condition, .. }
+            | Stmt::Call { condition, .. }
+            | Stmt::Return { condition } => condition.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn condition_mut(&mut self) -> Option<&mut Expr<V>> {
+        match self {
+            Stmt::Jump { condition, .. }
+            | Stmt::Call { condition, .. }
+            | Stmt::Return { condition } => condition.as_mut(),
+            _ => None,
+        }
+    }
+
+    pub fn visit_exprs<F: FnMut(&Expr<V>)>(&self, mut func: F) {
+        match self {
+            Stmt::Assign(_, value) | Stmt::AssignPair(_, _, value) => func(value),
+            Stmt::Store(address, value) => {
+                func(address);
+                func(value);
+            }
+            Stmt::Jump { condition, target } | Stmt::Call { condition, target } => {
+                if let Some(condition) = condition {
+                    func(condition);
+                }
+                if let Target::Indirect(expr) = target {
+                    func(expr);
+                }
+            }
+            Stmt::Return { condition } => {
+                if let Some(condition) = condition {
+                    func(condition);
+                }
+            }
+            Stmt::Halt | Stmt::Stop => (),
+        }
+    }
+
+    pub fn visit_exprs_mut<F: FnMut(&mut Expr<V>)>(&mut self, mut func: F) {
+        match self {
+            Stmt::Assign(_, value) | Stmt::AssignPair(_, _, value) => func(value),
+            Stmt::Store(address, value) => {
+                func(address);
+                func(value);
+            }
+            Stmt::Jump { condition, target } | Stmt::Call { condition, target } => {
+                if let Some(condition) = condition {
+                    func(condition);
+                }
+                if let Target::Indirect(expr) = target {
+                    func(expr);
+                }
+            }
+            Stmt::Return { condition } => {
+                if let Some(condition) = condition {
+                    func(condition);
+                }
+            }
+            Stmt::Halt | Stmt::Stop => (),
+        }
+    }
+
+    /// The constant addresses the statement reads, writes or transfers
+    /// control to.
+    pub fn constant_addresses(&self) -> Vec<usize> {
+        let mut addresses = Vec::new();
+        self.visit_exprs(|expr| {
+            addresses.extend(
+                expr.constant_loads()
+                    .iter()
+                    .map(|&address| address as usize),
+            )
+        });
+        match self {
+            Stmt::Store(address, _) => {
+                addresses.extend(address.constant_value().map(|address| address as usize))
+            }
+            Stmt::Jump {
+                target: Target::Direct(target),
+                ..
+            }
+            | Stmt::Call {
+                target: Target::Direct(target),
+                ..
+            } => addresses.push(*target),
+            _ => (),
+        }
+        addresses
+    }
+
+    pub fn used_vars(&self) -> Vec<V> {
+        let mut vars = Vec::new();
+        self.visit_exprs(|expr| expr.visit_vars(&mut |var| vars.push(var)));
+        vars
+    }
+
+    /// Rewrites the statement with different variables; `use_var` is applied
+    /// to every read before `def_var` is applied to the assigned variable.
+    pub fn map_vars<W, U, D>(&self, mut use_var: U, mut def_var: D) -> Stmt<W>
+    where
+        U: FnMut(V) -> W,
+        D: FnMut(V) -> W,
+    {
+        let mut map = |expr: &Expr<V>| expr.map_vars(&mut use_var);
+
+        match self {
+            Stmt::Assign(var, value) => {
+                let value = map(value);
+                Stmt::Assign(def_var(*var), value)
+            }
+            Stmt::AssignPair(high, low, value) => {
+                let value = map(value);
+                let high = def_var(*high);
+                Stmt::AssignPair(high, def_var(*low), value)
+            }
+            Stmt::Store(address, value) => Stmt::Store(map(address), map(value)),
+            Stmt::Jump { condition, target } => Stmt::Jump {
+                condition: condition.as_ref().map(&mut map),
+                target: map_target(target, &mut map),
+            },
+            Stmt::Call { condition, target } => Stmt::Call {
+                condition: condition.as_ref().map(&mut map),
+                target: map_target(target, &mut map),
+            },
+            Stmt::Return { condition } => Stmt::Return {
+                condition: condition.as_ref().map(&mut map),
+            },
+            Stmt::Halt => Stmt::Halt,
+            Stmt::Stop => Stmt::Stop,
+        }
+    }
+
+    pub fn has_side_effects(&self) -> bool {
+        match self {
+            Stmt::Assign(_, value) | Stmt::AssignPair(_, _, value) => value.reads_memory(),
+            _ => true,
+        }
+    }
+}
+
+impl<V: Variable + fmt::Display> fmt::Display for Stmt<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stmt::Assign(var, value) => write!(f, "{} = {};", var, value),
+            Stmt::AssignPair(high, low, value) => match pair_name(high.base(), low.base()) {
+                Some(name) => write!(f, "{} = {};", name, value),
+                None => write!(f, "{}:{} = {};", high, low, value),
+            },
+            Stmt::Store(address, value) => write!(f, "mem[{}] = {};", address, value),
+            Stmt::Jump {
+                condition: Some(condition),
+                target,
+            } => write!(f, "if ({}) goto {};", condition, target),
+            Stmt::Jump {
+                condition: None,
+                target,
+            } => write!(f, "goto {};", target),
+            Stmt::Call {
+                condition: Some(condition),
+                target,
+            } => write!(f, "if ({}) call {};", condition, target),
+            Stmt::Call {
+                condition: None,
+                target,
+            } => write!(f, "call {};", target),
+            Stmt::Return {
+                condition: Some(condition),
+            } => write!(f, "if ({}) return;", condition),
+            Stmt::Return { condition: None } => write!(f, "return;"),
+            Stmt::Halt => write!(f, "halt();"),
+            Stmt::Stop => write!(f, "stop();"),
+        }
+    }
+}
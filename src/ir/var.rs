@@ -0,0 +1,138 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Register {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Register::A => write!(f, "a"),
+            Register::B => write!(f, "b"),
+            Register::C => write!(f, "c"),
+            Register::D => write!(f, "d"),
+            Register::E => write!(f, "e"),
+            Register::H => write!(f, "h"),
+            Register::L => write!(f, "l"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Flag {
+    Zero,
+    Subtract,
+    HalfCarry,
+    Carry,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 4] = [Flag::Zero, Flag::Subtract, Flag::HalfCarry, Flag::Carry];
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Flag::Zero => write!(f, "f.z"),
+            Flag::Subtract => write!(f, "f.n"),
+            Flag::HalfCarry => write!(f, "f.h"),
+            Flag::Carry => write!(f, "f.c"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Var {
+    Register(Register),
+    Flag(Flag),
+    StackPointer,
+    InterruptsEnabled,
+    Temp(u8),
+}
+
+impl Var {
+    /// Number of distinct variables, temporaries included.
+    pub const COUNT: usize = 16;
+
+    /// A dense index below `Var::COUNT`, for use in tables and bit sets.
+    pub fn index(&self) -> usize {
+        match *self {
+            Var::Register(register) => register as usize,
+            Var::Flag(flag) => 7 + flag as usize,
+            Var::StackPointer => 11,
+            Var::InterruptsEnabled => 12,
+            Var::Temp(temp) => 13 + temp as usize,
+        }
+    }
+
+    pub fn width(&self) -> u8 {
+        match *self {
+            Var::Register(_) => 8,
+            Var::Flag(_) | Var::InterruptsEnabled => 1,
+            Var::StackPointer | Var::Temp(_) => 16,
+        }
+    }
+}
+
+impl fmt::Display for Var {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Var::Register(register) => write!(f, "{}", register),
+            Var::Flag(flag) => write!(f, "{}", flag),
+            Var::StackPointer => write!(f, "sp"),
+            Var::InterruptsEnabled => write!(f, "ime"),
+            Var::Temp(index) => write!(f, "t{}", index),
+        }
+    }
+}
+
+pub fn pair_name(high: Var, low: Var) -> Option<&'static str> {
+    match (high, low) {
+        (Var::Register(Register::B), Var::Register(Register::C)) => Some("bc"),
+        (Var::Register(Register::D), Var::Register(Register::E)) => Some("de"),
+        (Var::Register(Register::H), Var::Register(Register::L)) => Some("hl"),
+        _ => None,
+    }
+}
+
+pub fn pair_partner(var: Var) -> Option<Var> {
+    match var {
+        Var::Register(Register::B) => Some(Var::Register(Register::C)),
+        Var::Register(Register::C) => Some(Var::Register(Register::B)),
+        Var::Register(Register::D) => Some(Var::Register(Register::E)),
+        Var::Register(Register::E) => Some(Var::Register(Register::D)),
+        Var::Register(Register::H) => Some(Var::Register(Register::L)),
+        Var::Register(Register::L) => Some(Var::Register(Register::H)),
+        _ => None,
+    }
+}
+
+pub fn is_pair_high(var: Var) -> bool {
+    matches!(
+        var,
+        Var::Register(Register::B) | Var::Register(Register::D) | Var::Register(Register::H)
+    )
+}
+
+/// Implemented by anything that stands for one of the machine variables, so
+/// passes can work on both plain and SSA-renamed IR.
+pub trait Variable: Copy + Eq {
+    fn base(&self) -> Var;
+
+    fn is_boolean(&self) -> bool {
+        self.base().width() == 1
+    }
+}
+
+impl Variable for Var {
+    fn base(&self) -> Var {
+        *self
+    }
+}
@@ -0,0 +1,428 @@
+use analyzer::AnalysisData;
+use data::{Data, Toolchain};
+use disassembly;
+use gb::*;
+use graphics;
+use ir::Var;
+use noreturn;
+use std::fmt;
+
+/// The version of the schema in the readme, which goes up whenever a field
+/// changes.
+pub const VERSION: i64 = 1;
+
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        )
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(value) => write!(f, "{}", value),
+            Json::String(value) => write_string(f, value),
+            Json::Array(items) if items.is_empty() => write!(f, "[]"),
+            Json::Array(items) => {
+                writeln!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    write!(f, "{:width$}", "", width = indent + 2)?;
+                    item.write(f, indent + 2)?;
+                    let separator = if index + 1 < items.len() { "," } else { "" };
+                    writeln!(f, "{}", separator)?;
+                }
+                write!(f, "{:width$}]", "", width = indent)
+            }
+            Json::Object(fields) if fields.is_empty() => write!(f, "{{}}"),
+            Json::Object(fields) => {
+                writeln!(f, "{{")?;
+                for (index, (name, value)) in fields.iter().enumerate() {
+                    write!(f, "{:width$}", "", width = indent + 2)?;
+                    write_string(f, name)?;
+                    write!(f, ": ")?;
+                    value.write(f, indent + 2)?;
+                    let separator = if index + 1 < fields.len() { "," } else { "" };
+                    writeln!(f, "{}", separator)?;
+                }
+                write!(f, "{:width$}}}", "", width = indent)
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Json {
+        Json::Number(value as i64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Json {
+        Json::Bool(value)
+    }
+}
+
+impl<'a> From<&'a str> for Json {
+    fn from(value: &'a str) -> Json {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Json {
+        Json::String(value)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Json {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(values: Vec<T>) -> Json {
+        Json::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+fn vars(vars: &[Var]) -> Json {
+    vars.iter()
+        .map(|var| var.to_string())
+        .collect::<Vec<_>>()
+        .into()
+}
+
+fn cartridge(cart: &Cartridge) -> Json {
+    Json::object(vec![
+        ("title", cart.title().into()),
+        (
+            "type",
+            cart.cartridge_type()
+                .map(|kind| format!("{:?}", kind))
+                .into(),
+        ),
+        ("rom_size", cart.rom_size().into()),
+        ("cgb", cart.supports_cgb().into()),
+        ("sgb", cart.supports_sgb().into()),
+        ("header_checksum", (cart.header_checksum() as usize).into()),
+        ("global_checksum", (cart.global_checksum() as usize).into()),
+    ])
+}
+
+fn blocks(cart: &Cartridge, data: &Data, address: usize) -> Json {
+    let chunks = disassembly::chunk(cart, data, address);
+
+    let mut blocks: Vec<_> = chunks.iter().collect();
+    blocks.sort_by_key(|chunk| chunk.address());
+
+    blocks
+        .iter()
+        .map(|chunk| {
+            let instructions = chunk.instructions();
+            let successors: Vec<usize> = chunks
+                .successors(chunk.index())
+                .iter()
+                .map(|&index| chunks.get(index).address())
+                .collect();
+
+            Json::object(vec![
+                ("address", chunk.address().into()),
+                ("end", instructions[instructions.len() - 1].address.into()),
+                (
+                    "size",
+                    instructions
+                        .iter()
+                        .map(|instruction| instruction.size())
+                        .sum::<usize>()
+                        .into(),
+                ),
+                ("successors", successors.into()),
+            ])
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+fn functions(cart: &Cartridge, data: &Data) -> Json {
+    let mut functions: Vec<_> = data.functions.iter().collect();
+    functions.sort_by_key(|&(&address, _)| address);
+
+    functions
+        .iter()
+        .map(|&(&address, function)| {
+            let mut call_sites: Vec<usize> = function.call_sites.iter().cloned().collect();
+            call_sites.sort();
+            let convention = &function.convention;
+
+            Json::object(vec![
+                ("address", address.into()),
+                ("name", function.name.clone().into()),
+                ("call_sites", call_sites.into()),
+                ("can_return", function.can_return.into()),
+                (
+                    "noreturn",
+                    noreturn::reason(cart, data, address)
+                        .map(|reason| reason.describe(data))
+                        .into(),
+                ),
+                ("inputs", vars(&convention.inputs)),
+                ("outputs", vars(&convention.outputs)),
+                ("clobbers", vars(&convention.clobbered)),
+                ("preserves", vars(&convention.preserved)),
+                ("blocks", blocks(cart, data, address)),
+            ])
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+// The instructions of every function, the pointer tables, tiles and text
+// found, then the data the user marked
+fn code_map(cart: &Cartridge, data: &Data) -> Json {
+    let mut instructions: Vec<Instruction> = data
+        .functions
+        .iter()
+        .flat_map(|(&address, _)| disassembly::collect_instructions(cart, data, address))
+        .collect();
+    instructions.sort_by_key(|instruction| instruction.address);
+
+    let mut ranges: Vec<(usize, usize, String)> = Vec::new();
+    for instruction in instructions {
+        let end = instruction.address + instruction.size() - 1;
+        match ranges.last_mut() {
+            Some((_, last, _)) if *last + 1 >= instruction.address => *last = end.max(*last),
+            _ => ranges.push((instruction.address, end, "code".to_string())),
+        }
+    }
+    for (&address, entries) in &data.pointers.tables {
+        let end = address + 2 * entries.len() - 1;
+        ranges.push((address, end, "pointer table".to_string()));
+    }
+    for tiles in graphics::tile_sets(data) {
+        let end = tiles.source + tiles.len - 1;
+        ranges.push((tiles.source, end, tiles.format.extension().to_string()));
+    }
+    for tilemap in graphics::tilemaps(data) {
+        let end = tilemap.source + tilemap.len - 1;
+        let kind = graphics::tilemap_extension(&tilemap).to_string();
+        ranges.push((tilemap.source, end, kind));
+    }
+    for palette in graphics::palette_tables(data) {
+        ranges.push((
+            palette.source,
+            palette.source + palette.len - 1,
+            "palette".to_string(),
+        ));
+    }
+    for compressed in &data.compressed {
+        let end = compressed.source + compressed.len - 1;
+        let kind = compressed.compression.extension().to_string();
+        ranges.push((compressed.source, end, kind));
+    }
+    for table in data.sound.tables.iter().filter(|table| table.size() > 0) {
+        let end = table.address + table.size() - 1;
+        ranges.push((table.address, end, table.kind.label().to_string()));
+    }
+    for string in data.strings.values() {
+        let end = string.address + string.size - 1;
+        ranges.push((string.address, end, "text".to_string()));
+    }
+    for range in &data.data_ranges {
+        ranges.push((range.start, range.end, range.kind.clone()));
+    }
+    ranges.sort();
+    ranges.dedup();
+
+    ranges
+        .into_iter()
+        .map(|(start, end, kind)| {
+            Json::object(vec![
+                ("start", start.into()),
+                ("end", end.into()),
+                ("kind", kind.into()),
+            ])
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+// Jumps the path tracer couldn't follow, along with those in functions
+fn unknown_jumps(cart: &Cartridge, data: &Data, paths: &AnalysisData) -> Json {
+    let mut jumps: Vec<usize> = data
+        .functions
+        .iter()
+        .flat_map(|(&address, _)| disassembly::collect_instructions(cart, data, address))
+        .filter(|instruction| instruction.is_indirect_jump())
+        .map(|instruction| instruction.address)
+        .chain(paths.unknown_jumps.iter().cloned())
+        .collect();
+    jumps.sort();
+    jumps.dedup();
+    jumps.into()
+}
+
+fn sound(data: &Data) -> Json {
+    let functions: Vec<Json> = data
+        .sound
+        .functions
+        .iter()
+        .map(|(&address, role)| {
+            Json::object(vec![
+                ("address", address.into()),
+                ("role", role.to_string().into()),
+            ])
+        })
+        .collect();
+
+    Json::object(vec![
+        (
+            "driver",
+            data.sound
+                .driver
+                .map_or(Json::Null, |driver| driver.name().to_string().into()),
+        ),
+        ("functions", functions.into()),
+    ])
+}
+
+fn toolchain(data: &Data) -> Json {
+    let fingerprint = &data.toolchain;
+    Json::object(vec![
+        ("name", fingerprint.toolchain.map(Toolchain::name).into()),
+        (
+            "version",
+            fingerprint
+                .version
+                .map(|version| version.to_string())
+                .into(),
+        ),
+        (
+            "sdcccall",
+            fingerprint
+                .sdcccall
+                .map(|sdcccall| sdcccall.to_string())
+                .into(),
+        ),
+        ("evidence", fingerprint.evidence.clone().into()),
+    ])
+}
+
+/// The whole analysis, with what the path tracer found and the errors it
+/// ran into.
+pub fn analysis(
+    cart: &Cartridge,
+    data: &Data,
+    paths: &AnalysisData,
+    errors: &[DecodeError],
+) -> Json {
+    // Along with calls into bytes that aren't instructions
+    let errors: Vec<Json> = errors
+        .iter()
+        .chain(data.decode_errors.values())
+        .map(|error| {
+            Json::object(vec![
+                ("address", error.address.into()),
+                ("opcode", (error.opcode as usize).into()),
+                ("message", error.to_string().into()),
+            ])
+        })
+        .collect();
+
+    Json::object(vec![
+        ("version", Json::Number(VERSION)),
+        ("cartridge", cartridge(cart)),
+        ("functions", functions(cart, data)),
+        ("unknown_jumps", unknown_jumps(cart, data, paths)),
+        ("code_map", code_map(cart, data)),
+        ("sound", sound(data)),
+        ("toolchain", toolchain(data)),
+        ("errors", errors.into()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+
+    fn field<'a>(json: &'a Json, name: &str) -> &'a Json {
+        match json {
+            Json::Object(fields) => &fields.iter().find(|(field, _)| field == name).unwrap().1,
+            _ => panic!("not an object"),
+        }
+    }
+
+    #[test]
+    fn writes_indented_and_escaped() {
+        let json = Json::object(vec![
+            ("text", "say \"hi\"\n".into()),
+            ("empty", Json::Array(Vec::new())),
+            ("missing", None::<usize>.into()),
+        ]);
+        assert_eq!(
+            json.to_string(),
+            "{\n  \"text\": \"say \\\"hi\\\"\\n\",\n  \"empty\": [],\n  \"missing\": null\n}"
+        );
+    }
+
+    #[test]
+    fn lists_functions_by_address() {
+        let cart = Cartridge::with_function(&[0xC9]);
+        let (paths, _) = analyzer::trace_static_paths(&cart, false);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        let json = analysis(&cart, &data, &paths, &[]);
+
+        assert!(matches!(field(&json, "version"), Json::Number(VERSION)));
+        let functions = match field(&json, "functions") {
+            Json::Array(functions) => functions,
+            _ => panic!("functions isn't an array"),
+        };
+        let addresses: Vec<i64> = functions
+            .iter()
+            .map(|function| match field(function, "address") {
+                Json::Number(address) => *address,
+                _ => panic!("address isn't a number"),
+            })
+            .collect();
+        assert_eq!(addresses, vec![0x100, 0x200]);
+        assert!(matches!(
+            field(&functions[1], "can_return"),
+            Json::Bool(true)
+        ));
+    }
+}
@@ -0,0 +1,439 @@
+use constants;
+use constants::KnownState;
+use data::*;
+use disassembly;
+use gb::*;
+use graphics;
+use ir::{lift, Var};
+use noreturn;
+use stack;
+use stack::StackDepth;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Write;
+
+const COMMENT_COLUMN: usize = 24;
+
+/// Data without a known end is only shown this far.
+const DATA_PREVIEW: usize = 64;
+const BYTES_PER_LINE: usize = 8;
+
+/// What each pointer of a hUGEDriver song points to.
+const HUGE_POINTERS: [&str; 9] = [
+    "channel 1",
+    "channel 2",
+    "channel 3",
+    "channel 4",
+    "duty instruments",
+    "wave instruments",
+    "noise instruments",
+    "routines",
+    "waves",
+];
+
+pub fn var_list(vars: &[Var]) -> String {
+    let names: Vec<String> = vars.iter().map(|var| var.to_string()).collect();
+    if names.is_empty() {
+        "-".to_string()
+    } else {
+        names.join(" ")
+    }
+}
+
+// The names of the functions an instruction hands control to, the data it
+// points at and the variables it reads and writes
+fn reference_names(
+    cart: &Cartridge,
+    data: &Data,
+    state: Option<&KnownState>,
+    instruction: &Instruction,
+) -> Vec<String> {
+    let targets = instruction
+        .jump_target()
+        .into_iter()
+        .chain(instruction.call_target())
+        .filter_map(|address| match data.functions.get(address) {
+            Some(function) => Some(function.name.clone()),
+            None => data.symbols.get(address).map(str::to_string),
+        });
+    let pointers = lift(instruction)
+        .iter()
+        .flat_map(|stmt| stmt.constant_addresses())
+        .filter(|address| data.pointers.labels.contains(address))
+        .map(|address| data.label(address))
+        .collect::<Vec<_>>();
+
+    let state = state.cloned().unwrap_or_else(KnownState::unknown);
+    let variables = constants::accesses(cart, data, state, instruction)
+        .into_iter()
+        .filter_map(|(_, address)| data.variable_name(address));
+
+    let mut names: Vec<String> = Vec::new();
+    for name in targets.chain(pointers).chain(variables) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+pub fn listing(cart: &Cartridge, data: &Data, address: usize) -> String {
+    let chunks = disassembly::chunk(cart, data, address);
+    let known_values = constants::analyse(cart, data, &chunks);
+    let stack = stack::analyse(&chunks);
+
+    let mut output = String::new();
+
+    match data.functions.get(address) {
+        Some(function) => {
+            let convention = &function.convention;
+            writeln!(output, "; inputs:    {}", var_list(&convention.inputs)).unwrap();
+            writeln!(output, "; outputs:   {}", var_list(&convention.outputs)).unwrap();
+            writeln!(output, "; clobbers:  {}", var_list(&convention.clobbered)).unwrap();
+            writeln!(output, "; preserves: {}", var_list(&convention.preserved)).unwrap();
+            if let Some(copy) = data.ram_copy_at(address) {
+                let source = copy.source + address - copy.destination;
+                writeln!(output, "; copied from ${:04X}", source).unwrap();
+            }
+            if let Some(reason) = noreturn::reason(cart, data, address) {
+                writeln!(output, "; noreturn:  {}", reason.describe(data)).unwrap();
+            }
+            writeln!(output, "{}:", function.name).unwrap();
+        }
+        None => writeln!(output, "{}:", data.name(address)).unwrap(),
+    }
+
+    for instruction in chunks.instructions() {
+        if instruction.address != address {
+            if let Some(name) = data.symbols.get(instruction.address) {
+                writeln!(output, "{}:", name).unwrap();
+            }
+        }
+        if let Some(comment) = data.comments.get(&instruction.address) {
+            for line in comment.lines() {
+                writeln!(output, "    ; {}", line).unwrap();
+            }
+        }
+
+        let text = format!("{:04X}: {}", instruction.address, instruction.op_code);
+        let mut comment = known_values
+            .at(instruction.address)
+            .map(|state| state.to_string())
+            .unwrap_or_default();
+
+        match stack.depth_at(instruction.address) {
+            Some(StackDepth::Known(0)) | None => (),
+            Some(depth) => {
+                let separator = if comment.is_empty() { "" } else { " " };
+                comment = format!("stack={}{}{}", depth, separator, comment);
+            }
+        }
+
+        let names = reference_names(
+            cart,
+            data,
+            known_values.at(instruction.address),
+            instruction,
+        );
+        if !names.is_empty() {
+            let separator = if comment.is_empty() { "" } else { " " };
+            comment = format!("{}{}{}", names.join(", "), separator, comment);
+        }
+
+        if comment.is_empty() {
+            writeln!(output, "    {}", text).unwrap();
+        } else {
+            writeln!(
+                output,
+                "    {:width$} ; {}",
+                text,
+                comment,
+                width = COMMENT_COLUMN
+            )
+            .unwrap();
+        }
+    }
+
+    output
+}
+
+// Where the data at `address` ends, if nothing says: at code, other data or
+// the end of the bank
+fn data_end(
+    cart: &Cartridge,
+    data: &Data,
+    code: &HashSet<usize>,
+    starts: &BTreeSet<usize>,
+    address: usize,
+) -> usize {
+    let bank_end = if address < BANKED_ROM_START {
+        BANKED_ROM_START
+    } else {
+        ROM_END
+    };
+    let next_label = starts.range(address + 1..).next();
+    let limit = next_label
+        .map_or(bank_end, |&label| label.min(bank_end))
+        .min(cart.rom_size());
+
+    (address..limit)
+        .find(|address| code.contains(address) || data.is_data(*address))
+        .unwrap_or(limit)
+}
+
+// The lines of a song or order table, naming what it points to
+fn sound_table(cart: &Cartridge, data: &Data, table: &SoundTable) -> String {
+    let mut lines: Vec<(usize, String, &str)> = Vec::new();
+    let mut address = table.address;
+    if table.kind == SoundTableKind::HugeSong {
+        lines.push((address, format!("db ${:02X}", cart[address]), "tempo"));
+        let order_size = cart[address + 1] as usize | (cart[address + 2] as usize) << 8;
+        lines.push((
+            address + 1,
+            format!("dw ${:04X}", order_size),
+            "order table size",
+        ));
+        address += 3;
+    }
+    for (index, &pointer) in table.pointers.iter().enumerate() {
+        let comment = match table.kind {
+            SoundTableKind::HugeSong => HUGE_POINTERS[index],
+            _ => "",
+        };
+        lines.push((
+            address + 2 * index,
+            format!("dw {}", data.label(pointer)),
+            comment,
+        ));
+    }
+    if table.kind == SoundTableKind::GbtSong {
+        let end = address + 2 * table.pointers.len();
+        lines.push((end, "dw $0000".to_string(), "end"));
+    }
+
+    let mut output = String::new();
+    for (address, text, comment) in lines {
+        let text = format!("{:04X}: {}", address, text);
+        if comment.is_empty() {
+            writeln!(output, "    {}", text).unwrap();
+        } else {
+            writeln!(
+                output,
+                "    {:width$} ; {}",
+                text,
+                comment,
+                width = COMMENT_COLUMN
+            )
+            .unwrap();
+        }
+    }
+    output
+}
+
+/// The data found through pointers, the strings and the tiles found, with
+/// pointer tables as `dw` lines naming what they point at and tiles included
+/// from the files `graphics` writes.
+pub fn data_listing(cart: &Cartridge, data: &Data) -> String {
+    let code: HashSet<usize> = data
+        .functions
+        .iter()
+        .flat_map(|(&address, _)| disassembly::collect_instructions(cart, data, address))
+        .flat_map(|instruction| instruction.address..instruction.address + instruction.size())
+        .collect();
+
+    let tile_sets = graphics::tile_sets(data);
+    let tilemaps = graphics::tilemaps(data);
+    let palettes = graphics::palette_tables(data);
+    let starts: BTreeSet<usize> = data
+        .pointers
+        .labels
+        .iter()
+        .chain(data.strings.keys())
+        .cloned()
+        .chain(tile_sets.iter().map(|tiles| tiles.source))
+        .chain(tilemaps.iter().map(|tilemap| tilemap.source))
+        .chain(palettes.iter().map(|palette| palette.source))
+        .chain(data.compressed.iter().map(|compressed| compressed.source))
+        .chain(data.sound.tables.iter().map(|table| table.address))
+        .collect();
+
+    let mut output = String::new();
+    for &address in &starts {
+        if let Some(tiles) = tile_sets.iter().find(|tiles| tiles.source == address) {
+            let text = format!(
+                "{:04X}: INCBIN \"{}.{}\"",
+                address,
+                data.label(address),
+                tiles.format.extension()
+            );
+            writeln!(output, "{}:", data.label(address)).unwrap();
+            writeln!(
+                output,
+                "    {:width$} ; {} tiles, copied to ${:04X}",
+                text,
+                tiles.tile_count(),
+                tiles.destination,
+                width = COMMENT_COLUMN
+            )
+            .unwrap();
+            writeln!(output).unwrap();
+            continue;
+        }
+        if let Some(tilemap) = tilemaps.iter().find(|tilemap| tilemap.source == address) {
+            let text = format!(
+                "{:04X}: INCBIN \"{}.{}\"",
+                address,
+                data.label(address),
+                graphics::tilemap_extension(tilemap)
+            );
+            writeln!(output, "{}:", data.label(address)).unwrap();
+            writeln!(
+                output,
+                "    {:width$} ; copied to ${:04X}",
+                text,
+                tilemap.destination,
+                width = COMMENT_COLUMN
+            )
+            .unwrap();
+            writeln!(output).unwrap();
+            continue;
+        }
+        if let Some(palette) = palettes.iter().find(|palette| palette.source == address) {
+            writeln!(output, "{}:", data.label(address)).unwrap();
+            for start in (address..address + palette.len).step_by(8) {
+                let colours: Vec<String> = (start..(start + 8).min(address + palette.len))
+                    .step_by(2)
+                    .map(|address| {
+                        format!(
+                            "${:04X}",
+                            cart[address] as u16 | (cart[address + 1] as u16) << 8
+                        )
+                    })
+                    .collect();
+                let text = format!("{:04X}: dw {}", start, colours.join(","));
+                let number = (palette.index + start - address) / 8;
+                let kind = if palette.objects { "obj" } else { "bg" };
+                writeln!(
+                    output,
+                    "    {:width$} ; {} {}",
+                    text,
+                    kind,
+                    number,
+                    width = COMMENT_COLUMN
+                )
+                .unwrap();
+            }
+            writeln!(output).unwrap();
+            continue;
+        }
+        if let Some(compressed) = data
+            .compressed
+            .iter()
+            .find(|compressed| compressed.source == address)
+        {
+            let text = format!(
+                "{:04X}: INCBIN \"{}.{}\"",
+                address,
+                data.label(address),
+                compressed.compression.extension()
+            );
+            let destination = compressed
+                .destination
+                .map_or_else(String::new, |destination| {
+                    format!(" to ${:04X}", destination)
+                });
+            writeln!(output, "{}:", data.label(address)).unwrap();
+            writeln!(
+                output,
+                "    {:width$} ; {} bytes, {} bytes decompressed{}",
+                text,
+                compressed.len,
+                compressed.size,
+                destination,
+                width = COMMENT_COLUMN
+            )
+            .unwrap();
+            writeln!(output).unwrap();
+            continue;
+        }
+        // Pointers into graphics and compressed data are shown with them
+        if data.is_graphics(address) || data.compressed_at(address).is_some() {
+            continue;
+        }
+        if let Some(string) = data.strings.get(&address) {
+            writeln!(output, "{}:", data.label(address)).unwrap();
+            writeln!(output, "    {:04X}: db {}", address, string.to_db()).unwrap();
+            writeln!(output).unwrap();
+            continue;
+        }
+        if let Some(table) = data.sound.table(address).filter(|table| table.size() > 0) {
+            writeln!(output, "{}:", data.label(address)).unwrap();
+            output.push_str(&sound_table(cart, data, table));
+            writeln!(output).unwrap();
+            continue;
+        }
+        if let Some(entries) = data.pointers.tables.get(&address) {
+            writeln!(output, "{}:", data.label(address)).unwrap();
+            for (index, &entry) in entries.iter().enumerate() {
+                writeln!(
+                    output,
+                    "    {:04X}: dw {}",
+                    address + 2 * index,
+                    data.label(entry)
+                )
+                .unwrap();
+            }
+            writeln!(output).unwrap();
+            continue;
+        }
+        // Pointers into the middle of a table are shown with it
+        if data.pointers.table_at(address).is_some() {
+            continue;
+        }
+
+        writeln!(output, "{}:", data.label(address)).unwrap();
+        let end = data_end(cart, data, &code, &starts, address);
+        let shown = end.min(address + DATA_PREVIEW);
+        for start in (address..shown).step_by(BYTES_PER_LINE) {
+            let bytes: Vec<String> = (start..shown.min(start + BYTES_PER_LINE))
+                .map(|address| format!("${:02X}", cart[address]))
+                .collect();
+            writeln!(output, "    {:04X}: db {}", start, bytes.join(",")).unwrap();
+        }
+        if end > shown {
+            writeln!(output, "    ; {} more bytes", end - shown).unwrap();
+        }
+        writeln!(output).unwrap();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+
+    #[test]
+    fn lists_pointer_tables_by_name() {
+        // ld hl,$0300 / ret, with dw $0310,$0320 at $0300
+        let cart = Cartridge::with_code(&[
+            (0x100, &[0xCD, 0x00, 0x02, 0x18, 0xFE]),
+            (0x200, &[0x21, 0x00, 0x03, 0xC9]),
+            (0x300, &[0x10, 0x03, 0x20, 0x03]),
+            (0x310, &[0x01, 0x02, 0x03]),
+            (0x320, &[0xAA]),
+        ]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+
+        let output = data_listing(&cart, &data);
+        assert!(
+            output.starts_with(
+                "table_0300:\n    0300: dw data_0310\n    0302: dw data_0320\n\n\
+                 data_0310:\n    0310: db $01,$02,$03,$00,$00,$00,$00,$00\n"
+            ),
+            "{}",
+            output
+        );
+        // Data without a known end is cut short
+        assert!(output.contains("    ; 15520 more bytes\n"), "{}", output);
+    }
+}
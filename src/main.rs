@@ -1,13 +1,47 @@
 mod analyzer;
+mod compression;
+mod constants;
+mod convention;
+mod copies;
 mod data;
+mod decompiler;
 mod disassembly;
+mod emulator;
+mod frame;
 mod gb;
+mod graphics;
+mod html;
+mod import;
 mod instruction_walker;
+mod ir;
+mod json;
+mod listing;
+mod noreturn;
+mod pairs;
+mod parse;
+mod png;
+mod pointers;
+mod project;
+mod signatures;
+mod sound;
+mod ssa;
+mod stack;
+mod symbols;
+mod text;
+mod toolchain;
+mod trace;
+mod xrefs;
 
+use emulator::{Emulator, InputScript};
 use gb::Cartridge;
+use project::Project;
 use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use trace::Trace;
+use xrefs::Xrefs;
 
 fn get_filename() -> String {
     let mut args = env::args();
@@ -21,11 +55,195 @@ fn load_rom(filename: &str) -> Cartridge {
     Cartridge::new(data)
 }
 
+// Options can follow the ROM directly when there is no command
+fn get_command() -> Option<String> {
+    let mut args = env::args();
+    args.nth(2).filter(|arg| !arg.starts_with("--"))
+}
+
+// The values given to an option, like `--noreturn $0038`, in order
+fn option_values(name: &str) -> Vec<String> {
+    let args: Vec<String> = env::args().collect();
+    args.windows(2)
+        .filter(|pair| pair[0] == name)
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+fn parse_address(text: &str) -> usize {
+    let digits = text
+        .trim_start_matches('$')
+        .trim_start_matches("0x")
+        .trim_start_matches("0X");
+    usize::from_str_radix(digits, 16).expect("invalid address")
+}
+
+fn emulate(rom: &Cartridge, frames: u32) -> Trace {
+    let input = match option_values("--input").last() {
+        Some(path) => read_text(path, "input script", InputScript::parse),
+        None => InputScript::default(),
+    };
+
+    let mut emulator = Emulator::new(rom);
+    if let Err(error) = emulator.run(frames, &input) {
+        println!("Emulation stopped: {}", error);
+    }
+    emulator.trace
+}
+
+fn frames() -> Option<u32> {
+    option_values("--frames")
+        .last()
+        .map(|frames| frames.parse().expect("invalid frame count"))
+}
+
+fn import_trace(rom: &Cartridge, path: &str) -> Trace {
+    let mut bytes = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .expect("error reading trace");
+    import::import(rom, &bytes).unwrap_or_else(|error| panic!("{}: {}", path, error))
+}
+
+// Reads a text file named on the command line and parses it, stopping with
+// the file and line of any error
+fn read_text<P, T, E>(path: P, what: &str, parse: fn(&str) -> Result<T, E>) -> T
+where
+    P: AsRef<Path>,
+    E: fmt::Display,
+{
+    let path = path.as_ref();
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .unwrap_or_else(|_| panic!("error reading {}", what));
+    parse(&text).unwrap_or_else(|error| panic!("{}: {}", path.display(), error))
+}
+
+// `--charmap` and `--terminator`, or ASCII ending in a zero byte
+fn encoding() -> text::Encoding {
+    let mut encoding = text::Encoding::default();
+    if let Some(path) = option_values("--charmap").last() {
+        encoding.charmap = read_text(path, "charmap", text::Charmap::parse);
+    }
+    if let Some(terminator) = option_values("--terminator").last() {
+        let terminator = parse_address(terminator);
+        assert!(terminator <= 0xFF, "invalid terminator");
+        encoding.terminator = terminator as u8;
+    }
+    encoding
+}
+
+// `--project`, or the ROM with a `.toml` extension
+fn project_path() -> PathBuf {
+    match option_values("--project").last() {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(&get_filename()).with_extension("toml"),
+    }
+}
+
+fn load_project(rom: &Cartridge) -> Option<Project> {
+    let path = project_path();
+    if !path.exists() {
+        return None;
+    }
+
+    let project = read_text(&path, "project", Project::parse);
+
+    match project.checksum {
+        Some(checksum) if checksum != rom.global_checksum() => panic!(
+            "{}: made for a ROM with checksum ${:04X}, not ${:04X}",
+            path.display(),
+            checksum,
+            rom.global_checksum()
+        ),
+        _ => Some(project),
+    }
+}
+
+fn save_project(project: &Project) {
+    let path = project_path();
+    File::create(&path)
+        .and_then(|mut file| file.write_all(project.to_string().as_bytes()))
+        .expect("error writing project");
+    println!("Saved {}", path.display());
+}
+
+// Functions marked with `--noreturn`, names from `--symbols` files, library
+// functions from `--signatures` files, and what the project file knows
+fn static_data(rom: &Cartridge) -> data::Data {
+    let mut data = data::Data::new();
+    data.noreturn_overrides.extend(
+        option_values("--noreturn")
+            .iter()
+            .map(|text| parse_address(text)),
+    );
+    for path in option_values("--symbols") {
+        data.symbols
+            .merge(read_text(&path, "symbols", symbols::Symbols::parse));
+    }
+    for path in option_values("--signatures") {
+        data.signatures.merge(read_text(
+            &path,
+            "signatures",
+            signatures::Signatures::parse,
+        ));
+    }
+    if let Some(project) = load_project(rom) {
+        project.apply(rom, &mut data);
+    }
+    data
+}
+
+// Along with what was seen running the ROM for `--frames` frames, and in the
+// traces given with `--trace`
+fn analyse_functions(rom: &Cartridge) -> data::Data {
+    let mut data = static_data(rom);
+    if let Some(frames) = frames() {
+        data.trace = emulate(rom, frames);
+    }
+    for path in option_values("--trace") {
+        data.trace.merge(import_trace(rom, &path));
+    }
+    let mut data = analyzer::analyse_functions(rom, data);
+    data.strings = text::find_strings(rom, &data, &encoding());
+    data
+}
+
 fn main() {
     let filename = get_filename();
     let rom = load_rom(&filename);
 
-    match analyzer::analyse(&rom) {
+    match get_command().as_deref() {
+        None => match option_values("--format").last().map(String::as_str) {
+            Some("json") => print_json(&rom),
+            Some("text") | None => trace(&rom),
+            Some(format) => println!("Unknown format: {}", format),
+        },
+        Some("decompile") => decompile(&rom),
+        Some("disassemble") => disassemble(&rom),
+        Some("stack") => check_stack(&rom),
+        Some("run") => run(&rom),
+        Some("import") => import(&rom),
+        Some("symbols") => export_symbols(&rom),
+        Some("report") => report_html(&rom),
+        Some("project") => create_project(&rom),
+        Some("rename") => rename(&rom),
+        Some("comment") => comment(&rom),
+        Some("xrefs") => print_xrefs(&rom),
+        Some("strings") => print!("{}", text::csv(&analyse_functions(&rom))),
+        Some("charmap") => guess_charmap(&rom),
+        Some("graphics") => export_graphics(&rom),
+        Some("decompress") => export_compressed(&rom),
+        Some("sound") => print!("{}", sound::report(&analyse_functions(&rom))),
+        Some("signatures") => generate_signatures(&rom),
+        Some("toolchain") => print!("{}", toolchain::report(&analyse_functions(&rom))),
+        Some(command) => println!("Unknown command: {}", command),
+    }
+}
+
+fn trace(rom: &Cartridge) {
+    match analyzer::analyse(rom) {
         Ok(data) => {
             println!("ROM Analysis successful");
             log_data(&data);
@@ -34,6 +252,228 @@ fn main() {
     }
 }
 
+fn print_json(rom: &Cartridge) {
+    let (paths, result) = analyzer::trace_static_paths(rom, false);
+    let errors: Vec<_> = result.err().into_iter().collect();
+    let data = analyse_functions(rom);
+    println!("{}", json::analysis(rom, &data, &paths, &errors));
+}
+
+fn decompile(rom: &Cartridge) {
+    let data = analyse_functions(rom);
+
+    let mut addresses: Vec<usize> = data.functions.iter().map(|(address, _)| *address).collect();
+    addresses.sort();
+
+    for address in addresses {
+        println!("{}", decompiler::decompile(rom, &data, address));
+    }
+}
+
+fn disassemble(rom: &Cartridge) {
+    let data = analyse_functions(rom);
+
+    let mut addresses: Vec<usize> = data.functions.iter().map(|(address, _)| *address).collect();
+    addresses.sort();
+
+    for address in addresses {
+        println!("{}", listing::listing(rom, &data, address));
+    }
+    print!("{}", listing::data_listing(rom, &data));
+}
+
+fn check_stack(rom: &Cartridge) {
+    let data = analyse_functions(rom);
+
+    let mut addresses: Vec<usize> = data.functions.iter().map(|(address, _)| *address).collect();
+    addresses.sort();
+
+    for address in addresses {
+        let chunks = disassembly::chunk(rom, &data, address);
+        let analysis = stack::analyse(&chunks);
+
+        for diagnostic in &analysis.diagnostics {
+            println!(
+                "{}: {}",
+                data.functions.get(address).unwrap().name,
+                diagnostic
+            );
+        }
+    }
+}
+
+fn create_project(rom: &Cartridge) {
+    save_project(&load_project(rom).unwrap_or_else(|| Project::new(rom)));
+}
+
+fn rename(rom: &Cartridge) {
+    let args: Vec<String> = env::args().collect();
+    let (address, name) = match &args[3..] {
+        [address, name, ..] => (parse_address(address), name.clone()),
+        _ => panic!("Address and name are required"),
+    };
+
+    let mut project = load_project(rom).unwrap_or_else(|| Project::new(rom));
+    project.names.insert(address, name);
+    save_project(&project);
+}
+
+fn comment(rom: &Cartridge) {
+    let args: Vec<String> = env::args().collect();
+    let address = parse_address(args.get(3).expect("Address is required"));
+    let text = args[4..].join(" ");
+
+    let mut project = load_project(rom).unwrap_or_else(|| Project::new(rom));
+    if text.is_empty() {
+        project.comments.remove(&address);
+    } else {
+        project.comments.insert(address, text);
+    }
+    save_project(&project);
+}
+
+fn report_html(rom: &Cartridge) {
+    let directory = option_values("--html")
+        .pop()
+        .expect("Output directory is required (--html <dir>)");
+    let data = analyse_functions(rom);
+    let pages =
+        html::write_report(rom, &data, Path::new(&directory)).expect("error writing report");
+    println!("Wrote {} pages to {}", pages, directory);
+}
+
+fn export_graphics(rom: &Cartridge) {
+    let directory = env::args().nth(3).expect("Output directory is required");
+    let data = analyse_functions(rom);
+    let files = graphics::write_graphics(rom, &data, Path::new(&directory))
+        .expect("error writing graphics");
+    println!("Wrote {} files to {}", files, directory);
+}
+
+fn export_compressed(rom: &Cartridge) {
+    let directory = env::args().nth(3).expect("Output directory is required");
+    let data = analyse_functions(rom);
+    let blocks = compression::write_compressed(rom, &data, Path::new(&directory))
+        .expect("error writing compressed data");
+    println!("Wrote {} compressed blocks to {}", blocks, directory);
+}
+
+fn export_symbols(rom: &Cartridge) {
+    let data = analyse_functions(rom);
+    let variables = Xrefs::collect(rom, &data).addresses();
+    print!("{}", symbols::export(&data, &variables));
+}
+
+// Named by the symbol file given, which the ROM was built with
+fn generate_signatures(rom: &Cartridge) {
+    let path = env::args().nth(3).expect("Symbol file is required");
+    let mut data = static_data(rom);
+    data.symbols
+        .merge(read_text(&path, "symbols", symbols::Symbols::parse));
+    let data = analyzer::analyse_functions(rom, data);
+    print!("{}", signatures::Signatures::generate(rom, &data));
+}
+
+fn guess_charmap(rom: &Cartridge) {
+    let phrase = env::args().skip(3).collect::<Vec<_>>().join(" ");
+    assert!(!phrase.is_empty(), "Phrase is required");
+    let guesses = text::Charmap::guess(rom, &phrase);
+    if guesses.is_empty() {
+        println!("; \"{}\" not found", phrase);
+    }
+    for (address, charmap) in guesses {
+        println!("; \"{}\" at ${:04X}", phrase, address);
+        println!("{}", charmap);
+    }
+}
+
+fn print_xrefs(rom: &Cartridge) {
+    let address = parse_address(&env::args().nth(3).expect("Address is required"));
+    let data = analyse_functions(rom);
+    let xrefs = Xrefs::collect(rom, &data);
+
+    match data.variable_name(address) {
+        Some(name) => println!("{:04X} {}", address, name),
+        None => println!("{:04X}", address),
+    }
+    for xref in xrefs.to(address) {
+        println!(
+            "    {:5} {:04X} {:24} {}",
+            xref.access,
+            xref.instruction.address,
+            data.name(xref.function),
+            xref.instruction.op_code
+        );
+    }
+}
+
+// Runs the ROM, by default for a second, and reports what static analysis
+// couldn't find on its own
+fn run(rom: &Cartridge) {
+    report(rom, &emulate(rom, frames().unwrap_or(60)));
+}
+
+// Reads a trace recorded by another emulator and reports the same
+fn import(rom: &Cartridge) {
+    let path = env::args().nth(3).expect("Trace file is required");
+    report(rom, &import_trace(rom, &path));
+}
+
+fn print_ranges(title: &str, ranges: &[(trace::BankAddress, usize)]) {
+    if !ranges.is_empty() {
+        println!("{}:", title);
+        for &(start, end) in ranges {
+            println!("    {}-{}", start, trace::BankAddress::new(start.bank, end));
+        }
+    }
+}
+
+fn report(rom: &Cartridge, trace: &Trace) {
+    println!("Executed {} instruction addresses", trace.executed.len());
+
+    let mut jumps: Vec<_> = trace.indirect_jumps.iter().collect();
+    jumps.sort_by_key(|(jump, _)| **jump);
+    if !jumps.is_empty() {
+        println!("Indirect jumps:");
+        for (jump, targets) in jumps {
+            let mut targets: Vec<_> = targets.iter().map(|target| target.to_string()).collect();
+            targets.sort();
+            println!("    {} -> {}", jump, targets.join(", "));
+        }
+    }
+
+    let mut switches: Vec<_> = trace.bank_switches.iter().collect();
+    switches.sort_by_key(|(switch, _)| **switch);
+    if !switches.is_empty() {
+        println!("Bank switches:");
+        for (switch, banks) in switches {
+            let mut banks: Vec<_> = banks.iter().cloned().collect();
+            banks.sort();
+            let banks: Vec<_> = banks.iter().map(|bank| format!("{:02X}", bank)).collect();
+            println!("    {} -> {}", switch, banks.join(", "));
+        }
+    }
+
+    let data = analyzer::analyse_functions(rom, static_data(rom));
+    let covered = trace::covered(rom, &data);
+    print_ranges("Not found statically", &trace.uncovered(&covered));
+
+    let mut misread: Vec<usize> = covered
+        .iter()
+        .cloned()
+        .filter(|&address| trace.is_data(address))
+        .collect();
+    misread.sort();
+    let mut ranges: Vec<(trace::BankAddress, usize)> = Vec::new();
+    for address in misread {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 3 >= address => *end = address,
+            _ => ranges.push((trace::BankAddress::from_flat(address), address)),
+        }
+    }
+    print_ranges("Decoded as code but only read as data", &ranges);
+}
+
 fn log_data(data: &analyzer::AnalysisData) -> () {
     if !data.unknown_jumps.is_empty() {
         println!("Unknown jumps:");
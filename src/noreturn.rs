@@ -0,0 +1,146 @@
+use analyzer::RomAnalyzer;
+use data::Data;
+use disassembly;
+use disassembly::ChunkSet;
+use gb::*;
+use stack;
+
+/// Jumping or calling here restarts the game.
+const RESET_ADDRESSES: [usize; 2] = [0x0000, Cartridge::ENTRY_POINT];
+
+/// Why a function never returns to its caller.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoReturn {
+    Override,
+    Reset,
+    Calls(usize),
+    HaltLoop,
+    InfiniteLoop,
+}
+
+impl NoReturn {
+    pub fn describe(&self, data: &Data) -> String {
+        match self {
+            NoReturn::Override => "marked as not returning".to_string(),
+            NoReturn::Reset => "jumps to reset".to_string(),
+            NoReturn::Calls(address) => format!("calls {}", data.name(*address)),
+            NoReturn::HaltLoop => "halt loop".to_string(),
+            NoReturn::InfiniteLoop => "infinite loop".to_string(),
+        }
+    }
+}
+
+// Returns, and jumps to targets that can't be followed, might get back to the
+// caller; so might a tail call to a function that can return. A `ret` after
+// `push rr` jumps to the pushed address instead of returning.
+fn can_return(data: &Data, chunks: &ChunkSet) -> bool {
+    let stack = stack::analyse(chunks);
+    let exits = chunks.instructions().iter().any(|instruction| {
+        (instruction.is_return() && stack.computed_jump(instruction.address).is_none())
+            || instruction.is_indirect_jump()
+    });
+
+    exits
+        || chunks.tail_call_targets().into_iter().any(|target| {
+            data.functions
+                .get(target)
+                .is_some_and(|function| function.can_return)
+        })
+}
+
+/// Works out why the function at `address` can't return, or `None` if it can.
+pub fn reason(cart: &Cartridge, data: &Data, address: usize) -> Option<NoReturn> {
+    match data.functions.get(address) {
+        Some(function) if !function.can_return => (),
+        _ => return None,
+    }
+
+    if data.noreturn_overrides.contains(&address) {
+        return Some(NoReturn::Override);
+    }
+
+    let chunks = disassembly::chunk(cart, data, address);
+    let instructions = chunks.instructions();
+
+    let targets: Vec<usize> = instructions
+        .iter()
+        .filter_map(|instruction| instruction.call_target().or(instruction.jump_target()))
+        .collect();
+
+    if targets
+        .iter()
+        .any(|target| *target != address && RESET_ADDRESSES.contains(target))
+    {
+        return Some(NoReturn::Reset);
+    }
+
+    // Calls that end a chunk never came back
+    let callee = instructions
+        .iter()
+        .filter(|instruction| !instruction.is_conditional())
+        .filter_map(|instruction| instruction.call_target())
+        .chain(chunks.tail_call_targets())
+        .find(|target| {
+            data.functions
+                .get(*target)
+                .is_some_and(|function| !function.can_return)
+        });
+
+    if let Some(callee) = callee {
+        Some(NoReturn::Calls(callee))
+    } else if instructions
+        .iter()
+        .any(|instruction| matches!(instruction.op_code, OpCode::HALT))
+    {
+        Some(NoReturn::HaltLoop)
+    } else {
+        Some(NoReturn::InfiniteLoop)
+    }
+}
+
+/// Marks functions that can get back to their caller. Every function starts
+/// out as not returning, and the code after calls to it is left out until it
+/// is shown to return, so this is repeated until it settles. Overridden
+/// functions never return.
+pub struct NoReturnAnalyzer;
+
+impl RomAnalyzer for NoReturnAnalyzer {
+    fn run(&self, cart: &Cartridge, data: &mut Data) -> bool {
+        let mut addresses: Vec<usize> =
+            data.functions.iter().map(|(address, _)| *address).collect();
+        addresses.sort();
+
+        let mut changes = false;
+
+        for address in addresses {
+            if data.noreturn_overrides.contains(&address)
+                || data.functions.get(address).unwrap().can_return
+            {
+                continue;
+            }
+
+            let chunks = disassembly::chunk(cart, data, address);
+            if can_return(data, &chunks) {
+                data.functions.get_mut(address).unwrap().can_return = true;
+                changes = true;
+            }
+        }
+
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use analyzer;
+    use data::*;
+    use gb::*;
+
+    #[test]
+    fn computed_jumps_are_not_returns() {
+        // ld hl,$0300 / push hl / ret
+        let cart = Cartridge::with_function(&[0x21, 0x00, 0x03, 0xE5, 0xC9]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        assert!(!data.functions.get(0x200).unwrap().can_return);
+    }
+}
@@ -0,0 +1,490 @@
+use ir::*;
+use ssa::{Block, SsaFunction, SsaStmt, SsaVar};
+use std::collections::HashMap;
+
+// How many definitions deep an expression is expanded while looking for
+// 16-bit idioms; the high half of `adc` is the deepest at four.
+const EXPANSION_DEPTH: u32 = 4;
+
+type SsaExpr = Expr<SsaVar>;
+
+fn definitions(block: &Block) -> HashMap<SsaVar, SsaExpr> {
+    let mut values = HashMap::new();
+
+    // Recovered pair assignments are left opaque, so their halves can be
+    // matched as operands of later idioms
+    for stmt in &block.stmts {
+        if let Stmt::Assign(var, value) = &stmt.stmt {
+            if !value.reads_memory() {
+                values.insert(*var, value.clone());
+            }
+        }
+    }
+
+    values
+}
+
+fn expand(expr: &SsaExpr, values: &HashMap<SsaVar, SsaExpr>, depth: u32) -> SsaExpr {
+    let mut expanded = expr.clone();
+    if depth > 0 {
+        expanded.substitute(&mut |var| {
+            values
+                .get(&var)
+                .map(|value| expand(value, values, depth - 1))
+        });
+    }
+    simplify(expanded)
+}
+
+fn strip_mask(expr: &SsaExpr, mask: u16) -> &SsaExpr {
+    match expr {
+        Expr::Binary(BinaryOp::And, value, rhs) if rhs.as_const() == Some(mask) => value,
+        _ => expr,
+    }
+}
+
+fn binary_parts(expr: &SsaExpr, op: BinaryOp) -> Option<(&SsaExpr, &SsaExpr)> {
+    match expr {
+        Expr::Binary(expr_op, lhs, rhs) if *expr_op == op => Some((lhs, rhs)),
+        _ => None,
+    }
+}
+
+fn add_terms(expr: &SsaExpr, terms: &mut Vec<SsaExpr>) {
+    match binary_parts(expr, BinaryOp::Add) {
+        Some((lhs, rhs)) => {
+            add_terms(lhs, terms);
+            add_terms(rhs, terms);
+        }
+        None => terms.push(expr.clone()),
+    }
+}
+
+/// Combines the two halves of a 16-bit operand. Without a high half the low
+/// half is taken as a zero-extended 8-bit value.
+fn combine(low: &SsaExpr, high: Option<&SsaExpr>) -> Option<SsaExpr> {
+    match (low, high) {
+        (Expr::Const(low), None) => Some(Expr::Const(*low)),
+        (Expr::Const(low), Some(Expr::Const(high))) => Some(Expr::Const((*high << 8) | *low)),
+        (Expr::Var(_), None) => Some(low.clone()),
+        (Expr::Var(low_var), Some(Expr::Var(high_var)))
+            if pair_partner(low_var.var) == Some(high_var.var) && is_pair_high(high_var.var) =>
+        {
+            Some(Expr::binary(
+                BinaryOp::Or,
+                Expr::binary(BinaryOp::Shl, Expr::Var(*high_var), Expr::Const(8)),
+                Expr::Var(*low_var),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Pairs up the low operands `p` and `q` with the remaining high operands,
+/// trying both assignments.
+fn combine_operands(p: &SsaExpr, q: &SsaExpr, high: &[SsaExpr]) -> Option<(SsaExpr, SsaExpr)> {
+    match high.len() {
+        0 => Some((combine(p, None)?, combine(q, None)?)),
+        1 => combine(p, Some(&high[0]))
+            .and_then(|x| Some((x, combine(q, None)?)))
+            .or_else(|| Some((combine(q, Some(&high[0]))?, combine(p, None)?))),
+        2 => combine(p, Some(&high[0]))
+            .and_then(|x| Some((x, combine(q, Some(&high[1]))?)))
+            .or_else(|| Some((combine(p, Some(&high[1]))?, combine(q, Some(&high[0]))?))),
+        _ => None,
+    }
+}
+
+// ld a,l / add e / ld l,a / ld a,h / adc d / ld h,a
+fn match_add(low: &SsaExpr, high: &SsaExpr) -> Option<SsaExpr> {
+    let low_sum = strip_mask(low, 0xFF);
+    let (p, q) = binary_parts(low_sum, BinaryOp::Add)?;
+    let carry = Expr::binary(BinaryOp::Gt, low_sum.clone(), Expr::Const(0xFF));
+
+    let mut terms = Vec::new();
+    add_terms(strip_mask(high, 0xFF), &mut terms);
+    let carry_index = terms.iter().position(|term| *term == carry)?;
+    terms.remove(carry_index);
+
+    let (x, y) = combine_operands(p, q, &terms)?;
+    Some(Expr::mask(Expr::binary(BinaryOp::Add, x, y), 0xFFFF))
+}
+
+// ld a,l / sub e / ld l,a / ld a,h / sbc d / ld h,a
+fn match_sub(low: &SsaExpr, high: &SsaExpr) -> Option<SsaExpr> {
+    let (p, q) = binary_parts(strip_mask(low, 0xFF), BinaryOp::Sub)?;
+    let borrow = Expr::binary(BinaryOp::Lt, p.clone(), q.clone());
+
+    let (high_difference, high_borrow) = binary_parts(strip_mask(high, 0xFF), BinaryOp::Sub)?;
+    if *high_borrow != borrow {
+        return None;
+    }
+
+    let (x, y) = match binary_parts(high_difference, BinaryOp::Sub) {
+        Some((x_high, y_high)) => (combine(p, Some(x_high))?, combine(q, Some(y_high))?),
+        None => (combine(p, Some(high_difference))?, combine(q, None)?),
+    };
+    Some(Expr::mask(Expr::binary(BinaryOp::Sub, x, y), 0xFFFF))
+}
+
+// sla l / rl h
+fn match_shift_left(low: &SsaExpr, high: &SsaExpr) -> Option<SsaExpr> {
+    let (p, amount) = binary_parts(strip_mask(low, 0xFF), BinaryOp::Shl)?;
+    let (shifted_high, carry) = binary_parts(strip_mask(high, 0xFF), BinaryOp::Or)?;
+    let (x_high, high_amount) = binary_parts(shifted_high, BinaryOp::Shl)?;
+
+    if amount.as_const() != Some(1)
+        || high_amount.as_const() != Some(1)
+        || *carry != Expr::binary(BinaryOp::Shr, p.clone(), Expr::Const(7))
+    {
+        return None;
+    }
+
+    let x = combine(p, Some(x_high))?;
+    Some(Expr::mask(
+        Expr::binary(BinaryOp::Shl, x, Expr::Const(1)),
+        0xFFFF,
+    ))
+}
+
+// srl h / rr l and sra h / rr l
+fn match_shift_right(low: &SsaExpr, high: &SsaExpr) -> Option<SsaExpr> {
+    let (shifted_low, carry) = binary_parts(low, BinaryOp::Or)?;
+    let (p, amount) = binary_parts(shifted_low, BinaryOp::Shr)?;
+    let (carry_bit, carry_amount) = binary_parts(carry, BinaryOp::Shl)?;
+    let (x_high, carry_mask) = binary_parts(carry_bit, BinaryOp::And)?;
+
+    if amount.as_const() != Some(1)
+        || carry_amount.as_const() != Some(7)
+        || carry_mask.as_const() != Some(1)
+    {
+        return None;
+    }
+
+    let logical = Expr::binary(BinaryOp::Shr, x_high.clone(), Expr::Const(1));
+    let arithmetic = Expr::binary(
+        BinaryOp::Or,
+        logical.clone(),
+        Expr::mask(x_high.clone(), 0x80),
+    );
+
+    let x = combine(p, Some(x_high))?;
+    let shifted = Expr::binary(BinaryOp::Shr, x.clone(), Expr::Const(1));
+
+    if *high == logical {
+        Some(shifted)
+    } else if *high == arithmetic {
+        Some(Expr::binary(BinaryOp::Or, shifted, Expr::mask(x, 0x8000)))
+    } else {
+        None
+    }
+}
+
+// ld h,$C1 / ld l,$00 and ld h,d / ld l,e
+fn match_copy(low: &SsaExpr, high: &SsaExpr) -> Option<SsaExpr> {
+    combine(low, Some(high))
+}
+
+fn match_pair(low: &SsaExpr, high: &SsaExpr) -> Option<SsaExpr> {
+    match_copy(low, high)
+        .or_else(|| match_add(low, high))
+        .or_else(|| match_sub(low, high))
+        .or_else(|| match_shift_left(low, high))
+        .or_else(|| match_shift_right(low, high))
+}
+
+// ld a,l / sub e / ld a,h / sbc d, leaving hl < de in the carry flag. A
+// branch on no carry tests hl >= de instead.
+fn match_compare(carry: &SsaExpr) -> Option<SsaExpr> {
+    let op = match carry {
+        Expr::Binary(op @ BinaryOp::Lt, ..) | Expr::Binary(op @ BinaryOp::Ge, ..) => *op,
+        _ => return None,
+    };
+    let (x_high, rhs) = binary_parts(carry, op)?;
+    let (y_high, low_borrow) = match binary_parts(rhs, BinaryOp::Add) {
+        Some((y_high, low_borrow)) => (Some(y_high), low_borrow),
+        None => (None, rhs),
+    };
+    let (p, q) = binary_parts(low_borrow, BinaryOp::Lt)?;
+
+    let x = combine(p, Some(x_high))?;
+    let y = match y_high {
+        Some(y_high) => combine(q, Some(y_high))?,
+        None => combine(q, None)?,
+    };
+    Some(Expr::binary(op, x, y))
+}
+
+fn defining_index(block: &Block, var: SsaVar) -> Option<usize> {
+    block
+        .stmts
+        .iter()
+        .position(|stmt| stmt.stmt.defined_vars().contains(&var))
+}
+
+/// Checks whether `value` can be evaluated at `position` with versions
+/// dropped: no variable it reads may be redefined between its definition and
+/// `position`, not counting the definition at `skipped`.
+fn is_current(block: &Block, value: &SsaExpr, position: usize, skipped: Option<usize>) -> bool {
+    value.vars().into_iter().all(|var| {
+        let start = match defining_index(block, var) {
+            Some(index) if index >= position || Some(index) == skipped => return false,
+            Some(index) => index + 1,
+            None => 0,
+        };
+
+        (start..position).all(|index| {
+            Some(index) == skipped
+                || !block.stmts[index]
+                    .stmt
+                    .defined_vars()
+                    .iter()
+                    .chain(block.stmts[index].implicit_defs.iter())
+                    .any(|defined| defined.var == var.var)
+        })
+    })
+}
+
+fn is_used_between(block: &Block, var: SsaVar, start: usize, end: usize) -> bool {
+    block.stmts[start..=end]
+        .iter()
+        .any(|stmt| stmt.implicit_uses.contains(&var) || stmt.stmt.used_vars().contains(&var))
+}
+
+// The carry of a comparison is usually folded into the branch on it by the
+// time pairs are recovered
+fn recover_condition(
+    block: &mut Block,
+    values: &HashMap<SsaVar, SsaExpr>,
+    position: usize,
+) -> bool {
+    let condition = match block.stmts[position].stmt.condition() {
+        Some(condition) => expand(condition, values, EXPANSION_DEPTH),
+        None => return false,
+    };
+
+    match match_compare(&condition) {
+        Some(compare) if is_current(block, &compare, position, None) => {
+            *block.stmts[position].stmt.condition_mut().unwrap() = compare;
+            true
+        }
+        _ => false,
+    }
+}
+
+fn recover_in_block(block: &mut Block) -> bool {
+    let values = definitions(block);
+    let mut last_definition: HashMap<Var, usize> = HashMap::new();
+
+    for position in 0..block.stmts.len() {
+        let var = match block.stmts[position].stmt {
+            Stmt::Assign(var, _) => var,
+            _ => {
+                if recover_condition(block, &values, position) {
+                    return true;
+                }
+                for defined in block.stmts[position].stmt.defined_vars() {
+                    last_definition.remove(&defined.var);
+                }
+                for defined in &block.stmts[position].implicit_defs {
+                    last_definition.remove(&defined.var);
+                }
+                continue;
+            }
+        };
+
+        if var.var == Var::Flag(Flag::Carry) {
+            let value = expand(
+                block.stmts[position].stmt.value().unwrap(),
+                &values,
+                EXPANSION_DEPTH,
+            );
+            if let Some(compare) = match_compare(&value) {
+                if is_current(block, &compare, position, None) {
+                    block.stmts[position].stmt = Stmt::Assign(var, compare);
+                    return true;
+                }
+            }
+        }
+
+        let partner_position =
+            pair_partner(var.var).and_then(|partner| last_definition.get(&partner).cloned());
+        last_definition.insert(var.var, position);
+
+        let partner_position = match partner_position {
+            Some(partner_position) => partner_position,
+            None => continue,
+        };
+
+        let partner = match block.stmts[partner_position].stmt {
+            Stmt::Assign(partner, _) => partner,
+            _ => continue,
+        };
+
+        let (high, low) = if is_pair_high(var.var) {
+            (var, partner)
+        } else {
+            (partner, var)
+        };
+
+        let low_value = expand(&Expr::Var(low), &values, EXPANSION_DEPTH);
+        let high_value = expand(&Expr::Var(high), &values, EXPANSION_DEPTH);
+
+        let value = match match_pair(&low_value, &high_value) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        if is_used_between(block, partner, partner_position + 1, position)
+            || !is_current(block, &value, position, Some(partner_position))
+        {
+            continue;
+        }
+
+        block.stmts[position].stmt = Stmt::AssignPair(high, low, value);
+        block.stmts.remove(partner_position);
+        return true;
+    }
+
+    false
+}
+
+// add l / ld l,a / jr nc,.skip / inc h / .skip: only adds to the low half,
+// branching around `inc h` when there is no carry into the high half
+fn recover_pointer_add(function: &mut SsaFunction, index: usize) -> Option<()> {
+    let block = &function.blocks[index];
+    let jump = block.stmts.len().checked_sub(1)?;
+    let (condition, target) = match &block.stmts[jump].stmt {
+        Stmt::Jump {
+            condition: Some(condition),
+            target: Target::Direct(target),
+        } => (condition, *target),
+        _ => return None,
+    };
+    let (skip, increment) = match block.successors[..] {
+        [skip, increment] if function.blocks[skip].address == target => (skip, increment),
+        _ => return None,
+    };
+
+    // The chunk branched around may do nothing else
+    let increment_block = &function.blocks[increment];
+    let (high, increment_value) = match increment_block.stmts[..] {
+        [ref stmt] if increment_block.phis.is_empty() && increment_block.successors == [skip] => {
+            match &stmt.stmt {
+                Stmt::Assign(high, value) if is_pair_high(high.var) => (*high, value),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let phi = function.blocks[skip]
+        .phis
+        .iter()
+        .position(|phi| phi.sources.len() == 2 && phi.sources.contains(&(Some(increment), high)))?;
+    let merged = function.blocks[skip].phis[phi].target;
+    let old_high = function.blocks[skip].phis[phi]
+        .sources
+        .iter()
+        .find(|&&(predecessor, _)| predecessor == Some(index))?
+        .1;
+    let incremented = Expr::binary(BinaryOp::Add, Expr::Var(old_high), Expr::Const(1));
+    if *increment_value != Expr::mask(incremented, 0xFF) {
+        return None;
+    }
+
+    let low_var = pair_partner(high.var)?;
+    let position = block.stmts.iter().rposition(|stmt| {
+        stmt.stmt
+            .defined_vars()
+            .iter()
+            .chain(stmt.implicit_defs.iter())
+            .any(|defined| defined.var == low_var)
+    })?;
+    let low = match block.stmts[position].stmt {
+        Stmt::Assign(low, _) => low,
+        _ => return None,
+    };
+
+    let values = definitions(block);
+    let low_value = expand(&Expr::Var(low), &values, EXPANSION_DEPTH);
+    let carry = Expr::binary(
+        BinaryOp::Gt,
+        strip_mask(&low_value, 0xFF).clone(),
+        Expr::Const(0xFF),
+    );
+    let no_carry = simplify(Expr::unary(UnaryOp::Not, carry.clone()));
+    if expand(condition, &values, EXPANSION_DEPTH) != no_carry {
+        return None;
+    }
+
+    let high_value = Expr::mask(
+        Expr::binary(BinaryOp::Add, Expr::Var(old_high), carry),
+        0xFF,
+    );
+    let value = match_add(&low_value, &high_value)?;
+    if is_used_between(block, low, position + 1, jump)
+        || !is_current(block, &value, jump, Some(position))
+    {
+        return None;
+    }
+
+    // The branch is gone, leaving the chunk it went around empty
+    let address = block.stmts[position].address;
+    let block = &mut function.blocks[index];
+    block.stmts[jump] = SsaStmt {
+        address,
+        stmt: Stmt::AssignPair(merged, low, value),
+        implicit_uses: Vec::new(),
+        implicit_defs: Vec::new(),
+    };
+    block.stmts.remove(position);
+    block.successors = vec![increment];
+    function.blocks[increment].stmts.clear();
+    function.blocks[skip].phis.remove(phi);
+    Some(())
+}
+
+/// Rewrites sequences of 8-bit operations on the halves of a register pair
+/// into single 16-bit assignments and comparisons.
+pub fn recover_pairs(function: &mut SsaFunction) {
+    for index in 0..function.blocks.len() {
+        recover_pointer_add(function, index);
+    }
+    for block in function.blocks.iter_mut() {
+        while recover_in_block(block) {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use analyzer;
+    use data::Data;
+    use decompiler;
+    use gb::*;
+
+    fn decompile(code: &[u8]) -> String {
+        let cart = Cartridge::with_function(code);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        decompiler::decompile(&cart, &data, 0x200)
+    }
+
+    #[test]
+    fn recovers_compares_folded_into_branches() {
+        // ld a,l / sub e / ld a,h / sbc d / jr c,.less / ret / .less: ret
+        let output = decompile(&[0x7D, 0x93, 0x7C, 0x9A, 0x38, 0x01, 0xC9, 0xC9]);
+        assert!(output.contains("if (hl < de)"), "{}", output);
+
+        // The same with jr nc
+        let output = decompile(&[0x7D, 0x93, 0x7C, 0x9A, 0x30, 0x01, 0xC9, 0xC9]);
+        assert!(output.contains("if (hl >= de)"), "{}", output);
+    }
+
+    #[test]
+    fn recovers_adds_that_branch_around_the_high_half() {
+        // ld a,$10 / add l / ld l,a / jr nc,.skip / inc h / .skip: ld a,[hl]
+        // ret
+        let output = decompile(&[0x3E, 0x10, 0x85, 0x6F, 0x30, 0x01, 0x24, 0x7E, 0xC9]);
+        assert!(output.contains("hl += 0x10;"), "{}", output);
+        assert!(!output.contains("goto"), "{}", output);
+    }
+}
@@ -0,0 +1,32 @@
+use std::fmt;
+
+/// What is wrong with a line of a text file read from the command line, like
+/// an input script or a symbol file.
+#[derive(Debug)]
+pub struct LineError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Drops the comment `marker` starts from `line`, unless it is inside a
+/// string.
+pub fn strip_comment(line: &str, marker: char) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ if c == marker && !in_string => return &line[..index],
+            _ => (),
+        }
+    }
+    line
+}
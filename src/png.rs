@@ -0,0 +1,174 @@
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// Stored deflate blocks hold at most this many bytes.
+const MAX_BLOCK: usize = 0xFFFF;
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+// A zlib stream of uncompressed blocks, which is all PNG needs
+fn zlib_stored(bytes: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = if bytes.is_empty() {
+        vec![&[]]
+    } else {
+        bytes.chunks(MAX_BLOCK).collect()
+    };
+    for (index, block) in blocks.iter().enumerate() {
+        let last = index + 1 == blocks.len();
+        let len = block.len() as u16;
+        stream.push(last as u8);
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(bytes).to_be_bytes());
+    stream
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// A PNG of unfiltered rows, each already packed
+fn encode(
+    width: usize,
+    height: usize,
+    bit_depth: u8,
+    colour_type: u8,
+    rows: &[Vec<u8>],
+) -> Vec<u8> {
+    let mut raw = Vec::new();
+    for row in rows {
+        // No filter
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Then the default compression and filtering, and no interlacing
+    header.extend_from_slice(&[bit_depth, colour_type, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Encodes two-bit grayscale pixels, one a byte and row by row, where 0 is
+/// black and 3 white.
+pub fn encode_gray2(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    let rows: Vec<Vec<u8>> = pixels
+        .chunks(width)
+        .take(height)
+        .map(|row| {
+            let mut packed = vec![0u8; width.div_ceil(4)];
+            for (x, &pixel) in row.iter().enumerate() {
+                packed[x / 4] |= (pixel & 3) << (6 - 2 * (x % 4));
+            }
+            packed
+        })
+        .collect();
+    encode(width, height, 2, 0, &rows)
+}
+
+/// Reads back the width, height and pixels of a PNG `encode_gray2` wrote,
+/// for tests.
+#[cfg(test)]
+pub fn decode_gray2(png: &[u8]) -> (usize, usize, Vec<u8>) {
+    assert_eq!(png[..8], SIGNATURE);
+    let mut chunks = Vec::new();
+    let mut offset = 8;
+    while offset < png.len() {
+        let len = u32::from_be_bytes([
+            png[offset],
+            png[offset + 1],
+            png[offset + 2],
+            png[offset + 3],
+        ]);
+        let end = offset + 8 + len as usize;
+        let crc = &png[end..end + 4];
+        assert_eq!(crc, crc32(&png[offset + 4..end]).to_be_bytes());
+        chunks.push((&png[offset + 4..offset + 8], &png[offset + 8..end]));
+        offset = end + 4;
+    }
+
+    let kinds: Vec<&[u8]> = chunks.iter().map(|&(kind, _)| kind).collect();
+    assert_eq!(kinds, vec![&b"IHDR"[..], b"IDAT", b"IEND"]);
+    let header = chunks[0].1;
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    assert_eq!(header[8..], [2, 0, 0, 0, 0]);
+
+    // One stored block after the zlib header, then the checksum
+    let stream = chunks[1].1;
+    let raw = &stream[7..stream.len() - 4];
+    assert_eq!(stream[stream.len() - 4..], adler32(raw).to_be_bytes());
+
+    let row_size = width.div_ceil(4) + 1;
+    let mut pixels = Vec::new();
+    for row in raw.chunks(row_size) {
+        assert_eq!(row[0], 0);
+        for x in 0..width {
+            pixels.push(row[1 + x / 4] >> (6 - 2 * (x % 4)) & 3);
+        }
+    }
+    assert_eq!(pixels.len(), width * height);
+    (width, height, pixels)
+}
+
+/// Encodes 8-bit RGB pixels, row by row.
+pub fn encode_rgb(width: usize, height: usize, pixels: &[[u8; 3]]) -> Vec<u8> {
+    let rows: Vec<Vec<u8>> = pixels
+        .chunks(width)
+        .take(height)
+        .map(|row| row.iter().flatten().cloned().collect())
+        .collect();
+    encode(width, height, 8, 2, &rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let pixels = [0, 1, 2, 3, 0, 3, 2, 1, 0, 1];
+        let png = encode_gray2(5, 2, &pixels);
+        assert_eq!(decode_gray2(&png), (5, 2, pixels.to_vec()));
+        // The empty IEND chunk is always the same
+        assert_eq!(
+            png[png.len() - 12..],
+            [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]
+        );
+    }
+}
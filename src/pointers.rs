@@ -0,0 +1,216 @@
+use analyzer::RomAnalyzer;
+use data::*;
+use disassembly;
+use gb::*;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+/// Tables shorter than this are too likely to be a coincidence.
+const MIN_TABLE_ENTRIES: usize = 2;
+
+/// Data in the ROM found through pointers to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pointers {
+    /// Arrays of little-endian pointers, by address.
+    pub tables: BTreeMap<usize, Vec<usize>>,
+    /// Every address a pointer to data was found for, tables included.
+    pub labels: BTreeSet<usize>,
+}
+
+impl Pointers {
+    pub fn table_at(&self, address: usize) -> Option<(usize, &[usize])> {
+        self.tables
+            .range(..=address)
+            .next_back()
+            .filter(|&(&start, entries)| address < start + 2 * entries.len())
+            .map(|(&start, entries)| (start, entries.as_slice()))
+    }
+}
+
+// The bytes and instruction starts of every function
+struct Code {
+    bytes: HashSet<usize>,
+    starts: HashSet<usize>,
+}
+
+impl Code {
+    fn find(cart: &Cartridge, data: &Data) -> (Code, Vec<Instruction>) {
+        let mut instructions: Vec<Instruction> = data
+            .functions
+            .iter()
+            .flat_map(|(&address, _)| disassembly::collect_instructions(cart, data, address))
+            .collect();
+        instructions.sort_by_key(|instruction| instruction.address);
+        instructions.dedup_by_key(|instruction| instruction.address);
+
+        let mut code = Code {
+            bytes: HashSet::new(),
+            starts: HashSet::new(),
+        };
+        for instruction in &instructions {
+            code.starts.insert(instruction.address);
+            code.bytes
+                .extend(instruction.address..instruction.address + instruction.size());
+        }
+        (code, instructions)
+    }
+
+    // Whether a pointer to `address` could be real: to an instruction, or to
+    // bytes past the header that aren't part of one
+    fn is_plausible(&self, cart: &Cartridge, address: usize) -> bool {
+        address < cart.rom_size().min(ROM_END)
+            && (self.starts.contains(&address)
+                || (address >= Cartridge::HEADER_END && !self.bytes.contains(&address)))
+    }
+}
+
+// `ld bc/de/hl, d16` loading the address of something in the ROM
+fn immediate_pointer(instruction: &Instruction) -> Option<usize> {
+    match instruction.op_code {
+        OpCode::LD_BC_d16(value) | OpCode::LD_DE_d16(value) | OpCode::LD_HL_d16(value) => {
+            Some(value.value as usize)
+        }
+        _ => None,
+    }
+}
+
+/// The little-endian word at `address`.
+pub fn read_pointer(cart: &Cartridge, address: usize) -> usize {
+    cart[address] as usize | (cart[address + 1] as usize) << 8
+}
+
+fn bank_half(address: usize) -> bool {
+    address >= BANKED_ROM_START
+}
+
+// The entries of a pointer table at `address`, which ends at the first
+// implausible pointer, code, other data, or the data it points to. The
+// entries of a table only found through another table have to point to
+// code, or past it within the same half of the address space, as data
+// usually follows the table indexing it.
+fn find_table(
+    cart: &Cartridge,
+    data: &Data,
+    code: &Code,
+    labels: &BTreeSet<usize>,
+    address: usize,
+    nested: bool,
+) -> Option<Vec<usize>> {
+    let mut entries = Vec::new();
+    let mut end = address;
+
+    while end + 1 < cart.rom_size() {
+        let ends_here = code.bytes.contains(&end)
+            || code.bytes.contains(&(end + 1))
+            || data.is_data(end)
+            || (end != address && labels.contains(&end))
+            || entries
+                .iter()
+                .any(|&entry| entry > address && entry <= end + 1);
+        if ends_here {
+            break;
+        }
+
+        let entry = read_pointer(cart, end);
+        let is_code = code.starts.contains(&entry);
+        let plausible = code.is_plausible(cart, entry)
+            && entries
+                .iter()
+                .all(|&other| bank_half(other) == bank_half(entry))
+            && (!nested || is_code || (entry > address && bank_half(entry) == bank_half(address)));
+        if !plausible {
+            break;
+        }
+        entries.push(entry);
+        end += 2;
+    }
+
+    // A run of one repeated value is more likely filler than a table
+    let repeated = entries.windows(2).all(|pair| pair[0] == pair[1]);
+    if entries.len() >= MIN_TABLE_ENTRIES && !repeated {
+        Some(entries)
+    } else {
+        None
+    }
+}
+
+/// Follows `ld bc/de/hl, d16` into the ROM, marking what they point at as
+/// data, and reads arrays of pointers found there, following those in turn.
+pub struct PointerAnalyzer;
+
+impl RomAnalyzer for PointerAnalyzer {
+    fn run(&self, cart: &Cartridge, data: &mut Data) -> bool {
+        let (code, instructions) = Code::find(cart, data);
+
+        let mut todo: Vec<usize> = instructions
+            .iter()
+            .filter_map(immediate_pointer)
+            .filter(|&address| code.is_plausible(cart, address))
+            .filter(|address| !code.starts.contains(address))
+            .collect();
+        todo.sort();
+        todo.dedup();
+
+        // Everything pointed at is known before looking for tables, so that
+        // one table doesn't run into the data after it
+        let mut pointers = Pointers {
+            labels: todo.iter().cloned().collect(),
+            ..Pointers::default()
+        };
+        let mut todo: Vec<(usize, bool)> = todo
+            .into_iter()
+            .rev()
+            .map(|address| (address, false))
+            .collect();
+
+        while let Some((address, nested)) = todo.pop() {
+            if pointers.table_at(address).is_some() {
+                continue;
+            }
+            let entries = match find_table(cart, data, &code, &pointers.labels, address, nested) {
+                Some(entries) => entries,
+                None => continue,
+            };
+
+            for &entry in &entries {
+                if !code.starts.contains(&entry) && pointers.labels.insert(entry) {
+                    todo.push((entry, true));
+                }
+            }
+            pointers.tables.insert(address, entries);
+        }
+
+        let changes = data.pointers != pointers;
+        data.pointers = pointers;
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+
+    #[test]
+    fn reads_tables_of_pointers_to_data() {
+        // ld hl,$0300 / ret, with dw $0310,$0320 at $0300
+        let cart = Cartridge::with_code(&[
+            (0x100, &[0xCD, 0x00, 0x02, 0x18, 0xFE]),
+            (0x200, &[0x21, 0x00, 0x03, 0xC9]),
+            (0x300, &[0x10, 0x03, 0x20, 0x03]),
+            (0x310, &[0x01, 0x02, 0x03]),
+            (0x320, &[0xAA]),
+        ]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+
+        assert_eq!(data.pointers.tables[&0x300], vec![0x310, 0x320]);
+        assert_eq!(
+            data.pointers.labels,
+            [0x300, 0x310, 0x320].iter().cloned().collect()
+        );
+        assert_eq!(
+            data.pointers.table_at(0x303).map(|(start, _)| start),
+            Some(0x300)
+        );
+        assert_eq!(data.pointers.table_at(0x304), None);
+    }
+}
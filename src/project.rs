@@ -0,0 +1,336 @@
+use data::{Data, DataRange};
+use gb::*;
+use parse::{strip_comment, LineError};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// What the user knows about a ROM, kept between runs in a small subset of
+/// TOML:
+///
+/// ```toml
+/// checksum = 0x1A2B
+/// entry_points = [0x0200]
+/// noreturn = [0x0038]
+///
+/// [names]
+/// 0150 = "Main"
+///
+/// [comments]
+/// 0157 = "Wait for vblank"
+///
+/// [data]
+/// 4000-40FF = "tiles"
+///
+/// [jump_tables]
+/// 3000 = 12
+/// ```
+///
+/// Addresses in keys are hex. Jump tables are given by their address and
+/// the number of two-byte entries.
+#[derive(Clone, Debug, Default)]
+pub struct Project {
+    /// The global checksum of the ROM the project was made for.
+    pub checksum: Option<u16>,
+    pub entry_points: BTreeSet<usize>,
+    pub noreturn: BTreeSet<usize>,
+    pub names: BTreeMap<usize, String>,
+    pub comments: BTreeMap<usize, String>,
+    pub data: BTreeMap<(usize, usize), String>,
+    pub jump_tables: BTreeMap<usize, usize>,
+}
+
+enum Value {
+    Integer(usize),
+    String(String),
+    Array(Vec<usize>),
+}
+
+fn parse_integer(text: &str) -> Option<usize> {
+    let text = text.replace('_', "");
+    match text.strip_prefix("0x") {
+        Some(digits) => usize::from_str_radix(digits, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_string(text: &str) -> Option<String> {
+    let text = text.strip_prefix('"')?.strip_suffix('"')?;
+    let mut string = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => string.push('\n'),
+            't' => string.push('\t'),
+            c @ ('"' | '\\') => string.push(c),
+            _ => return None,
+        }
+    }
+    Some(string)
+}
+
+fn parse_value(text: &str) -> Option<Value> {
+    if text.starts_with('"') {
+        parse_string(text).map(Value::String)
+    } else if let Some(items) = text.strip_prefix('[') {
+        let items = items.strip_suffix(']')?.trim();
+        if items.is_empty() {
+            return Some(Value::Array(Vec::new()));
+        }
+        items
+            .split(',')
+            .map(|item| parse_integer(item.trim()))
+            .collect::<Option<Vec<usize>>>()
+            .map(Value::Array)
+    } else {
+        parse_integer(text).map(Value::Integer)
+    }
+}
+
+fn parse_address(text: &str) -> Option<usize> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    usize::from_str_radix(digits, 16).ok()
+}
+
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (start, end) = text.split_once('-')?;
+    Some((parse_address(start)?, parse_address(end)?))
+}
+
+fn quote(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
+
+fn address_list(addresses: &BTreeSet<usize>) -> String {
+    let addresses: Vec<String> = addresses
+        .iter()
+        .map(|address| format!("0x{:04X}", address))
+        .collect();
+    format!("[{}]", addresses.join(", "))
+}
+
+impl Project {
+    pub fn new(cart: &Cartridge) -> Project {
+        Project {
+            checksum: Some(cart.global_checksum()),
+            ..Project::default()
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Project, LineError> {
+        let mut project = Project::default();
+        let mut section = String::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = strip_comment(line, '#').trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = |message: String| LineError {
+                line: index + 1,
+                message,
+            };
+
+            if let Some(name) = line.strip_prefix('[') {
+                section = name
+                    .strip_suffix(']')
+                    .ok_or_else(|| error("unclosed section header".to_string()))?
+                    .trim()
+                    .to_string();
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected `key = value`".to_string()))?;
+            let key = key.trim().trim_matches('"');
+            let value = parse_value(value.trim())
+                .ok_or_else(|| error(format!("invalid value for `{}`", key)))?;
+            let invalid_key = || error(format!("invalid key `{}` in [{}]", key, section));
+
+            match (section.as_str(), value) {
+                ("", Value::Integer(checksum)) if key == "checksum" => {
+                    if checksum > 0xFFFF {
+                        return Err(error(format!("checksum {:#X} is over 16 bits", checksum)));
+                    }
+                    project.checksum = Some(checksum as u16)
+                }
+                ("", Value::Array(addresses)) if key == "entry_points" => {
+                    project.entry_points.extend(addresses)
+                }
+                ("", Value::Array(addresses)) if key == "noreturn" => {
+                    project.noreturn.extend(addresses)
+                }
+                ("names", Value::String(name)) => {
+                    let address = parse_address(key).ok_or_else(invalid_key)?;
+                    project.names.insert(address, name);
+                }
+                ("comments", Value::String(comment)) => {
+                    let address = parse_address(key).ok_or_else(invalid_key)?;
+                    project.comments.insert(address, comment);
+                }
+                ("data", Value::String(kind)) => {
+                    let range = parse_range(key).ok_or_else(invalid_key)?;
+                    project.data.insert(range, kind);
+                }
+                ("jump_tables", Value::Integer(entries)) => {
+                    let address = parse_address(key).ok_or_else(invalid_key)?;
+                    project.jump_tables.insert(address, entries);
+                }
+                _ => return Err(invalid_key()),
+            }
+        }
+
+        Ok(project)
+    }
+
+    /// Hands what the project knows to the analysis, before it starts.
+    pub fn apply(&self, cart: &Cartridge, data: &mut Data) {
+        data.entry_points.extend(self.entry_points.iter().cloned());
+        data.noreturn_overrides
+            .extend(self.noreturn.iter().cloned());
+        for (&address, name) in &self.names {
+            data.symbols.insert(address, name.clone());
+        }
+        data.comments.extend(
+            self.comments
+                .iter()
+                .map(|(&address, comment)| (address, comment.clone())),
+        );
+        for (&(start, end), kind) in &self.data {
+            data.data_ranges.push(DataRange {
+                start,
+                end,
+                kind: kind.clone(),
+            });
+        }
+
+        for (&address, &entries) in &self.jump_tables {
+            let end = address + 2 * entries;
+            if end > cart.rom_size() {
+                continue;
+            }
+            for entry in (address..end).step_by(2) {
+                data.entry_points
+                    .insert(cart[entry] as usize | (cart[entry + 1] as usize) << 8);
+            }
+            data.data_ranges.push(DataRange {
+                start: address,
+                end: end - 1,
+                kind: "jump table".to_string(),
+            });
+        }
+    }
+}
+
+impl fmt::Display for Project {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "# gb-decompiler project")?;
+        if let Some(checksum) = self.checksum {
+            writeln!(f, "checksum = 0x{:04X}", checksum)?;
+        }
+        if !self.entry_points.is_empty() {
+            writeln!(f, "entry_points = {}", address_list(&self.entry_points))?;
+        }
+        if !self.noreturn.is_empty() {
+            writeln!(f, "noreturn = {}", address_list(&self.noreturn))?;
+        }
+
+        if !self.names.is_empty() {
+            writeln!(f, "\n[names]")?;
+            for (address, name) in &self.names {
+                writeln!(f, "{:04X} = {}", address, quote(name))?;
+            }
+        }
+        if !self.comments.is_empty() {
+            writeln!(f, "\n[comments]")?;
+            for (address, comment) in &self.comments {
+                writeln!(f, "{:04X} = {}", address, quote(comment))?;
+            }
+        }
+        if !self.data.is_empty() {
+            writeln!(f, "\n[data]")?;
+            for ((start, end), kind) in &self.data {
+                writeln!(f, "{:04X}-{:04X} = {}", start, end, quote(kind))?;
+            }
+        }
+        if !self.jump_tables.is_empty() {
+            writeln!(f, "\n[jump_tables]")?;
+            for (address, entries) in &self.jump_tables {
+                writeln!(f, "{:04X} = {}", address, entries)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROJECT: &str = r#"checksum = 0x1A2B # of the US release
+entry_points = [0x0200, 0x0300]
+noreturn = []
+
+[names]
+0150 = "Main"
+
+[comments]
+0157 = "Wait for \"vblank\" # here"
+
+[data]
+4000-40FF = "tiles"
+
+[jump_tables]
+3000 = 12
+"#;
+
+    #[test]
+    fn parses_every_section() {
+        let project = Project::parse(PROJECT).unwrap();
+        assert_eq!(project.checksum, Some(0x1A2B));
+        assert_eq!(
+            project.entry_points,
+            [0x200, 0x300].iter().cloned().collect()
+        );
+        assert!(project.noreturn.is_empty());
+        assert_eq!(project.names[&0x150], "Main");
+        assert_eq!(project.comments[&0x157], "Wait for \"vblank\" # here");
+        assert_eq!(project.data[&(0x4000, 0x40FF)], "tiles");
+        assert_eq!(project.jump_tables[&0x3000], 12);
+    }
+
+    #[test]
+    fn saves_what_it_parses() {
+        let project = Project::parse(PROJECT).unwrap();
+        let saved = project.to_string();
+        assert_eq!(Project::parse(&saved).unwrap().to_string(), saved);
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        let error = Project::parse("[names\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 1: unclosed section header");
+
+        let error = Project::parse("\n[names]\nMain\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 3: expected `key = value`");
+
+        let error = Project::parse("[names]\nmain = \"Main\"\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: invalid key `main` in [names]");
+
+        let error = Project::parse("checksum = 0x12345\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 1: checksum 0x12345 is over 16 bits"
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use data::*;
+use disassembly;
+use gb::*;
+use parse::{strip_comment, LineError};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Signatures cover at most this many bytes from the start of a function.
+const MAX_SIGNATURE_BYTES: usize = 32;
+/// Signatures with fewer bytes that aren't wildcards would match too much.
+const MIN_FIXED_BYTES: usize = 4;
+
+/// Immediates in this range are addresses of variables, which the linker
+/// places differently in each game.
+const VARIABLES: (usize, usize) = (SRAM_START, 0xE000);
+/// Immediates loaded into BC, DE or HL in this range can be pointers to
+/// code or data in the ROM, which moves too.
+const ROM: (usize, usize) = (0, ROM_END);
+
+/// The first bytes of a library function, with `None` for the bytes of
+/// operands the linker fills in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub pattern: Vec<Option<u8>>,
+}
+
+impl Signature {
+    fn fixed_bytes(&self) -> usize {
+        self.pattern.iter().filter(|byte| byte.is_some()).count()
+    }
+
+    fn matches(&self, cart: &Cartridge, address: usize) -> bool {
+        address + self.pattern.len() <= cart.rom_size()
+            && self
+                .pattern
+                .iter()
+                .enumerate()
+                .all(|(index, byte)| byte.is_none_or(|byte| cart[address + index] == byte))
+    }
+}
+
+// Whether the operand of `op_code` is an address the linker relocates: any
+// `a16`, and `d16` immediates pointing at variables or into the ROM
+fn is_relocated(op_code: &OpCode) -> bool {
+    let is_in = |(start, end): (usize, usize), value: u16| (start..end).contains(&(value as usize));
+    match op_code {
+        OpCode::JP_a16(_)
+        | OpCode::JP_NZ_a16(_)
+        | OpCode::JP_Z_a16(_)
+        | OpCode::JP_NC_a16(_)
+        | OpCode::JP_C_a16(_)
+        | OpCode::CALL_a16(_)
+        | OpCode::CALL_NZ_a16(_)
+        | OpCode::CALL_Z_a16(_)
+        | OpCode::CALL_NC_a16(_)
+        | OpCode::CALL_C_a16(_)
+        | OpCode::LD_pa16_A(_)
+        | OpCode::LD_A_pa16(_)
+        | OpCode::LD_pa16_SP(_) => true,
+        OpCode::LD_BC_d16(value) | OpCode::LD_DE_d16(value) | OpCode::LD_HL_d16(value) => {
+            is_in(VARIABLES, value.value) || is_in(ROM, value.value)
+        }
+        OpCode::LD_SP_d16(value) => is_in(VARIABLES, value.value),
+        _ => false,
+    }
+}
+
+// The signature of the function at `address`: its instructions in order
+// from the start, up to the first gap or `MAX_SIGNATURE_BYTES`
+fn signature(cart: &Cartridge, data: &Data, address: usize, name: &str) -> Signature {
+    let mut instructions = disassembly::collect_instructions(cart, data, address);
+    instructions.sort_by_key(|instruction| instruction.address);
+    instructions.dedup_by_key(|instruction| instruction.address);
+
+    let mut pattern = Vec::new();
+    for instruction in instructions {
+        let size = instruction.size();
+        if instruction.address != address + pattern.len()
+            || pattern.len() + size > MAX_SIGNATURE_BYTES
+        {
+            break;
+        }
+        let relocated = is_relocated(&instruction.op_code);
+        for offset in 0..size {
+            // Operands always follow the opcode byte
+            let is_operand = offset > 0;
+            pattern.push(if relocated && is_operand {
+                None
+            } else {
+                Some(cart[instruction.address + offset])
+            });
+        }
+    }
+    Signature {
+        name: name.to_string(),
+        pattern,
+    }
+}
+
+/// A database of library function signatures, one a line:
+///
+/// ```text
+/// ; GBDK 2020
+/// _memcpy 2A 12 13 0B 78 B1 20 F8 C9
+/// .wait_vbl_done F0 40 E6 80 C8 AF EA .. .. 76 00 FA .. .. B7 28 F8 C9
+/// ```
+///
+/// where `..` stands for any byte.
+#[derive(Clone, Debug, Default)]
+pub struct Signatures {
+    signatures: Vec<Signature>,
+}
+
+impl Signatures {
+    pub fn parse(text: &str) -> Result<Signatures, LineError> {
+        let mut signatures = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = strip_comment(line, ';').trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = |message: String| LineError {
+                line: index + 1,
+                message,
+            };
+
+            let mut words = line.split_whitespace();
+            let name = words.next().unwrap().to_string();
+            let pattern = words
+                .map(|word| match word {
+                    ".." => Ok(None),
+                    _ if word.len() == 2 => u8::from_str_radix(word, 16)
+                        .map(Some)
+                        .map_err(|_| error(format!("invalid byte `{}`", word))),
+                    _ => Err(error(format!("invalid byte `{}`", word))),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if pattern.is_empty() {
+                return Err(error("missing bytes".to_string()));
+            }
+
+            signatures.push(Signature { name, pattern });
+        }
+
+        Ok(Signatures { signatures })
+    }
+
+    pub fn merge(&mut self, other: Signatures) {
+        self.signatures.extend(other.signatures);
+    }
+
+    /// Signatures for every function found that a symbol names. Functions
+    /// that start the same way under different names are left out, as
+    /// they can't be told apart.
+    pub fn generate(cart: &Cartridge, data: &Data) -> Signatures {
+        let mut addresses: Vec<usize> = data
+            .functions
+            .iter()
+            .map(|(&address, _)| address)
+            .filter(|&address| address < ROM_END)
+            .collect();
+        addresses.sort();
+
+        let mut signatures: Vec<Signature> = Vec::new();
+        let mut names: HashMap<Vec<Option<u8>>, Vec<String>> = HashMap::new();
+        for address in addresses {
+            let name = match data.symbols.get(address) {
+                Some(name) => name,
+                None => continue,
+            };
+            let signature = signature(cart, data, address, name);
+            if signature.fixed_bytes() < MIN_FIXED_BYTES {
+                continue;
+            }
+            let same = names.entry(signature.pattern.clone()).or_default();
+            if !same.contains(&signature.name) {
+                same.push(signature.name.clone());
+                signatures.push(signature);
+            }
+        }
+
+        signatures.retain(|signature| names[&signature.pattern].len() == 1);
+        Signatures { signatures }
+    }
+
+    /// The name of the library function at `address`, going by the longest
+    /// signature matching it, unless signatures as long name it otherwise.
+    pub fn find(&self, cart: &Cartridge, address: usize) -> Option<&str> {
+        let matching: Vec<&Signature> = self
+            .signatures
+            .iter()
+            .filter(|signature| signature.matches(cart, address))
+            .collect();
+        let longest = matching
+            .iter()
+            .map(|signature| signature.pattern.len())
+            .max()?;
+        let mut names = matching
+            .iter()
+            .filter(|signature| signature.pattern.len() == longest)
+            .map(|signature| signature.name.as_str());
+        let name = names.next()?;
+        if names.all(|other| other == name) {
+            Some(name)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Signatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for signature in &self.signatures {
+            let bytes: Vec<String> = signature
+                .pattern
+                .iter()
+                .map(|byte| byte.map_or_else(|| "..".to_string(), |byte| format!("{:02X}", byte)))
+                .collect();
+            writeln!(f, "{} {}", signature.name, bytes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Names the functions in the ROM that library signatures match, unless a
+/// symbol names them.
+pub fn apply(cart: &Cartridge, data: &mut Data) {
+    let addresses: Vec<usize> = data.functions.iter().map(|(&address, _)| address).collect();
+    for address in addresses {
+        if address >= ROM_END || data.symbols.get(address).is_some() {
+            continue;
+        }
+        if let Some(name) = data.signatures.find(cart, address) {
+            data.functions.get_mut(address).unwrap().name = name.to_string();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_patterns_with_wildcards() {
+        let text =
+            "; GBDK 2020\n_memcpy 2A 12 13 0B 78 B1 20 F8 C9 ; the loop\n\n.wait EA .. .. C9\n";
+        let signatures = Signatures::parse(text).unwrap();
+        assert_eq!(
+            signatures.signatures[1],
+            Signature {
+                name: ".wait".to_string(),
+                pattern: vec![Some(0xEA), None, None, Some(0xC9)],
+            }
+        );
+        assert_eq!(
+            Signatures::parse(&signatures.to_string())
+                .unwrap()
+                .to_string(),
+            signatures.to_string()
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        let error = Signatures::parse("_memcpy 2A 1\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 1: invalid byte `1`");
+
+        let error = Signatures::parse("\n_memcpy\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: missing bytes");
+    }
+
+    #[test]
+    fn leaves_out_relocated_pointers() {
+        // ld hl,$4000 / ld de,$C000 / ld bc,$FF40 / ld a,[hli] / ret
+        let cart = Cartridge::with_code(&[(
+            0x200,
+            &[
+                0x21, 0x00, 0x40, 0x11, 0x00, 0xC0, 0x01, 0x40, 0xFF, 0x2A, 0xC9,
+            ],
+        )]);
+        let mut data = Data::new();
+        data.functions.get_or_add(0x200);
+        let signatures = Signatures {
+            signatures: vec![signature(&cart, &data, 0x200, "copy")],
+        };
+        assert_eq!(
+            signatures.to_string(),
+            "copy 21 .. .. 11 .. .. 01 40 FF 2A C9\n"
+        );
+
+        let moved = Cartridge::with_code(&[(
+            0x300,
+            &[
+                0x21, 0x00, 0x50, 0x11, 0x80, 0xC1, 0x01, 0x40, 0xFF, 0x2A, 0xC9,
+            ],
+        )]);
+        assert_eq!(signatures.find(&moved, 0x300), Some("copy"));
+    }
+}
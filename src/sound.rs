@@ -0,0 +1,533 @@
+use analyzer::RomAnalyzer;
+use constants;
+use data::*;
+use disassembly;
+use gb::*;
+use ir::*;
+use pointers::read_pointer;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+use xrefs::Access;
+
+/// NR10 to NR52, then wave RAM.
+const SOUND_REGISTERS: (usize, usize) = (0xFF10, 0xFF27);
+const WAVE_RAM: (usize, usize) = (0xFF30, 0xFF40);
+const NR52: usize = 0xFF26;
+
+/// A routine playing music writes to at least this many sound registers.
+const MIN_UPDATE_REGISTERS: usize = 4;
+/// Longer GBT Player songs are more likely to be something else.
+const MAX_PATTERNS: usize = 256;
+
+/// Text that drivers leave in the ROMs using them.
+const DEVSOUND_NAME: &str = "DevSound";
+const ID_STRINGS: [(SoundDriver, &str); 1] = [(SoundDriver::DevSound, DEVSOUND_NAME)];
+const JP_A16: u8 = 0xC3;
+
+fn is_sound_register(address: usize) -> bool {
+    let ((start, end), (wave_start, wave_end)) = (SOUND_REGISTERS, WAVE_RAM);
+    (start..end).contains(&address) || (wave_start..wave_end).contains(&address)
+}
+
+// Every function `address` calls, directly or not, and itself
+fn reachable(graph: &HashMap<usize, BTreeSet<usize>>, address: usize) -> BTreeSet<usize> {
+    let mut seen = BTreeSet::new();
+    let mut pending = vec![address];
+    while let Some(address) = pending.pop() {
+        if seen.insert(address) {
+            pending.extend(graph.get(&address).into_iter().flatten());
+        }
+    }
+    seen
+}
+
+// Whether `address` could hold music data for a table at `table`, in the
+// same half of the address space and not in code
+fn is_music_data(cart: &Cartridge, code: &HashSet<usize>, table: usize, address: usize) -> bool {
+    address >= Cartridge::HEADER_END
+        && address < cart.rom_size().min(ROM_END)
+        && (address >= BANKED_ROM_START) == (table >= BANKED_ROM_START)
+        && !code.contains(&address)
+}
+
+// The tables of the hUGEDriver song descriptor at `address`, if that is
+// what it is
+fn huge_song(cart: &Cartridge, code: &HashSet<usize>, address: usize) -> Option<Vec<SoundTable>> {
+    let size = 21;
+    if !is_music_data(cart, code, address, address)
+        || !is_music_data(cart, code, address, address + size - 1)
+    {
+        return None;
+    }
+    // Each of the four order tables is this many bytes
+    let order_size = read_pointer(cart, address + 1);
+    let pointers: Vec<usize> = (0..9)
+        .map(|index| read_pointer(cart, address + 3 + 2 * index))
+        .collect();
+    let is_valid = order_size > 0
+        && order_size.is_multiple_of(2)
+        && order_size <= 2 * MAX_PATTERNS
+        && pointers[..4]
+            .iter()
+            .all(|&orders| is_music_data(cart, code, address, orders + order_size - 1))
+        && pointers
+            .iter()
+            .all(|&pointer| is_music_data(cart, code, address, pointer));
+    if !is_valid {
+        return None;
+    }
+
+    let mut tables = vec![SoundTable {
+        address,
+        kind: SoundTableKind::HugeSong,
+        pointers: pointers.clone(),
+    }];
+    let mut patterns = BTreeSet::new();
+    for &orders in &pointers[..4] {
+        let entries: Vec<usize> = (0..order_size / 2)
+            .map(|index| read_pointer(cart, orders + 2 * index))
+            .collect();
+        if !entries
+            .iter()
+            .all(|&pattern| is_music_data(cart, code, address, pattern))
+        {
+            return None;
+        }
+        patterns.extend(entries.iter().cloned());
+        tables.push(SoundTable {
+            address: orders,
+            kind: SoundTableKind::Orders,
+            pointers: entries,
+        });
+    }
+    let kinds = [
+        SoundTableKind::Instruments,
+        SoundTableKind::Instruments,
+        SoundTableKind::Instruments,
+        SoundTableKind::Routines,
+        SoundTableKind::Waves,
+    ];
+    for (&pointer, &kind) in pointers[4..].iter().zip(&kinds) {
+        tables.push(SoundTable {
+            address: pointer,
+            kind,
+            pointers: Vec::new(),
+        });
+    }
+    tables.extend(patterns.into_iter().map(|pattern| SoundTable {
+        address: pattern,
+        kind: SoundTableKind::Pattern,
+        pointers: Vec::new(),
+    }));
+    Some(tables)
+}
+
+// The tables of the GBT Player song at `address`, a list of pointers to
+// patterns ending with zero, if that is what it is
+fn gbt_song(cart: &Cartridge, code: &HashSet<usize>, address: usize) -> Option<Vec<SoundTable>> {
+    let mut patterns = Vec::new();
+    loop {
+        let entry = address + 2 * patterns.len();
+        if patterns.len() > MAX_PATTERNS || !is_music_data(cart, code, address, entry + 1) {
+            return None;
+        }
+        match read_pointer(cart, entry) {
+            0 => break,
+            pattern if is_music_data(cart, code, address, pattern) => patterns.push(pattern),
+            _ => return None,
+        }
+    }
+    if patterns.is_empty() {
+        return None;
+    }
+
+    let unique: BTreeSet<usize> = patterns.iter().cloned().collect();
+    let mut tables = vec![SoundTable {
+        address,
+        kind: SoundTableKind::GbtSong,
+        pointers: patterns,
+    }];
+    tables.extend(unique.into_iter().map(|pattern| SoundTable {
+        address: pattern,
+        kind: SoundTableKind::Pattern,
+        pointers: Vec::new(),
+    }));
+    Some(tables)
+}
+
+// DevSound's init and play, the first two of the jumps to its routines that
+// its name follows. Either the jump or where it goes can be the function,
+// depending on which the game calls.
+fn devsound_routines(cart: &Cartridge, data: &Data) -> Option<(usize, usize)> {
+    let end = cart.rom_size().min(ROM_END);
+    let routine = |entry: usize| {
+        [entry, read_pointer(cart, entry + 1)]
+            .iter()
+            .cloned()
+            .find(|&address| data.functions.is_start(address))
+    };
+
+    (0..end)
+        .filter(|&address| cart[address..end].starts_with(DEVSOUND_NAME.as_bytes()))
+        .find_map(|name| {
+            let mut start = name;
+            while start >= 3 && cart[start - 3] == JP_A16 {
+                start -= 3;
+            }
+            let entries: Vec<usize> = (start..name).step_by(3).collect();
+            match entries[..] {
+                [init, play, ..] => Some((routine(init)?, routine(play)?)),
+                _ => None,
+            }
+        })
+}
+
+fn contains_text(cart: &Cartridge, text: &str) -> bool {
+    let end = cart.rom_size().min(ROM_END);
+    (0..end)
+        .map(|address| &cart[address..end])
+        .any(|bytes| bytes.starts_with(text.as_bytes()))
+}
+
+/// What the analysis found out about the sound driver.
+struct Findings {
+    driver: Option<SoundDriver>,
+    inits: BTreeSet<usize>,
+    /// The update, when the driver's code says which it is.
+    update: Option<usize>,
+    tables: Vec<SoundTable>,
+    /// The sound registers each function writes to itself.
+    writes: HashMap<usize, BTreeSet<usize>>,
+    /// The functions each function calls or jumps to.
+    graph: HashMap<usize, BTreeSet<usize>>,
+}
+
+/// A call, with what HL and DE hold if that is known.
+struct Call {
+    address: usize,
+    target: usize,
+    hl: Option<usize>,
+    de: Option<usize>,
+}
+
+// Calls that pass a song to a routine, which is then the driver's init
+fn find_songs(cart: &Cartridge, code: &HashSet<usize>, calls: &[Call], findings: &mut Findings) {
+    let writes_sound = |address: usize| {
+        reachable(&findings.graph, address).iter().any(|callee| {
+            findings
+                .writes
+                .get(callee)
+                .is_some_and(|writes| !writes.is_empty())
+        })
+    };
+
+    // hUGEDriver takes its song in HL, GBT Player in DE. A song is only
+    // passed by its first call, as the register can still hold it for the
+    // calls after.
+    let mut calls: Vec<&Call> = calls.iter().collect();
+    calls.sort_by_key(|call| call.address);
+    let mut songs = HashSet::new();
+    let mut found = Vec::new();
+    for call in calls {
+        if let Some(tables) = call.hl.and_then(|song| huge_song(cart, code, song)) {
+            if songs.insert(tables[0].address) {
+                found.push((SoundDriver::HugeDriver, call.target, tables));
+            }
+        } else if let Some(tables) = call.de.and_then(|song| gbt_song(cart, code, song)) {
+            if writes_sound(call.target) && songs.insert(tables[0].address) {
+                found.push((SoundDriver::GbtPlayer, call.target, tables));
+            }
+        }
+    }
+
+    // Only songs for hUGEDriver are kept if there are any
+    found.sort_by_key(|&(driver, target, _)| (driver != SoundDriver::HugeDriver, target));
+    for (driver, target, tables) in found {
+        if findings.driver.is_some_and(|found| found != driver) {
+            continue;
+        }
+        findings.driver = Some(driver);
+        findings.inits.insert(target);
+        for table in tables {
+            if !findings.tables.contains(&table) {
+                findings.tables.push(table);
+            }
+        }
+    }
+}
+
+fn analyse(cart: &Cartridge, data: &Data) -> (Findings, BTreeMap<usize, SoundRole>) {
+    let mut code = HashSet::new();
+    let mut calls = Vec::new();
+    let mut findings = Findings {
+        driver: None,
+        inits: BTreeSet::new(),
+        update: None,
+        tables: Vec::new(),
+        writes: HashMap::new(),
+        graph: HashMap::new(),
+    };
+    let mut starts_sound = BTreeSet::new();
+
+    for (&address, _) in data.functions.iter() {
+        let chunks = disassembly::chunk(cart, data, address);
+        let known_values = constants::analyse(cart, data, &chunks);
+        let mut writes = BTreeSet::new();
+        let mut callees = BTreeSet::new();
+        for instruction in chunks.instructions() {
+            code.extend(instruction.address..instruction.address + instruction.size());
+
+            let target = instruction
+                .call_target()
+                .or_else(|| instruction.jump_target())
+                .filter(|&target| target != address && data.functions.is_start(target));
+            callees.extend(target);
+
+            let state = match known_values.at(instruction.address) {
+                Some(state) => *state,
+                None => continue,
+            };
+            for (access, address) in constants::accesses(cart, data, state, instruction) {
+                if access == Access::Write && is_sound_register(address) {
+                    writes.insert(address);
+                }
+            }
+            if let Some(target) = instruction.call_target() {
+                let pair = |high, low| state.pair(high, low).map(|value| value as usize);
+                calls.push(Call {
+                    address: instruction.address,
+                    target,
+                    hl: pair(Register::H, Register::L),
+                    de: pair(Register::D, Register::E),
+                });
+            }
+        }
+        if writes.contains(&NR52) {
+            starts_sound.insert(address);
+        }
+        findings.writes.insert(address, writes);
+        findings.graph.insert(address, callees);
+    }
+
+    find_songs(cart, &code, &calls, &mut findings);
+    if findings.driver.is_none() {
+        if let Some((init, play)) = devsound_routines(cart, data) {
+            findings.driver = Some(SoundDriver::DevSound);
+            findings.inits.insert(init);
+            findings.update = Some(play);
+        }
+    }
+    if findings.driver.is_none() {
+        findings.driver = ID_STRINGS
+            .iter()
+            .find(|(_, text)| contains_text(cart, text))
+            .map(|&(driver, _)| driver);
+    }
+    // Without a song to go by, what turns the sound on sets up the driver
+    if findings.inits.is_empty() {
+        findings.inits = starts_sound
+            .into_iter()
+            .filter(|&address| address != Cartridge::ENTRY_POINT)
+            .collect();
+    }
+
+    let roles = roles(&findings);
+    (findings, roles)
+}
+
+// The init routines, the routine writing to the most sound registers that
+// doesn't start a song, and what only they call
+fn roles(findings: &Findings) -> BTreeMap<usize, SoundRole> {
+    let graph = &findings.graph;
+    let written = |address: usize| {
+        reachable(graph, address)
+            .iter()
+            .filter_map(|callee| findings.writes.get(callee))
+            .flatten()
+            .collect::<BTreeSet<_>>()
+            .len()
+    };
+
+    let mut roles = BTreeMap::new();
+    for &address in &findings.inits {
+        roles.insert(address, SoundRole::Init);
+    }
+
+    // Of the routines writing to as many registers, the update is the one
+    // the others call, like an interrupt handler calling it
+    let candidates: Vec<(usize, usize)> = graph
+        .keys()
+        .filter(|&&address| address != Cartridge::ENTRY_POINT)
+        .filter(|&&address| {
+            reachable(graph, address)
+                .iter()
+                .all(|callee| !findings.inits.contains(callee))
+        })
+        .map(|&address| (address, written(address)))
+        .filter(|&(_, count)| count >= MIN_UPDATE_REGISTERS)
+        .collect();
+    let most = candidates.iter().map(|&(_, count)| count).max();
+    let busiest: Vec<usize> = candidates
+        .iter()
+        .filter(|&&(_, count)| Some(count) == most)
+        .map(|&(address, _)| address)
+        .collect();
+    let update = busiest.iter().cloned().find(|&address| {
+        reachable(graph, address)
+            .iter()
+            .all(|&callee| callee == address || !busiest.contains(&callee))
+    });
+    if let Some(update) = findings.update.or(update) {
+        roles.insert(update, SoundRole::Update);
+    }
+
+    // Functions only the driver calls are part of it
+    let mut callers: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+    for (&caller, callees) in graph {
+        for &callee in callees {
+            callers.entry(callee).or_default().insert(caller);
+        }
+    }
+    loop {
+        let mut driver: Vec<usize> = Vec::new();
+        for &address in roles.keys() {
+            for callee in graph.get(&address).into_iter().flatten() {
+                let only_driver = callers
+                    .get(callee)
+                    .into_iter()
+                    .flatten()
+                    .all(|caller| roles.contains_key(caller));
+                if !roles.contains_key(callee) && only_driver {
+                    driver.push(*callee);
+                }
+            }
+        }
+        if driver.is_empty() {
+            break;
+        }
+        for address in driver {
+            roles.insert(address, SoundRole::Driver);
+        }
+    }
+    roles
+}
+
+// What to call a function of the driver, if it is the only one in its role
+fn role_name(driver: Option<SoundDriver>, role: SoundRole) -> &'static str {
+    match (driver, role) {
+        (Some(SoundDriver::HugeDriver), SoundRole::Init) => "hUGE_init",
+        (Some(SoundDriver::HugeDriver), SoundRole::Update) => "hUGE_dosound",
+        (Some(SoundDriver::GbtPlayer), SoundRole::Init) => "gbt_play",
+        (Some(SoundDriver::GbtPlayer), SoundRole::Update) => "gbt_update",
+        (Some(SoundDriver::DevSound), SoundRole::Init) => "DevSound_Init",
+        (Some(SoundDriver::DevSound), SoundRole::Update) => "DevSound_Play",
+        (None, SoundRole::Init) => "SoundInit",
+        (None, SoundRole::Update) => "SoundUpdate",
+        (_, SoundRole::Driver) => "sound",
+    }
+}
+
+/// Finds the sound driver: the routine that plays music each frame, those
+/// that set it up or start songs, and what they call. hUGEDriver and GBT
+/// Player are recognised by the songs passed to them, whose tables are
+/// found in turn, and DevSound by its name, which the jumps to its init and
+/// play come before.
+pub struct SoundAnalyzer;
+
+impl RomAnalyzer for SoundAnalyzer {
+    fn run(&self, cart: &Cartridge, data: &mut Data) -> bool {
+        let (findings, roles) = analyse(cart, data);
+
+        let changes = findings.driver != data.sound.driver || findings.tables != data.sound.tables;
+        data.sound = Sound {
+            driver: findings.driver,
+            functions: roles,
+            tables: findings.tables,
+        };
+
+        for (&address, &role) in &data.sound.functions {
+            let name = role_name(data.sound.driver, role);
+            let shared = data
+                .sound
+                .functions
+                .values()
+                .filter(|&&other| other == role)
+                .count()
+                > 1;
+            let name = if shared || role == SoundRole::Driver {
+                format!("{}_{:04X}", name, address)
+            } else {
+                name.to_string()
+            };
+            data.functions.suggest_name(address, name);
+        }
+
+        changes
+    }
+}
+
+/// The driver, its functions and the music data found.
+pub fn report(data: &Data) -> String {
+    let mut output = String::new();
+    let driver = data.sound.driver.map_or("unknown", SoundDriver::name);
+    writeln!(output, "Driver: {}", driver).unwrap();
+
+    writeln!(output).unwrap();
+    writeln!(output, "Functions:").unwrap();
+    for (&address, role) in &data.sound.functions {
+        writeln!(
+            output,
+            "    {:04X} {:24} {}",
+            address,
+            data.name(address),
+            role
+        )
+        .unwrap();
+    }
+
+    writeln!(output).unwrap();
+    writeln!(output, "Tables:").unwrap();
+    let mut tables: Vec<&SoundTable> = data.sound.tables.iter().collect();
+    tables.sort_by_key(|table| table.address);
+    for table in tables {
+        writeln!(
+            output,
+            "    {:04X} {}",
+            table.address,
+            data.label(table.address)
+        )
+        .unwrap();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+
+    #[test]
+    fn finds_devsound_by_its_jump_table() {
+        // call DS_Init / .loop: call DS_Play / jr .loop
+        // DS_Init: jp $0300 / DS_Play: jp $0310 / DS_Stop: jp $0320
+        let mut table = vec![0xC3, 0x00, 0x03, 0xC3, 0x10, 0x03, 0xC3, 0x20, 0x03];
+        table.extend_from_slice(b"DevSound GB music player");
+        let cart = Cartridge::with_code(&[
+            (0x100, &[0xC3, 0x50, 0x01]),
+            (0x150, &[0xCD, 0x00, 0x02, 0xCD, 0x03, 0x02, 0x18, 0xFB]),
+            (0x200, &table),
+            // ld a,$80 / ldh [rNR52],a / ret
+            (0x300, &[0x3E, 0x80, 0xE0, 0x26, 0xC9]),
+            // xor a / ldh [rNR12],a / ldh [rNR22],a / ret
+            (0x310, &[0xAF, 0xE0, 0x12, 0xE0, 0x17, 0xC9]),
+            (0x320, &[0xC9]),
+        ]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+
+        assert_eq!(data.sound.driver, Some(SoundDriver::DevSound));
+        assert_eq!(data.sound.functions[&0x200], SoundRole::Init);
+        assert_eq!(data.sound.functions[&0x203], SoundRole::Update);
+        assert_eq!(data.functions.get(0x200).unwrap().name, "DevSound_Init");
+        assert_eq!(data.functions.get(0x203).unwrap().name, "DevSound_Play");
+    }
+}
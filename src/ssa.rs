@@ -0,0 +1,432 @@
+use convention::{implicit_defs, implicit_uses};
+use data::Data;
+use disassembly::ChunkSet;
+use ir::*;
+use stack;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SsaVar {
+    pub var: Var,
+    pub version: u32,
+}
+
+impl Variable for SsaVar {
+    fn base(&self) -> Var {
+        self.var
+    }
+}
+
+impl SsaVar {
+    pub fn initial(var: Var) -> SsaVar {
+        SsaVar { var, version: 0 }
+    }
+}
+
+// Versions are left out: the output keeps every version of a variable in the
+// same place, so they never need to be told apart when printing.
+impl fmt::Display for SsaVar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.var)
+    }
+}
+
+/// Variables that carry values between chunks. Temporaries never outlive the
+/// instruction that defines them, so they are not tracked.
+pub const TRACKED_VARS: [Var; 13] = [
+    Var::Register(Register::A),
+    Var::Register(Register::B),
+    Var::Register(Register::C),
+    Var::Register(Register::D),
+    Var::Register(Register::E),
+    Var::Register(Register::H),
+    Var::Register(Register::L),
+    Var::Flag(Flag::Zero),
+    Var::Flag(Flag::Subtract),
+    Var::Flag(Flag::HalfCarry),
+    Var::Flag(Flag::Carry),
+    Var::StackPointer,
+    Var::InterruptsEnabled,
+];
+
+pub struct Phi {
+    pub target: SsaVar,
+    /// One source per predecessor, `None` standing for the function entry.
+    pub sources: Vec<(Option<usize>, SsaVar)>,
+}
+
+pub struct SsaStmt {
+    pub address: usize,
+    pub stmt: Stmt<SsaVar>,
+    pub implicit_uses: Vec<SsaVar>,
+    pub implicit_defs: Vec<SsaVar>,
+}
+
+impl SsaStmt {
+    fn visit_uses<F: FnMut(SsaVar)>(&self, func: &mut F) {
+        self.stmt
+            .visit_exprs(|expr| expr.visit_vars(&mut |var| func(var)));
+        for var in &self.implicit_uses {
+            func(*var);
+        }
+    }
+}
+
+pub struct Block {
+    pub address: usize,
+    pub phis: Vec<Phi>,
+    pub stmts: Vec<SsaStmt>,
+    pub successors: Vec<usize>,
+}
+
+pub struct SsaFunction {
+    pub entry: usize,
+    pub blocks: Vec<Block>,
+}
+
+struct Renamer {
+    versions: HashMap<Var, u32>,
+}
+
+impl Renamer {
+    fn define(&mut self, var: Var, current: &mut HashMap<Var, SsaVar>) -> SsaVar {
+        let version = self.versions.entry(var).or_insert(0);
+        *version += 1;
+        let ssa_var = SsaVar {
+            var,
+            version: *version,
+        };
+        current.insert(var, ssa_var);
+        ssa_var
+    }
+}
+
+fn lookup(current: &HashMap<Var, SsaVar>, var: Var) -> SsaVar {
+    current
+        .get(&var)
+        .cloned()
+        .unwrap_or_else(|| SsaVar::initial(var))
+}
+
+pub fn build(chunks: &ChunkSet, data: &Data) -> SsaFunction {
+    let predecessors = chunks.predecessors();
+    let entry = chunks.entry_index();
+    let stack = stack::analyse(chunks);
+    let mut renamer = Renamer {
+        versions: HashMap::new(),
+    };
+
+    let mut blocks: Vec<Block> = chunks
+        .iter()
+        .map(|chunk| Block {
+            address: chunk.address(),
+            phis: Vec::new(),
+            stmts: Vec::new(),
+            successors: chunks.successors(chunk.index()),
+        })
+        .collect();
+
+    let mut block_out: Vec<Option<HashMap<Var, SsaVar>>> = vec![None; blocks.len()];
+
+    for index in chunks.reverse_postorder() {
+        let block_predecessors = &predecessors[index];
+        let mut current = HashMap::new();
+
+        if index == entry && block_predecessors.is_empty() {
+            // Every variable starts out at version 0
+        } else if index != entry && block_predecessors.len() == 1 {
+            current = block_out[block_predecessors[0]].clone().unwrap();
+        } else {
+            for &var in TRACKED_VARS.iter() {
+                let target = renamer.define(var, &mut current);
+                blocks[index].phis.push(Phi {
+                    target,
+                    sources: Vec::new(),
+                });
+            }
+        }
+
+        for instruction in chunks.get(index).instructions() {
+            let stmts = chunks.lift(&stack, instruction);
+
+            for stmt in stmts {
+                let implicit_uses = implicit_uses(data, chunks, instruction.address, &stmt)
+                    .into_iter()
+                    .map(|var| lookup(&current, var))
+                    .collect();
+
+                let ssa_stmt = {
+                    let current_uses = current.clone();
+                    stmt.map_vars(
+                        |var| lookup(&current_uses, var),
+                        |var| renamer.define(var, &mut current),
+                    )
+                };
+
+                let implicit_defs = implicit_defs(data, &stmt)
+                    .into_iter()
+                    .map(|var| renamer.define(var, &mut current))
+                    .collect();
+
+                blocks[index].stmts.push(SsaStmt {
+                    address: instruction.address,
+                    stmt: ssa_stmt,
+                    implicit_uses,
+                    implicit_defs,
+                });
+            }
+        }
+
+        block_out[index] = Some(current);
+    }
+
+    // Now that every chunk has been renamed, the phi sources can be filled in
+    for index in 0..blocks.len() {
+        let mut phis = std::mem::take(&mut blocks[index].phis);
+
+        for phi in phis.iter_mut() {
+            if index == entry {
+                phi.sources.push((None, SsaVar::initial(phi.target.var)));
+            }
+            for &predecessor in &predecessors[index] {
+                if let Some(out) = &block_out[predecessor] {
+                    phi.sources
+                        .push((Some(predecessor), lookup(out, phi.target.var)));
+                }
+            }
+        }
+
+        blocks[index].phis = phis;
+    }
+
+    let mut function = SsaFunction { entry, blocks };
+    function.remove_trivial_phis();
+    function
+}
+
+impl SsaFunction {
+    fn replace_uses(&mut self, replacements: &HashMap<SsaVar, SsaVar>) {
+        let resolve = |mut var: SsaVar| {
+            while let Some(replacement) = replacements.get(&var) {
+                var = *replacement;
+            }
+            var
+        };
+
+        for block in self.blocks.iter_mut() {
+            for phi in block.phis.iter_mut() {
+                for source in phi.sources.iter_mut() {
+                    source.1 = resolve(source.1);
+                }
+            }
+            for stmt in block.stmts.iter_mut() {
+                stmt.stmt.visit_exprs_mut(|expr| {
+                    expr.substitute(&mut |var| Some(Expr::Var(resolve(var))))
+                });
+                for var in stmt.implicit_uses.iter_mut() {
+                    *var = resolve(*var);
+                }
+            }
+        }
+    }
+
+    /// Removes phis whose sources are all the same value (or the phi itself),
+    /// replacing their uses with that value.
+    fn remove_trivial_phis(&mut self) {
+        loop {
+            let mut replacements = HashMap::new();
+
+            for block in self.blocks.iter_mut() {
+                block.phis.retain(|phi| {
+                    let mut unique = None;
+
+                    for &(_, source) in &phi.sources {
+                        if source == phi.target || Some(source) == unique {
+                            continue;
+                        }
+                        if unique.is_some() {
+                            return true;
+                        }
+                        unique = Some(source);
+                    }
+
+                    if let Some(unique) = unique {
+                        replacements.insert(phi.target, unique);
+                    }
+                    false
+                });
+            }
+
+            if replacements.is_empty() {
+                break;
+            }
+
+            self.replace_uses(&replacements);
+        }
+    }
+
+    pub fn use_counts(&self) -> HashMap<SsaVar, usize> {
+        let mut counts = HashMap::new();
+
+        for block in &self.blocks {
+            for phi in &block.phis {
+                for &(_, source) in &phi.sources {
+                    *counts.entry(source).or_insert(0) += 1;
+                }
+            }
+            for stmt in &block.stmts {
+                stmt.visit_uses(&mut |var| *counts.entry(var).or_insert(0) += 1);
+            }
+        }
+
+        counts
+    }
+
+    /// Removes assignments and phis whose value is never used, until no more
+    /// can be removed.
+    pub fn eliminate_dead_code(&mut self) {
+        loop {
+            let counts = self.use_counts();
+            let is_used = |var: &SsaVar| counts.get(var).is_some_and(|&count| count > 0);
+            let mut changed = false;
+
+            for block in self.blocks.iter_mut() {
+                let phi_count = block.phis.len();
+                block.phis.retain(|phi| is_used(&phi.target));
+
+                let stmt_count = block.stmts.len();
+                block.stmts.retain(|stmt| {
+                    let defined_vars = stmt.stmt.defined_vars();
+                    defined_vars.is_empty()
+                        || defined_vars.iter().any(&is_used)
+                        || stmt.stmt.has_side_effects()
+                });
+
+                changed |= phi_count != block.phis.len() || stmt_count != block.stmts.len();
+            }
+
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    /// Folds flag and temporary definitions that have a single use later in
+    /// the same chunk into that use, so `cp $90 / jr nz` reads as one
+    /// comparison.
+    pub fn propagate_expressions(&mut self) {
+        let counts = self.use_counts();
+
+        for block in self.blocks.iter_mut() {
+            let mut index = 0;
+
+            while index < block.stmts.len() {
+                if let Some(target) = propagation_target(block, index, &counts) {
+                    let stmt = block.stmts.remove(index);
+                    let (var, value) = match stmt.stmt {
+                        Stmt::Assign(var, value) => (var, value),
+                        _ => unreachable!(),
+                    };
+
+                    // The use has moved up one position after the removal
+                    let user = &mut block.stmts[target - 1];
+                    user.stmt.visit_exprs_mut(|expr| {
+                        expr.substitute(&mut |used| {
+                            if used == var {
+                                Some(value.clone())
+                            } else {
+                                None
+                            }
+                        });
+                        *expr = simplify(expr.clone());
+                    });
+                } else {
+                    index += 1;
+                }
+            }
+        }
+    }
+}
+
+fn propagation_target(
+    block: &Block,
+    index: usize,
+    counts: &HashMap<SsaVar, usize>,
+) -> Option<usize> {
+    let (var, value) = match &block.stmts[index].stmt {
+        Stmt::Assign(var, value) => (*var, value),
+        _ => return None,
+    };
+
+    let is_candidate = matches!(var.var, Var::Flag(_) | Var::Temp(_));
+
+    if !is_candidate || counts.get(&var).cloned() != Some(1) {
+        return None;
+    }
+
+    let read_vars: Vec<Var> = value.vars().into_iter().map(|used| used.var).collect();
+    let reads_memory = value.reads_memory();
+
+    for (offset, stmt) in block.stmts[index + 1..].iter().enumerate() {
+        let mut is_user = false;
+        stmt.stmt.visit_exprs(|expr| {
+            expr.visit_vars(&mut |used| is_user |= used == var);
+        });
+
+        if is_user {
+            return Some(index + 1 + offset);
+        }
+
+        if stmt.implicit_uses.contains(&var) {
+            return None;
+        }
+
+        // Moving the expression past a redefinition of anything it reads
+        // would change its meaning once versions are dropped
+        let redefines = stmt
+            .stmt
+            .defined_vars()
+            .into_iter()
+            .chain(stmt.implicit_defs.iter().cloned())
+            .any(|defined| read_vars.contains(&defined.var));
+
+        let writes_memory = matches!(stmt.stmt, Stmt::Store(..) | Stmt::Call { .. });
+
+        if redefines || (reads_memory && writes_memory) {
+            return None;
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+    use disassembly;
+    use gb::*;
+
+    #[test]
+    fn removes_flags_nothing_reads() {
+        // add b / adc c / ld [$C000],a / ret: only the carry of the add is
+        // read
+        let cart = Cartridge::with_function(&[0x80, 0x89, 0xEA, 0x00, 0xC0, 0xC9]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        let mut function = build(&disassembly::chunk(&cart, &data, 0x200), &data);
+        function.eliminate_dead_code();
+
+        let defined: Vec<Var> = function
+            .blocks
+            .iter()
+            .flat_map(|block| block.stmts.iter())
+            .flat_map(|stmt| stmt.stmt.defined_vars())
+            .map(|var| var.var)
+            .collect();
+        assert!(defined.contains(&Var::Flag(Flag::Carry)));
+        assert!(!defined.contains(&Var::Flag(Flag::HalfCarry)));
+        assert!(!defined.contains(&Var::Flag(Flag::Subtract)));
+        assert!(!defined.contains(&Var::Flag(Flag::Zero)));
+    }
+}
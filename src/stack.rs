@@ -0,0 +1,366 @@
+use disassembly::ChunkSet;
+use gb::*;
+use instruction_walker::{walk_chunks, Join};
+use ir::*;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Number of bytes pushed since function entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackDepth {
+    Known(i32),
+    /// Paths disagree on the depth, or SP was loaded with something other
+    /// than an address on the stack.
+    Unknown,
+}
+
+impl Join for StackDepth {
+    fn join(&self, other: &StackDepth) -> StackDepth {
+        if self == other {
+            *self
+        } else {
+            StackDepth::Unknown
+        }
+    }
+}
+
+impl fmt::Display for StackDepth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackDepth::Known(depth) => write!(f, "{}", depth),
+            StackDepth::Unknown => write!(f, "?"),
+        }
+    }
+}
+
+fn step(depth: StackDepth, op_code: OpCode) -> StackDepth {
+    let depth = match depth {
+        StackDepth::Known(depth) => depth,
+        StackDepth::Unknown => return StackDepth::Unknown,
+    };
+
+    // Calls are assumed to leave the stack as they found it
+    match op_code {
+        OpCode::PUSH_BC | OpCode::PUSH_DE | OpCode::PUSH_HL | OpCode::PUSH_AF => {
+            StackDepth::Known(depth + 2)
+        }
+        OpCode::POP_BC | OpCode::POP_DE | OpCode::POP_HL | OpCode::POP_AF => {
+            StackDepth::Known(depth - 2)
+        }
+        OpCode::DEC_SP => StackDepth::Known(depth + 1),
+        OpCode::INC_SP => StackDepth::Known(depth - 1),
+        OpCode::ADD_SP_r8(r8) => StackDepth::Known(depth - i32::from(r8.value)),
+        OpCode::LD_SP_d16(_) | OpCode::LD_SP_HL => StackDepth::Unknown,
+        _ => StackDepth::Known(depth),
+    }
+}
+
+/// What HL holds, as far as it can move SP: a number to add SP to, or an
+/// address on the stack, by the depth SP would have there.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HlValue {
+    Offset(i32),
+    Stack(i32),
+    Unknown,
+}
+
+/// The stack depth, along with HL, which SDCC moves SP through for frames
+/// too big for `add sp,n`: `ld hl,-n / add hl,sp / ld sp,hl`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackState {
+    pub depth: StackDepth,
+    hl: HlValue,
+}
+
+impl Join for StackState {
+    fn join(&self, other: &StackState) -> StackState {
+        StackState {
+            depth: self.depth.join(&other.depth),
+            hl: if self.hl == other.hl {
+                self.hl
+            } else {
+                HlValue::Unknown
+            },
+        }
+    }
+}
+
+impl StackState {
+    pub fn entry() -> StackState {
+        StackState {
+            depth: StackDepth::Known(0),
+            hl: HlValue::Unknown,
+        }
+    }
+
+    pub fn step(self, instruction: &Instruction) -> StackState {
+        let writes_hl = || {
+            lift(instruction).iter().any(|stmt| {
+                stmt.defined_vars().iter().any(|&var| {
+                    var == Var::Register(Register::H) || var == Var::Register(Register::L)
+                })
+            })
+        };
+
+        let hl = match (instruction.op_code, self.hl, self.depth) {
+            (OpCode::LD_HL_d16(value), ..) => HlValue::Offset(i32::from(value.value as i16)),
+            (OpCode::LD_HL_SPp_r8(value), _, StackDepth::Known(depth)) => {
+                HlValue::Stack(depth - i32::from(value.value))
+            }
+            (OpCode::ADD_HL_SP, HlValue::Offset(offset), StackDepth::Known(depth)) => {
+                HlValue::Stack(depth - offset)
+            }
+            (OpCode::INC_HL, HlValue::Stack(depth), _) => HlValue::Stack(depth - 1),
+            (OpCode::DEC_HL, HlValue::Stack(depth), _) => HlValue::Stack(depth + 1),
+            _ if instruction.is_call() || writes_hl() => HlValue::Unknown,
+            (_, hl, _) => hl,
+        };
+
+        let depth = match (instruction.op_code, self.hl) {
+            (OpCode::LD_SP_HL, HlValue::Stack(depth)) => StackDepth::Known(depth),
+            (op_code, _) => step(self.depth, op_code),
+        };
+
+        StackState { depth, hl }
+    }
+}
+
+fn pushed_pair(op_code: OpCode) -> Option<(Register, Register)> {
+    match op_code {
+        OpCode::PUSH_BC => Some((Register::B, Register::C)),
+        OpCode::PUSH_DE => Some((Register::D, Register::E)),
+        OpCode::PUSH_HL => Some((Register::H, Register::L)),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ComputedJump {
+    pub push_address: usize,
+    pub high: Register,
+    pub low: Register,
+}
+
+#[derive(Debug)]
+pub enum StackDiagnostic {
+    /// A return with bytes still pushed, or with bytes of the caller's stack
+    /// popped.
+    UnbalancedReturn {
+        address: usize,
+        depth: i32,
+    },
+    UnknownDepthReturn {
+        address: usize,
+    },
+}
+
+impl fmt::Display for StackDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StackDiagnostic::UnbalancedReturn { address, depth } if depth > 0 => write!(
+                f,
+                "return at ${:04X} with {} bytes left on the stack",
+                address, depth
+            ),
+            StackDiagnostic::UnbalancedReturn { address, depth } => write!(
+                f,
+                "return at ${:04X} after popping {} bytes of the caller's stack",
+                address, -depth
+            ),
+            StackDiagnostic::UnknownDepthReturn { address } => {
+                write!(f, "return at ${:04X} with an unknown stack depth", address)
+            }
+        }
+    }
+}
+
+pub struct StackAnalysis {
+    depths: HashMap<usize, StackDepth>,
+    computed_jumps: HashMap<usize, ComputedJump>,
+    pub diagnostics: Vec<StackDiagnostic>,
+}
+
+impl StackAnalysis {
+    /// The stack depth before the instruction at `address`.
+    pub fn depth_at(&self, address: usize) -> Option<StackDepth> {
+        self.depths.get(&address).cloned()
+    }
+
+    /// The `push rr` feeding a return that really jumps to the pushed value.
+    pub fn computed_jump(&self, address: usize) -> Option<&ComputedJump> {
+        self.computed_jumps.get(&address)
+    }
+
+    pub fn is_computed_jump_push(&self, address: usize) -> bool {
+        self.computed_jumps
+            .values()
+            .any(|jump| jump.push_address == address)
+    }
+
+    /// Lifts an instruction, turning `push rr / ret` into a jump to the
+    /// pushed value rather than a return.
+    pub fn lift(&self, instruction: &Instruction) -> Vec<Stmt> {
+        match self.computed_jump(instruction.address) {
+            Some(jump) => vec![Stmt::Jump {
+                condition: None,
+                target: Target::Indirect(Expr::pair(jump.high, jump.low)),
+            }],
+            None if self.is_computed_jump_push(instruction.address) => Vec::new(),
+            None => lift(instruction),
+        }
+    }
+}
+
+// Looks for `push rr` earlier in the chunk with nothing in between touching
+// the stack or the pushed pair.
+fn find_computed_jump(instructions: &[Instruction]) -> Option<ComputedJump> {
+    let (ret, before) = instructions.split_last()?;
+    if !matches!(ret.op_code, OpCode::RET) {
+        return None;
+    }
+
+    for (index, instruction) in before.iter().enumerate().rev() {
+        if let Some((high, low)) = pushed_pair(instruction.op_code) {
+            let pair_written = before[index + 1..].iter().any(|later| {
+                lift(later).iter().any(|stmt| {
+                    stmt.defined_vars()
+                        .iter()
+                        .any(|&var| var == Var::Register(high) || var == Var::Register(low))
+                })
+            });
+
+            return if pair_written {
+                None
+            } else {
+                Some(ComputedJump {
+                    push_address: instruction.address,
+                    high,
+                    low,
+                })
+            };
+        }
+
+        if step(StackDepth::Known(0), instruction.op_code) != StackDepth::Known(0)
+            || instruction.is_call()
+        {
+            return None;
+        }
+    }
+
+    None
+}
+
+pub fn analyse(chunks: &ChunkSet) -> StackAnalysis {
+    let depths: HashMap<usize, StackDepth> =
+        walk_chunks(chunks, StackState::entry(), |state, instruction| {
+            state.step(instruction)
+        })
+        .into_iter()
+        .map(|(address, state)| (address, state.depth))
+        .collect();
+
+    let mut computed_jumps = HashMap::new();
+    let mut diagnostics = Vec::new();
+
+    for chunk in chunks.iter() {
+        let instructions = chunk.instructions();
+
+        for (index, instruction) in instructions.iter().enumerate() {
+            if !instruction.is_return() {
+                continue;
+            }
+
+            let address = instruction.address;
+            let depth = depths.get(&address).cloned().unwrap_or(StackDepth::Unknown);
+
+            match depth {
+                StackDepth::Known(0) => (),
+                StackDepth::Known(depth) => {
+                    match find_computed_jump(&instructions[..=index]).filter(|_| depth == 2) {
+                        Some(jump) => {
+                            computed_jumps.insert(address, jump);
+                        }
+                        None => {
+                            diagnostics.push(StackDiagnostic::UnbalancedReturn { address, depth })
+                        }
+                    }
+                }
+                StackDepth::Unknown => {
+                    diagnostics.push(StackDiagnostic::UnknownDepthReturn { address })
+                }
+            }
+        }
+    }
+
+    StackAnalysis {
+        depths,
+        computed_jumps,
+        diagnostics,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+    use data::Data;
+    use disassembly;
+
+    fn analyse_function(code: &[u8]) -> StackAnalysis {
+        let cart = Cartridge::with_function(code);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        analyse(&disassembly::chunk(&cart, &data, 0x200))
+    }
+
+    #[test]
+    fn balanced_functions_return_at_depth_zero() {
+        // push bc / push de / pop de / pop bc / ret
+        let stack = analyse_function(&[0xC5, 0xD5, 0xD1, 0xC1, 0xC9]);
+        assert_eq!(stack.depth_at(0x202), Some(StackDepth::Known(4)));
+        assert_eq!(stack.depth_at(0x204), Some(StackDepth::Known(0)));
+        assert!(stack.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn reports_unbalanced_returns() {
+        // push bc / push de / pop de / ret
+        let stack = analyse_function(&[0xC5, 0xD5, 0xD1, 0xC9]);
+        match stack.diagnostics[..] {
+            [StackDiagnostic::UnbalancedReturn {
+                address: 0x203,
+                depth: 2,
+            }] => (),
+            _ => panic!("{:?}", stack.diagnostics),
+        }
+    }
+
+    #[test]
+    fn push_and_ret_is_a_jump() {
+        // ld hl,$0300 / push hl / ret
+        let stack = analyse_function(&[0x21, 0x00, 0x03, 0xE5, 0xC9]);
+        assert!(stack.diagnostics.is_empty());
+        let jump = stack.computed_jump(0x204).unwrap();
+        assert_eq!(jump.push_address, 0x203);
+        assert_eq!((jump.high, jump.low), (Register::H, Register::L));
+    }
+
+    #[test]
+    fn follows_frames_made_with_add_sp() {
+        // add sp,-4 / add sp,4 / ret
+        let stack = analyse_function(&[0xE8, 0xFC, 0xE8, 0x04, 0xC9]);
+        assert_eq!(stack.depth_at(0x202), Some(StackDepth::Known(4)));
+        assert!(stack.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn follows_frames_made_through_hl() {
+        // ld hl,-300 / add hl,sp / ld sp,hl / ld hl,300 / add hl,sp
+        // ld sp,hl / ret
+        let stack = analyse_function(&[
+            0x21, 0xD4, 0xFE, 0x39, 0xF9, 0x21, 0x2C, 0x01, 0x39, 0xF9, 0xC9,
+        ]);
+        assert_eq!(stack.depth_at(0x205), Some(StackDepth::Known(300)));
+        assert_eq!(stack.depth_at(0x20A), Some(StackDepth::Known(0)));
+        assert!(stack.diagnostics.is_empty());
+    }
+}
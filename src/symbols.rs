@@ -0,0 +1,118 @@
+use data::Data;
+use gb::is_io;
+use parse::LineError;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt::Write;
+use trace::BankAddress;
+
+/// Names for functions, labels and variables, read from symbol files in the
+/// format RGBDS, BGB and no$gmb share: a `bank:address` followed by a name on
+/// each line, with `;` starting a comment.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    names: HashMap<BankAddress, String>,
+}
+
+impl Symbols {
+    pub fn parse(text: &str) -> Result<Symbols, LineError> {
+        let mut names = HashMap::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = |message: String| LineError {
+                line: index + 1,
+                message,
+            };
+
+            let mut words = line.split_whitespace();
+            let address = words.next().unwrap();
+            let address = BankAddress::parse(address)
+                .ok_or_else(|| error(format!("invalid address `{}`", address)))?;
+            let name = words
+                .next()
+                .ok_or_else(|| error("missing name".to_string()))?;
+
+            names.insert(address, name.to_string());
+        }
+
+        Ok(Symbols { names })
+    }
+
+    pub fn merge(&mut self, other: Symbols) {
+        self.names.extend(other.names);
+    }
+
+    /// Names an address of the flat address space.
+    pub fn insert(&mut self, address: usize, name: String) {
+        self.names.insert(BankAddress::from_flat(address), name);
+    }
+
+    /// The name given to an address of the flat address space.
+    pub fn get(&self, address: usize) -> Option<&str> {
+        self.names
+            .get(&BankAddress::from_flat(address))
+            .map(String::as_str)
+    }
+}
+
+/// The names of every function found, along with the symbols read in, as a
+/// symbol file for debuggers to load.
+pub fn export(data: &Data, variables: &[usize]) -> String {
+    let mut names: BTreeMap<BankAddress, String> = data
+        .symbols
+        .names
+        .iter()
+        .map(|(&address, name)| (address, name.clone()))
+        .collect();
+    for (&address, function) in data.functions.iter() {
+        names.insert(BankAddress::from_flat(address), function.name.clone());
+    }
+    for &address in &data.pointers.labels {
+        names
+            .entry(BankAddress::from_flat(address))
+            .or_insert_with(|| data.label(address));
+    }
+    for &address in variables.iter().filter(|&&address| !is_io(address)) {
+        if let Some(name) = data.variable_name(address) {
+            names.entry(BankAddress::from_flat(address)).or_insert(name);
+        }
+    }
+
+    let mut output = String::new();
+    writeln!(output, "; File generated by gb-decompiler").unwrap();
+    for (address, name) in names {
+        writeln!(output, "{} {}", address, name).unwrap();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_by_bank() {
+        let symbols = Symbols::parse(
+            "; RGBDS\n00:0150 Start\n01:4000 Bank1 ; comment\n02:4000 Bank2\nC0:C000 wCounter\n",
+        )
+        .unwrap();
+        assert_eq!(symbols.get(0x150), Some("Start"));
+        assert_eq!(symbols.get(0x4000), Some("Bank1"));
+        assert_eq!(symbols.get(0xC000), Some("wCounter"));
+        assert_eq!(symbols.get(0x151), None);
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        let error = Symbols::parse("00:0150 Start\n0150 Main\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: invalid address `0150`");
+
+        let error = Symbols::parse("\n00:0150\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: missing name");
+    }
+}
@@ -0,0 +1,410 @@
+use data::*;
+use disassembly;
+use gb::*;
+use parse::{strip_comment, LineError};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fmt::Write;
+
+/// Strings found by scanning, rather than through a pointer, need at least
+/// this many characters.
+const MIN_STRING_LENGTH: usize = 4;
+/// Longer runs of characters are more likely to be something else.
+const MAX_STRING_SIZE: usize = 1024;
+
+/// How a game's text is encoded, read from RGBDS `charmap` lines:
+///
+/// ```text
+/// charmap "A", $80
+/// charmap "<NEXT>", $4E
+/// ```
+///
+/// Other lines are ignored, so the include file of a disassembly can be used
+/// as it is.
+#[derive(Clone, Debug, Default)]
+pub struct Charmap {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+fn parse_byte(text: &str) -> Option<u8> {
+    let text = text.trim();
+    let value = if let Some(digits) = text.strip_prefix('$') {
+        u32::from_str_radix(digits, 16).ok()?
+    } else if let Some(digits) = text.strip_prefix("0x") {
+        u32::from_str_radix(digits, 16).ok()?
+    } else if let Some(digits) = text.strip_prefix('%') {
+        u32::from_str_radix(digits, 2).ok()?
+    } else {
+        text.parse().ok()?
+    };
+    if value <= 0xFF {
+        Some(value as u8)
+    } else {
+        None
+    }
+}
+
+// The string at the start of `text` and what follows it
+fn parse_string(text: &str) -> Option<(String, &str)> {
+    let text = text.strip_prefix('"')?;
+    let mut string = String::new();
+    let mut chars = text.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return Some((string, &text[index + 1..])),
+            '\\' => match chars.next()?.1 {
+                'n' => string.push('\n'),
+                't' => string.push('\t'),
+                c => string.push(c),
+            },
+            c => string.push(c),
+        }
+    }
+    None
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('{', "\\{")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+}
+
+impl Charmap {
+    pub fn parse(text: &str) -> Result<Charmap, LineError> {
+        let mut entries = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = strip_comment(line, ';').trim();
+            let rest = match line.strip_prefix("charmap") {
+                Some(rest) if rest.starts_with(char::is_whitespace) => rest.trim(),
+                _ => continue,
+            };
+
+            let error = |message: &str| LineError {
+                line: index + 1,
+                message: message.to_string(),
+            };
+
+            let (string, rest) = parse_string(rest).ok_or_else(|| error("expected a string"))?;
+            let values = rest
+                .trim()
+                .strip_prefix(',')
+                .ok_or_else(|| error("expected `,` after the string"))?;
+            let bytes = values
+                .split(',')
+                .map(parse_byte)
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| error("invalid byte value"))?;
+            if string.is_empty() {
+                return Err(error("empty string"));
+            }
+
+            entries.push((string, bytes));
+        }
+
+        Ok(Charmap { entries })
+    }
+
+    /// Printable ASCII, for games that use it.
+    pub fn ascii() -> Charmap {
+        Charmap {
+            entries: (0x20u8..0x7F)
+                .map(|byte| ((byte as char).to_string(), vec![byte]))
+                .collect(),
+        }
+    }
+
+    // The longest entry `bytes` starts with, and its length in bytes
+    fn decode_at(&self, bytes: &[u8]) -> Option<(&str, usize)> {
+        self.entries
+            .iter()
+            .filter(|(_, encoded)| bytes.starts_with(encoded))
+            .max_by_key(|(_, encoded)| encoded.len())
+            .map(|(string, encoded)| (string.as_str(), encoded.len()))
+    }
+
+    fn name_of(&self, byte: u8) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(_, encoded)| encoded.as_slice() == [byte])
+            .map(|(string, _)| string.as_str())
+    }
+
+    /// Charmaps under which `phrase` appears in the ROM, by relative search:
+    /// letters of each case are assumed to be in alphabetical order, and
+    /// anything else in the phrase is mapped to what is found in its place.
+    pub fn guess(cart: &Cartridge, phrase: &str) -> Vec<(usize, Charmap)> {
+        let chars: Vec<char> = phrase.chars().collect();
+        let letters = chars.iter().filter(|c| c.is_ascii_alphabetic()).count();
+        if letters < 3 {
+            return Vec::new();
+        }
+
+        let mut found: Vec<(usize, Charmap)> = Vec::new();
+        let mut offsets_seen = HashSet::new();
+
+        for address in 0..cart.rom_size().saturating_sub(chars.len() - 1) {
+            let mut upper: Option<i32> = None;
+            let mut lower: Option<i32> = None;
+            let mut others: BTreeMap<char, u8> = BTreeMap::new();
+
+            let matches = chars.iter().enumerate().all(|(index, &c)| {
+                let byte = cart[address + index];
+                let (offset, first) = match c {
+                    'A'..='Z' => (&mut upper, 'A'),
+                    'a'..='z' => (&mut lower, 'a'),
+                    _ => return *others.entry(c).or_insert(byte) == byte,
+                };
+                let value = byte as i32 - (c as i32 - first as i32);
+                *offset.get_or_insert(value) == value
+            });
+
+            // Everything else must map to different bytes than the letters
+            let distinct = {
+                let letter_bytes: HashSet<u8> = chars
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| c.is_ascii_alphabetic())
+                    .map(|(index, _)| cart[address + index])
+                    .collect();
+                let other_bytes: HashSet<u8> = others.values().cloned().collect();
+                letter_bytes.is_disjoint(&other_bytes) && other_bytes.len() == others.len()
+            };
+
+            if !matches || !distinct || !offsets_seen.insert((upper, lower, others.clone())) {
+                continue;
+            }
+
+            let mut entries = Vec::new();
+            for &(offset, first) in &[(upper, b'A'), (lower, b'a')] {
+                if let Some(offset) = offset {
+                    for letter in 0..26 {
+                        let byte = offset + letter;
+                        if (0..0x100).contains(&byte) {
+                            let c = ((first + letter as u8) as char).to_string();
+                            entries.push((c, vec![byte as u8]));
+                        }
+                    }
+                }
+            }
+            for (c, byte) in others {
+                entries.push((c.to_string(), vec![byte]));
+            }
+
+            found.push((address, Charmap { entries }));
+        }
+
+        found
+    }
+}
+
+impl fmt::Display for Charmap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (string, bytes) in &self.entries {
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("${:02X}", byte)).collect();
+            writeln!(f, "charmap \"{}\", {}", escape(string), bytes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A charmap, and the byte ending each string.
+#[derive(Clone, Debug)]
+pub struct Encoding {
+    pub charmap: Charmap,
+    pub terminator: u8,
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding {
+            charmap: Charmap::ascii(),
+            terminator: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TextString {
+    pub address: usize,
+    pub text: String,
+    /// The number of bytes, including the terminator.
+    pub size: usize,
+    pub terminator: u8,
+    /// What the terminator is called in the charmap, if it has a name.
+    pub terminator_name: Option<String>,
+}
+
+impl TextString {
+    /// The string as an RGBDS `db` operand.
+    pub fn to_db(&self) -> String {
+        match &self.terminator_name {
+            Some(name) => format!("\"{}{}\"", escape(&self.text), escape(name)),
+            None if self.text.is_empty() => format!("${:02X}", self.terminator),
+            None => format!("\"{}\", ${:02X}", escape(&self.text), self.terminator),
+        }
+    }
+}
+
+// The string at `address`, if every byte up to the terminator before `end`
+// is in the charmap
+fn decode(cart: &Cartridge, encoding: &Encoding, address: usize, end: usize) -> Option<TextString> {
+    let end = end.min(address + MAX_STRING_SIZE);
+    let mut text = String::new();
+    let mut characters = 0;
+    let mut position = address;
+
+    while position < end {
+        if cart[position] == encoding.terminator {
+            return Some(TextString {
+                address,
+                text,
+                size: position + 1 - address,
+                terminator: encoding.terminator,
+                terminator_name: encoding
+                    .charmap
+                    .name_of(encoding.terminator)
+                    .map(str::to_string),
+            })
+            .filter(|_| characters > 0);
+        }
+        let (string, size) = encoding.charmap.decode_at(&cart[position..end])?;
+        text.push_str(string);
+        characters += 1;
+        position += size;
+    }
+    None
+}
+
+// Mostly letters, rather than bytes that happen to be in the charmap
+fn is_plausible(text: &TextString) -> bool {
+    let chars: Vec<char> = text.text.chars().collect();
+    let letters = chars.iter().filter(|c| c.is_alphabetic()).count();
+    chars.len() >= MIN_STRING_LENGTH && letters * 2 >= chars.len()
+}
+
+/// Strings in the bytes of the ROM that aren't code. Those that code or a
+/// pointer table points to only need one character; the rest have to look
+/// like text.
+pub fn find_strings(
+    cart: &Cartridge,
+    data: &Data,
+    encoding: &Encoding,
+) -> BTreeMap<usize, TextString> {
+    let code: HashSet<usize> = data
+        .functions
+        .iter()
+        .flat_map(|(&address, _)| disassembly::collect_instructions(cart, data, address))
+        .flat_map(|instruction| instruction.address..instruction.address + instruction.size())
+        .collect();
+    let is_text = |address: usize| {
+        !code.contains(&address)
+            && data.pointers.table_at(address).is_none()
+            && !data.is_graphics(address)
+            && data.compressed_at(address).is_none()
+            && data.sound.table_at(address).is_none()
+    };
+
+    let end = cart.rom_size().min(ROM_END);
+    let mut strings = BTreeMap::new();
+    let mut address = Cartridge::HEADER_END;
+
+    while address < end {
+        if !is_text(address) {
+            address += 1;
+            continue;
+        }
+
+        let region_end = (address..end)
+            .find(|&address| !is_text(address))
+            .unwrap_or(end);
+        while address < region_end {
+            let string = decode(cart, encoding, address, region_end)
+                .filter(|string| data.pointers.labels.contains(&address) || is_plausible(string));
+            match string {
+                Some(string) => {
+                    address += string.size;
+                    strings.insert(string.address, string);
+                }
+                None => address += 1,
+            }
+        }
+    }
+
+    strings
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// The strings as CSV, with an empty column for a translation.
+pub fn csv(data: &Data) -> String {
+    let mut output = String::new();
+    writeln!(output, "address,label,bytes,text,translation").unwrap();
+    for (&address, string) in &data.strings {
+        writeln!(
+            output,
+            "{:04X},{},{},{},",
+            address,
+            csv_field(&data.label(address)),
+            string.size,
+            csv_field(&string.text)
+        )
+        .unwrap();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHARMAP: &str = r#"; Pokémon-style text
+charmap "A", $80
+charmap "<NEXT>", $4E ; starts a new line
+charmap ";", %00100000
+charmap "é", 0xBA
+charmap "<PLAYER>", $52, $01
+NEWCHARMAP other
+"#;
+
+    #[test]
+    fn parses_charmap_lines() {
+        let charmap = Charmap::parse(CHARMAP).unwrap();
+        assert_eq!(charmap.decode_at(&[0x80, 0x4E]), Some(("A", 1)));
+        assert_eq!(charmap.decode_at(&[0x4E]), Some(("<NEXT>", 1)));
+        assert_eq!(charmap.decode_at(&[0x20]), Some((";", 1)));
+        assert_eq!(charmap.decode_at(&[0xBA]), Some(("é", 1)));
+        assert_eq!(charmap.decode_at(&[0x52, 0x01]), Some(("<PLAYER>", 2)));
+        assert_eq!(charmap.decode_at(&[0x52, 0x02]), None);
+    }
+
+    #[test]
+    fn prints_what_it_parses() {
+        let charmap = Charmap::parse(CHARMAP).unwrap();
+        let printed = charmap.to_string();
+        assert_eq!(Charmap::parse(&printed).unwrap().to_string(), printed);
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        let error = Charmap::parse("charmap A, $80\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 1: expected a string");
+
+        let error = Charmap::parse("\ncharmap \"A\" $80\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: expected `,` after the string");
+
+        let error = Charmap::parse("charmap \"A\", $100\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 1: invalid byte value");
+
+        let error = Charmap::parse("charmap \"\", $80\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 1: empty string");
+    }
+}
@@ -0,0 +1,317 @@
+use analyzer::RomAnalyzer;
+use data::*;
+use disassembly;
+use gb::*;
+use std::fmt::Write;
+
+/// `di` then `ld d,a`, keeping the CPU type the boot ROM leaves in A, as
+/// GBDK's crt0 starts.
+const GBDK_STARTUP: [u8; 2] = [0xF3, 0x57];
+/// The `rst $28` routine of Nintendo's games, jumping to the entry of the
+/// table after the call numbered by A.
+const NINTENDO_JUMP_TABLE: [u8; 12] = [
+    0x87, 0xE1, 0x5F, 0x16, 0x00, 0x19, 0x5E, 0x23, 0x56, 0xD5, 0xE1, 0xE9,
+];
+const NINTENDO_LICENSEE: &str = "01";
+
+/// Fewer than this many stack frames or argument clean ups could be hand
+/// written.
+const MIN_COMPILED: usize = 3;
+/// How far into a function SDCC sets up its stack frame.
+const PROLOGUE_LEN: usize = 4;
+
+fn starts_with(cart: &Cartridge, address: usize, bytes: &[u8]) -> bool {
+    cart.rom_size() >= address + bytes.len() && cart[address..address + bytes.len()] == *bytes
+}
+
+// `push hl`, `ld hl,handlers` and `jp`, after a `push af` in later releases
+fn is_gbdk_vector(cart: &Cartridge, address: usize) -> bool {
+    let address = if cart[address] == 0xF5 {
+        address + 1
+    } else {
+        address
+    };
+    cart[address] == 0xE5 && cart[address + 1] == 0x21 && cart[address + 4] == 0xC3
+}
+
+// Whether the function makes room for locals or reads arguments past its
+// return address before doing anything else
+fn has_stack_frame(instructions: &[Instruction]) -> bool {
+    instructions
+        .iter()
+        .take(PROLOGUE_LEN)
+        .any(|instruction| match instruction.op_code {
+            OpCode::ADD_SP_r8(value) => value.value < 0,
+            OpCode::LD_HL_SPp_r8(value) => value.value >= 2,
+            _ => false,
+        })
+}
+
+// Whether the function reads arguments past its return address before doing
+// anything else
+fn reads_stack_arguments(instructions: &[Instruction]) -> bool {
+    instructions
+        .iter()
+        .take(PROLOGUE_LEN)
+        .any(|instruction| match instruction.op_code {
+            OpCode::LD_HL_SPp_r8(value) => value.value >= 2,
+            _ => false,
+        })
+}
+
+// Calls followed by removing what was pushed for them, as only the caller
+// does with `__sdcccall(0)`
+fn argument_cleanups(instructions: &[Instruction]) -> usize {
+    instructions
+        .windows(2)
+        .filter(|pair| {
+            matches!(pair[0].op_code, OpCode::CALL_a16(_))
+                && pair[1].address == pair[0].address + pair[0].size()
+                && match pair[1].op_code {
+                    OpCode::ADD_SP_r8(value) => value.value > 0,
+                    OpCode::INC_SP => true,
+                    _ => false,
+                }
+        })
+        .count()
+}
+
+/// What the ROM's header, startup code and functions give away of the
+/// toolchain that built it.
+pub fn identify(cart: &Cartridge, data: &Data) -> Fingerprint {
+    let mut evidence = Vec::new();
+
+    let gbdk_startup = starts_with(cart, Cartridge::HEADER_END, &GBDK_STARTUP);
+    if gbdk_startup {
+        evidence.push("startup code at $0150 keeps the CPU type in D, as GBDK's does".to_string());
+    }
+    let gbdk_vectors = is_gbdk_vector(cart, 0x40) && is_gbdk_vector(cart, 0x48);
+    if gbdk_vectors {
+        evidence
+            .push("interrupt vectors jump to a handler list dispatcher, as GBDK's do".to_string());
+    }
+
+    let mut addresses: Vec<usize> = data
+        .functions
+        .iter()
+        .map(|(&address, _)| address)
+        .filter(|&address| address < ROM_END)
+        .collect();
+    addresses.sort();
+
+    let mut runtime = 0;
+    let mut stack_arguments = 0;
+    let mut register_arguments = 0;
+    let mut frames = 0;
+    let mut cleanups = 0;
+    for &address in &addresses {
+        // GBDK's assembly runtime starts its names with a dot, and SDCC
+        // puts an underscore before C names
+        let name = data
+            .signatures
+            .find(cart, address)
+            .filter(|name| name.starts_with('.') || name.starts_with('_'));
+        if name.is_some() {
+            runtime += 1;
+        }
+
+        let mut instructions = disassembly::collect_instructions(cart, data, address);
+        instructions.sort_by_key(|instruction| instruction.address);
+        instructions.dedup_by_key(|instruction| instruction.address);
+        if has_stack_frame(&instructions) {
+            frames += 1;
+        }
+        cleanups += argument_cleanups(&instructions);
+
+        // The C library was rebuilt for each calling convention
+        if name.is_some_and(|name| name.starts_with('_')) {
+            let takes_registers = data
+                .functions
+                .get(address)
+                .is_some_and(|function| !function.convention.inputs.is_empty());
+            if reads_stack_arguments(&instructions) {
+                stack_arguments += 1;
+            } else if takes_registers {
+                register_arguments += 1;
+            }
+        }
+    }
+    if runtime > 0 {
+        evidence.push(format!(
+            "{} functions match GBDK runtime signatures",
+            runtime
+        ));
+    }
+    if stack_arguments > 0 {
+        evidence.push(format!(
+            "{} GBDK C library functions read their arguments from the stack, as before 4.1",
+            stack_arguments
+        ));
+    }
+    if register_arguments > 0 {
+        evidence.push(format!(
+            "{} GBDK C library functions take their arguments in registers, as from 4.1",
+            register_arguments
+        ));
+    }
+    if frames > 0 {
+        evidence.push(format!("{} functions set up SDCC stack frames", frames));
+    }
+    if cleanups > 0 {
+        evidence.push(format!(
+            "{} calls are followed by removing their arguments from the stack",
+            cleanups
+        ));
+    }
+
+    let nintendo_licensee = cart.licensee() == NINTENDO_LICENSEE;
+    if nintendo_licensee {
+        evidence.push("the header names Nintendo as the licensee".to_string());
+    }
+    let nintendo_jump_table = starts_with(cart, 0x28, &NINTENDO_JUMP_TABLE);
+    if nintendo_jump_table {
+        evidence.push("rst $28 is Nintendo's jump table routine".to_string());
+    }
+
+    let compiled = frames >= MIN_COMPILED || cleanups >= MIN_COMPILED;
+    let toolchain = if gbdk_startup || gbdk_vectors || runtime > 0 {
+        Some(Toolchain::Gbdk)
+    } else if compiled {
+        Some(Toolchain::Sdcc)
+    } else if nintendo_licensee || nintendo_jump_table {
+        Some(Toolchain::Nintendo)
+    } else if addresses.len() > 1 {
+        evidence.push("nothing looks compiled".to_string());
+        Some(Toolchain::Rgbds)
+    } else {
+        None
+    };
+
+    let version = match (toolchain, stack_arguments, register_arguments) {
+        (Some(Toolchain::Gbdk), 1.., 0) => Some(GbdkVersion::Before4_1),
+        (Some(Toolchain::Gbdk), 0, 1..) => Some(GbdkVersion::Since4_1),
+        _ => None,
+    };
+
+    let sdcccall = match toolchain {
+        Some(Toolchain::Gbdk) | Some(Toolchain::Sdcc) if cleanups >= MIN_COMPILED => {
+            Some(SdccCall::Stack)
+        }
+        Some(Toolchain::Gbdk) | Some(Toolchain::Sdcc) if compiled => Some(SdccCall::Registers),
+        _ => None,
+    };
+
+    Fingerprint {
+        toolchain,
+        version,
+        sdcccall,
+        evidence,
+    }
+}
+
+/// Identifies the toolchain, which decides what calls to functions that
+/// aren't known are taken to read and how made up names look.
+pub struct ToolchainAnalyzer;
+
+impl RomAnalyzer for ToolchainAnalyzer {
+    fn run(&self, cart: &Cartridge, data: &mut Data) -> bool {
+        let fingerprint = identify(cart, data);
+        let changes = fingerprint != data.toolchain;
+        data.toolchain = fingerprint;
+
+        if starts_with(cart, 0x28, &NINTENDO_JUMP_TABLE) {
+            data.functions.suggest_name(0x28, "JumpTable".to_string());
+        }
+
+        changes
+    }
+}
+
+/// The toolchain and what gave it away.
+pub fn report(data: &Data) -> String {
+    let mut output = String::new();
+    let fingerprint = &data.toolchain;
+    let toolchain = fingerprint.toolchain.map_or("unknown", Toolchain::name);
+    match fingerprint.version {
+        Some(version) => writeln!(output, "Toolchain: {} {}", toolchain, version).unwrap(),
+        None => writeln!(output, "Toolchain: {}", toolchain).unwrap(),
+    }
+    if let Some(sdcccall) = fingerprint.sdcccall {
+        writeln!(output, "Calling convention: {}", sdcccall).unwrap();
+    }
+
+    writeln!(output).unwrap();
+    writeln!(output, "Evidence:").unwrap();
+    for evidence in &fingerprint.evidence {
+        writeln!(output, "    {}", evidence).unwrap();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+    use signatures::Signatures;
+
+    // jp $0150, then `startup` followed by calls to each function and `jr @`
+    fn cartridge(startup: &[u8], functions: &[(usize, &[u8])]) -> Cartridge {
+        let mut entry = startup.to_vec();
+        for &(address, _) in functions {
+            entry.extend_from_slice(&[0xCD, address as u8, (address >> 8) as u8]);
+        }
+        entry.extend_from_slice(&[0x18, 0xFE]);
+
+        let mut code: Vec<(usize, &[u8])> = vec![(0x100, &[0xC3, 0x50, 0x01]), (0x150, &entry)];
+        code.extend_from_slice(functions);
+        Cartridge::with_code(&code)
+    }
+
+    fn analyse(cart: &Cartridge, signatures: &str) -> Fingerprint {
+        let mut data = Data::new();
+        data.signatures = Signatures::parse(signatures).unwrap();
+        analyzer::analyse_functions(cart, data).toolchain
+    }
+
+    #[test]
+    fn finds_gbdk_by_its_startup_code() {
+        let cart = cartridge(&GBDK_STARTUP, &[(0x200, &[0xC9])]);
+        let fingerprint = analyse(&cart, "");
+        assert_eq!(fingerprint.toolchain, Some(Toolchain::Gbdk));
+        assert_eq!(fingerprint.version, None);
+    }
+
+    #[test]
+    fn finds_sdcc_by_its_stack_frames() {
+        // add sp,-2 / add sp,2 / ret
+        let frame: &[u8] = &[0xE8, 0xFE, 0xE8, 0x02, 0xC9];
+        let cart = cartridge(&[], &[(0x200, frame), (0x210, frame), (0x220, frame)]);
+        let fingerprint = analyse(&cart, "");
+        assert_eq!(fingerprint.toolchain, Some(Toolchain::Sdcc));
+        assert_eq!(fingerprint.sdcccall, Some(SdccCall::Registers));
+    }
+
+    #[test]
+    fn finds_nintendo_by_its_jump_table() {
+        let cart = cartridge(&[], &[(0x28, &NINTENDO_JUMP_TABLE)]);
+        let fingerprint = analyse(&cart, "");
+        assert_eq!(fingerprint.toolchain, Some(Toolchain::Nintendo));
+        assert_eq!(fingerprint.sdcccall, None);
+    }
+
+    #[test]
+    fn tells_gbdk_releases_apart_by_their_c_library() {
+        // ld hl,sp+2 / ld e,[hl] / inc hl / ld d,[hl] / ret
+        let cart = cartridge(
+            &GBDK_STARTUP,
+            &[(0x200, &[0xF8, 0x02, 0x5E, 0x23, 0x56, 0xC9])],
+        );
+        let fingerprint = analyse(&cart, "_set_data F8 02 5E 23 56 C9");
+        assert_eq!(fingerprint.version, Some(GbdkVersion::Before4_1));
+
+        // ld a,e / ld [bc],a / inc bc / ret
+        let cart = cartridge(&GBDK_STARTUP, &[(0x200, &[0x7B, 0x02, 0x03, 0xC9])]);
+        let fingerprint = analyse(&cart, "_set_data 7B 02 03 C9");
+        assert_eq!(fingerprint.version, Some(GbdkVersion::Since4_1));
+    }
+}
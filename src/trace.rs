@@ -0,0 +1,260 @@
+use analyzer::RomAnalyzer;
+use data::*;
+use disassembly;
+use gb::*;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+
+/// An address together with the ROM bank mapped at `$4000-$7FFF` when it was
+/// reached. The bank is 0 for everything outside that window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BankAddress {
+    pub bank: usize,
+    pub address: usize,
+}
+
+impl BankAddress {
+    /// Where an address of the flat address space lives, which has bank 1 in
+    /// the switchable window.
+    pub fn from_flat(address: usize) -> BankAddress {
+        BankAddress::new(1, address)
+    }
+
+    pub fn new(bank: usize, address: usize) -> BankAddress {
+        let bank = if (BANKED_ROM_START..ROM_END).contains(&address) {
+            bank
+        } else {
+            0
+        };
+        BankAddress { bank, address }
+    }
+
+    /// Reads `ROM1:4000`, `01:4000` or `$01:4000`, as emulators and symbol
+    /// files write them.
+    pub fn parse(text: &str) -> Option<BankAddress> {
+        let (bank, address) = text.split_once(':')?;
+        let bank = bank.trim_start_matches('$').trim_start_matches("ROM");
+        let address = address.trim_end_matches(|c: char| !c.is_ascii_alphanumeric());
+
+        if bank.is_empty() || address.len() != 4 {
+            return None;
+        }
+
+        let bank = usize::from_str_radix(bank, 16).ok()?;
+        let address = usize::from_str_radix(address, 16).ok()?;
+        Some(BankAddress::new(bank, address))
+    }
+
+    /// The address as the rest of the analysis sees it, which only knows
+    /// about bank 1 in the switchable window.
+    pub fn flat(&self) -> Option<usize> {
+        if (BANKED_ROM_START..ROM_END).contains(&self.address) && self.bank != 1 {
+            None
+        } else {
+            Some(self.address)
+        }
+    }
+}
+
+impl fmt::Display for BankAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02X}:{:04X}", self.bank, self.address)
+    }
+}
+
+/// What was seen while running the ROM, by us or another emulator.
+#[derive(Clone, Debug, Default)]
+pub struct Trace {
+    /// The addresses of executed instructions.
+    pub executed: HashSet<BankAddress>,
+    /// Bytes read as data.
+    pub read: HashSet<BankAddress>,
+    /// Where each indirect jump went, by the address of the jump.
+    pub indirect_jumps: HashMap<BankAddress, HashSet<BankAddress>>,
+    /// Interrupt handlers that were called.
+    pub interrupts: HashSet<usize>,
+    /// The ROM banks selected by each instruction that switched bank.
+    pub bank_switches: HashMap<BankAddress, HashSet<usize>>,
+}
+
+impl Trace {
+    pub fn merge(&mut self, other: Trace) {
+        self.executed.extend(other.executed);
+        self.read.extend(other.read);
+        self.interrupts.extend(other.interrupts);
+        for (jump, targets) in other.indirect_jumps {
+            self.indirect_jumps.entry(jump).or_default().extend(targets);
+        }
+        for (switch, banks) in other.bank_switches {
+            self.bank_switches.entry(switch).or_default().extend(banks);
+        }
+    }
+
+    /// Whether the byte at `address` was read as data but never run.
+    pub fn is_data(&self, address: usize) -> bool {
+        let address = BankAddress::from_flat(address);
+        self.read.contains(&address) && !self.executed.contains(&address)
+    }
+
+    // Executed instructions that nothing executed could have fallen through
+    // to, so they were jumped or called to
+    fn code_starts(&self) -> Vec<BankAddress> {
+        let mut starts: Vec<BankAddress> = self
+            .executed
+            .iter()
+            .filter(|address| {
+                (1..=3).all(|offset| {
+                    address.address < offset
+                        || !self
+                            .executed
+                            .contains(&BankAddress::new(address.bank, address.address - offset))
+                })
+            })
+            .cloned()
+            .collect();
+        starts.sort();
+        starts
+    }
+
+    /// Executed instructions that no function covers, as ranges within a
+    /// bank.
+    pub fn uncovered(&self, covered: &HashSet<usize>) -> Vec<(BankAddress, usize)> {
+        let mut addresses: Vec<BankAddress> = self
+            .executed
+            .iter()
+            .filter(|address| address.flat().is_none_or(|flat| !covered.contains(&flat)))
+            .cloned()
+            .collect();
+        addresses.sort();
+
+        let mut ranges: Vec<(BankAddress, usize)> = Vec::new();
+        for address in addresses {
+            // Instructions are at most three bytes long
+            match ranges.last_mut() {
+                Some((start, end)) if start.bank == address.bank && *end + 3 >= address.address => {
+                    *end = address.address
+                }
+                _ => ranges.push((address, address.address)),
+            }
+        }
+        ranges
+    }
+}
+
+/// The addresses of the instructions of every function.
+pub fn covered(cart: &Cartridge, data: &Data) -> HashSet<usize> {
+    data.functions
+        .iter()
+        .flat_map(|(&address, _)| disassembly::collect_instructions(cart, data, address))
+        .map(|instruction| instruction.address)
+        .collect()
+}
+
+/// Makes functions of the interrupt handlers and the targets of indirect
+/// jumps seen while running the ROM, as static analysis can't find them.
+/// Once those are all known, executed code no function reaches is made into
+/// functions where it starts.
+pub struct TraceAnalyzer;
+
+impl RomAnalyzer for TraceAnalyzer {
+    fn run(&self, cart: &Cartridge, data: &mut Data) -> bool {
+        let mut targets: Vec<usize> = data
+            .trace
+            .indirect_jumps
+            .values()
+            .flatten()
+            .filter_map(BankAddress::flat)
+            .chain(data.trace.interrupts.iter().cloned())
+            .filter(|&target| {
+                data.rom_address(target).is_some() && !data.decode_errors.contains_key(&target)
+            })
+            .collect();
+        targets.sort();
+
+        let mut changes = false;
+        for target in targets {
+            changes |= data.functions.get_or_add(target).0;
+        }
+        if changes {
+            return true;
+        }
+
+        let covered = covered(cart, data);
+        let starts: Vec<usize> = data
+            .trace
+            .code_starts()
+            .iter()
+            .filter_map(BankAddress::flat)
+            .filter(|&start| {
+                !covered.contains(&start)
+                    && data.rom_address(start).is_some()
+                    && !data.decode_errors.contains_key(&start)
+            })
+            .collect();
+
+        for start in starts {
+            changes |= data.functions.get_or_add(start).0;
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+
+    #[test]
+    fn parses_bank_addresses_as_emulators_write_them() {
+        let address = Some(BankAddress::new(1, 0x4000));
+        assert_eq!(BankAddress::parse("ROM1:4000"), address);
+        assert_eq!(BankAddress::parse("01:4000"), address);
+        assert_eq!(BankAddress::parse("$01:4000,"), address);
+        // Only the switchable window has a bank
+        assert_eq!(
+            BankAddress::parse("03:C000"),
+            Some(BankAddress::new(0, 0xC000))
+        );
+        assert_eq!(BankAddress::parse("4000"), None);
+    }
+
+    #[test]
+    fn groups_uncovered_code_into_ranges() {
+        let trace = Trace {
+            executed: [(0, 0x150), (0, 0x151), (0, 0x154), (0, 0x160), (2, 0x4000)]
+                .iter()
+                .map(|&(bank, address)| BankAddress::new(bank, address))
+                .collect(),
+            ..Trace::default()
+        };
+        let covered = [0x150].iter().cloned().collect();
+        assert_eq!(
+            trace.uncovered(&covered),
+            vec![
+                (BankAddress::new(0, 0x151), 0x154),
+                (BankAddress::new(0, 0x160), 0x160),
+                (BankAddress::new(2, 0x4000), 0x4000),
+            ]
+        );
+    }
+
+    #[test]
+    fn makes_functions_of_traced_jump_targets() {
+        // jp hl, which went to a ret at $0300
+        let cart = Cartridge::with_code(&[
+            (0x100, &[0xC3, 0x00, 0x02]),
+            (0x200, &[0xE9]),
+            (0x300, &[0xC9]),
+        ]);
+        let mut data = Data::new();
+        data.trace
+            .indirect_jumps
+            .entry(BankAddress::new(0, 0x200))
+            .or_default()
+            .insert(BankAddress::new(0, 0x300));
+
+        let data = analyzer::analyse_functions(&cart, data);
+        assert!(data.functions.is_start(0x300));
+    }
+}
@@ -0,0 +1,115 @@
+use constants;
+use constants::KnownState;
+use data::Data;
+use disassembly;
+use gb::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => f.pad("read"),
+            Access::Write => f.pad("write"),
+        }
+    }
+}
+
+/// An instruction reading or writing an address.
+#[derive(Clone, Copy, Debug)]
+pub struct Xref {
+    pub instruction: Instruction,
+    /// The function the instruction was found in.
+    pub function: usize,
+    pub access: Access,
+}
+
+/// The reads and writes of every address the functions found access,
+/// whether through a constant address or a register pair with a known
+/// value, like `ld hl, wHP` followed by `ld [hl], a`.
+pub struct Xrefs {
+    by_address: HashMap<usize, Vec<Xref>>,
+}
+
+impl Xrefs {
+    pub fn collect(cart: &Cartridge, data: &Data) -> Xrefs {
+        let mut functions: Vec<usize> =
+            data.functions.iter().map(|(&address, _)| address).collect();
+        functions.sort();
+
+        let mut by_address: HashMap<usize, Vec<Xref>> = HashMap::new();
+        // Code shared between functions is only counted once
+        let mut seen = HashSet::new();
+
+        for function in functions {
+            let chunks = disassembly::chunk(cart, data, function);
+            let known_values = constants::analyse(cart, data, &chunks);
+
+            for instruction in chunks.instructions() {
+                let state = known_values
+                    .at(instruction.address)
+                    .cloned()
+                    .unwrap_or_else(KnownState::unknown);
+
+                for (access, address) in constants::accesses(cart, data, state, instruction) {
+                    if seen.insert((address, instruction.address, access)) {
+                        by_address.entry(address).or_default().push(Xref {
+                            instruction: *instruction,
+                            function,
+                            access,
+                        });
+                    }
+                }
+            }
+        }
+
+        for xrefs in by_address.values_mut() {
+            xrefs.sort_by_key(|xref| (xref.instruction.address, xref.access));
+        }
+        Xrefs { by_address }
+    }
+
+    /// The reads and writes of `address`, in address order.
+    pub fn to(&self, address: usize) -> &[Xref] {
+        self.by_address
+            .get(&address)
+            .map_or(&[], |xrefs| xrefs.as_slice())
+    }
+
+    pub fn addresses(&self) -> Vec<usize> {
+        let mut addresses: Vec<usize> = self.by_address.keys().cloned().collect();
+        addresses.sort();
+        addresses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analyzer;
+
+    #[test]
+    fn follows_pointers_loaded_into_hl() {
+        // ld hl,$C100 / ld [hl],a / ld a,[$C101] / ret
+        let cart = Cartridge::with_function(&[0x21, 0x00, 0xC1, 0x77, 0xFA, 0x01, 0xC1, 0xC9]);
+        let data = analyzer::analyse_functions(&cart, Data::new());
+        let xrefs = Xrefs::collect(&cart, &data);
+
+        let accesses = |address| -> Vec<(usize, usize, Access)> {
+            xrefs
+                .to(address)
+                .iter()
+                .map(|xref| (xref.instruction.address, xref.function, xref.access))
+                .collect()
+        };
+        assert_eq!(accesses(0xC100), vec![(0x203, 0x200, Access::Write)]);
+        assert_eq!(accesses(0xC101), vec![(0x204, 0x200, Access::Read)]);
+        assert!(xrefs.to(0xC102).is_empty());
+    }
+}